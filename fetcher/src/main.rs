@@ -2,9 +2,9 @@ use adblock::engine::Engine;
 use adblock::lists::{FilterSet, ParseOptions};
 use read_transform::ReadTransformer;
 use std::fmt::Debug;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{BufRead, BufReader, BufWriter, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::thread::spawn;
 
@@ -24,77 +24,184 @@ impl Num for u128 {
     }
 }
 
-fn download_geo_ip<N: Num>(url: &str, file_name: &Path)
+// Writes the new content of `file_name` to a temporary file next to it, and only moves it
+// in place when `write` succeeds with at least one record, so a bad download keeps the
+// previous file.
+fn replace_file(
+    file_name: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<usize, String>,
+) -> Result<(), String> {
+    let mut tmp_name = file_name.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_name = PathBuf::from(tmp_name);
+
+    let result = File::create(&tmp_name)
+        .map_err(|e| format!("Creating {tmp_name:?}: {e}"))
+        .and_then(|file| {
+            let mut output = BufWriter::new(file);
+            let records = write(&mut output)?;
+            if records == 0 {
+                return Err(format!("No records for {file_name:?}"));
+            }
+            let file = output.into_inner().map_err(|e| e.to_string())?;
+            file.sync_all().map_err(|e| e.to_string())
+        })
+        .and_then(|_| {
+            fs::rename(&tmp_name, file_name).map_err(|e| format!("Replacing {file_name:?}: {e}"))
+        });
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_name);
+    }
+    result
+}
+
+fn download(url: &str) -> Result<impl BufRead, String> {
+    let res = ureq::get(url)
+        .call()
+        .map_err(|e| format!("Downloading {url}: {e}"))?;
+    Ok(BufReader::new(res.into_reader()))
+}
+
+// Returns the number of ranges written
+fn write_geo_ip<N: Num>(mut input: impl BufRead, output: &mut impl Write) -> Result<usize, String>
 where
     <N as FromStr>::Err: Debug,
 {
-    let mut output = BufWriter::new(File::create(file_name).unwrap());
-    let mut res = BufReader::new(ureq::get(url).call().unwrap().into_reader());
-
     let mut line = Default::default();
     let mut last_start = None;
+    let mut records = 0;
 
-    while res.read_line(&mut line).unwrap() > 0 {
+    while input.read_line(&mut line).map_err(|e| e.to_string())? > 0 {
         {
             let line = line.trim_matches('\n');
             let mut splits = line.split(',');
-            let start: N = splits.next().unwrap().parse().unwrap();
-            let end: N = splits.next().unwrap().parse().unwrap();
-            let code = splits.next().unwrap();
+            let (start, end, code) = match (splits.next(), splits.next(), splits.next()) {
+                (Some(start), Some(end), Some(code)) => (start, end, code),
+                _ => return Err(format!("Invalid line {line}")),
+            };
+            let start: N = start
+                .parse()
+                .map_err(|e| format!("Invalid start {start}: {e:?}"))?;
+            let end: N = end
+                .parse()
+                .map_err(|e| format!("Invalid end {end}: {e:?}"))?;
             if code.as_bytes().len() != 2 {
-                panic!("Invalid country code {code}");
+                return Err(format!("Invalid country code {code}"));
             }
 
             match last_start {
-                Some(last) if last > start => panic!("Order is wrong!"),
+                Some(last) if last > start => return Err("Order is wrong!".to_string()),
                 _ => {}
             };
             last_start = Some(start);
 
-            start.write_be(&mut output);
-            end.write_be(&mut output);
+            start.write_be(output);
+            end.write_be(output);
             output.write_all(code.as_bytes()).unwrap();
+            records += 1;
         }
 
         line.clear();
     }
+
+    Ok(records)
 }
 
-fn download_gfw_list(url: &str, output: &Path) {
-    let mut output = File::create(output).unwrap();
-    let mut reader = ReadTransformer::new(
-        ureq::get(url).call().unwrap().into_reader(),
-        256,
-        Box::new(|buf: &mut [u8], _, _| {
-            return Some((
-                buf.iter().filter(|x| **x != b'\n').map(|x| *x).collect(),
-                buf.len(),
-            ));
-        }),
-    );
-
-    let mut reader = BufReader::new(base64::read::DecoderReader::new(
-        &mut reader,
-        base64::STANDARD_NO_PAD,
-    ));
+fn download_geo_ip<N: Num>(url: &str, file_name: &Path) -> Result<(), String>
+where
+    <N as FromStr>::Err: Debug,
+{
+    replace_file(file_name, |output| {
+        write_geo_ip::<N>(download(url)?, output)
+    })
+}
 
+// Returns the number of ranges written
+fn write_asn(mut input: impl BufRead, output: &mut impl Write) -> Result<usize, String> {
     let mut line = Default::default();
-    let mut fs = FilterSet::new(true);
-    while reader.read_line(&mut line).unwrap() > 0 {
+    let mut last_start = None;
+    let mut records = 0;
+
+    while input.read_line(&mut line).map_err(|e| e.to_string())? > 0 {
         {
-            let line = line.trim_matches('\n').trim();
-            if !line.is_empty() && !line.starts_with('!') {
-                fs.add_filter(line, ParseOptions::default()).unwrap();
-            }
+            let line = line.trim_matches('\n');
+            let mut splits = line.split(',');
+            let (start, end, asn) = match (splits.next(), splits.next(), splits.next()) {
+                (Some(start), Some(end), Some(asn)) => (start, end, asn),
+                _ => return Err(format!("Invalid line {line}")),
+            };
+            let start: u32 = start
+                .parse()
+                .map_err(|e| format!("Invalid start {start}: {e}"))?;
+            let end: u32 = end.parse().map_err(|e| format!("Invalid end {end}: {e}"))?;
+            let asn: u32 = asn.parse().map_err(|e| format!("Invalid ASN {asn}: {e}"))?;
+
+            match last_start {
+                Some(last) if last > start => return Err("Order is wrong!".to_string()),
+                _ => {}
+            };
+            last_start = Some(start);
+
+            start.write_be(output);
+            end.write_be(output);
+            asn.write_be(output);
+            records += 1;
         }
 
         line.clear();
     }
 
-    let engine = Engine::from_filter_set(fs, true);
-    output
-        .write_all(engine.serialize_compressed().unwrap().as_slice())
-        .unwrap();
+    Ok(records)
+}
+
+fn download_asn(url: &str, file_name: &Path) -> Result<(), String> {
+    replace_file(file_name, |output| write_asn(download(url)?, output))
+}
+
+fn download_gfw_list(url: &str, output: &Path) -> Result<(), String> {
+    replace_file(output, |output| {
+        let mut reader = ReadTransformer::new(
+            ureq::get(url)
+                .call()
+                .map_err(|e| format!("Downloading {url}: {e}"))?
+                .into_reader(),
+            256,
+            Box::new(|buf: &mut [u8], _, _| {
+                return Some((
+                    buf.iter().filter(|x| **x != b'\n').map(|x| *x).collect(),
+                    buf.len(),
+                ));
+            }),
+        );
+
+        let mut reader = BufReader::new(base64::read::DecoderReader::new(
+            &mut reader,
+            base64::STANDARD_NO_PAD,
+        ));
+
+        let mut line = Default::default();
+        let mut fs = FilterSet::new(true);
+        let mut filters = 0;
+        while reader.read_line(&mut line).map_err(|e| e.to_string())? > 0 {
+            {
+                let line = line.trim_matches('\n').trim();
+                if !line.is_empty() && !line.starts_with('!') {
+                    fs.add_filter(line, ParseOptions::default())
+                        .map_err(|e| format!("Invalid filter {line}: {e:?}"))?;
+                    filters += 1;
+                }
+            }
+
+            line.clear();
+        }
+
+        let engine = Engine::from_filter_set(fs, true);
+        output
+            .write_all(engine.serialize_compressed().unwrap().as_slice())
+            .map_err(|e| e.to_string())?;
+        Ok(filters)
+    })
 }
 
 fn main() {
@@ -112,6 +219,15 @@ fn main() {
         })
     };
 
+    let download_asn = {
+        let out_dir = out_dir.clone();
+        spawn(move || {
+            download_asn(
+                "https://raw.githubusercontent.com/sapics/ip-location-db/master/asn/asn-ipv4-num.csv",
+                &Path::new(out_dir.as_str()).join("geoip").join("asn_ipv4.dat"))
+        })
+    };
+
     // let download_ipv6 = {
     //     let out_dir = out_dir.clone();
     //     spawn(move || {
@@ -126,6 +242,40 @@ fn main() {
     //     &Path::new(out_dir.as_str()).join("abp").join("gfw_list.dat"),
     // );
 
-    download_ipv4.join().unwrap();
-    // download_ipv6.join().unwrap();
+    download_ipv4.join().unwrap().unwrap();
+    download_asn.join().unwrap().unwrap();
+    // download_ipv6.join().unwrap().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bad_download_keeps_previous_file() {
+        let dir = std::env::temp_dir().join(format!("fetcher-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file_name = dir.join("ipv4.dat");
+
+        let good = "16777216,16777471,AU\n16777472,16778239,CN\n";
+        replace_file(&file_name, |output| {
+            write_geo_ip::<u32>(good.as_bytes(), output)
+        })
+        .unwrap();
+        let previous = fs::read(&file_name).unwrap();
+        assert_eq!(previous.len(), 2 * 10);
+
+        // Garbage and empty downloads are both rejected, leaving the file as it was
+        for bad in ["<html>Service Unavailable</html>\n", ""] {
+            assert!(replace_file(&file_name, |output| write_geo_ip::<u32>(
+                bad.as_bytes(),
+                output
+            ))
+            .is_err());
+            assert_eq!(fs::read(&file_name).unwrap(), previous);
+        }
+        assert!(!dir.join("ipv4.dat.tmp").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -1,13 +1,21 @@
+mod whitelist;
+
 use std::fmt::{Debug, Formatter};
 use std::{
     borrow::Cow,
     io::Read,
     path::{Path, PathBuf},
-    sync::RwLock,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        RwLock,
+    },
     time::{Duration, SystemTime},
 };
 
-use crate::{fetch::fetch_http_with_proxy, socks5::Address};
+use crate::{
+    fetch::{fetch_http_following_redirects, DEFAULT_MAX_REDIRECTS},
+    socks5::Address,
+};
 use adblock::{
     engine::Engine,
     lists::{FilterSet, ParseOptions},
@@ -17,7 +25,136 @@ use chrono::{DateTime, Utc};
 use futures::AsyncWriteExt;
 use lazy_static::lazy_static;
 use rust_embed::RustEmbed;
-use smol::fs::{create_dir_all, File};
+use serde::{Deserialize, Serialize};
+use smol::{
+    fs::{create_dir_all, File},
+    Timer,
+};
+
+pub use whitelist::HostWhitelist;
+
+// The max number of rules to build into one engine. Building an engine from a large
+// FilterSet at once takes a lot of memory, so the rules are split into batches and each
+// batch gets its own engine.
+const ENGINE_BATCH_SIZE: usize = 10000;
+
+// Serialized engine sets start with this, followed by |len(u32 BE)|engine|...
+// Anything else is treated as a single serialized engine.
+const ENGINE_SET_MAGIC: &[u8] = b"CPXYABPSET1";
+
+// How many times a rule list is downloaded before giving up, unless configured otherwise
+pub const DEFAULT_FETCH_ATTEMPTS: usize = 3;
+
+// The wait before the first retry, doubled after each failed attempt up to the max
+const FETCH_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_FETCH_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy)]
+struct FetchRetry {
+    max_attempts: usize,
+    initial_delay: Duration,
+}
+
+impl FetchRetry {
+    fn new(max_attempts: Option<usize>) -> Self {
+        Self {
+            max_attempts: max_attempts.unwrap_or(DEFAULT_FETCH_ATTEMPTS).max(1),
+            initial_delay: FETCH_RETRY_DELAY,
+        }
+    }
+}
+
+struct EngineSet(Vec<Engine>);
+
+// Whether the rule carries the `$badfilter` option, as opposed to merely mentioning it
+fn is_badfilter(line: &str) -> bool {
+    line.rsplit_once('$')
+        .is_some_and(|(_, options)| options.split(',').any(|o| o.trim() == "badfilter"))
+}
+
+impl EngineSet {
+    // Exception and badfilter rules have to live alongside the rules they apply to,
+    // so they are added to every batch.
+    fn build(rules: &[u8], batch_size: usize) -> (Self, usize) {
+        let lines = || {
+            rules
+                .split(|x| *x == b'\n')
+                .map(|line| String::from_utf8_lossy(line).trim().to_string())
+                .filter(|line| !line.starts_with('#') && !line.starts_with('!') && !line.is_empty())
+        };
+        let is_shared = |line: &str| line.starts_with("@@") || is_badfilter(line);
+
+        let shared: Vec<String> = lines().filter(|line| is_shared(line)).collect();
+        let mut line_count = 0;
+        let mut engines = Vec::new();
+        let mut batch = Vec::new();
+
+        let build_engine = |batch: &mut Vec<String>, line_count: &mut usize| {
+            let mut filter_set = FilterSet::new(true);
+            for line in shared.iter().chain(batch.iter()) {
+                if let Err(err) = filter_set.add_filter(line, ParseOptions::default()) {
+                    log::error!("Error pasing rule: '{line}': {err:?}");
+                } else if !is_shared(line) {
+                    *line_count += 1;
+                }
+            }
+            batch.clear();
+            Engine::from_filter_set(filter_set, true)
+        };
+
+        for line in lines().filter(|line| !is_shared(line)) {
+            batch.push(line);
+            if batch.len() >= batch_size {
+                engines.push(build_engine(&mut batch, &mut line_count));
+            }
+        }
+
+        if !batch.is_empty() || engines.is_empty() {
+            engines.push(build_engine(&mut batch, &mut line_count));
+        }
+
+        (Self(engines), line_count + shared.len())
+    }
+
+    fn deserialize(data: &[u8]) -> anyhow::Result<Self> {
+        let mut engines = Vec::new();
+        match data.strip_prefix(ENGINE_SET_MAGIC) {
+            Some(mut data) => {
+                while !data.is_empty() {
+                    if data.len() < 4 {
+                        bail!("Invalid engine set data");
+                    }
+                    let len = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+                    let engine = data
+                        .get(4..4 + len)
+                        .ok_or_else(|| anyhow!("Invalid engine set data"))?;
+                    engines.push(create_engine(engine)?);
+                    data = &data[4 + len..];
+                }
+            }
+            None => engines.push(create_engine(data)?),
+        }
+        Ok(Self(engines))
+    }
+
+    fn serialize(&self) -> anyhow::Result<Vec<u8>> {
+        let mut buf = ENGINE_SET_MAGIC.to_vec();
+        for engine in &self.0 {
+            let data = engine
+                .serialize_compressed()
+                .map_err(|e| anyhow!("Error serializing adblock engine: {e:?}"))?;
+            buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            buf.extend_from_slice(&data);
+        }
+        Ok(buf)
+    }
+
+    fn matches(&self, url: &str) -> bool {
+        self.0
+            .iter()
+            .any(|engine| engine.check_network_urls(url, url, "").matched)
+    }
+}
 
 fn create_engine(data: &[u8]) -> anyhow::Result<Engine> {
     let mut engine = Engine::new(true);
@@ -28,144 +165,298 @@ fn create_engine(data: &[u8]) -> anyhow::Result<Engine> {
 }
 
 struct EngineState {
-    engine: Option<(Engine, SystemTime)>,
+    engine: Option<(EngineSet, SystemTime)>,
+    // Where the engine is persisted, `None` if it isn't
     cache_file_path: Option<PathBuf>,
+    cache_file_name: String,
+    // The embedded rules to fall back on, if they haven't been loaded yet
+    pending_asset: Option<String>,
 }
 
 #[derive(RustEmbed)]
 #[folder = "src/abp/dat"]
 struct Asset;
 
+// The data directory, unless a cache directory is configured
+fn default_cache_dir() -> Option<PathBuf> {
+    dirs::data_dir().map(|mut r| {
+        r.push("cjk_proxy");
+        r.push("abp");
+        r
+    })
+}
+
+fn cache_file_path(cache_dir: Option<&Path>, file_name: &str) -> Option<PathBuf> {
+    let dir = match cache_dir {
+        Some(dir) => Some(dir.to_path_buf()),
+        None => default_cache_dir(),
+    };
+    if dir.is_none() {
+        log::warn!("No data directory to cache {file_name} in, rule lists won't be persisted");
+    }
+    dir.map(|dir| dir.join(file_name))
+}
+
 impl EngineState {
-    fn engine_from_file(p: &Path) -> anyhow::Result<(Engine, SystemTime)> {
+    fn engine_from_file(p: &Path) -> anyhow::Result<(EngineSet, SystemTime)> {
         let meta = std::fs::metadata(p)?;
         let mut file = std::fs::File::open(p)?;
         let mut buf = Vec::with_capacity(meta.len() as usize);
         let _ = file.read_to_end(&mut buf)?;
         Ok((
-            create_engine(buf.as_ref())?,
+            EngineSet::deserialize(buf.as_ref())?,
             meta.modified().unwrap_or(SystemTime::UNIX_EPOCH),
         ))
     }
 
-    fn new(file_name: &str) -> Self {
-        let cache_file_path = dirs::data_dir().map(|mut r| {
-            r.push("cjk_proxy");
-            r.push("abp");
-            r.push(file_name);
-            r
-        });
-
-        let engine = match &cache_file_path {
-            Some(p) => match Self::engine_from_file(p.as_path()) {
-                Ok(v) => Some(v),
-                Err(e) => {
-                    log::error!("Error reading file: {p:?}: {e:#}");
-                    None
-                }
-            },
-            _ => None,
-        };
+    fn load_cached(cache_file_path: Option<&Path>) -> Option<(EngineSet, SystemTime)> {
+        let p = cache_file_path?;
+        match Self::engine_from_file(p) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                log::error!("Error reading file: {p:?}: {e:#}");
+                None
+            }
+        }
+    }
 
+    // Cached under `cache_dir`, or the default cache directory if not given
+    fn new(cache_dir: Option<&Path>, file_name: &str) -> Self {
+        let cache_file_path = cache_file_path(cache_dir, file_name);
         Self {
-            engine,
+            engine: Self::load_cached(cache_file_path.as_deref()),
             cache_file_path,
+            cache_file_name: file_name.to_string(),
+            pending_asset: None,
+        }
+    }
+
+    // Moves the cache, picking up what's been cached there already
+    fn set_cache_dir(&mut self, cache_dir: Option<&Path>) {
+        let cache_file_path = cache_file_path(cache_dir, &self.cache_file_name);
+        if cache_file_path == self.cache_file_path {
+            return;
+        }
+
+        if self.pending_asset.is_some() {
+            self.cache_file_path = cache_file_path;
+            return;
         }
+
+        if let Some(engine) = Self::load_cached(cache_file_path.as_deref()) {
+            self.engine = Some(engine);
+        }
+        self.cache_file_path = cache_file_path;
     }
 
+    // Nothing is read until `load_pending` is called, so an engine that's never used
+    // doesn't pay for deserializing its rules.
     fn from_embedded(asset_name: &str, cache_file_name: &str) -> Self {
-        let mut r = Self::new(cache_file_name);
-        if r.engine.is_some() {
-            return r;
+        Self {
+            engine: None,
+            cache_file_path: cache_file_path(None, cache_file_name),
+            cache_file_name: cache_file_name.to_string(),
+            pending_asset: Some(asset_name.to_string()),
         }
+    }
 
-        if let Some(f) = Asset::get(asset_name) {
-            if let Ok(engine) = create_engine(f.data.as_ref()) {
-                r.engine = Some((
+    // Loads the cached engine, or the embedded one if nothing was cached
+    fn load_pending(&mut self) {
+        let Some(asset_name) = self.pending_asset.take() else {
+            return;
+        };
+
+        self.engine = Self::load_cached(self.cache_file_path.as_deref());
+        if self.engine.is_some() {
+            return;
+        }
+
+        if let Some(f) = Asset::get(&asset_name) {
+            if let Ok(engine) = EngineSet::deserialize(f.data.as_ref()) {
+                self.engine = Some((
                     engine,
                     SystemTime::UNIX_EPOCH
                         + Duration::from_secs(f.metadata.last_modified().unwrap_or_default()),
                 ))
             }
         }
+    }
+}
 
-        r
+// Each source's rules are cached next to the engine, so the engine can be rebuilt when
+// only some of the sources have changed.
+fn source_cache_path(engine_cache_path: &Path, url: &str) -> PathBuf {
+    // FNV-1a, to have a stable file name for the url
+    let hash = url.bytes().fold(0xcbf29ce484222325u64, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    let mut name = engine_cache_path
+        .file_name()
+        .unwrap_or_default()
+        .to_os_string();
+    name.push(format!(".{hash:016x}.txt"));
+    engine_cache_path.with_file_name(name)
+}
+
+fn decode_rules(mut body: Vec<u8>, is_base64: bool) -> anyhow::Result<Vec<u8>> {
+    if is_base64 {
+        // Remove new lines first
+        body.retain(|x| *x != b'\r' && *x != b'\n');
+        body = base64::decode(body)?;
     }
+    Ok(body)
 }
 
-async fn update_engine(
-    state: &RwLock<EngineState>,
+// Returns the decoded rules if the list has changed since it was last cached. Failed
+// downloads are retried with backoff, asking about the same cached copy each time.
+async fn fetch_source(
     proxy: &Address<'_>,
     rule_list_url: &str,
     is_base64: bool,
-) -> anyhow::Result<usize> {
+    cache_path: Option<&Path>,
+    retry: FetchRetry,
+    max_redirects: usize,
+) -> anyhow::Result<Option<Vec<u8>>> {
     log::info!("Downloading rule list: {rule_list_url}");
 
-    let last_modified = match state.read() {
-        Ok(g) => {
-            if let EngineState {
-                engine: Some((_, t)),
-                ..
-            } = &*g
-            {
-                Some(t.clone())
-            } else {
-                None
+    let last_modified = cache_path
+        .and_then(|p| std::fs::metadata(p).ok())
+        .and_then(|m| m.modified().ok())
+        .map(|v| {
+            DateTime::<chrono::Utc>::from(v)
+                .format("%a, %d %b %Y %H:%M:%S GMT")
+                .to_string()
+        });
+
+    let mut delay = retry.initial_delay;
+    let mut attempt = 1;
+    let body = loop {
+        let headers = last_modified
+            .iter()
+            .map(|v| {
+                (
+                    Cow::Borrowed("If-Modified-Since"),
+                    Cow::Owned(v.clone().into_bytes()),
+                )
+            })
+            .collect();
+
+        let result = match fetch_http_following_redirects(
+            rule_list_url,
+            "GET",
+            headers,
+            proxy,
+            None,
+            max_redirects,
+        )
+        .await
+        {
+            Ok(mut r) if r.status_code == 200 => r.body().await,
+            Ok(r) if r.status_code == 304 => return Ok(None),
+            Ok(r) => Err(anyhow!(
+                "Invalid http response from {rule_list_url}: {}",
+                r.status_code
+            )),
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(body) => break body,
+            Err(e) if attempt >= retry.max_attempts => return Err(e),
+            Err(e) => {
+                log::warn!(
+                    "Error downloading {rule_list_url} (attempt {attempt}/{}), retrying in {delay:?}: {e:#}",
+                    retry.max_attempts
+                );
+                Timer::after(delay).await;
+                delay = (delay * 2).min(MAX_FETCH_RETRY_DELAY);
+                attempt += 1;
             }
         }
-        Err(_) => bail!("Error locking state"),
     };
 
-    let last_modified = last_modified
-        .map(|v| DateTime::<chrono::Utc>::from(v).format("%a, %d %b %Y %H:%M:%S GMT"))
-        .into_iter()
-        .map(|v| {
-            (
-                Cow::Borrowed("If-Modified-Since"),
-                Cow::Owned(v.to_string().into_bytes()),
-            )
-        });
+    let body = decode_rules(body, is_base64)?;
+    if let Some(p) = cache_path {
+        write_file(p, &body).await?;
+    }
+    Ok(Some(body))
+}
 
-    let mut body =
-        match fetch_http_with_proxy(rule_list_url, "GET", last_modified, proxy, None).await? {
-            mut r if r.status_code == 200 => r.body().await?,
-            r if r.status_code == 304 => return Ok(0),
-            r => bail!("Invalid http response: {}", r.status_code),
-        };
+async fn write_file(p: &Path, buf: &[u8]) -> anyhow::Result<()> {
+    if let Some(parent) = p.parent() {
+        create_dir_all(parent).await?;
+    }
 
-    if is_base64 {
-        // Remove new lines first
-        body.retain(|x| *x != b'\r' && *x != b'\n');
-        body = base64::decode(body)?;
+    let mut file = File::create(p).await?;
+    file.write_all(buf).await?;
+    file.flush().await?;
+    Ok(())
+}
+
+// All the lists are merged into one set of rules
+fn build_combined(lists: impl IntoIterator<Item = Vec<u8>>) -> (EngineSet, usize) {
+    let mut rules = Vec::new();
+    for list in lists {
+        rules.extend_from_slice(&list);
+        rules.push(b'\n');
     }
+    EngineSet::build(&rules, ENGINE_BATCH_SIZE)
+}
 
-    let mut filter_set = FilterSet::new(true);
-    let mut line_count = 0;
+// Rebuilds the engine if any list has changed, or regardless when `rebuild` is set
+async fn update_engine(
+    state: &RwLock<EngineState>,
+    proxy: &Address<'_>,
+    sources: &[(String, bool)],
+    rebuild: bool,
+    retry: FetchRetry,
+    max_redirects: usize,
+) -> anyhow::Result<usize> {
+    let (cache_file_path, has_engine) = match state.read() {
+        Ok(g) => (g.cache_file_path.clone(), g.engine.is_some()),
+        Err(_) => bail!("Error locking state"),
+    };
 
-    for line in body.split(|x| *x == b'\n') {
-        let line = String::from_utf8_lossy(line);
-        let line = line.trim();
-        if line.starts_with("#") || line.starts_with("!") || line.is_empty() {
-            continue;
-        }
+    let mut lists = Vec::with_capacity(sources.len());
+    for (url, is_base64) in sources {
+        let cache_path = cache_file_path
+            .as_deref()
+            .map(|p| source_cache_path(p, url));
+        let list = fetch_source(
+            proxy,
+            url,
+            *is_base64,
+            cache_path.as_deref(),
+            retry,
+            max_redirects,
+        )
+        .await?;
+        lists.push((list, cache_path));
+    }
 
-        if let Err(err) = filter_set.add_filter(line, ParseOptions::default()) {
-            log::error!("Error pasing rule: '{line}': {err:?}");
-        } else {
-            line_count += 1;
-        }
+    if has_engine && !rebuild && lists.iter().all(|(list, _)| list.is_none()) {
+        return Ok(0);
     }
-    drop(body);
 
+    // Unchanged lists come from the cache
+    let lists = lists
+        .into_iter()
+        .map(|(list, cache_path)| match (list, cache_path) {
+            (Some(list), _) => Ok(list),
+            (None, Some(p)) => Ok(std::fs::read(p)?),
+            (None, None) => bail!("Rule list unavailable"),
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let (new_engine, line_count) = build_combined(lists);
     let last_updated = SystemTime::now();
-    let new_engine = Engine::from_filter_set(filter_set, true);
 
     let (file_to_write, contents) = match state.write() {
         Ok(mut g) => {
             let contents = g
                 .cache_file_path
                 .as_ref()
-                .and_then(|_| new_engine.serialize_compressed().ok());
+                .and_then(|_| new_engine.serialize().ok());
             g.engine = Some((new_engine, last_updated));
             (g.cache_file_path.clone(), contents)
         }
@@ -173,13 +464,7 @@ async fn update_engine(
     };
 
     if let (Some(p), Some(buf)) = (file_to_write, contents) {
-        if let Some(parent) = p.parent() {
-            create_dir_all(parent).await?;
-        }
-
-        let mut file = File::create(p).await?;
-        file.write_all(buf.as_ref()).await?;
-        file.flush().await?;
+        write_file(&p, &buf).await?;
     }
 
     Ok(line_count)
@@ -201,27 +486,147 @@ fn matches_abp(state: &RwLock<EngineState>, addr: &Address) -> bool {
         _ => format!("http://{}", addr.get_host()),
     };
 
-    engine
-        .check_network_urls(url.as_str(), url.as_str(), "")
-        .matched
+    engine.matches(url.as_str())
+}
+
+// A rule list to download, as it's written in the config
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AbpSource {
+    pub url: String,
+    // Whether the list is base64 encoded, as the gfw list is
+    #[serde(default)]
+    pub base64: bool,
 }
 
 pub struct ABPEngine {
     state: RwLock<EngineState>,
-    rule_url: &'static str,
-    is_base64: bool,
+    default_sources: Vec<(String, bool)>,
+    sources: RwLock<Vec<(String, bool)>>,
+    // Set when the sources change, so the next update rebuilds even if no list has
+    sources_changed: AtomicBool,
+    whitelist: RwLock<HostWhitelist>,
+    enabled: AtomicBool,
 }
 
 impl ABPEngine {
-    pub async fn update(&self, proxy: &Address<'_>) -> anyhow::Result<usize> {
-        update_engine(&self.state, proxy, self.rule_url, self.is_base64).await
+    // Creates an engine combining the given `(url, is_base64)` rule lists, cached under
+    // `cache_file_name`.
+    pub fn new(sources: Vec<(String, bool)>, cache_file_name: &str) -> Self {
+        Self::with_state(EngineState::new(None, cache_file_name), sources)
+    }
+
+    fn with_state(state: EngineState, sources: Vec<(String, bool)>) -> Self {
+        Self {
+            state: RwLock::new(state),
+            sources: RwLock::new(sources.clone()),
+            default_sources: sources,
+            sources_changed: AtomicBool::new(false),
+            whitelist: Default::default(),
+            enabled: AtomicBool::new(true),
+        }
+    }
+
+    // Replaces the rule lists the engine is built from, or goes back to the ones it was
+    // created with if `None`. Takes effect on the next update.
+    pub fn set_sources(&self, sources: Option<&[AbpSource]>) {
+        let sources = match sources {
+            Some(sources) => sources.iter().map(|s| (s.url.clone(), s.base64)).collect(),
+            None => self.default_sources.clone(),
+        };
+
+        if let Ok(mut g) = self.sources.write() {
+            if *g != sources {
+                *g = sources;
+                self.sources_changed.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // A disabled engine matches nothing and isn't updated
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    // Whitelisted hosts never match, regardless of the rule lists
+    pub fn set_whitelist(&self, whitelist: HostWhitelist) {
+        if let Ok(mut g) = self.whitelist.write() {
+            *g = whitelist;
+        }
+    }
+
+    // Where the engine and its lists are cached, the data directory if `None`
+    pub fn set_cache_dir(&self, cache_dir: Option<&Path>) {
+        if let Ok(mut g) = self.state.write() {
+            g.set_cache_dir(cache_dir);
+        }
+    }
+
+    fn load_pending(&self) {
+        let pending = match self.state.read() {
+            Ok(g) => g.pending_asset.is_some(),
+            Err(_) => false,
+        };
+        if pending {
+            if let Ok(mut g) = self.state.write() {
+                g.load_pending();
+            }
+        }
+    }
+
+    // Each list is tried up to `max_attempts` times, `DEFAULT_FETCH_ATTEMPTS` if not given,
+    // following up to `max_redirects` redirects, `DEFAULT_MAX_REDIRECTS` if not given
+    pub async fn update(
+        &self,
+        proxy: &Address<'_>,
+        max_attempts: Option<usize>,
+        max_redirects: Option<usize>,
+    ) -> anyhow::Result<usize> {
+        if !self.is_enabled() {
+            bail!("Rule list is disabled");
+        }
+        self.load_pending();
+
+        let sources = match self.sources.read() {
+            Ok(g) => g.clone(),
+            Err(_) => bail!("Error locking sources"),
+        };
+        let rebuild = self.sources_changed.swap(false, Ordering::Relaxed);
+        let result = update_engine(
+            &self.state,
+            proxy,
+            &sources,
+            rebuild,
+            FetchRetry::new(max_attempts),
+            max_redirects.unwrap_or(DEFAULT_MAX_REDIRECTS),
+        )
+        .await;
+        if result.is_err() && rebuild {
+            self.sources_changed.store(true, Ordering::Relaxed);
+        }
+        result
     }
 
     pub fn matches(&self, target: &Address<'_>) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+        self.load_pending();
+
+        if let Ok(whitelist) = self.whitelist.read() {
+            if whitelist.contains(&target.get_host()) {
+                return false;
+            }
+        }
+
         matches_abp(&self.state, target)
     }
 
     pub fn get_last_updated(&self) -> anyhow::Result<Option<DateTime<Utc>>> {
+        self.load_pending();
         let g = match self.state.read() {
             Ok(g) => g,
             Err(_) => bail!("Error locking state"),
@@ -233,22 +638,30 @@ impl ABPEngine {
 
 pub fn adblock_list_engine() -> &'static ABPEngine {
     lazy_static! {
-        static ref ENGINE: ABPEngine = ABPEngine {
-            state: RwLock::new(EngineState::new("abplist.abp")),
-            rule_url: "https://easylist.to/easylist/easylist.txt",
-            is_base64: false
-        };
+        static ref ENGINE: ABPEngine = ABPEngine::new(
+            vec![(
+                "https://easylist.to/easylist/easylist.txt".to_string(),
+                false
+            )],
+            "abplist.abp"
+        );
     }
     &ENGINE
 }
 
+fn new_gfw_list_engine() -> ABPEngine {
+    ABPEngine::with_state(
+        EngineState::from_embedded("gfw_list.dat", "gfwlist.abp"),
+        vec![(
+            "https://raw.githubusercontent.com/gfwlist/gfwlist/master/gfwlist.txt".to_string(),
+            true,
+        )],
+    )
+}
+
 pub fn gfw_list_engine() -> &'static ABPEngine {
     lazy_static! {
-        static ref ENGINE: ABPEngine = ABPEngine {
-            state: RwLock::new(EngineState::from_embedded("gfw_list.dat", "gfwlist.abp")),
-            rule_url: "https://raw.githubusercontent.com/gfwlist/gfwlist/master/gfwlist.txt",
-            is_base64: true
-        };
+        static ref ENGINE: ABPEngine = new_gfw_list_engine();
     }
     &ENGINE
 }
@@ -264,7 +677,7 @@ impl Eq for ABPEngine {}
 impl Debug for ABPEngine {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("ABPEngine")
-            .field("url", &self.rule_url)
+            .field("sources", &self.sources.read().ok())
             .finish()
     }
 }
@@ -280,4 +693,381 @@ mod test {
         assert!(gfw_list_engine().matches(&"twitter.com:22".parse().unwrap()));
         assert!(!gfw_list_engine().matches(&"www.qq.com:443".parse().unwrap()));
     }
+
+    #[test]
+    fn disabled_gfw_list_matches_nothing() {
+        let engine = new_gfw_list_engine();
+        let google = "www.google.com:443".parse().unwrap();
+        assert!(engine.matches(&google));
+
+        engine.set_enabled(false);
+        assert!(!engine.matches(&google));
+        assert!(
+            smol::block_on(engine.update(&"127.0.0.1:1".parse().unwrap(), Some(1), None)).is_err()
+        );
+
+        engine.set_enabled(true);
+        assert!(engine.matches(&google));
+    }
+
+    #[test]
+    fn disabled_gfw_list_is_not_loaded() {
+        let engine = new_gfw_list_engine();
+        engine.set_enabled(false);
+        engine.set_cache_dir(Some(
+            &std::env::temp_dir().join(uuid::Uuid::new_v4().to_string()),
+        ));
+        assert!(!engine.matches(&"www.google.com:443".parse().unwrap()));
+        assert!(engine.state.read().unwrap().engine.is_none());
+
+        engine.set_enabled(true);
+        assert!(engine.matches(&"www.google.com:443".parse().unwrap()));
+        assert!(engine.state.read().unwrap().pending_asset.is_none());
+    }
+
+    #[test]
+    fn batched_engine_matches_single_engine() {
+        let mut rules = String::from("! Comment\n[AutoProxy 0.2.9]\n");
+        for i in 0..2000 {
+            rules.push_str(&format!("||domain{i}.com^\n"));
+            if i % 100 == 0 {
+                rules.push_str(&format!("@@||www.domain{i}.com^\n"));
+            }
+        }
+        rules.push_str("||domain1.com^$badfilter\n");
+        rules.push_str("||badfilter.com^\n");
+
+        let (single, single_count) = EngineSet::build(rules.as_bytes(), usize::MAX);
+        let (batched, batched_count) = EngineSet::build(rules.as_bytes(), 150);
+        assert_eq!(single.0.len(), 1);
+        assert!(batched.0.len() > 10);
+        assert_eq!(single_count, batched_count);
+
+        let batched = EngineSet::deserialize(&batched.serialize().unwrap()).unwrap();
+
+        let mut matched = 0;
+        for i in 0..2100 {
+            for host in [format!("domain{i}.com"), format!("www.domain{i}.com")] {
+                let url = format!("https://{host}");
+                let expected = single.matches(&url);
+                assert_eq!(expected, batched.matches(&url), "Mismatch for {url}");
+                matched += expected as usize;
+            }
+        }
+
+        assert!(matched > 0);
+        assert!(!single.matches("https://domain1.com"));
+        assert!(!single.matches("https://www.domain100.com"));
+        assert!(batched.matches("https://badfilter.com"));
+        // A rule that only mentions "badfilter" is still counted as an ordinary rule
+        let without = rules.replace("||badfilter.com^\n", "");
+        assert_eq!(
+            EngineSet::build(without.as_bytes(), 150).1 + 1,
+            batched_count
+        );
+    }
+
+    #[test]
+    fn badfilter_option_is_detected() {
+        assert!(is_badfilter("||domain1.com^$badfilter"));
+        assert!(is_badfilter("||domain1.com^$third-party,badfilter"));
+        assert!(!is_badfilter("||badfilter.com^"));
+        assert!(!is_badfilter("||example.com/badfilter$script"));
+    }
+
+    #[test]
+    fn combined_lists_work() {
+        let easylist = b"! Comment\n||ads.example.com^\n".to_vec();
+        let gfwlist = base64::encode("! Comment\n||blocked.example.org^\n").into_bytes();
+
+        let lists = vec![
+            decode_rules(easylist, false).unwrap(),
+            decode_rules(gfwlist, true).unwrap(),
+        ];
+        let (engine, line_count) = build_combined(lists);
+        assert_eq!(line_count, 2);
+
+        let engine = ABPEngine::with_state(
+            EngineState {
+                engine: Some((engine, SystemTime::now())),
+                cache_file_path: None,
+                cache_file_name: Default::default(),
+                pending_asset: None,
+            },
+            Default::default(),
+        );
+
+        assert!(engine.matches(&"ads.example.com:443".parse().unwrap()));
+        assert!(engine.matches(&"blocked.example.org:80".parse().unwrap()));
+        assert!(!engine.matches(&"www.example.net:443".parse().unwrap()));
+
+        // Whitelisting takes effect without rebuilding the engine
+        engine.set_whitelist(HostWhitelist::new(["ads.example.com", "*.example.org"]));
+        assert!(!engine.matches(&"ads.example.com:443".parse().unwrap()));
+        assert!(!engine.matches(&"blocked.example.org:80".parse().unwrap()));
+
+        engine.set_whitelist(Default::default());
+        assert!(engine.matches(&"ads.example.com:443".parse().unwrap()));
+    }
+
+    // Acts as the HTTP proxy, answering one request per status with the same list.
+    // Resolves to the requests received.
+    async fn mock_proxy(
+        statuses: &'static [&'static str],
+    ) -> (smol::Task<Vec<String>>, std::net::SocketAddr) {
+        use futures::AsyncReadExt;
+
+        let (server, proxy_addr) = crate::test::create_tcp_server().await;
+        let task = smol::spawn(async move {
+            let mut requests = Vec::new();
+            for status in statuses {
+                let (mut stream, _) = server.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    assert!(n > 0);
+                    request.extend_from_slice(&buf[..n]);
+                }
+                requests.push(String::from_utf8(request).unwrap());
+
+                let body: &[u8] = if status.starts_with("200") {
+                    b"||ads.example.com^\n"
+                } else {
+                    b""
+                };
+                let response = format!(
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\n\r\n",
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+                stream.write_all(body).await.unwrap();
+            }
+            requests
+        });
+        (task, proxy_addr)
+    }
+
+    #[test]
+    fn fetch_retries_until_list_loads() {
+        smol::block_on(async move {
+            let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+            let cache_file_path = dir.join("test.abp");
+            let url = "http://rules.example.com/list.txt";
+            let sources = vec![(url.to_string(), false)];
+            // A stale copy, so that every request asks whether it has changed
+            write_file(
+                &source_cache_path(&cache_file_path, url),
+                b"||old.example.com^\n",
+            )
+            .await
+            .unwrap();
+
+            // Fails twice, then serves the list, then says it's unchanged
+            let (server_task, proxy_addr) = mock_proxy(&[
+                "500 Internal Server Error",
+                "502 Bad Gateway",
+                "200 OK",
+                "304 Not Modified",
+            ])
+            .await;
+
+            let state = RwLock::new(EngineState {
+                engine: None,
+                cache_file_path: Some(cache_file_path),
+                cache_file_name: Default::default(),
+                pending_asset: None,
+            });
+            let retry = FetchRetry {
+                max_attempts: 3,
+                initial_delay: Duration::from_millis(10),
+            };
+            let proxy = Address::IP(proxy_addr);
+
+            assert_eq!(
+                update_engine(
+                    &state,
+                    &proxy,
+                    &sources,
+                    false,
+                    retry,
+                    DEFAULT_MAX_REDIRECTS
+                )
+                .await
+                .unwrap(),
+                1
+            );
+            // Unchanged lists aren't retried
+            assert_eq!(
+                update_engine(
+                    &state,
+                    &proxy,
+                    &sources,
+                    false,
+                    retry,
+                    DEFAULT_MAX_REDIRECTS
+                )
+                .await
+                .unwrap(),
+                0
+            );
+
+            let requests = server_task.await;
+            let _ = std::fs::remove_dir_all(&dir);
+            assert!(requests.iter().all(|r| r
+                .starts_with("GET http://rules.example.com:80/list.txt")
+                && r.contains("If-Modified-Since")));
+            // The last modified time is only moved on by the successful download
+            assert_eq!(
+                requests[0]
+                    .lines()
+                    .find(|l| l.starts_with("If-Modified-Since")),
+                requests[2]
+                    .lines()
+                    .find(|l| l.starts_with("If-Modified-Since")),
+            );
+
+            let engine = ABPEngine::with_state(state.into_inner().unwrap(), sources);
+            assert!(engine.matches(&"ads.example.com:443".parse().unwrap()));
+            assert!(!engine.matches(&"old.example.com:443".parse().unwrap()));
+        });
+    }
+
+    #[test]
+    fn fetch_follows_redirects() {
+        smol::block_on(async move {
+            let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+            let cache_path = dir.join("list.txt");
+            write_file(&cache_path, b"||old.example.com^\n")
+                .await
+                .unwrap();
+            let retry = FetchRetry {
+                max_attempts: 1,
+                initial_delay: Duration::from_millis(10),
+            };
+
+            let (server_task, proxy_addr) = mock_proxy(&[
+                "301 Moved Permanently\r\nLocation: http://mirror.example.com/lists/list.txt",
+                "302 Found\r\nLocation: latest.txt",
+                "200 OK",
+            ])
+            .await;
+            let list = fetch_source(
+                &Address::IP(proxy_addr),
+                "http://rules.example.com/list.txt",
+                false,
+                Some(&cache_path),
+                retry,
+                DEFAULT_MAX_REDIRECTS,
+            )
+            .await
+            .unwrap();
+            assert_eq!(list.as_deref(), Some(&b"||ads.example.com^\n"[..]));
+
+            let requests = server_task.await;
+            let _ = std::fs::remove_dir_all(&dir);
+            assert!(requests[0].starts_with("GET http://rules.example.com:80/list.txt "));
+            assert!(requests[1].starts_with("GET http://mirror.example.com:80/lists/list.txt "));
+            assert!(requests[2].starts_with("GET http://mirror.example.com:80/lists/latest.txt "));
+            // Only the original URL is asked whether it has changed
+            assert!(requests[0].contains("If-Modified-Since"));
+            assert!(!requests[1].contains("If-Modified-Since"));
+            assert!(!requests[2].contains("If-Modified-Since"));
+
+            // Going over the limit, or from https to http, fails
+            let (_server_task, proxy_addr) = mock_proxy(&[
+                "302 Found\r\nLocation: /a.txt",
+                "302 Found\r\nLocation: /b.txt",
+            ])
+            .await;
+            let err = fetch_source(
+                &Address::IP(proxy_addr),
+                "http://rules.example.com/list.txt",
+                false,
+                None,
+                retry,
+                1,
+            )
+            .await
+            .unwrap_err();
+            assert!(err.to_string().contains("Too many redirects"), "{err:?}");
+
+            let (_server_task, proxy_addr) =
+                mock_proxy(&["302 Found\r\nLocation: http://rules.example.com/list.txt"]).await;
+            let err = fetch_source(
+                &Address::IP(proxy_addr),
+                "https://rules.example.com/list.txt",
+                false,
+                None,
+                retry,
+                DEFAULT_MAX_REDIRECTS,
+            )
+            .await
+            .unwrap_err();
+            assert!(err.to_string().contains("Refusing to redirect"), "{err:?}");
+        });
+    }
+
+    #[test]
+    fn configured_sources_are_fetched() {
+        smol::block_on(async move {
+            let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+            let built_in = "http://rules.example.com/list.txt";
+            let configured = "http://mine.example.com/list.txt";
+            let (server_task, proxy_addr) =
+                mock_proxy(&["200 OK", "200 OK", "304 Not Modified"]).await;
+            let proxy = Address::IP(proxy_addr);
+
+            let engine = ABPEngine::new(vec![(built_in.to_string(), false)], "test.abp");
+            engine.set_cache_dir(Some(&dir));
+            assert_eq!(engine.update(&proxy, None, None).await.unwrap(), 1);
+
+            engine.set_sources(Some(&[AbpSource {
+                url: configured.to_string(),
+                base64: false,
+            }]));
+            assert_eq!(engine.update(&proxy, None, None).await.unwrap(), 1);
+
+            // Going back rebuilds from the cached list, even though it hasn't changed
+            engine.set_sources(None);
+            assert_eq!(engine.update(&proxy, None, None).await.unwrap(), 1);
+            engine.set_sources(None);
+            assert!(!engine.sources_changed.load(Ordering::Relaxed));
+
+            let requests = server_task.await;
+            let _ = std::fs::remove_dir_all(&dir);
+            assert!(requests[0].starts_with("GET http://rules.example.com:80/list.txt "));
+            assert!(requests[1].starts_with("GET http://mine.example.com:80/list.txt "));
+            assert!(requests[2].starts_with("GET http://rules.example.com:80/list.txt "));
+        });
+    }
+
+    #[test]
+    fn engine_is_cached_in_configured_dir() {
+        smol::block_on(async move {
+            let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+            let url = "http://rules.example.com/list.txt";
+            let (server_task, proxy_addr) = mock_proxy(&["200 OK"]).await;
+
+            let engine = ABPEngine::new(vec![(url.to_string(), false)], "test.abp");
+            engine.set_cache_dir(Some(&dir));
+            assert_eq!(
+                engine
+                    .update(&Address::IP(proxy_addr), None, None)
+                    .await
+                    .unwrap(),
+                1
+            );
+            server_task.await;
+
+            assert!(dir.join("test.abp").exists());
+            let reloaded =
+                ABPEngine::with_state(EngineState::new(Some(&dir), "test.abp"), Default::default());
+            let _ = std::fs::remove_dir_all(&dir);
+
+            assert!(reloaded.get_last_updated().unwrap().is_some());
+            assert!(reloaded.matches(&"ads.example.com:443".parse().unwrap()));
+            assert!(!reloaded.matches(&"www.example.com:443".parse().unwrap()));
+        });
+    }
 }
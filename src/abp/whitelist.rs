@@ -0,0 +1,144 @@
+use std::collections::HashSet;
+
+// Hosts that are never matched by a rule list. Entries are either exact domains or
+// `*.suffix` wildcards, which match the subdomains of the suffix but not the suffix itself.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct HostWhitelist {
+    exact: HashSet<String>,
+    // The wildcard suffixes with their labels reversed and a trailing dot, sorted and
+    // with none a prefix of another, so a host needs a single search to find its match
+    suffixes: Vec<String>,
+}
+
+fn normalise(host: &str) -> String {
+    host.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+// `www.example.com` becomes `com.example.www`
+fn reverse_labels(host: &str) -> String {
+    host.rsplit('.').collect::<Vec<_>>().join(".")
+}
+
+impl HostWhitelist {
+    pub fn new<'a>(entries: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut r = Self::default();
+        let mut suffixes = Vec::new();
+        for entry in entries.into_iter().map(normalise) {
+            match entry.strip_prefix("*.") {
+                Some(suffix) if !suffix.is_empty() => {
+                    suffixes.push(format!("{}.", reverse_labels(suffix)))
+                }
+                _ if !entry.is_empty() => {
+                    r.exact.insert(entry);
+                }
+                _ => {}
+            }
+        }
+
+        // A suffix already covered by a shorter one is redundant. Once sorted, the shorter
+        // one comes right before everything it covers.
+        suffixes.sort_unstable();
+        for suffix in suffixes {
+            if r.suffixes
+                .last()
+                .is_none_or(|last| !suffix.starts_with(last.as_str()))
+            {
+                r.suffixes.push(suffix);
+            }
+        }
+        r
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.suffixes.is_empty()
+    }
+
+    pub fn contains(&self, host: &str) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+
+        let host = normalise(host);
+        self.exact.contains(&host) || self.matches_suffix(&reverse_labels(&host))
+    }
+
+    fn matches_suffix(&self, reversed_host: &str) -> bool {
+        // The only suffix that can match is the greatest one not after the host
+        match self
+            .suffixes
+            .partition_point(|s| s.as_str() <= reversed_host)
+        {
+            0 => false,
+            i => reversed_host.starts_with(self.suffixes[i - 1].as_str()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn whitelist_works() {
+        let list = HostWhitelist::new(["ads.example.com", "*.cdn.example.org", " ", "*."]);
+
+        assert!(list.contains("ads.example.com"));
+        assert!(list.contains("ADS.example.com."));
+        assert!(!list.contains("www.ads.example.com"));
+
+        assert!(list.contains("a.cdn.example.org"));
+        assert!(list.contains("a.b.cdn.example.org"));
+        assert!(!list.contains("cdn.example.org"));
+        assert!(!list.contains("badcdn.example.org"));
+
+        assert!(!HostWhitelist::default().contains("ads.example.com"));
+    }
+
+    #[test]
+    fn suffix_index_matches_linear_search() {
+        let mut entries = vec![
+            String::from("*.example.com"),
+            String::from("*.a.example.com"),
+            String::from("*.org"),
+            String::from("*.co.uk"),
+        ];
+        for i in 0..500 {
+            entries.push(format!("*.d{i}.net"));
+            entries.push(format!("*.s{}.d{i}.net", i % 7));
+        }
+        let list = HostWhitelist::new(entries.iter().map(String::as_str));
+        assert_eq!(list.suffixes.len(), 503);
+
+        let linear = |host: &str| {
+            entries
+                .iter()
+                .any(|e| host.ends_with(e.strip_prefix('*').unwrap()))
+        };
+
+        let mut hosts = vec![
+            "example.com",
+            "www.example.com",
+            "b.a.example.com",
+            "badexample.com",
+            "org",
+            "x.org",
+            "co.uk",
+            "www.bbc.co.uk",
+            "uk",
+            "net",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect::<Vec<_>>();
+        for i in (0..600).step_by(3) {
+            hosts.push(format!("d{i}.net"));
+            hosts.push(format!("www.d{i}.net"));
+            hosts.push(format!("s1.d{i}.net"));
+            hosts.push(format!("xd{i}.net"));
+        }
+
+        for host in hosts {
+            assert_eq!(list.contains(&host), linear(&host), "{host}");
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use std::time::Duration;
+
+// Each power of two of microseconds is split into this many buckets, so a reported
+// percentile is within 1/8 (12.5%) of the real value
+const SUB_BUCKET_BITS: u32 = 3;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+
+#[derive(Debug, Default, Clone)]
+pub struct LatencyHistogram {
+    buckets: Vec<u64>,
+    count: u64,
+    max: Duration,
+}
+
+fn bucket_of(micros: u64) -> usize {
+    if micros < SUB_BUCKETS {
+        return micros as usize;
+    }
+
+    let shift = 63 - micros.leading_zeros() - SUB_BUCKET_BITS;
+    let sub = (micros >> shift) - SUB_BUCKETS;
+    ((shift as u64 + 1) * SUB_BUCKETS + sub) as usize
+}
+
+// The largest value that falls into the bucket
+fn bucket_upper_bound(index: usize) -> u64 {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return index;
+    }
+
+    let shift = index / SUB_BUCKETS - 1;
+    let sub = index % SUB_BUCKETS;
+    ((SUB_BUCKETS + sub + 1) << shift) - 1
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, latency: Duration) {
+        let index = bucket_of(latency.as_micros().try_into().unwrap_or(u64::MAX));
+        if self.buckets.len() <= index {
+            self.buckets.resize(index + 1, 0);
+        }
+        self.buckets[index] += 1;
+        self.count += 1;
+        self.max = self.max.max(latency);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    // `p` is in 0..=100. Returns None when nothing has been recorded.
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let rank = ((p.clamp(0.0, 100.0) / 100.0 * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let upper = Duration::from_micros(bucket_upper_bound(index));
+                return Some(upper.min(self.max));
+            }
+        }
+        Some(self.max)
+    }
+
+    pub fn summary(&self) -> String {
+        match (
+            self.percentile(50.0),
+            self.percentile(90.0),
+            self.percentile(99.0),
+        ) {
+            (Some(p50), Some(p90), Some(p99)) => format!(
+                "p50 = {p50:?}, p90 = {p90:?}, p99 = {p99:?} ({} samples)",
+                self.count()
+            ),
+            _ => "no samples".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_are_ordered() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.percentile(50.0), None);
+
+        for ms in 1..=100 {
+            histogram.record(Duration::from_millis(ms));
+        }
+        assert_eq!(histogram.count(), 100);
+
+        let p50 = histogram.percentile(50.0).unwrap();
+        let p90 = histogram.percentile(90.0).unwrap();
+        let p99 = histogram.percentile(99.0).unwrap();
+        assert!(p50 <= p90 && p90 <= p99, "{}", histogram.summary());
+
+        for (actual, expect) in [(p50, 50), (p90, 90), (p99, 99)] {
+            let expect = Duration::from_millis(expect);
+            assert!(actual >= expect, "{actual:?} < {expect:?}");
+            assert!(
+                actual <= expect + expect / 8,
+                "{actual:?} too far from {expect:?}"
+            );
+        }
+
+        assert_eq!(
+            histogram.percentile(100.0),
+            Some(Duration::from_millis(100))
+        );
+    }
+
+    #[test]
+    fn buckets_are_contiguous() {
+        for micros in 0..100_000 {
+            let index = bucket_of(micros);
+            assert!(micros <= bucket_upper_bound(index));
+            assert!(index == 0 || micros > bucket_upper_bound(index - 1));
+        }
+    }
+}
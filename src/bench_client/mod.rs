@@ -4,16 +4,17 @@ use std::{
 };
 
 use anyhow::Context;
-use futures::{AsyncReadExt, AsyncWriteExt};
+use bytes::Bytes;
+use futures::{AsyncReadExt, AsyncWriteExt, SinkExt, StreamExt};
+use smol_timeout::TimeoutExt;
+
+mod histogram;
+use histogram::LatencyHistogram;
 
 use crate::{
     config::{ClientConfig, UpstreamConfig},
-    proxy::{
-        protocol::{ProxyRequest, ProxyResult},
-        request_proxy_upstream_with_config,
-        udp_stream::{PacketReader, PacketWriter},
-    },
-    rt::TimeoutExt,
+    protocol::{Protocol, TrafficType},
+    socks5::Address,
 };
 
 const MAX_BYTES: usize = 10 * 1024 * 1024;
@@ -21,15 +22,30 @@ const MAX_TIME: Duration = Duration::from_secs(15);
 
 const TIMEOUT: Duration = Duration::from_secs(5);
 
+// How many extra connections are made to sample the establishment latency
+const CONNECT_SAMPLES: usize = 20;
+
+const UDP_PAYLOAD: [u8; 4096] = [0u8; 4096];
+
 fn format_bytes(n: usize) -> String {
     if n < 1024 * 1024 {
-        return format!("{}KB", n / 1024);
+        format!("{}KB", n / 1024)
     } else {
-        return format!("{}MB", n / 1024 / 1024);
+        format!("{}MB", n / 1024 / 1024)
+    }
+}
+
+// Bytes per second, or 0 before anything took a measurable time
+fn rate(total: (usize, Duration)) -> usize {
+    match total.1.as_millis() as usize {
+        0 => 0,
+        ms => total.0 * 1000 / ms,
     }
 }
 
-pub async fn run_perf_tests(config_file: &Path) -> anyhow::Result<()> {
+// Measures each upstream against `echo`, which has to echo back what's sent to it over both
+// TCP and UDP
+pub async fn run_perf_tests(config_file: &Path, echo: &Address<'_>) -> anyhow::Result<()> {
     let config: ClientConfig = serde_yaml::from_reader(
         std::fs::File::open(config_file)
             .with_context(|| format!("Opening config file {config_file:?}"))?,
@@ -37,148 +53,179 @@ pub async fn run_perf_tests(config_file: &Path) -> anyhow::Result<()> {
 
     for (name, upstream) in &config.upstreams {
         println!("Perf testing upstream: {name}, {upstream:?}");
-        run_upstream_test_tcp(&config, upstream).await?;
-        run_upstream_test_udp(&config, upstream).await?;
+        if upstream.protocol.supports(TrafficType::Stream) {
+            run_upstream_test_connect(&config, upstream, echo).await?;
+            run_upstream_test_tcp(&config, upstream, echo).await?;
+        }
+        if upstream.protocol.supports(TrafficType::Datagram) {
+            run_upstream_test_udp(&config, upstream, echo).await?;
+        }
     }
 
     Ok(())
 }
 
+async fn run_upstream_test_connect(
+    c: &ClientConfig,
+    upstream_config: &UpstreamConfig,
+    echo: &Address<'_>,
+) -> anyhow::Result<()> {
+    let mut histogram = LatencyHistogram::default();
+    for _ in 0..CONNECT_SAMPLES {
+        let start = Instant::now();
+        upstream_config
+            .protocol
+            .new_stream(echo, None, &Default::default(), &c.tcp_options())
+            .timeout(TIMEOUT)
+            .await
+            .context("Connect: Timeout requesting proxy")??;
+        histogram.record(start.elapsed());
+    }
+
+    println!("Connect: Establishment latency {}", histogram.summary());
+    Ok(())
+}
+
 async fn run_upstream_test_tcp(
     c: &ClientConfig,
     upstream_config: &UpstreamConfig,
+    echo: &Address<'_>,
 ) -> anyhow::Result<()> {
     println!("TCP: Connecting to {upstream_config:?}");
-    match request_proxy_upstream_with_config(c.fwmark, upstream_config, &ProxyRequest::EchoTestTcp)
+    let start = Instant::now();
+    let mut upstream = upstream_config
+        .protocol
+        .new_stream(echo, None, &Default::default(), &c.tcp_options())
         .timeout(TIMEOUT)
         .await
-        .context("TCP: Timeout requesting proxy")??
-    {
-        (ProxyResult::Granted { .. }, mut upstream, delay) => {
-            println!("TCP: Connected to {upstream_config:?}, initial delay = {delay:?}");
-
-            let mut buf = [0u8; 8192];
-            let start = Instant::now();
-            let mut total_uploaded = (0usize, Duration::default());
-            let mut total_downloaded = (0usize, Duration::default());
-            let mut last_print = start;
-
-            while total_uploaded.0 < MAX_BYTES && Instant::now().duration_since(start) < MAX_TIME {
-                let upload_start = Instant::now();
-                let len = upstream
-                    .write(&buf)
-                    .timeout(TIMEOUT)
-                    .await
-                    .context("TCP: Timeout writing")??;
-                total_uploaded = (
-                    total_uploaded.0 + len,
-                    total_uploaded.1 + Instant::now().duration_since(upload_start),
-                );
-
-                let download_start = Instant::now();
-                let len = upstream
-                    .read(&mut buf)
-                    .timeout(TIMEOUT)
-                    .await
-                    .context("TCP: Timeout reading")??;
-                total_downloaded = (
-                    total_downloaded.0 + len,
-                    total_downloaded.1 + Instant::now().duration_since(download_start),
-                );
-
-                let now = Instant::now();
-
-                let duration_since_last_print = now.duration_since(last_print);
-
-                if duration_since_last_print > Duration::from_secs(2) {
-                    let down =
-                        total_downloaded.0 / (total_downloaded.1.as_millis() as usize) * 1000;
-                    let up = total_uploaded.0 / (total_uploaded.1.as_millis() as usize) * 1000;
-                    println!(
-                        "TCP: Average down: {}/s, up: {}/s",
-                        format_bytes(down),
-                        format_bytes(up)
-                    );
-                    last_print = now;
-                }
-            }
-
-            Ok(())
+        .context("TCP: Timeout requesting proxy")??;
+    println!(
+        "TCP: Connected to {upstream_config:?}, initial delay = {:?}",
+        start.elapsed()
+    );
+
+    let mut buf = [0u8; 8192];
+    let start = Instant::now();
+    let mut total_uploaded = (0usize, Duration::default());
+    let mut total_downloaded = (0usize, Duration::default());
+    let mut last_print = start;
+    let mut round_trips = LatencyHistogram::default();
+
+    while total_uploaded.0 < MAX_BYTES && Instant::now().duration_since(start) < MAX_TIME {
+        let upload_start = Instant::now();
+        let len = upstream
+            .write(&buf)
+            .timeout(TIMEOUT)
+            .await
+            .context("TCP: Timeout writing")??;
+        total_uploaded = (
+            total_uploaded.0 + len,
+            total_uploaded.1 + Instant::now().duration_since(upload_start),
+        );
+
+        let download_start = Instant::now();
+        let len = upstream
+            .read(&mut buf)
+            .timeout(TIMEOUT)
+            .await
+            .context("TCP: Timeout reading")??;
+        total_downloaded = (
+            total_downloaded.0 + len,
+            total_downloaded.1 + Instant::now().duration_since(download_start),
+        );
+        round_trips.record(upload_start.elapsed());
+
+        let now = Instant::now();
+
+        let duration_since_last_print = now.duration_since(last_print);
+
+        if duration_since_last_print > Duration::from_secs(2) {
+            println!(
+                "TCP: Average down: {}/s, up: {}/s",
+                format_bytes(rate(total_downloaded)),
+                format_bytes(rate(total_uploaded))
+            );
+            last_print = now;
         }
-        (r, _, _) => Err(r.into()),
     }
+
+    println!("TCP: Round trip latency {}", round_trips.summary());
+    Ok(())
 }
 
 async fn run_upstream_test_udp(
     c: &ClientConfig,
     upstream_config: &UpstreamConfig,
+    echo: &Address<'_>,
 ) -> anyhow::Result<()> {
     println!("UDP: Connecting to {upstream_config:?}");
-    match request_proxy_upstream_with_config(c.fwmark, upstream_config, &ProxyRequest::EchoTestUdp)
+    let payload = Bytes::from_static(&UDP_PAYLOAD);
+    let echo_addr = echo.clone().into_owned();
+
+    // The first packet goes out as the initial data
+    let start = Instant::now();
+    let (mut sink, mut stream) = upstream_config
+        .protocol
+        .new_datagram(echo, payload.clone(), &Default::default(), &c.tcp_options())
         .timeout(TIMEOUT)
         .await
-        .context("UDP: Timeout requesting proxy")??
-    {
-        (ProxyResult::Granted { .. }, mut upstream, delay) => {
-            println!("UDP: Connected to {upstream_config:?}, initial delay = {delay:?}");
-
-            // let (upstream_r, mut upstream_w) = upstream.split();
-            let mut packet_stream = PacketReader::new();
-            let mut packet_writer = PacketWriter::new();
-
-            let start = Instant::now();
-            let mut total_uploaded = (0usize, Duration::default());
-            let mut total_downloaded = (0usize, Duration::default());
-            let mut last_print = start;
-
-            let test_address = "1.2.3.4:53".try_into().unwrap();
-
-            while total_uploaded.0 < MAX_BYTES && Instant::now().duration_since(start) < MAX_TIME {
-                let upload_start = Instant::now();
-                let len = packet_writer
-                    .write(&mut upstream, &test_address, &[0u8; 4986])
-                    .timeout(TIMEOUT)
-                    .await
-                    .context("UDP: Timeout writing packet")??;
-                total_uploaded = (
-                    total_uploaded.0 + len,
-                    total_uploaded.1 + Instant::now().duration_since(upload_start),
-                );
-
-                let download_start = Instant::now();
-                let len = packet_stream
-                    .read(&mut upstream)
-                    .timeout(TIMEOUT)
-                    .await
-                    .context("UDP: Timeout receiving packet")?
-                    .context("UDP: Error receiving packet")?
-                    .0
-                    .as_ref()
-                    .len();
-                total_downloaded = (
-                    total_downloaded.0 + len,
-                    total_downloaded.1 + Instant::now().duration_since(download_start),
-                );
-
-                let now = Instant::now();
-
-                let duration_since_last_print = now.duration_since(last_print);
-
-                if duration_since_last_print >= Duration::from_secs(2) {
-                    let down =
-                        total_downloaded.0 / (total_downloaded.1.as_millis() as usize) * 1000;
-                    let up = total_uploaded.0 / (total_uploaded.1.as_millis() as usize) * 1000;
-                    println!(
-                        "UDP: Average down: {}/s, up: {}/s",
-                        format_bytes(down),
-                        format_bytes(up)
-                    );
-                    last_print = now;
-                }
-            }
-
-            Ok(())
+        .context("UDP: Timeout requesting proxy")??;
+    println!(
+        "UDP: Connected to {upstream_config:?}, initial delay = {:?}",
+        start.elapsed()
+    );
+
+    let mut total_uploaded = (payload.len(), start.elapsed());
+    let mut total_downloaded = (0usize, Duration::default());
+    let mut last_print = start;
+    let mut round_trips = LatencyHistogram::default();
+    let mut upload_start = start;
+
+    loop {
+        let download_start = Instant::now();
+        let len = stream
+            .next()
+            .timeout(TIMEOUT)
+            .await
+            .context("UDP: Timeout receiving packet")?
+            .context("UDP: Upstream closed")?
+            .context("UDP: Error receiving packet")?
+            .0
+            .len();
+        total_downloaded = (
+            total_downloaded.0 + len,
+            total_downloaded.1 + Instant::now().duration_since(download_start),
+        );
+        round_trips.record(upload_start.elapsed());
+
+        let now = Instant::now();
+
+        let duration_since_last_print = now.duration_since(last_print);
+
+        if duration_since_last_print >= Duration::from_secs(2) {
+            println!(
+                "UDP: Average down: {}/s, up: {}/s",
+                format_bytes(rate(total_downloaded)),
+                format_bytes(rate(total_uploaded))
+            );
+            last_print = now;
         }
-        (r, _, _) => Err(r.into()),
+
+        if total_uploaded.0 >= MAX_BYTES || now.duration_since(start) >= MAX_TIME {
+            break;
+        }
+
+        upload_start = Instant::now();
+        sink.send((payload.clone(), echo_addr.clone()))
+            .timeout(TIMEOUT)
+            .await
+            .context("UDP: Timeout writing packet")??;
+        total_uploaded = (
+            total_uploaded.0 + payload.len(),
+            total_uploaded.1 + Instant::now().duration_since(upload_start),
+        );
     }
+
+    println!("UDP: Round trip latency {}", round_trips.summary());
+    Ok(())
 }
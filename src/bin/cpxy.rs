@@ -1,15 +1,23 @@
 use anyhow::Context;
 use async_net::{TcpListener, UdpSocket};
 use clap::{Parser, Subcommand};
+use cpxy::bench_client::run_perf_tests;
 use cpxy::controller::run_controller;
 use cpxy::io::bind_tcp;
-use cpxy::protocol::{firetcp, tcpman, udpman};
+use cpxy::logging::{init_logger, LogFormat};
+use cpxy::protocol::{
+    allowed_ports::AllowedPorts, firetcp, load_shed::LoadShedder,
+    log_sampler::CONNECTION_LOG_SAMPLER, tcpman, udpman,
+};
+use cpxy::runtime::set_worker_threads;
 use cpxy::socks5::Address;
 use futures::future::select_all;
 use futures::Future;
 use smol::{spawn, Task};
 use std::net::{IpAddr, SocketAddr};
-use std::path::Path;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 // #[cfg(not(target_env = "msvc"))]
 // #[global_allocator]
@@ -20,6 +28,14 @@ use std::path::Path;
 struct Cli {
     #[clap(subcommand)]
     cmd: Command,
+
+    /// How log lines are written: human readable or one JSON object per line
+    #[clap(value_enum, default_value_t = LogFormat::Human, long, global = true)]
+    log_format: LogFormat,
+
+    /// How many threads run the proxy's tasks. 1 suits routers and other low-resource devices. Defaults to $SMOL_THREADS, or 1
+    #[clap(long, global = true)]
+    worker_threads: Option<NonZeroUsize>,
 }
 
 #[derive(Subcommand)]
@@ -32,11 +48,47 @@ enum Command {
         /// The TCPMan port to listen on
         #[clap(long)]
         tcpman_port: Option<u16>,
+        /// The UDP port to listen on for TCPMan over QUIC
+        #[clap(long, requires_all = ["tcpman_quic_cert", "tcpman_quic_key"])]
+        tcpman_quic_port: Option<u16>,
+        /// The PEM certificate chain presented to TCPMan clients over QUIC
+        #[clap(long)]
+        tcpman_quic_cert: Option<PathBuf>,
+        /// The PEM private key of the TCPMan QUIC certificate
+        #[clap(long)]
+        tcpman_quic_key: Option<PathBuf>,
+        /// Serve TCPMan clients under this path, e.g. "/cdn" behind a reverse proxy
+        #[clap(long)]
+        tcpman_path_prefix: Option<tcpman::server::PathPrefix>,
+        /// Answer TCPMan requests shorter than this, or missing the headers browsers send, as if nothing was there
+        #[clap(long, default_value_t = 0)]
+        tcpman_min_request_bytes: usize,
+        /// Let TCPMan clients compress their streams. Compressed traffic's length can give away what's in it
+        #[clap(long)]
+        tcpman_allow_compression: bool,
+        /// Refuse streams over this many on one multiplexed TCPMan connection. 0 means no limit
+        #[clap(default_value_t = 0, long)]
+        tcpman_max_mux_streams: usize,
         /// The UDPMan port to listen on
         #[clap(long)]
         udpman_port: Option<u16>,
+        /// Who may send to a UDPMan client's mapped port: full-cone or restricted-cone
+        #[clap(long, default_value = "full-cone")]
+        udpman_nat_filtering: udpman::nat::NatFiltering,
+        /// Seconds a UDPMan client's mapped port is kept without traffic
+        #[clap(long, default_value_t = 60)]
+        udpman_nat_idle_secs: u64,
         #[clap(long)]
         firetcp_port: Option<u16>,
+        /// The destination ports clients may connect to, e.g. "80,443,8000-9000". All ports are allowed if not given
+        #[clap(long)]
+        allowed_ports: Option<AllowedPorts>,
+        /// Log the summary of 1 in every N connections. Errors are always logged
+        #[clap(default_value_t = 1, long)]
+        log_sample_rate: usize,
+        /// Refuse new TCP connections once this many are active. 0 means no limit
+        #[clap(default_value_t = 0, long)]
+        max_connections: usize,
     },
 
     #[clap()]
@@ -51,6 +103,18 @@ enum Command {
         #[clap(default_value_t = 4000, long)]
         controller_port: u16,
     },
+
+    /// Measure the throughput and latency of each upstream in a configuration file
+    #[clap()]
+    Bench {
+        #[clap(long)]
+        /// Path to the configuration file
+        config: String,
+
+        /// A server echoing back what's sent to it over both TCP and UDP, e.g. "1.2.3.4:7"
+        #[clap(long)]
+        echo: Address<'static>,
+    },
 }
 
 async fn start_serving_tcp<Fut: Future<Output = anyhow::Result<()>> + Send + Sync + 'static>(
@@ -81,36 +145,129 @@ async fn start_serving_udp<Fut: Future<Output = anyhow::Result<()>> + Send + Syn
     Ok(spawn(run(listener)))
 }
 
+// Resolves once the process is asked to stop with SIGINT or SIGTERM
+#[cfg(unix)]
+async fn shutdown_signal() {
+    use async_signal::{Signal, Signals};
+    use futures::StreamExt;
+
+    let mut signals = match Signals::new([Signal::Int, Signal::Term]) {
+        Ok(v) => v,
+        Err(e) => {
+            log::error!("Error listening for signals: {e:?}");
+            return futures::future::pending().await;
+        }
+    };
+    match signals.next().await {
+        Some(Ok(signal)) => log::info!("Received {signal:?}, shutting down"),
+        _ => futures::future::pending().await,
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+    futures::future::pending().await
+}
+
 fn main() -> anyhow::Result<()> {
     smol::block_on(async move {
         if std::env::var_os("RUST_LOG").is_none() {
             std::env::set_var("RUST_LOG", "info");
         }
 
-        env_logger::init();
+        let Cli {
+            cmd,
+            log_format,
+            worker_threads,
+        } = Cli::parse();
+        if let Some(threads) = worker_threads {
+            set_worker_threads(threads);
+        }
+        init_logger(log_format);
 
-        let Cli { cmd } = Cli::parse();
         match cmd {
             Command::Server {
                 host,
                 tcpman_port,
+                tcpman_quic_port,
+                tcpman_quic_cert,
+                tcpman_quic_key,
+                tcpman_path_prefix,
+                tcpman_min_request_bytes,
+                tcpman_allow_compression,
+                tcpman_max_mux_streams,
                 udpman_port,
+                udpman_nat_filtering,
+                udpman_nat_idle_secs,
                 firetcp_port,
+                allowed_ports,
+                log_sample_rate,
+                max_connections,
             } => {
+                CONNECTION_LOG_SAMPLER.set_rate(log_sample_rate);
+                let load_shedder = LoadShedder::new(max_connections);
+                let allowed_ports = allowed_ports.unwrap_or_default();
                 let mut tasks = Vec::<Task<anyhow::Result<()>>>::new();
 
+                let tcpman_options = tcpman::server::ServerOptions {
+                    allowed_ports: allowed_ports.clone(),
+                    // Several can be given while rotating the password
+                    credentials: match std::env::var("TCPMAN_CREDENTIALS") {
+                        Ok(v) => v.parse().context("Parsing env TCPMAN_CREDENTIALS")?,
+                        Err(_) => Default::default(),
+                    },
+                    handshake_rules: tcpman::server::HandshakeRules {
+                        path_prefix: tcpman_path_prefix.unwrap_or_default(),
+                        min_request_bytes: tcpman_min_request_bytes,
+                        allow_compression: tcpman_allow_compression,
+                    },
+                    load_shedder: load_shedder.clone(),
+                    max_mux_streams: tcpman_max_mux_streams,
+                };
+
                 if let Some(port) = tcpman_port {
+                    let options = tcpman_options.clone();
                     tasks.push(
-                        start_serving_tcp("tcpman", host, port, tcpman::server::run_server).await?,
+                        start_serving_tcp("tcpman", host, port, move |listener| {
+                            tcpman::server::run_server(listener, options)
+                        })
+                        .await?,
                     );
                 }
 
+                if let (Some(port), Some(cert), Some(key)) =
+                    (tcpman_quic_port, tcpman_quic_cert, tcpman_quic_key)
+                {
+                    let addr = SocketAddr::new(host, port);
+                    let config = tcpman::quic::server_config(
+                        &std::fs::read(&cert)
+                            .with_context(|| format!("Reading {}", cert.display()))?,
+                        &std::fs::read(&key)
+                            .with_context(|| format!("Reading {}", key.display()))?,
+                        true,
+                    )?;
+                    let endpoint = tcpman::quic::bind_server(addr, config)
+                        .with_context(|| format!("Binding on {addr} for tcpman over QUIC"))?;
+                    log::info!("tcpman over QUIC started on {addr}");
+                    tasks.push(spawn(tcpman::server::run_quic_server(
+                        endpoint,
+                        tcpman_options,
+                    )));
+                }
+
                 if let Some(port) = firetcp_port {
                     let password = std::env::var("FIRETCP_PASSWORD")
                         .context("Firetcp password must be given via env FIRETCP_PASSWORD")?;
+                    let allowed_ports = allowed_ports.clone();
                     tasks.push(
                         start_serving_tcp("firetcp", host, port, move |listener| async {
-                            firetcp::server::run_server(listener, password.into()).await
+                            firetcp::server::run_server(
+                                listener,
+                                password.into(),
+                                allowed_ports,
+                                load_shedder,
+                            )
+                            .await
                         })
                         .await?,
                     );
@@ -118,12 +275,25 @@ fn main() -> anyhow::Result<()> {
 
                 if let Some(port) = udpman_port {
                     tasks.push(
-                        start_serving_udp("udpman", host, port, udpman::server::serve_socket)
-                            .await?,
+                        start_serving_udp("udpman", host, port, move |socket| {
+                            udpman::server::serve_socket(
+                                socket,
+                                udpman::nat::NatTable::new(
+                                    udpman_nat_filtering,
+                                    Duration::from_secs(udpman_nat_idle_secs),
+                                ),
+                                allowed_ports,
+                            )
+                        })
+                        .await?,
                     )
                 }
 
-                select_all(tasks).await.0
+                smol::future::or(async { select_all(tasks).await.0 }, async {
+                    shutdown_signal().await;
+                    Ok(())
+                })
+                .await
             }
             Command::Client {
                 config,
@@ -137,9 +307,11 @@ fn main() -> anyhow::Result<()> {
                         .await
                         .context("Binding controller socket")?,
                     Path::new(&config),
+                    shutdown_signal(),
                 )
                 .await
             }
+            Command::Bench { config, echo } => run_perf_tests(Path::new(&config), &echo).await,
         }
     })
 }
@@ -23,11 +23,10 @@ impl RWBuffer {
 
     fn grow(&mut self) {
         let old_len = self.buf.len();
-        let new_len = old_len * 15 / 10;
-        if new_len > old_len {
-            log::debug!("Growing buffer from {old_len} to {new_len}");
-            self.buf.resize(new_len, 0);
-        }
+        // Always make room, or reading into an empty slice would look like EOF
+        let new_len = (old_len * 15 / 10).max(old_len + 16);
+        log::debug!("Growing buffer from {old_len} to {new_len}");
+        self.buf.resize(new_len, 0);
     }
 
     pub fn remaining_read(&self) -> usize {
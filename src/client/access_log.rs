@@ -0,0 +1,252 @@
+use std::{
+    fmt::Display,
+    fs::OpenOptions,
+    io::{BufWriter, Write},
+    net::IpAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        mpsc::{sync_channel, SyncSender, TrySendError},
+        Arc,
+    },
+    time::Instant,
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+use serde::Serialize;
+use serde_with::{DeserializeFromStr, SerializeDisplay};
+
+use crate::{counter::Counter, logging::set_log_upstream, socks5::Address, socks5::ConnStatusCode};
+
+// Entries waiting to be written. Connections never wait on the log: once the queue is
+// full, new entries are dropped.
+const ACCESS_LOG_QUEUE_SIZE: usize = 1024;
+
+// Where to write the access log: `stdout`, or a file path to append to
+#[derive(Debug, Clone, PartialEq, Eq, SerializeDisplay, DeserializeFromStr)]
+pub enum AccessLogSink {
+    Stdout,
+    File(PathBuf),
+}
+
+impl FromStr for AccessLogSink {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" => anyhow::bail!("Expecting stdout or a file path for the access log"),
+            "stdout" => Ok(Self::Stdout),
+            path => Ok(Self::File(path.into())),
+        }
+    }
+}
+
+impl Display for AccessLogSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Stdout => f.write_str("stdout"),
+            Self::File(path) => Display::fmt(&path.display(), f),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct AccessLogEntry {
+    timestamp: DateTime<Utc>,
+    src: Option<IpAddr>,
+    dst: String,
+    outcome: &'static str,
+    upstream: Option<String>,
+    tx: usize,
+    rx: usize,
+    duration_ms: u128,
+    error: Option<String>,
+}
+
+// Writes one JSON line per finished connection, from a thread of its own
+#[derive(Default)]
+pub struct AccessLog {
+    writer: RwLock<Option<(AccessLogSink, SyncSender<AccessLogEntry>)>>,
+}
+
+fn spawn_writer(sink: &AccessLogSink) -> anyhow::Result<SyncSender<AccessLogEntry>> {
+    let writer: Box<dyn Write + Send> = match sink {
+        AccessLogSink::Stdout => Box::new(std::io::stdout()),
+        AccessLogSink::File(path) => Box::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Opening access log {}", path.display()))?,
+        ),
+    };
+
+    let (tx, rx) = sync_channel::<AccessLogEntry>(ACCESS_LOG_QUEUE_SIZE);
+    std::thread::Builder::new()
+        .name("access-log".to_string())
+        .spawn(move || {
+            let mut writer = BufWriter::new(writer);
+            while let Ok(entry) = rx.recv() {
+                // Write everything queued up before flushing
+                for entry in std::iter::once(entry).chain(rx.try_iter()) {
+                    let _ = serde_json::to_writer(&mut writer, &entry);
+                    let _ = writer.write_all(b"\n");
+                }
+                if let Err(e) = writer.flush() {
+                    log::error!("Error writing access log: {e:?}");
+                }
+            }
+        })
+        .context("Starting access log writer")?;
+    Ok(tx)
+}
+
+impl AccessLog {
+    // Keeps the current writer if the sink hasn't changed
+    pub fn set_sink(&self, sink: Option<&AccessLogSink>) {
+        let mut writer = self.writer.write();
+        if writer.as_ref().map(|(s, _)| s) == sink {
+            return;
+        }
+
+        *writer = sink.and_then(|sink| match spawn_writer(sink) {
+            Ok(tx) => Some((sink.clone(), tx)),
+            Err(e) => {
+                log::error!("Access log disabled: {e:?}");
+                None
+            }
+        });
+    }
+
+    fn emit(&self, entry: AccessLogEntry) {
+        if let Some((_, tx)) = self.writer.read().as_ref() {
+            if let Err(TrySendError::Full(_)) = tx.try_send(entry) {
+                log::warn!("Access log is falling behind, dropping entries");
+            }
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref ACCESS_LOG: AccessLog = Default::default();
+}
+
+// What a connection has done so far, logged when it finishes
+pub struct ConnectionRecord {
+    timestamp: DateTime<Utc>,
+    started: Instant,
+    src: Option<IpAddr>,
+    dst: String,
+    upstream: Option<String>,
+    // Client to upstream
+    pub tx: Arc<Counter>,
+    // Upstream to client
+    pub rx: Arc<Counter>,
+}
+
+impl ConnectionRecord {
+    pub fn new(src: Option<IpAddr>, dst: &Address<'_>) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            started: Instant::now(),
+            src,
+            dst: dst.to_string(),
+            upstream: None,
+            tx: Default::default(),
+            rx: Default::default(),
+        }
+    }
+
+    pub fn set_upstream(&mut self, name: &str) {
+        set_log_upstream(name);
+        self.upstream = Some(name.to_string());
+    }
+
+    pub fn finish(self, result: &anyhow::Result<()>) {
+        self.finish_to(&ACCESS_LOG, result)
+    }
+
+    fn finish_to(self, log: &AccessLog, result: &anyhow::Result<()>) {
+        let outcome = match result {
+            Ok(_) => "proxied",
+            Err(e) if ConnStatusCode::from_error(e) == ConnStatusCode::NOT_ALLOWED => "rejected",
+            Err(_) => "failed",
+        };
+
+        log.emit(AccessLogEntry {
+            timestamp: self.timestamp,
+            src: self.src,
+            dst: self.dst,
+            outcome,
+            upstream: self.upstream,
+            tx: self.tx.get(),
+            rx: self.rx.get(),
+            duration_ms: self.started.elapsed().as_millis(),
+            error: result.as_ref().err().map(|e| format!("{e:#}")),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use smol::Timer;
+
+    use super::*;
+
+    #[test]
+    fn access_log_records_connections() {
+        smol::block_on(async move {
+            let path = std::env::temp_dir().join(format!("{}.log", uuid::Uuid::new_v4()));
+            let log = AccessLog::default();
+            log.set_sink(Some(&AccessLogSink::File(path.clone())));
+
+            let dst: Address = "127.0.0.1:80".parse().unwrap();
+            let src = Some("10.0.0.1".parse().unwrap());
+            let mut record = ConnectionRecord::new(src, &dst);
+            record.set_upstream("direct");
+            record.tx.inc(12);
+            record.rx.inc(34);
+            record.finish_to(&log, &Ok(()));
+
+            let rejected = anyhow::Error::new(ConnStatusCode::NOT_ALLOWED);
+            ConnectionRecord::new(src, &dst).finish_to(&log, &Err(rejected));
+
+            let mut entries = Vec::new();
+            for _ in 0..50 {
+                entries = std::fs::read_to_string(&path)
+                    .unwrap_or_default()
+                    .lines()
+                    .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+                    .collect();
+                if entries.len() == 2 {
+                    break;
+                }
+                Timer::after(Duration::from_millis(20)).await;
+            }
+            log.set_sink(None);
+            let _ = std::fs::remove_file(&path);
+
+            let [proxied, rejected] = entries.as_slice() else {
+                panic!("Expecting two entries but got {entries:?}");
+            };
+            assert_eq!(proxied["src"], "10.0.0.1");
+            assert_eq!(proxied["dst"], "127.0.0.1:80");
+            assert_eq!(proxied["outcome"], "proxied");
+            assert_eq!(proxied["upstream"], "direct");
+            assert_eq!(proxied["tx"], 12);
+            assert_eq!(proxied["rx"], 34);
+            assert!(proxied["duration_ms"].is_u64());
+            assert!(proxied["timestamp"].is_string());
+            assert!(proxied["error"].is_null());
+
+            assert_eq!(rejected["outcome"], "rejected");
+            assert!(rejected["upstream"].is_null());
+            assert!(rejected["error"].is_string());
+        });
+    }
+}
@@ -0,0 +1,136 @@
+use std::{
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr},
+};
+
+use anyhow::Context;
+use smol::net::{TcpListener, TcpStream};
+use smol_timeout::TimeoutExt;
+
+use crate::{
+    config::ClientConfig,
+    handshake::Handshaker,
+    io::bind_tcp,
+    protocol::TrafficType,
+    socks5::{Address, ConnStatusCode},
+    utils::copy_duplex,
+};
+
+use super::ClientStatistics;
+
+// Where the peer will connect from, or None if `dst` doesn't say
+async fn expected_peer_ips(dst: &Address<'_>) -> anyhow::Result<Option<Vec<IpAddr>>> {
+    match dst {
+        Address::IP(addr) if addr.ip().is_unspecified() => Ok(None),
+        Address::IP(addr) => Ok(Some(vec![addr.ip().to_canonical()])),
+        Address::Name { .. } => Ok(Some(
+            dst.resolve()
+                .await?
+                .map(|addr| addr.ip().to_canonical())
+                .collect(),
+        )),
+    }
+}
+
+// Fails with NOT_ALLOWED if the traffic rules reject connections to `addr`. BIND is served
+// here whatever upstream the rules pick, so rejecting is all they can do.
+async fn check_rules(
+    addr: &Address<'_>,
+    src: Option<IpAddr>,
+    config: &ClientConfig,
+    stats: &ClientStatistics,
+) -> anyhow::Result<()> {
+    match config
+        .find_best_upstream(TrafficType::Stream, stats, addr, src, None)
+        .await
+    {
+        Err(e) if ConnStatusCode::from_error(&e) == ConnStatusCode::NOT_ALLOWED => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// Waits for a peer from `expected` that the rules allow to connect to `listener`. Other
+// connections are dropped.
+async fn accept_peer(
+    listener: &TcpListener,
+    dst: &Address<'_>,
+    expected: Option<&[IpAddr]>,
+    src: Option<IpAddr>,
+    config: &ClientConfig,
+    stats: &ClientStatistics,
+) -> anyhow::Result<(TcpStream, SocketAddr)> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        if expected.is_some_and(|ips| !ips.contains(&peer.ip().to_canonical())) {
+            log::warn!("Dropping connection from {peer} while waiting for {dst} to bind");
+            continue;
+        }
+        if let Err(e) = check_rules(&peer.into(), src, config, stats).await {
+            log::warn!("Dropping connection from {peer} for BIND to {dst}: {e:?}");
+            continue;
+        }
+        return Ok((stream, peer));
+    }
+}
+
+// SOCKS5 BIND is served here rather than through an upstream: the client is told where
+// its peer can connect, and the peer's connection is relayed once it's made.
+pub async fn serve_bind_proxy_conn(
+    dst: Address<'_>,
+    src: Option<IpAddr>,
+    config: &ClientConfig,
+    stats: &ClientStatistics,
+    mut socks: TcpStream,
+    hs: Handshaker,
+) -> anyhow::Result<()> {
+    let timeout = config.socks5_bind_timeout();
+
+    // The peer connects to the address the client reached us on
+    let listener = async {
+        let expected = expected_peer_ips(&dst)
+            .await
+            .with_context(|| format!("Resolving BIND peer {dst}"))?;
+        if expected.is_some() {
+            check_rules(&dst, src, config, stats).await?;
+        }
+
+        let listener = bind_tcp(&SocketAddr::new(socks.local_addr()?.ip(), 0).into()).await?;
+        let addr = listener.local_addr()?;
+        anyhow::Ok((listener, addr, expected))
+    }
+    .await
+    .context("Listening for BIND");
+    let (listener, listening, expected) = match listener {
+        Ok(v) => v,
+        Err(e) => {
+            hs.respond_err(&mut socks, &e).await?;
+            return Err(e);
+        }
+    };
+
+    log::info!("Waiting on {listening} for {dst} to bind");
+    hs.respond_bind_listening(&mut socks, listening).await?;
+
+    let (peer, peer_addr) =
+        match accept_peer(&listener, &dst, expected.as_deref(), src, config, stats)
+            .timeout(timeout)
+            .await
+            .unwrap_or_else(|| {
+                Err(std::io::Error::new(
+                    ErrorKind::TimedOut,
+                    format!("No connection from {dst} after {timeout:?}"),
+                )
+                .into())
+            }) {
+            Ok(v) => v,
+            Err(e) => {
+                hs.respond_err(&mut socks, &e).await?;
+                return Err(e).context("Waiting for BIND peer");
+            }
+        };
+    drop(listener);
+
+    log::info!("{peer_addr} connected for BIND to {dst}");
+    hs.respond_ok(&mut socks, Some(peer_addr)).await?;
+    copy_duplex(socks, peer, None, None).await
+}
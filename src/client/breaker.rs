@@ -0,0 +1,180 @@
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+// When to stop trying an upstream that keeps failing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CircuitBreakerConfig {
+    // How many failures in a row open the circuit
+    pub failures: usize,
+    // How close together the failures have to be
+    pub window_secs: u64,
+    // How long the upstream is skipped before it's tried again
+    pub cooldown_secs: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Closed {
+        failures: usize,
+        first_failure: Option<Instant>,
+    },
+    Open {
+        since: Instant,
+    },
+    // A single probe is let through to see if the upstream is back
+    HalfOpen {
+        probe_started: Instant,
+    },
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    state: Mutex<State>,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self {
+            state: Mutex::new(State::Closed {
+                failures: 0,
+                first_failure: None,
+            }),
+        }
+    }
+}
+
+impl CircuitBreaker {
+    // Whether the upstream should be tried. Once the cooldown is over, only one attempt is
+    // allowed until it's known to have failed or succeeded.
+    pub fn allows(&self, config: &CircuitBreakerConfig) -> bool {
+        let cooldown = Duration::from_secs(config.cooldown_secs);
+        let mut state = self.state.lock();
+        match *state {
+            State::Closed { .. } => true,
+            // A probe that never reported back doesn't keep the upstream shut forever
+            State::Open { since: started }
+            | State::HalfOpen {
+                probe_started: started,
+            } if started.elapsed() >= cooldown => {
+                *state = State::HalfOpen {
+                    probe_started: Instant::now(),
+                };
+                true
+            }
+            State::Open { .. } | State::HalfOpen { .. } => false,
+        }
+    }
+
+    pub fn record_success(&self) {
+        *self.state.lock() = State::Closed {
+            failures: 0,
+            first_failure: None,
+        };
+    }
+
+    pub fn record_failure(&self, config: &CircuitBreakerConfig) {
+        let window = Duration::from_secs(config.window_secs);
+        let mut state = self.state.lock();
+        *state = match *state {
+            State::Closed {
+                failures,
+                first_failure: Some(first_failure),
+            } if first_failure.elapsed() <= window => {
+                if failures + 1 >= config.failures {
+                    State::Open {
+                        since: Instant::now(),
+                    }
+                } else {
+                    State::Closed {
+                        failures: failures + 1,
+                        first_failure: Some(first_failure),
+                    }
+                }
+            }
+            State::Closed { .. } if config.failures <= 1 => State::Open {
+                since: Instant::now(),
+            },
+            State::Closed { .. } => State::Closed {
+                failures: 1,
+                first_failure: Some(Instant::now()),
+            },
+            State::Open { .. } | State::HalfOpen { .. } => State::Open {
+                since: Instant::now(),
+            },
+        };
+    }
+
+    pub fn is_open(&self) -> bool {
+        !matches!(*self.state.lock(), State::Closed { .. })
+    }
+
+    pub fn state_name(&self) -> &'static str {
+        match *self.state.lock() {
+            State::Closed { .. } => "closed",
+            State::Open { .. } => "open",
+            State::HalfOpen { .. } => "half-open",
+        }
+    }
+}
+
+// Shown in the stats by its state. The timings behind it mean nothing to another process,
+// so it's read back closed.
+impl Serialize for CircuitBreaker {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.state_name())
+    }
+}
+
+impl<'de> Deserialize<'de> for CircuitBreaker {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        serde::de::IgnoredAny::deserialize(deserializer)?;
+        Ok(Default::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    #[test]
+    fn opens_and_recovers_through_probe() {
+        let config = CircuitBreakerConfig {
+            failures: 3,
+            window_secs: 60,
+            cooldown_secs: 1,
+        };
+        let breaker = CircuitBreaker::default();
+
+        breaker.record_failure(&config);
+        breaker.record_failure(&config);
+        assert!(breaker.allows(&config));
+        // A success in between starts the count again
+        breaker.record_success();
+        breaker.record_failure(&config);
+        breaker.record_failure(&config);
+        assert!(!breaker.is_open());
+
+        breaker.record_failure(&config);
+        assert!(breaker.is_open());
+        assert!(!breaker.allows(&config));
+
+        // One probe is let through after the cooldown, and a failed one reopens it
+        sleep(Duration::from_millis(1100));
+        assert!(breaker.allows(&config));
+        assert!(!breaker.allows(&config));
+        breaker.record_failure(&config);
+        assert!(!breaker.allows(&config));
+
+        // A successful probe closes it again
+        sleep(Duration::from_millis(1100));
+        assert!(breaker.allows(&config));
+        breaker.record_success();
+        assert!(!breaker.is_open());
+        assert!(breaker.allows(&config));
+        assert!(breaker.allows(&config));
+    }
+}
@@ -1,49 +1,112 @@
-use std::time::Instant;
+use std::{net::IpAddr, time::Instant};
 
 use anyhow::{anyhow, Context};
 
 use crate::{
-    config::ClientConfig,
+    config::{ClientConfig, UpstreamProtocol},
+    io::{is_timeout_error, read_first_bytes, AsyncStreamCounter, IdleTimeoutStream},
     protocol::{AsyncStream, Protocol, Stats, TrafficType},
-    socks5::Address,
+    socks5::{Address, ConnStatusCode},
 };
 
 use super::ClientStatistics;
 
-pub async fn find_and_connect_stream(
+// Returns the name of the upstream connected to along with the stream
+pub async fn find_and_connect_stream<'a>(
     dst: &Address<'_>,
+    src: Option<IpAddr>,
     initial_data: Option<&[u8]>,
-    client_config: &ClientConfig,
+    client_config: &'a ClientConfig,
     stats: &ClientStatistics,
-) -> anyhow::Result<Box<dyn AsyncStream>> {
-    let mut upstreams =
-        client_config.find_best_upstream(TrafficType::Stream, stats, dst, initial_data.clone())?;
+) -> anyhow::Result<(&'a str, Box<dyn AsyncStream>)> {
+    let mut upstreams = client_config
+        .find_best_upstream(TrafficType::Stream, stats, dst, src, initial_data)
+        .await?;
     let mut last_error = None;
 
     while let Some((name, config)) = upstreams.pop() {
         log::debug!("Trying TCP:://{dst} on {name}");
 
+        // A direct upstream's failures are the destination's own
+        let breaker = client_config
+            .upstream_circuit_breaker
+            .as_ref()
+            .filter(|_| !matches!(config.protocol, UpstreamProtocol::Direct(_)))
+            .zip(stats.upstreams.get(name).map(|s| &s.breaker));
+        if let Some((breaker_config, breaker)) = breaker {
+            if !breaker.allows(breaker_config) {
+                log::debug!("Skipping upstream {name} while its circuit is open");
+                last_error.replace(anyhow!("Circuit open for upstream {name}"));
+                continue;
+            }
+        }
+
         let protocol_stats = stats
             .upstreams
             .get(name)
             .map(|s| Stats {
                 tx: s.tx.clone(),
                 rx: s.rx.clone(),
+                compression: s.compression.clone(),
             })
             .unwrap_or_default();
 
         let start = Instant::now();
 
-        match config
+        let upstream = config
             .protocol
-            .new_stream(dst, initial_data, &protocol_stats, client_config.fwmark)
+            .new_stream(
+                dst,
+                initial_data,
+                &protocol_stats,
+                &client_config.tcp_options(),
+            )
             .await
-            .with_context(|| format!("Requesting new streaming connection from {name}"))
-        {
+            .with_context(|| format!("Requesting new streaming connection from {name}"));
+        let latency = start.elapsed();
+
+        // Only the connection and handshake with the upstream tell whether it's up
+        if let Some((breaker_config, breaker)) = breaker {
+            match &upstream {
+                Err(err) if is_upstream_failure(err) => breaker.record_failure(breaker_config),
+                _ => breaker.record_success(),
+            }
+        }
+
+        // Only wait for the response when there's a request to respond to
+        let upstream = match (upstream, client_config.first_byte_timeout()) {
+            (Ok(upstream), Some(timeout)) if initial_data.map(|d| !d.is_empty()) == Some(true) => {
+                read_first_bytes(upstream, timeout)
+                    .await
+                    .map(|s| Box::new(s) as Box<dyn AsyncStream>)
+                    .with_context(|| format!("Waiting for response from {name}"))
+            }
+            (upstream, _) => upstream,
+        };
+
+        match upstream {
             Ok(upstream) => {
-                let latency = start.elapsed();
                 stats.update_upstream(name, latency);
-                return Ok(upstream);
+                let upstream = match config.idle_timeout() {
+                    Some(timeout) => Box::new(IdleTimeoutStream::new(upstream, timeout)),
+                    None => upstream,
+                };
+                let Some(s) = stats.upstreams.get(name) else {
+                    return Ok((name, upstream));
+                };
+                s.payload_tx.inc(initial_data.map_or(0, <[u8]>::len));
+                return Ok((
+                    name,
+                    Box::new(AsyncStreamCounter::new(
+                        upstream,
+                        s.payload_rx.clone(),
+                        s.payload_tx.clone(),
+                    )),
+                ));
+            }
+            Err(err) if is_timeout_error(&err) => {
+                log::warn!("Timeout connecting to upstream: {name}, trying next one");
+                last_error.replace(err)
             }
             Err(err) => {
                 log::error!("Error connecting to upstream: {name}: {err:?}");
@@ -54,3 +117,334 @@ pub async fn find_and_connect_stream(
 
     Err(last_error.unwrap_or_else(|| anyhow!("No upstream available")))
 }
+
+// An upstream that answered it couldn't reach the destination is up
+fn is_upstream_failure(err: &anyhow::Error) -> bool {
+    !err.chain().any(|e| e.is::<ConnStatusCode>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client::CircuitBreakerConfig,
+        config::UpstreamConfig,
+        protocol::{direct::Direct, socks5::Socks5},
+        test::{create_tcp_server, echo_tcp_server},
+    };
+    use futures::{AsyncReadExt, AsyncWriteExt};
+    use maplit::hashmap;
+    use smol::spawn;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use std::time::Duration;
+
+    fn config_with_first_byte_timeout() -> ClientConfig {
+        ClientConfig {
+            upstreams: hashmap! {
+                String::from("direct") => UpstreamConfig {
+                    protocol: UpstreamProtocol::Direct(Direct),
+                    enabled: true,
+                    idle_timeout_secs: None,
+                    groups: Default::default(),
+                }
+            },
+            first_byte_timeout_secs: Some(1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn first_byte_timeout_works() {
+        smol::block_on(async move {
+            let config = config_with_first_byte_timeout();
+            let stats = ClientStatistics::new(&config);
+
+            // An upstream that accepts but never responds
+            let (server, addr) = create_tcp_server().await;
+            let _server_task = spawn(async move {
+                let (_stream, _) = server.accept().await.unwrap();
+                futures::future::pending::<()>().await;
+            });
+
+            let started = Instant::now();
+            let err = find_and_connect_stream(&addr.into(), None, Some(b"hello"), &config, &stats)
+                .await
+                .err()
+                .expect("To time out");
+            assert!(is_timeout_error(&err));
+            assert!(started.elapsed() >= Duration::from_secs(1));
+
+            // The response read while waiting is still delivered
+            let (_echo_task, echo_addr) = echo_tcp_server().await;
+            let (_, mut stream) =
+                find_and_connect_stream(&echo_addr.into(), None, Some(b"hello"), &config, &stats)
+                    .await
+                    .unwrap();
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+
+            stream.write_all(b"world").await.unwrap();
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"world");
+        });
+    }
+
+    #[test]
+    fn upstream_attempts_are_capped() {
+        smol::block_on(async move {
+            // Every upstream points at a server that hangs up straight away
+            let (server, addr) = create_tcp_server().await;
+            let attempts = Arc::new(AtomicUsize::new(0));
+            let _server_task = spawn({
+                let attempts = attempts.clone();
+                async move {
+                    while let Ok((stream, _)) = server.accept().await {
+                        attempts.fetch_add(1, Ordering::SeqCst);
+                        drop(stream);
+                    }
+                }
+            });
+
+            let config = ClientConfig {
+                upstreams: (0..5)
+                    .map(|i| {
+                        (
+                            format!("failing{i}"),
+                            UpstreamConfig {
+                                protocol: UpstreamProtocol::Socks5(Socks5 {
+                                    address: addr.into(),
+                                    supports_udp: false,
+                                    udp_mtu: None,
+                                }),
+                                enabled: true,
+                                idle_timeout_secs: None,
+                                groups: Default::default(),
+                            },
+                        )
+                    })
+                    .collect(),
+                max_upstream_attempts: Some(2),
+                ..Default::default()
+            };
+            let stats = ClientStatistics::new(&config);
+
+            let dst: Address = "1.2.3.4:80".parse().unwrap();
+            find_and_connect_stream(&dst, None, None, &config, &stats)
+                .await
+                .err()
+                .expect("To fail");
+            assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        });
+    }
+
+    #[test]
+    fn failing_upstream_trips_circuit_breaker() {
+        smol::block_on(async move {
+            // Hangs up straight away until told to be healthy, then proxies anything
+            let (server, addr) = create_tcp_server().await;
+            let attempts = Arc::new(AtomicUsize::new(0));
+            let healthy = Arc::new(AtomicUsize::new(0));
+            let _server_task = spawn({
+                let attempts = attempts.clone();
+                let healthy = healthy.clone();
+                async move {
+                    while let Ok((mut stream, _)) = server.accept().await {
+                        attempts.fetch_add(1, Ordering::SeqCst);
+                        if healthy.load(Ordering::SeqCst) == 0 {
+                            continue;
+                        }
+                        // Accept the socks5 greeting and the request for 1.2.3.4:80
+                        spawn(async move {
+                            let mut buf = [0u8; 10];
+                            stream.read_exact(&mut buf[..3]).await?;
+                            stream.write_all(&[5, 0]).await?;
+                            stream.read_exact(&mut buf).await?;
+                            stream.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80]).await?;
+                            stream.read(&mut buf).await
+                        })
+                        .detach();
+                    }
+                }
+            });
+
+            let config = ClientConfig {
+                upstreams: hashmap! {
+                    String::from("flaky") => UpstreamConfig {
+                        protocol: UpstreamProtocol::Socks5(Socks5 {
+                            address: addr.into(),
+                            supports_udp: false,
+                            udp_mtu: None,
+                        }),
+                        enabled: true,
+                        idle_timeout_secs: None,
+                        groups: Default::default(),
+                    }
+                },
+                upstream_circuit_breaker: Some(CircuitBreakerConfig {
+                    failures: 2,
+                    window_secs: 60,
+                    cooldown_secs: 1,
+                }),
+                ..Default::default()
+            };
+            let stats = ClientStatistics::new(&config);
+            let dst: Address = "1.2.3.4:80".parse().unwrap();
+
+            for _ in 0..2 {
+                find_and_connect_stream(&dst, None, None, &config, &stats)
+                    .await
+                    .err()
+                    .expect("To fail");
+            }
+            assert_eq!(attempts.load(Ordering::SeqCst), 2);
+            assert!(stats.upstreams["flaky"].breaker.is_open());
+
+            // While open, the upstream isn't even tried
+            let err = find_and_connect_stream(&dst, None, None, &config, &stats)
+                .await
+                .err()
+                .expect("To fail fast");
+            assert!(err.to_string().contains("Circuit open"));
+            assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+            // After the cooldown, a successful probe closes the circuit
+            healthy.store(1, Ordering::SeqCst);
+            smol::Timer::after(Duration::from_millis(1100)).await;
+            find_and_connect_stream(&dst, None, None, &config, &stats)
+                .await
+                .unwrap();
+            assert_eq!(attempts.load(Ordering::SeqCst), 3);
+            assert!(!stats.upstreams["flaky"].breaker.is_open());
+        });
+    }
+
+    #[test]
+    fn unreachable_destinations_leave_circuit_closed() {
+        smol::block_on(async move {
+            // A healthy socks5 upstream that can't reach anything
+            let (server, addr) = create_tcp_server().await;
+            let _server_task = spawn(async move {
+                while let Ok((mut stream, _)) = server.accept().await {
+                    spawn(async move {
+                        let mut buf = [0u8; 10];
+                        stream.read_exact(&mut buf[..3]).await?;
+                        stream.write_all(&[5, 0]).await?;
+                        stream.read_exact(&mut buf).await?;
+                        stream.write_all(&[5, 4, 0, 1, 0, 0, 0, 0, 0, 0]).await
+                    })
+                    .detach();
+                }
+            });
+
+            let config = ClientConfig {
+                upstreams: hashmap! {
+                    String::from("live") => UpstreamConfig {
+                        protocol: UpstreamProtocol::Socks5(Socks5 {
+                            address: addr.into(),
+                            supports_udp: false,
+                            udp_mtu: None,
+                        }),
+                        enabled: true,
+                        idle_timeout_secs: None,
+                        groups: Default::default(),
+                    }
+                },
+                upstream_circuit_breaker: Some(CircuitBreakerConfig {
+                    failures: 2,
+                    window_secs: 60,
+                    cooldown_secs: 60,
+                }),
+                ..Default::default()
+            };
+            let stats = ClientStatistics::new(&config);
+            let dst: Address = "1.2.3.4:80".parse().unwrap();
+
+            for _ in 0..3 {
+                let err = find_and_connect_stream(&dst, None, None, &config, &stats)
+                    .await
+                    .err()
+                    .expect("To fail");
+                assert_eq!(
+                    ConnStatusCode::from_error(&err),
+                    ConnStatusCode::HOST_UNREACHABLE
+                );
+            }
+            assert!(!stats.upstreams["live"].breaker.is_open());
+
+            let json = serde_json::to_value(&stats).unwrap();
+            assert_eq!(json["upstreams"]["live"]["breaker"], "closed");
+            stats.upstreams["live"]
+                .breaker
+                .record_failure(&CircuitBreakerConfig {
+                    failures: 1,
+                    window_secs: 60,
+                    cooldown_secs: 60,
+                });
+            let json = serde_json::to_value(&stats).unwrap();
+            assert_eq!(json["upstreams"]["live"]["breaker"], "open");
+            assert!(stats
+                .to_prometheus()
+                .contains("cpxy_upstream_circuit_open{upstream=\"live\"} 1"));
+        });
+    }
+
+    #[test]
+    fn bypass_networks_skip_upstreams() {
+        smol::block_on(async move {
+            // The only upstream counts the connections it gets
+            let (server, upstream_addr) = create_tcp_server().await;
+            let attempts = Arc::new(AtomicUsize::new(0));
+            let _server_task = spawn({
+                let attempts = attempts.clone();
+                async move {
+                    while let Ok((stream, _)) = server.accept().await {
+                        attempts.fetch_add(1, Ordering::SeqCst);
+                        drop(stream);
+                    }
+                }
+            });
+
+            let config = ClientConfig {
+                upstreams: hashmap! {
+                    String::from("socks5") => UpstreamConfig {
+                        protocol: UpstreamProtocol::Socks5(Socks5 {
+                            address: upstream_addr.into(),
+                            supports_udp: false,
+                            udp_mtu: None,
+                        }),
+                        enabled: true,
+                        idle_timeout_secs: None,
+                        groups: Default::default(),
+                    }
+                },
+                bypass_networks: vec!["127.0.0.0/8".parse().unwrap()],
+                ..Default::default()
+            };
+            let stats = ClientStatistics::new(&config);
+
+            let (_echo_task, echo_addr) = echo_tcp_server().await;
+            let (name, mut stream) =
+                find_and_connect_stream(&echo_addr.into(), None, None, &config, &stats)
+                    .await
+                    .unwrap();
+            assert_eq!(name, "bypass");
+
+            stream.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+            assert_eq!(attempts.load(Ordering::SeqCst), 0);
+
+            // Anything else still goes through the upstream
+            let dst: Address = "1.2.3.4:80".parse().unwrap();
+            assert!(find_and_connect_stream(&dst, None, None, &config, &stats)
+                .await
+                .is_err());
+            assert_eq!(attempts.load(Ordering::SeqCst), 1);
+        });
+    }
+}
@@ -1,9 +1,14 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use crate::{
-    client::tcp::serve_tcp_tproxy_conn,
+    abp::{adblock_list_engine, gfw_list_engine, HostWhitelist},
+    client::{access_log::ACCESS_LOG, tcp::serve_tcp_tproxy_conn},
+    dns::{set_split_dns, QUERY_LIMITER, STALE_CACHE},
+    drain::ConnectionTracker,
+    geoip::set_geoip_overlap_policy,
     io::{bind_tcp, TcpStreamExt},
     iptables as ipt,
+    logging::with_connection_context,
 };
 use anyhow::Context;
 use futures::{Stream, StreamExt};
@@ -20,7 +25,8 @@ use crate::{
 };
 
 use super::{
-    http::serve_http_proxy_conn, tcp::serve_tcp_proxy_conn, udp::serve_udp_proxy_conn,
+    bind::serve_bind_proxy_conn, http::serve_http_proxy_conn, probe::run_health_checks,
+    sni::run_sni_proxy_with, tcp::serve_tcp_proxy_conn, udp::serve_udp_proxy_conn,
     ClientStatistics,
 };
 
@@ -29,6 +35,7 @@ pub async fn run_client(
         + Send
         + Sync
         + Unpin,
+    connections: ConnectionTracker,
 ) -> anyhow::Result<()> {
     let mut current_tasks = Vec::<Task<_>>::with_capacity(2);
     loop {
@@ -46,6 +53,24 @@ pub async fn run_client(
             task.cancel().await;
         }
         let _ = ipt::clean_up();
+        QUERY_LIMITER.set_limit(config.max_concurrent_dns_queries);
+        STALE_CACHE.set_max_stale(config.dns_serve_stale_secs.map(Duration::from_secs));
+        if let Err(e) = set_split_dns(&config.dns_servers) {
+            log::error!("Error loading DNS servers, keeping what was loaded: {e:#}");
+        }
+        ACCESS_LOG.set_sink(config.access_log.as_ref());
+        if let Err(e) = set_geoip_overlap_policy(config.geoip_overlap_policy) {
+            log::error!("Error loading GeoIP data, keeping what was loaded: {e:#}");
+        }
+        for engine in [gfw_list_engine(), adblock_list_engine()] {
+            engine.set_cache_dir(config.abp_cache_dir.as_deref());
+            engine.set_whitelist(HostWhitelist::new(
+                config.abp_whitelist.iter().map(String::as_str),
+            ));
+        }
+        gfw_list_engine().set_sources(config.gfw_list_sources.as_deref());
+        adblock_list_engine().set_sources(config.adblock_list_sources.as_deref());
+        gfw_list_engine().set_enabled(!config.disable_gfw_list);
 
         let proxy_listener = match bind_tcp(&Address::IP(config.socks5_address)).await {
             Ok(v) => v,
@@ -59,6 +84,7 @@ pub async fn run_client(
             if let Err(e) = ipt::add_rules(
                 config.socks5_address.port(),
                 config.udp_tproxy_address.map(|v| v.port()),
+                &config.bypass_networks,
             ) {
                 log::error!("Error setting router rules: {e:?}");
                 let _ = ipt::clean_up();
@@ -71,8 +97,32 @@ pub async fn run_client(
             proxy_listener,
             config.clone(),
             stats.clone(),
+            connections.clone(),
         )));
 
+        if let Some(interval) = config.health_check.as_ref().and_then(|h| h.interval_secs) {
+            let (config, stats) = (config.clone(), stats.clone());
+            current_tasks.push(spawn(async move {
+                run_health_checks(&config, &stats, Duration::from_secs(interval)).await;
+                Ok(())
+            }));
+        }
+
+        if let Some(addr) = config.sni_proxy_address {
+            match bind_tcp(&Address::IP(addr)).await {
+                Ok(listener) => {
+                    log::info!("TLS passthrough listening on {addr}");
+                    current_tasks.push(spawn(run_sni_proxy_with(
+                        listener,
+                        config.clone(),
+                        stats.clone(),
+                        connections.clone(),
+                    )));
+                }
+                Err(e) => log::error!("Error listening for TLS passthrough: {e:?}"),
+            }
+        }
+
         // UDP tproxy?
         #[cfg(target_os = "linux")]
         {
@@ -100,6 +150,7 @@ pub async fn run_proxy_with(
     proxy_listener: TcpListener,
     config: Arc<ClientConfig>,
     stats: Arc<ClientStatistics>,
+    connections: ConnectionTracker,
 ) -> anyhow::Result<()> {
     loop {
         let (sock, addr) = proxy_listener
@@ -109,14 +160,13 @@ pub async fn run_proxy_with(
 
         let config = config.clone();
         let stats = stats.clone();
-        spawn(async move {
+        connections.spawn(with_connection_context(async move {
             log::info!("Client {addr} connected");
             if let Err(e) = serve_proxy_conn(sock, config, stats).await {
                 log::error!("Error serving client {addr}: {e:?}");
             }
             log::info!("Client {addr} disconnected");
-        })
-        .detach();
+        }));
     }
 }
 
@@ -125,9 +175,10 @@ async fn serve_proxy_conn(
     config: Arc<ClientConfig>,
     stats: Arc<ClientStatistics>,
 ) -> anyhow::Result<()> {
+    let src = socks.peer_addr().ok().map(|addr| addr.ip());
     if let Some(orig_dst) = socks.get_original_dst() {
         log::info!("Requesting to proxy to {orig_dst} transparently");
-        return serve_tcp_tproxy_conn(orig_dst.into(), &config, &stats, socks).await;
+        return serve_tcp_tproxy_conn(orig_dst.into(), src, &config, &stats, socks).await;
     }
 
     let mut buf = RWBuffer::new_vec_uninitialised(512);
@@ -137,11 +188,15 @@ async fn serve_proxy_conn(
     log::info!("Requesting to proxy {req:?}");
 
     match req {
-        HR::TCP { dst } => serve_tcp_proxy_conn(dst, &config, &stats, socks, hs).await,
+        HR::TCP { dst } => serve_tcp_proxy_conn(dst, src, &config, &stats, socks, hs).await,
         HR::HTTP { dst, https, req } => {
-            serve_http_proxy_conn(dst, https, req, &config, &stats, socks, hs).await
+            serve_http_proxy_conn(dst, src, https, req, &config, &stats, socks, hs).await
+        }
+
+        HR::UDP { dst } => {
+            serve_udp_proxy_conn(&config, &stats, src, dst, socks.is_v4(), socks, hs).await
         }
 
-        HR::UDP { .. } => serve_udp_proxy_conn(&config, &stats, socks.is_v4(), socks, hs).await,
+        HR::Bind { dst } => serve_bind_proxy_conn(dst, src, &config, &stats, socks, hs).await,
     }
 }
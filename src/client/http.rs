@@ -1,16 +1,22 @@
+use std::net::IpAddr;
+
 use anyhow::Context;
-use async_native_tls::TlsConnector;
-use futures::{AsyncRead, AsyncWrite, TryFutureExt};
+use futures::{AsyncRead, AsyncWrite, AsyncWriteExt, TryFutureExt};
 
 use crate::{
-    config::ClientConfig, handshake::Handshaker, http::HttpRequest, socks5::Address,
+    config::ClientConfig,
+    handshake::Handshaker,
+    http::HttpRequest,
+    socks5::Address,
+    tls::{connect_tls, TlsOptions},
     utils::copy_duplex,
 };
 
-use super::{common::find_and_connect_stream, ClientStatistics};
+use super::{access_log::ConnectionRecord, common::find_and_connect_stream, ClientStatistics};
 
 pub async fn serve_http_proxy_conn(
     dst: Address<'_>,
+    src: Option<IpAddr>,
     https: bool,
     req: HttpRequest<'_>,
     config: &ClientConfig,
@@ -18,57 +24,85 @@ pub async fn serve_http_proxy_conn(
     mut stream: impl AsyncRead + AsyncWrite + Unpin + Send + Sync,
     handshaker: Handshaker,
 ) -> anyhow::Result<()> {
-    if https {
-        let upstream = match find_and_connect_tls(&dst, config, stats)
-            .and_then(move |mut upstream| async move {
-                req.to_async_writer(&mut upstream).await?;
-                Ok(upstream)
-            })
+    let mut record = ConnectionRecord::new(src, &dst);
+    let result = async {
+        if https {
+            let request = req.to_builder().finalise();
+            let written = &request;
+            let (name, upstream) = match find_and_connect_tls(&dst, src, config, stats)
+                .and_then(move |(name, mut upstream)| async move {
+                    upstream.write_all(written).await?;
+                    Ok((name, upstream))
+                })
+                .await
+            {
+                Ok(s) => {
+                    handshaker.respond_ok(&mut stream, None).await?;
+                    s
+                }
+                Err(e) => {
+                    handshaker.respond_err(&mut stream, &e).await?;
+                    return Err(e);
+                }
+            };
+            record.set_upstream(name);
+            record.tx.inc(request.len());
+            copy_duplex(
+                stream,
+                upstream,
+                Some(record.tx.clone()),
+                Some(record.rx.clone()),
+            )
             .await
-        {
-            Ok(s) => {
-                handshaker.respond_ok(&mut stream, None).await?;
-                s
-            }
-            Err(e) => {
-                handshaker.respond_err(&mut stream).await?;
-                return Err(e);
-            }
-        };
-        copy_duplex(stream, upstream, None, None).await
-    } else {
-        let upstream = match find_and_connect_stream(
-            &dst,
-            Some(req.to_builder().finalise().as_slice()),
-            config,
-            stats,
-        )
-        .await
-        {
-            Ok(s) => {
-                handshaker.respond_ok(&mut stream, None).await?;
-                s
-            }
-            Err(e) => {
-                handshaker.respond_err(&mut stream).await?;
-                return Err(e);
-            }
-        };
-        copy_duplex(stream, upstream, None, None).await
+        } else {
+            let initial_data = req.to_builder().finalise();
+            let (name, upstream) = match find_and_connect_stream(
+                &dst,
+                src,
+                Some(initial_data.as_slice()),
+                config,
+                stats,
+            )
+            .await
+            {
+                Ok(s) => {
+                    handshaker.respond_ok(&mut stream, None).await?;
+                    s
+                }
+                Err(e) => {
+                    handshaker.respond_err(&mut stream, &e).await?;
+                    return Err(e);
+                }
+            };
+            record.set_upstream(name);
+            record.tx.inc(initial_data.len());
+            copy_duplex(
+                stream,
+                upstream,
+                Some(record.tx.clone()),
+                Some(record.rx.clone()),
+            )
+            .await
+        }
     }
+    .await;
+    record.finish(&result);
+    result
 }
 
-async fn find_and_connect_tls(
+async fn find_and_connect_tls<'a>(
     dst: &Address<'_>,
-    config: &ClientConfig,
+    src: Option<IpAddr>,
+    config: &'a ClientConfig,
     stats: &ClientStatistics,
-) -> anyhow::Result<impl AsyncRead + AsyncWrite + Unpin + Send + Sync> {
-    let upstream = find_and_connect_stream(&dst, None, config, stats)
+) -> anyhow::Result<(&'a str, impl AsyncRead + AsyncWrite + Unpin + Send + Sync)> {
+    let (name, upstream) = find_and_connect_stream(dst, src, None, config, stats)
         .await
         .context("Connecting to upstream")?;
 
-    TlsConnector::new()
-        .connect(dst.get_host().as_ref(), upstream)
+    let options = TlsOptions::default();
+    let stream = connect_tls(options.connector()?, &options.server_name(dst), upstream)
         .await
-        .context("Connecting to TLS stream")
+        .context("Connecting to TLS stream")?;
+    Ok((name, stream))
 }
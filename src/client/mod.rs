@@ -1,6 +1,11 @@
+mod access_log;
+mod bind;
+mod breaker;
 mod common;
 mod handler;
 mod http;
+mod probe;
+mod sni;
 mod stats;
 mod tcp;
 #[cfg(target_os = "linux")]
@@ -8,5 +13,8 @@ mod transparent;
 mod udp;
 mod utils;
 
+pub use access_log::AccessLogSink;
+pub use breaker::{CircuitBreaker, CircuitBreakerConfig};
 pub use handler::*;
+pub use probe::{probe_upstreams, HealthCheckConfig};
 pub use stats::*;
@@ -0,0 +1,256 @@
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use async_io::Timer;
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use smol_timeout::TimeoutExt;
+
+use crate::{
+    config::{ClientConfig, UpstreamConfig},
+    io::TcpOptions,
+    protocol::{Protocol, Stats},
+    socks5::Address,
+};
+
+use super::ClientStatistics;
+
+// Checks the upstreams by connecting through them, instead of waiting for real traffic
+// to find the ones that are down
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthCheckConfig {
+    // What the probes connect to through each upstream
+    pub target: Address<'static>,
+    // How often the upstreams are probed. Without it they're only probed through the API.
+    #[serde(default)]
+    pub interval_secs: Option<u64>,
+    // How long a probe has to connect. Defaults to the connect timeout.
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProbeResult {
+    pub delay_ms: Option<u64>,
+    pub error: Option<String>,
+}
+
+impl From<&anyhow::Result<Duration>> for ProbeResult {
+    fn from(r: &anyhow::Result<Duration>) -> Self {
+        match r {
+            Ok(delay) => Self {
+                delay_ms: Some(delay.as_millis() as u64),
+                error: None,
+            },
+            Err(e) => Self {
+                delay_ms: None,
+                error: Some(format!("{e:#}")),
+            },
+        }
+    }
+}
+
+// Opens a stream to `target` through the upstream, returning how long it took to connect
+// and go through the upstream's handshakes
+pub async fn probe(
+    upstream: &UpstreamConfig,
+    target: &Address<'_>,
+    timeout: Duration,
+    options: &TcpOptions,
+) -> anyhow::Result<Duration> {
+    let started = Instant::now();
+    let stream = upstream
+        .protocol
+        .new_stream(target, None, &Stats::default(), options)
+        .timeout(timeout)
+        .await
+        .ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::TimedOut,
+                format!("No connection to {target} after {timeout:?}"),
+            )
+        })??;
+    let delay = started.elapsed();
+    drop(stream);
+    Ok(delay)
+}
+
+// Probes the enabled upstreams, or just the one named, all at once. Failures count
+// towards opening their circuit breakers, and successes close them.
+pub async fn probe_upstreams(
+    config: &ClientConfig,
+    stats: &ClientStatistics,
+    name: Option<&str>,
+) -> anyhow::Result<HashMap<String, ProbeResult>> {
+    let health_check = config
+        .health_check
+        .as_ref()
+        .context("No health check is configured")?;
+    let timeout = health_check
+        .timeout_secs
+        .map(Duration::from_secs)
+        .unwrap_or_else(|| config.connect_timeout());
+    let options = &config.tcp_options();
+
+    let probes = config
+        .upstreams
+        .iter()
+        .filter(|(n, u)| u.enabled && name.is_none_or(|name| name == n.as_str()))
+        .map(|(n, upstream)| async move {
+            let result = probe(upstream, &health_check.target, timeout, options).await;
+            if let Some(s) = stats.upstreams.get(n) {
+                match &result {
+                    Ok(delay) => {
+                        s.last_latency.set(delay.as_millis() as usize);
+                        s.breaker.record_success();
+                    }
+                    Err(e) => {
+                        log::warn!("Upstream {n} failed its health check: {e:#}");
+                        if let Some(breaker_config) = &config.upstream_circuit_breaker {
+                            s.breaker.record_failure(breaker_config);
+                        }
+                    }
+                }
+            }
+            (n.clone(), ProbeResult::from(&result))
+        });
+
+    let results: HashMap<_, _> = join_all(probes).await.into_iter().collect();
+    if let Some(name) = name {
+        if results.is_empty() {
+            anyhow::bail!("No enabled upstream named {name}");
+        }
+    }
+    Ok(results)
+}
+
+// Probes the upstreams every `interval`, starting straight away
+pub(super) async fn run_health_checks(
+    config: &ClientConfig,
+    stats: &ClientStatistics,
+    interval: Duration,
+) {
+    loop {
+        if let Err(e) = probe_upstreams(config, stats, None).await {
+            log::error!("Error checking upstreams: {e:#}");
+        }
+        Timer::after(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client::{run_proxy_with, CircuitBreakerConfig},
+        config::UpstreamProtocol,
+        protocol::{direct::Direct, socks5::Socks5},
+        test::{create_tcp_server, echo_tcp_server},
+    };
+    use maplit::hashmap;
+    use smol::spawn;
+    use std::{net::SocketAddr, sync::Arc};
+
+    fn socks5_upstream(address: SocketAddr) -> UpstreamConfig {
+        UpstreamConfig {
+            protocol: UpstreamProtocol::Socks5(Socks5 {
+                address: address.into(),
+                supports_udp: false,
+                udp_mtu: None,
+            }),
+            groups: None,
+            enabled: true,
+            idle_timeout_secs: None,
+        }
+    }
+
+    #[test]
+    fn probes_live_and_dead_upstreams() {
+        smol::block_on(async move {
+            // A proxy of our own is the live upstream
+            let (listener, live_addr) = create_tcp_server().await;
+            let proxy_config = ClientConfig {
+                upstreams: hashmap! {
+                    String::from("direct") => UpstreamConfig {
+                        protocol: UpstreamProtocol::Direct(Direct),
+                        groups: None,
+                        enabled: true,
+                        idle_timeout_secs: None,
+                    }
+                },
+                ..Default::default()
+            };
+            let proxy_stats = ClientStatistics::new(&proxy_config);
+            let _proxy = spawn(run_proxy_with(
+                listener,
+                Arc::new(proxy_config),
+                Arc::new(proxy_stats),
+                Default::default(),
+            ));
+            let (_echo, echo_addr) = echo_tcp_server().await;
+
+            // Nothing answers from there
+            let dead_addr: SocketAddr = "10.255.255.1:1080".parse().unwrap();
+            let timeout = Duration::from_millis(500);
+
+            let config = ClientConfig {
+                upstreams: hashmap! {
+                    String::from("live") => socks5_upstream(live_addr),
+                    String::from("dead") => socks5_upstream(dead_addr),
+                },
+                health_check: Some(HealthCheckConfig {
+                    target: echo_addr.into(),
+                    interval_secs: None,
+                    timeout_secs: Some(1),
+                }),
+                upstream_circuit_breaker: Some(CircuitBreakerConfig {
+                    failures: 1,
+                    window_secs: 60,
+                    cooldown_secs: 60,
+                }),
+                ..Default::default()
+            };
+            let stats = ClientStatistics::new(&config);
+
+            let delay = probe(
+                &config.upstreams["live"],
+                &echo_addr.into(),
+                timeout,
+                &Default::default(),
+            )
+            .await
+            .unwrap();
+            assert!(delay < timeout);
+
+            let started = Instant::now();
+            assert!(probe(
+                &config.upstreams["dead"],
+                &echo_addr.into(),
+                timeout,
+                &Default::default()
+            )
+            .await
+            .is_err());
+            assert!(started.elapsed() < timeout * 2);
+
+            // The results go to the circuit breakers
+            let results = probe_upstreams(&config, &stats, None).await.unwrap();
+            assert!(results["live"].delay_ms.is_some());
+            assert!(results["dead"].error.is_some());
+            assert!(!stats.upstreams["live"].breaker.is_open());
+            assert!(stats.upstreams["dead"].breaker.is_open());
+
+            let results = probe_upstreams(&config, &stats, Some("live"))
+                .await
+                .unwrap();
+            assert_eq!(results.len(), 1);
+            assert!(probe_upstreams(&config, &stats, Some("missing"))
+                .await
+                .is_err());
+        });
+    }
+}
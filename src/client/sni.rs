@@ -0,0 +1,222 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use futures::{AsyncRead, AsyncReadExt};
+use smol::net::{TcpListener, TcpStream};
+use smol_timeout::TimeoutExt;
+
+use crate::{
+    config::ClientConfig, drain::ConnectionTracker, io::TcpStreamExt,
+    logging::with_connection_context, protocol::AsyncStream, sni::extract_ssl_sni_host,
+    socks5::Address,
+};
+
+use super::{tcp::proxy_with_initial_data, ClientStatistics};
+
+// A TLS record is at most 16KiB after its 5 byte header
+const MAX_CLIENT_HELLO_LEN: usize = 5 + (1 << 14);
+
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Reads until the end of the first TLS record, which carries the ClientHello, into `buf`.
+// Stops as soon as it's clear the client isn't speaking TLS.
+async fn read_client_hello(
+    stream: &mut (impl AsyncRead + Unpin),
+    buf: &mut Vec<u8>,
+) -> std::io::Result<()> {
+    let mut chunk = [0u8; 4096];
+    loop {
+        let record_len = match buf.as_slice() {
+            [0x16, _, _, hi, lo, ..] => 5 + u16::from_be_bytes([*hi, *lo]) as usize,
+            [0x16, ..] | [] => 5,
+            _ => return Ok(()),
+        };
+        if buf.len() >= record_len.min(MAX_CLIENT_HELLO_LEN) {
+            return Ok(());
+        }
+
+        match stream.read(&mut chunk).await? {
+            0 => return Ok(()),
+            n => buf.extend_from_slice(&chunk[..n]),
+        }
+    }
+}
+
+// Routes a TLS connection by the server name in its ClientHello, without decrypting it.
+// Without a server name, it goes to where it was originally headed, if that's known.
+pub async fn serve_sni_proxy_conn(
+    orig_dst: Option<SocketAddr>,
+    src: Option<IpAddr>,
+    config: &ClientConfig,
+    stats: &ClientStatistics,
+    mut stream: impl AsyncStream,
+) -> anyhow::Result<()> {
+    let mut initial_data = Vec::new();
+    if let Some(Err(e)) = read_client_hello(&mut stream, &mut initial_data)
+        .timeout(CLIENT_HELLO_TIMEOUT)
+        .await
+    {
+        return Err(e).context("Reading ClientHello");
+    }
+
+    let dst = match (extract_ssl_sni_host(&initial_data), orig_dst) {
+        (Some(host), _) => Address::Name {
+            host: host.to_string().into(),
+            port: orig_dst.map_or(443, |addr| addr.port()),
+        },
+        (None, Some(addr)) => addr.into(),
+        (None, None) => bail!("No server name in the ClientHello, nor an original destination"),
+    };
+    log::info!("Requesting to proxy TLS to {dst}");
+
+    let initial_data = (!initial_data.is_empty()).then_some(initial_data.as_slice());
+    proxy_with_initial_data(dst, src, initial_data, config, stats, stream).await
+}
+
+pub async fn run_sni_proxy_with(
+    listener: TcpListener,
+    config: Arc<ClientConfig>,
+    stats: Arc<ClientStatistics>,
+    connections: ConnectionTracker,
+) -> anyhow::Result<()> {
+    loop {
+        let (sock, addr): (TcpStream, _) = listener
+            .accept()
+            .await
+            .context("Listening for TLS connections")?;
+
+        let config = config.clone();
+        let stats = stats.clone();
+        connections.spawn(with_connection_context(async move {
+            log::info!("TLS client {addr} connected");
+            // Connections made to the listener itself weren't redirected
+            let orig_dst = sock
+                .get_original_dst()
+                .filter(|dst| Some(*dst) != sock.local_addr().ok());
+            if let Err(e) =
+                serve_sni_proxy_conn(orig_dst, Some(addr.ip()), &config, &stats, sock).await
+            {
+                log::error!("Error serving TLS client {addr}: {e:?}");
+            }
+            log::info!("TLS client {addr} disconnected");
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::AsyncWriteExt;
+    use maplit::hashmap;
+    use smol::{spawn, Task, Timer};
+
+    use super::*;
+    use crate::{
+        config::{UpstreamConfig, UpstreamProtocol},
+        protocol::socks5::Socks5,
+        test::{create_tcp_server, duplex},
+    };
+
+    // Accepts one SOCKS5 connection, resolving to where it was asked to connect and the
+    // first `len` bytes relayed
+    async fn mock_socks5_server(len: usize) -> (Task<(Address<'static>, Vec<u8>)>, SocketAddr) {
+        let (server, addr) = create_tcp_server().await;
+        let task = spawn(async move {
+            let (mut stream, _) = server.accept().await.unwrap();
+            let mut greeting = [0u8; 2];
+            stream.read_exact(&mut greeting).await.unwrap();
+            let mut auths = vec![0u8; greeting[1] as usize];
+            stream.read_exact(&mut auths).await.unwrap();
+            stream.write_all(&[5, 0]).await.unwrap();
+
+            let mut request = [0u8; 3];
+            stream.read_exact(&mut request).await.unwrap();
+            let dst = Address::parse_async(&mut stream).await.unwrap();
+            stream
+                .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+
+            let mut data = vec![0u8; len];
+            stream.read_exact(&mut data).await.unwrap();
+            (dst, data)
+        });
+        (task, addr)
+    }
+
+    #[test]
+    fn routes_by_server_name() {
+        smol::block_on(async move {
+            let client_hello = include_bytes!("../test/raw_tls_packet.bin");
+            let mut sent = client_hello.to_vec();
+            sent.extend_from_slice(b"application data");
+            let plain = b"not a ClientHello".to_vec();
+
+            let (by_name, by_name_addr) = mock_socks5_server(sent.len()).await;
+            let (by_ip, by_ip_addr) = mock_socks5_server(plain.len()).await;
+            let upstream = |addr: SocketAddr| UpstreamConfig {
+                protocol: UpstreamProtocol::Socks5(Socks5 {
+                    address: addr.into(),
+                    supports_udp: false,
+                    udp_mtu: None,
+                }),
+                enabled: true,
+                idle_timeout_secs: None,
+                groups: Default::default(),
+            };
+            let config = Arc::new(ClientConfig {
+                upstreams: hashmap! {
+                    String::from("by_name") => upstream(by_name_addr),
+                    String::from("by_ip") => upstream(by_ip_addr),
+                },
+                traffic_rules: serde_json::from_value(serde_json::json!(
+                    "main:\n  tls -d domain:matches:gstatic\\.com -a proxy:by_name\n  \
+                     local -d network:127.0.0.0/8 -a proxy:by_ip\n  rest -a reject"
+                ))
+                .unwrap(),
+                ..Default::default()
+            });
+            let stats = Arc::new(ClientStatistics::new(&config));
+            let orig_dst: SocketAddr = "127.0.0.1:8443".parse().unwrap();
+
+            let serve = |stream| {
+                let config = config.clone();
+                let stats = stats.clone();
+                spawn(async move {
+                    serve_sni_proxy_conn(Some(orig_dst), None, &config, &stats, stream).await
+                })
+            };
+
+            // The ClientHello arrives in pieces, with more following it
+            let (mut client, server) = duplex(0).await;
+            let _task = serve(server);
+            client.write_all(&sent[..3]).await.unwrap();
+            Timer::after(Duration::from_millis(50)).await;
+            client.write_all(&sent[3..100]).await.unwrap();
+            Timer::after(Duration::from_millis(50)).await;
+            client.write_all(&sent[100..]).await.unwrap();
+
+            let (dst, data) = by_name.timeout(Duration::from_secs(5)).await.unwrap();
+            assert_eq!(
+                dst,
+                Address::Name {
+                    host: "www.gstatic.com".into(),
+                    port: 8443
+                }
+            );
+            assert_eq!(data, sent);
+
+            // Without a server name, the original destination is used
+            let (mut client, server) = duplex(0).await;
+            let _task = serve(server);
+            client.write_all(&plain).await.unwrap();
+
+            let (dst, data) = by_ip.timeout(Duration::from_secs(5)).await.unwrap();
+            assert_eq!(dst, Address::IP(orig_dst));
+            assert_eq!(data, plain);
+        });
+    }
+}
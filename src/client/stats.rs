@@ -1,12 +1,19 @@
 use std::{
     collections::HashMap,
+    fmt::Write,
     sync::Arc,
     time::{Duration, UNIX_EPOCH},
 };
 
 use serde::{Deserialize, Serialize};
 
-use crate::{config::ClientConfig, counter::Counter, protocol::Stats};
+use super::CircuitBreaker;
+use crate::{
+    config::ClientConfig,
+    counter::{Counter, Histogram},
+    protocol::Stats,
+    ws::CompressionStats,
+};
 
 #[derive(Default, Serialize, Deserialize, Debug, Clone)]
 pub struct UpstreamStatistics {
@@ -14,11 +21,33 @@ pub struct UpstreamStatistics {
     pub rx: Arc<Counter>,
     pub last_activity: Arc<Counter>,
     pub last_latency: Arc<Counter>,
+    #[serde(default)]
+    pub connections: Arc<Counter>,
+    #[serde(skip)]
+    pub latency: Arc<Histogram>,
+    #[serde(default)]
+    pub breaker: Arc<CircuitBreaker>,
+    #[serde(default)]
+    pub compression: Arc<CompressionStats>,
+    // What was relayed through the upstream, leaving out its handshakes, framing and
+    // ciphers, which `tx` and `rx` count
+    #[serde(default)]
+    pub payload_tx: Arc<Counter>,
+    #[serde(default)]
+    pub payload_rx: Arc<Counter>,
 }
 
 #[derive(Default, Serialize, Deserialize, Debug, Clone)]
 pub struct ClientStatistics {
     pub upstreams: HashMap<String, UpstreamStatistics>,
+    // Number of times the traffic rules have been evaluated, and the total time spent
+    #[serde(default)]
+    pub rule_evaluations: Arc<Counter>,
+    #[serde(default)]
+    pub rule_evaluation_us: Arc<Counter>,
+    // Connections relayed with splice(2) rather than copied through userspace
+    #[serde(default)]
+    pub spliced_connections: Arc<Counter>,
 }
 
 impl ClientStatistics {
@@ -29,22 +58,206 @@ impl ClientStatistics {
                 .iter()
                 .map(|(n, _)| (n.clone(), Default::default()))
                 .collect(),
+            ..Default::default()
         }
     }
 
+    pub fn update_rule_evaluation(&self, elapsed: Duration) {
+        self.rule_evaluations.inc(1);
+        self.rule_evaluation_us.inc(elapsed.as_micros() as usize);
+    }
+
     pub fn update_upstream(&self, name: &str, latency: Duration) {
         if let Some(stats) = self.upstreams.get(name) {
             stats
                 .last_activity
                 .set(UNIX_EPOCH.elapsed().unwrap().as_secs() as usize);
             stats.last_latency.set(latency.as_millis() as usize);
+            stats.latency.observe(latency.as_millis() as usize);
+            stats.connections.inc(1);
+        }
+    }
+
+    // Renders the statistics in Prometheus' text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let mut upstreams: Vec<_> = self.upstreams.iter().collect();
+        upstreams.sort_by_key(|(name, _)| *name);
+
+        let mut out = String::new();
+        write_metric_header(
+            &mut out,
+            "cpxy_upstream_connections_total",
+            "counter",
+            "Number of connections established through the upstream",
+        );
+        for (name, s) in &upstreams {
+            let name = escape_label(name);
+            let _ = writeln!(
+                out,
+                "cpxy_upstream_connections_total{{upstream=\"{name}\"}} {}",
+                s.connections.get()
+            );
+        }
+
+        write_metric_header(
+            &mut out,
+            "cpxy_upstream_bytes_total",
+            "counter",
+            "Number of bytes transferred through the upstream",
+        );
+        for (name, s) in &upstreams {
+            let name = escape_label(name);
+            for (direction, c) in [("tx", &s.tx), ("rx", &s.rx)] {
+                let _ = writeln!(
+                    out,
+                    "cpxy_upstream_bytes_total{{upstream=\"{name}\",direction=\"{direction}\"}} {}",
+                    c.get()
+                );
+            }
+        }
+
+        write_metric_header(
+            &mut out,
+            "cpxy_upstream_payload_bytes_total",
+            "counter",
+            "Number of bytes relayed through the upstream, without its protocol overhead",
+        );
+        for (name, s) in &upstreams {
+            let name = escape_label(name);
+            for (direction, c) in [("tx", &s.payload_tx), ("rx", &s.payload_rx)] {
+                let _ = writeln!(
+                    out,
+                    "cpxy_upstream_payload_bytes_total{{upstream=\"{name}\",direction=\"{direction}\"}} {}",
+                    c.get()
+                );
+            }
+        }
+
+        write_metric_header(
+            &mut out,
+            "cpxy_upstream_last_activity_seconds",
+            "gauge",
+            "Unix timestamp of the last connection through the upstream",
+        );
+        for (name, s) in &upstreams {
+            let name = escape_label(name);
+            let _ = writeln!(
+                out,
+                "cpxy_upstream_last_activity_seconds{{upstream=\"{name}\"}} {}",
+                s.last_activity.get()
+            );
         }
+
+        write_metric_header(
+            &mut out,
+            "cpxy_upstream_connect_latency_seconds",
+            "histogram",
+            "Time taken to establish connections through the upstream",
+        );
+        for (name, s) in &upstreams {
+            let name = escape_label(name);
+            for (bound, count) in s.latency.cumulative_buckets() {
+                let _ = writeln!(
+                    out,
+                    "cpxy_upstream_connect_latency_seconds_bucket{{upstream=\"{name}\",le=\"{}\"}} {count}",
+                    bound as f64 / 1000.0
+                );
+            }
+            let _ = writeln!(
+                out,
+                "cpxy_upstream_connect_latency_seconds_bucket{{upstream=\"{name}\",le=\"+Inf\"}} {}",
+                s.latency.count()
+            );
+            let _ = writeln!(
+                out,
+                "cpxy_upstream_connect_latency_seconds_sum{{upstream=\"{name}\"}} {}",
+                s.latency.sum_ms() as f64 / 1000.0
+            );
+            let _ = writeln!(
+                out,
+                "cpxy_upstream_connect_latency_seconds_count{{upstream=\"{name}\"}} {}",
+                s.latency.count()
+            );
+        }
+
+        write_metric_header(
+            &mut out,
+            "cpxy_upstream_circuit_open",
+            "gauge",
+            "Whether the upstream is being skipped, or probed, after failing repeatedly",
+        );
+        for (name, s) in &upstreams {
+            let name = escape_label(name);
+            let _ = writeln!(
+                out,
+                "cpxy_upstream_circuit_open{{upstream=\"{name}\"}} {}",
+                u8::from(s.breaker.is_open())
+            );
+        }
+
+        write_metric_header(
+            &mut out,
+            "cpxy_upstream_compression_ratio",
+            "gauge",
+            "Size of the traffic compressed by the upstream relative to its plain size",
+        );
+        for (name, s) in &upstreams {
+            if let Some(ratio) = s.compression.ratio() {
+                let name = escape_label(name);
+                let _ = writeln!(
+                    out,
+                    "cpxy_upstream_compression_ratio{{upstream=\"{name}\"}} {ratio}"
+                );
+            }
+        }
+
+        write_metric_header(
+            &mut out,
+            "cpxy_rule_evaluation_seconds",
+            "summary",
+            "Time spent evaluating the traffic rules to pick upstreams",
+        );
+        let _ = writeln!(
+            out,
+            "cpxy_rule_evaluation_seconds_sum {}",
+            self.rule_evaluation_us.get() as f64 / 1_000_000.0
+        );
+        let _ = writeln!(
+            out,
+            "cpxy_rule_evaluation_seconds_count {}",
+            self.rule_evaluations.get()
+        );
+
+        write_metric_header(
+            &mut out,
+            "cpxy_spliced_connections_total",
+            "counter",
+            "Number of connections relayed with splice(2) rather than copied through userspace",
+        );
+        let _ = writeln!(
+            out,
+            "cpxy_spliced_connections_total {}",
+            self.spliced_connections.get()
+        );
+
+        out
     }
 
     pub fn get_protocol_stats(&self, name: &str) -> Option<Stats> {
         self.upstreams.get(name).map(|s| Stats {
             rx: s.rx.clone(),
             tx: s.tx.clone(),
+            compression: s.compression.clone(),
         })
     }
 }
+
+fn write_metric_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+}
+
+fn escape_label(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
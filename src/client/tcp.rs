@@ -1,49 +1,118 @@
-use std::time::Duration;
+use std::{net::IpAddr, time::Duration};
 
 use anyhow::Context;
-use futures::{AsyncRead, AsyncReadExt, AsyncWrite};
+use futures::AsyncReadExt;
 use smol_timeout::TimeoutExt;
 
 use crate::{
     config::ClientConfig,
     handshake::Handshaker,
-    socks5::Address,
-    utils::{copy_duplex, new_vec_uninitialised, VecExt},
+    io::{connect_tcp, write_initial_data},
+    protocol::AsyncStream,
+    socks5::{Address, ConnStatusCode},
+    utils::{new_vec_uninitialised, relay_duplex, RelayPath, VecExt},
 };
 
-use super::{common::find_and_connect_stream, ClientStatistics};
+use super::{access_log::ConnectionRecord, common::find_and_connect_stream, ClientStatistics};
+
+const BLOCK_PAGE_UPSTREAM_NAME: &str = "block_page";
+
+// The content type of the TLS record carrying a ClientHello
+const TLS_HANDSHAKE: u8 = 0x16;
+
+// Like `find_and_connect_stream`, but TLS connections rejected by the rules go to the block
+// page server if there's one, as a 403 can't be shown inside them. Without initial data to
+// tell, connections to port 443 are taken to be TLS.
+async fn connect_or_block_page<'a>(
+    dst: &Address<'_>,
+    src: Option<IpAddr>,
+    initial_data: Option<&[u8]>,
+    config: &'a ClientConfig,
+    stats: &ClientStatistics,
+) -> anyhow::Result<(&'a str, Box<dyn AsyncStream>)> {
+    let err = match find_and_connect_stream(dst, src, initial_data, config, stats).await {
+        Ok(v) => return Ok(v),
+        Err(e) => e,
+    };
+
+    let is_tls = match initial_data {
+        Some(data) => data.first() == Some(&TLS_HANDSHAKE),
+        None => dst.get_port() == 443,
+    };
+    let block_page = match config.block_page_address {
+        Some(addr) if is_tls && ConnStatusCode::from_error(&err) == ConnStatusCode::NOT_ALLOWED => {
+            addr
+        }
+        _ => return Err(err),
+    };
+
+    log::info!("{dst} is rejected, sending it to the block page at {block_page}");
+    let block_page = Address::IP(block_page);
+    let mut stream = connect_tcp(&block_page)
+        .await
+        .with_context(|| format!("Connecting to block page at {block_page}"))?;
+    write_initial_data(&mut stream, initial_data, &block_page).await?;
+    Ok((BLOCK_PAGE_UPSTREAM_NAME, Box::new(stream)))
+}
 
 pub async fn serve_tcp_proxy_conn(
     dst: Address<'_>,
+    src: Option<IpAddr>,
     config: &ClientConfig,
     stats: &ClientStatistics,
-    mut stream: impl AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    mut stream: impl AsyncStream,
     handshaker: Handshaker,
 ) -> anyhow::Result<()> {
-    let upstream = match find_and_connect_stream(&dst, None, config, stats)
-        .await
-        .with_context(|| format!("Finding proxy for tcp://{dst}"))
-    {
-        Ok(v) => {
-            handshaker.respond_ok(&mut stream, None).await?;
-            v
-        }
-        Err(e) => {
-            handshaker.respond_err(&mut stream).await?;
-            return Err(e);
-        }
-    };
+    let mut record = ConnectionRecord::new(src, &dst);
+    let result = async {
+        let upstream = match connect_or_block_page(&dst, src, None, config, stats)
+            .await
+            .with_context(|| format!("Finding proxy for tcp://{dst}"))
+        {
+            Ok((name, v)) => {
+                record.set_upstream(name);
+                handshaker.respond_ok(&mut stream, None).await?;
+                v
+            }
+            Err(e) => {
+                handshaker.respond_err(&mut stream, &e).await?;
+                return Err(e);
+            }
+        };
+
+        relay(stream, upstream, &record, stats).await
+    }
+    .await;
+    record.finish(&result);
+    result
+}
 
-    copy_duplex(stream, upstream, None, None).await
+async fn relay(
+    stream: impl AsyncStream,
+    upstream: impl AsyncStream,
+    record: &ConnectionRecord,
+    stats: &ClientStatistics,
+) -> anyhow::Result<()> {
+    if RelayPath::of(&stream, &upstream) == RelayPath::Splice {
+        stats.spliced_connections.inc(1);
+    }
+    relay_duplex(
+        stream,
+        upstream,
+        Some(record.tx.clone()),
+        Some(record.rx.clone()),
+    )
+    .await
 }
 
 const TCP_PROXY_PRE_READ_TIMEOUT: Duration = Duration::from_millis(200);
 
 pub async fn serve_tcp_tproxy_conn(
     dst: Address<'_>,
+    src: Option<IpAddr>,
     config: &ClientConfig,
     stats: &ClientStatistics,
-    mut stream: impl AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    mut stream: impl AsyncStream,
 ) -> anyhow::Result<()> {
     let initial_data = match dst.get_port() {
         80 | 443 => {
@@ -64,14 +133,173 @@ pub async fn serve_tcp_tproxy_conn(
         _ => None,
     };
 
-    let upstream = find_and_connect_stream(
-        &dst,
-        initial_data.as_ref().map(|s| s.as_ref()),
-        config,
-        stats,
-    )
-    .await
-    .with_context(|| format!("Finding proxy for tcp://{dst}"))?;
+    proxy_with_initial_data(dst, src, initial_data.as_deref(), config, stats, stream).await
+}
+
+// Relays a connection whose first bytes have already been read off it
+pub(super) async fn proxy_with_initial_data(
+    dst: Address<'_>,
+    src: Option<IpAddr>,
+    initial_data: Option<&[u8]>,
+    config: &ClientConfig,
+    stats: &ClientStatistics,
+    stream: impl AsyncStream,
+) -> anyhow::Result<()> {
+    let mut record = ConnectionRecord::new(src, &dst);
+    let result = async {
+        let (name, upstream) = connect_or_block_page(&dst, src, initial_data, config, stats)
+            .await
+            .with_context(|| format!("Finding proxy for tcp://{dst}"))?;
+        record.set_upstream(name);
+        record.tx.inc(initial_data.map_or(0, |d| d.len()));
+
+        relay(stream, upstream, &record, stats).await
+    }
+    .await;
+    record.finish(&result);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc};
+
+    use futures::AsyncWriteExt;
+    use smol::spawn;
+
+    use super::*;
+    use crate::{
+        buf::RWBuffer,
+        config::{UpstreamConfig, UpstreamProtocol},
+        handshake::HandshakeRequest,
+        protocol::direct::Direct,
+        test::{create_tcp_server, duplex, echo_tcp_server, send_socks5_request},
+    };
+
+    #[test]
+    fn rejected_tls_goes_to_block_page() {
+        smol::block_on(async move {
+            let client_hello = include_bytes!("../test/raw_tls_packet.bin");
+            let mut sent = client_hello.to_vec();
+            sent.extend_from_slice(b"application data");
+
+            // Takes what's sent to the block page
+            let (block_page, block_page_addr) = create_tcp_server().await;
+            let len = sent.len();
+            let block_page_task = spawn(async move {
+                let (mut stream, _) = block_page.accept().await.unwrap();
+                let mut received = vec![0u8; len];
+                stream.read_exact(&mut received).await.unwrap();
+                received
+            });
+
+            let config = Arc::new(ClientConfig {
+                traffic_rules: serde_json::from_value(serde_json::json!(
+                    "main:\n  blocked -d domain:matches:gstatic\\.com -a reject"
+                ))
+                .unwrap(),
+                block_page_address: Some(block_page_addr),
+                ..Default::default()
+            });
+            let stats = Arc::new(ClientStatistics::new(&config));
+
+            let dst: SocketAddr = "127.0.0.1:443".parse().unwrap();
+            let (mut client, server) = duplex(0).await;
+            let _task = spawn({
+                let config = config.clone();
+                let stats = stats.clone();
+                async move {
+                    proxy_with_initial_data(
+                        dst.into(),
+                        None,
+                        Some(client_hello),
+                        &config,
+                        &stats,
+                        server,
+                    )
+                    .await
+                }
+            });
+            client.write_all(b"application data").await.unwrap();
+            assert_eq!(block_page_task.await, sent);
+
+            // Anything other than TLS is still turned away
+            let (_client, server) = duplex(0).await;
+            let err = proxy_with_initial_data(
+                "www.gstatic.com:80".parse().unwrap(),
+                None,
+                Some(b"GET / HTTP/1.1\r\nHost: www.gstatic.com\r\n\r\n"),
+                &config,
+                &stats,
+                server,
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(
+                ConnStatusCode::from_error(&err),
+                ConnStatusCode::NOT_ALLOWED
+            );
+        });
+    }
+
+    #[test]
+    fn socks5_connections_are_spliced() {
+        smol::block_on(async move {
+            let (_echo_task, echo_addr) = echo_tcp_server().await;
+
+            // Relays one SOCKS5 connection through a direct upstream and returns the stats
+            let proxy = |idle_timeout_secs: Option<u64>| async move {
+                let config = Arc::new(ClientConfig {
+                    upstreams: maplit::hashmap! {
+                        String::from("direct") => UpstreamConfig {
+                            protocol: UpstreamProtocol::Direct(Direct),
+                            enabled: true,
+                            idle_timeout_secs,
+                            groups: Default::default(),
+                        }
+                    },
+                    ..Default::default()
+                });
+                let stats = Arc::new(ClientStatistics::new(&config));
+
+                let (listener, proxy_addr) = create_tcp_server().await;
+                let task = spawn({
+                    let stats = stats.clone();
+                    async move {
+                        let (mut socks, _) = listener.accept().await?;
+                        let mut buf = RWBuffer::new_vec_uninitialised(512);
+                        let (hs, req) = Handshaker::start(&mut socks, &mut buf).await?;
+                        let HandshakeRequest::TCP { dst } = req else {
+                            anyhow::bail!("Expecting a TCP request but got {req:?}");
+                        };
+                        serve_tcp_proxy_conn(dst, None, &config, &stats, socks, hs).await
+                    }
+                });
+
+                let mut client = smol::net::TcpStream::connect(proxy_addr).await.unwrap();
+                send_socks5_request(&mut client, &echo_addr.into(), false)
+                    .await
+                    .unwrap();
+                client.write_all(b"hello").await.unwrap();
+                let mut buf = [0u8; 5];
+                client.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hello");
+
+                drop(client);
+                task.await.unwrap();
+                stats
+            };
+
+            let stats = proxy(None).await;
+            let expected = match cfg!(target_os = "linux") {
+                true => 1,
+                false => 0,
+            };
+            assert_eq!(stats.spliced_connections.get(), expected);
 
-    copy_duplex(stream, upstream, None, None).await
+            // An idle timeout has to see the bytes, so they're copied
+            let stats = proxy(Some(60)).await;
+            assert_eq!(stats.spliced_connections.get(), 0);
+        });
+    }
 }
@@ -62,6 +62,8 @@ pub async fn serve_udp_transparent_proxy(
                 "TProxy received {} bytes from client {src}, orig dst = {dst}",
                 buf.len()
             );
+            // Every datagram of a session, not just the one that starts it
+            let buf = config.dns_client_subnet.apply_to_datagram(dst.port(), buf);
             let key = UdpSessionKey { src, dst };
             match sessions.get_mut(&key) {
                 Some(s) => match s.tx.try_send(buf) {
@@ -101,12 +103,15 @@ impl UdpSession {
         let (tx, rx) = bounded(10);
         let dst_addr: Address = dst.into();
         let _task = spawn(async move {
-            let mut upstreams = config.find_best_upstream(
-                TrafficType::Datagram,
-                &stats,
-                &dst_addr,
-                Some(&initial_data),
-            )?;
+            let mut upstreams = config
+                .find_best_upstream(
+                    TrafficType::Datagram,
+                    &stats,
+                    &dst_addr,
+                    Some(src.ip()),
+                    Some(&initial_data),
+                )
+                .await?;
             let mut result = Ok(());
             while let Some((name, upstream)) = upstreams.pop() {
                 log::debug!("Trying upstream {name} for UDP://{dst_addr}");
@@ -116,7 +121,7 @@ impl UdpSession {
                         &dst_addr,
                         initial_data.clone(),
                         &stats.get_protocol_stats(name).unwrap_or_default(),
-                        config.fwmark,
+                        &config.tcp_options(),
                     )
                     .await
                     .with_context(|| format!("Creating upstream dgram for UDP://{dst}"))
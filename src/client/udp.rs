@@ -1,4 +1,4 @@
-use std::time::Duration;
+use std::{net::IpAddr, time::Duration};
 
 use crate::{
     io::{get_one_off_udp_query_timeout, Timer},
@@ -6,14 +6,17 @@ use crate::{
     utils::new_vec_uninitialised,
 };
 use anyhow::{anyhow, Context};
+use bytes::Bytes;
 use futures::{
-    select, AsyncRead, AsyncReadExt, AsyncWrite, FutureExt, SinkExt, StreamExt, TryStreamExt,
+    select, AsyncRead, AsyncReadExt, AsyncWrite, FutureExt, Sink, SinkExt, Stream, StreamExt,
+    TryStreamExt,
 };
 use smol_timeout::TimeoutExt;
 
 use crate::{
-    config::ClientConfig, handshake::Handshaker, socks5::new_udp_relay,
-    socks5::UdpRepr as Socks5UdpRepr,
+    config::ClientConfig,
+    handshake::Handshaker,
+    socks5::{new_udp_relay, Address, RelaySource, UdpPacket, UdpRepr as Socks5UdpRepr},
 };
 use smol::spawn;
 
@@ -24,27 +27,60 @@ const UDP_IDLING_TIMEOUT: Duration = Duration::from_secs(60);
 pub async fn serve_udp_proxy_conn(
     c: &ClientConfig,
     stats: &ClientStatistics,
+    src: Option<IpAddr>,
+    associated: Option<Address<'_>>,
     is_v4: bool,
     mut stream: impl AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
     handshaker: Handshaker,
 ) -> anyhow::Result<()> {
-    let (relay_addr, mut tx, mut rx) = match new_udp_relay(is_v4).await {
+    // Only the client may use the relay. The port it said it'd send from is only
+    // enforced in strict mode, as clients behind NAT often can't know it.
+    let source = src.map(|ip| RelaySource {
+        ip,
+        port: match associated {
+            Some(Address::IP(addr)) if c.udp_associate_strict_port && addr.port() != 0 => {
+                Some(addr.port())
+            }
+            _ => None,
+        },
+    });
+
+    let (relay_addr, tx, rx) = match new_udp_relay(is_v4, None, source).await {
         Ok(v) => v,
         Err(e) => {
             log::error!("Error creating UDP relay: {e:?}");
-            handshaker.respond_err(&mut stream).await?;
+            handshaker.respond_err(&mut stream, &e).await?;
             return Err(e);
         }
     };
 
     handshaker.respond_ok(&mut stream, Some(relay_addr)).await?;
 
+    // The relay lives only as long as the control connection, so that its socket is
+    // freed as soon as the client goes away
+    select! {
+        v = relay_udp(c, stats, src, tx, rx).fuse() => v,
+        v = drain_socks(&mut stream).fuse() => v,
+    }
+}
+
+async fn relay_udp(
+    c: &ClientConfig,
+    stats: &ClientStatistics,
+    src: Option<IpAddr>,
+    mut tx: impl Sink<UdpPacket<Bytes>, Error = anyhow::Error> + Unpin + Send + 'static,
+    mut rx: impl Stream<Item = anyhow::Result<UdpPacket<Bytes>>> + Unpin + Send + 'static,
+) -> anyhow::Result<()> {
     // Wait for first packet to decide where to go
     let pkt = rx.next().await.context("Waiting for first packet")??;
     let addr = pkt.addr().into_owned();
+    let payload = c
+        .dns_client_subnet
+        .apply_to_datagram(addr.get_port(), pkt.payload_bytes());
 
-    let mut upstreams =
-        c.find_best_upstream(TrafficType::Datagram, stats, &addr, Some(pkt.payload()))?;
+    let mut upstreams = c
+        .find_best_upstream(TrafficType::Datagram, stats, &addr, src, Some(&payload))
+        .await?;
     let mut last_error = None;
 
     while let Some((name, upstream)) = upstreams.pop() {
@@ -53,9 +89,9 @@ pub async fn serve_udp_proxy_conn(
             .protocol
             .new_datagram(
                 &addr,
-                pkt.payload_bytes(),
+                payload.clone(),
                 &stats.get_protocol_stats(name).unwrap_or_default(),
-                c.fwmark,
+                &c.tcp_options(),
             )
             .await
         {
@@ -66,6 +102,13 @@ pub async fn serve_udp_proxy_conn(
             }
         };
 
+        let (payload_tx, payload_rx) = stats
+            .upstreams
+            .get(name)
+            .map(|s| (s.payload_tx.clone(), s.payload_rx.clone()))
+            .unwrap_or_default();
+        payload_tx.inc(payload.len());
+
         if let Some(timeout) = get_one_off_udp_query_timeout(&addr) {
             match upstream_stream.next().timeout(timeout).await {
                 None => {
@@ -77,6 +120,7 @@ pub async fn serve_udp_proxy_conn(
                     break;
                 }
                 Some(Some(Ok((data, addr)))) => {
+                    payload_rx.inc(data.len());
                     tx.send(
                         Socks5UdpRepr {
                             addr: &addr.into(),
@@ -98,11 +142,19 @@ pub async fn serve_udp_proxy_conn(
 
         let timer = Timer::new(UDP_IDLING_TIMEOUT);
 
+        // Later datagrams may be DNS queries even if the first one wasn't
+        let client_subnet = c.dns_client_subnet;
         let upload_task = {
             let timer = timer.clone();
             spawn(
                 rx.inspect(move |_| timer.reset())
-                    .map_ok(|pkt| (pkt.payload_bytes(), pkt.addr().into_owned()))
+                    .map_ok(move |pkt| {
+                        let addr = pkt.addr().into_owned();
+                        let payload =
+                            client_subnet.apply_to_datagram(addr.get_port(), pkt.payload_bytes());
+                        (payload, addr)
+                    })
+                    .inspect_ok(move |(payload, _)| payload_tx.inc(payload.len()))
                     .forward(upstream_sink),
             )
         };
@@ -111,6 +163,7 @@ pub async fn serve_udp_proxy_conn(
             let timer = timer.clone();
             spawn(
                 upstream_stream
+                    .inspect_ok(move |(data, _)| payload_rx.inc(data.len()))
                     .map(|item| {
                         item.and_then(|(data, addr)| {
                             Socks5UdpRepr {
@@ -130,7 +183,6 @@ pub async fn serve_udp_proxy_conn(
             _ = upload_task.fuse() => Ok(()),
             _ = download_task.fuse() => Ok(()),
             _ = timer.fuse() => Ok(()),
-            v = drain_socks(&mut stream).fuse() => v,
         };
     }
 
@@ -144,4 +196,53 @@ async fn drain_socks(socks: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<()>
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use futures::AsyncWriteExt;
+    use smol::net::{TcpStream, UdpSocket};
+
+    use super::*;
+    use crate::{buf::RWBuffer, handshake::HandshakeRequest, test::create_tcp_server};
+
+    #[test]
+    fn relay_is_freed_with_control_connection() {
+        smol::block_on(async move {
+            let (server, proxy_addr) = create_tcp_server().await;
+            let server_task = spawn(async move {
+                let (mut socks, _) = server.accept().await.unwrap();
+                let mut buf = RWBuffer::new_vec_uninitialised(512);
+                let (hs, req) = Handshaker::start(&mut socks, &mut buf).await.unwrap();
+                assert!(matches!(req, HandshakeRequest::UDP { .. }));
+                let config = ClientConfig::default();
+                let stats = ClientStatistics::new(&config);
+                serve_udp_proxy_conn(&config, &stats, None, None, true, socks, hs).await
+            });
+
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            client.write_all(&[5, 1, 0]).await.unwrap();
+            let mut reply = [0u8; 2];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, [5, 0]);
+
+            client
+                .write_all(&[5, 3, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            let mut reply = [0u8; 10];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(&reply[..4], &[5, 0, 0, 1]);
+            let relay_port = u16::from_be_bytes([reply[8], reply[9]]);
+
+            // No datagram ever arrives, but closing the control connection ends the relay
+            drop(client);
+            server_task
+                .timeout(Duration::from_secs(5))
+                .await
+                .expect("Relay to stop with the control connection")
+                .unwrap();
+
+            UdpSocket::bind(("0.0.0.0", relay_port))
+                .await
+                .expect("Relay port to be freed");
+        });
+    }
+}
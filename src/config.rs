@@ -1,20 +1,33 @@
+use anyhow::bail;
 use async_trait::async_trait;
+use blake2::{
+    digest::{Update, VariableOutput},
+    Blake2bVar,
+};
 use bytes::Bytes;
+use ipnetwork::IpNetwork;
+use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::time::UNIX_EPOCH;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
-use crate::client::ClientStatistics;
-use crate::dns::DnsCache;
-use crate::geoip::find_geoip;
+use crate::abp::AbpSource;
+use crate::client::{AccessLogSink, CircuitBreakerConfig, ClientStatistics, HealthCheckConfig};
+use crate::dns::{ClientSubnetPolicy, DnsCache};
+use crate::external_decision::{self, ExternalOutcome, ExternalQuery};
+use crate::geoip::{find_geoip, OverlapPolicy};
+use crate::io::{AddressFamilyPreference, Dscp, TcpOptions, DEFAULT_CONNECT_TIMEOUT};
 use crate::protocol::{
     direct, firetcp, http, socks5, tcpman, udpman, AsyncStream, BoxedSink, BoxedStream, Protocol,
     Stats, TrafficType,
 };
-use crate::rule::{PacketDestination, RuleExecutionResult, RuleProtocol, RuleString};
-use crate::socks5::Address;
+use crate::rule::{
+    GroupSelection, LiveRules, PacketDestination, RuleExecutionResult, RuleProtocol, RuleString,
+};
+use crate::socks5::{Address, ConnStatusCode};
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 #[serde(tag = "type")]
@@ -38,6 +51,34 @@ pub enum UpstreamProtocol {
     Http(http::HttpProxy),
 }
 
+impl UpstreamProtocol {
+    pub fn address(&self) -> Option<&Address<'static>> {
+        match self {
+            UpstreamProtocol::UdpMan(p) => Some(&p.addr),
+            UpstreamProtocol::TcpMan(p) => Some(&p.address),
+            UpstreamProtocol::Socks5(p) => Some(&p.address),
+            UpstreamProtocol::Direct(_) => None,
+            UpstreamProtocol::FireTcp(p) => Some(p.address()),
+            UpstreamProtocol::Http(p) => Some(&p.address),
+        }
+    }
+}
+
+const DEFAULT_DRAIN_GRACE_PERIOD: Duration = Duration::from_secs(30);
+const DEFAULT_BIND_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_EXTERNAL_DECISION_TIMEOUT: Duration = Duration::from_millis(1000);
+
+const BYPASS_UPSTREAM_NAME: &str = "bypass";
+
+lazy_static! {
+    static ref BYPASS_UPSTREAM: UpstreamConfig = UpstreamConfig {
+        protocol: UpstreamProtocol::Direct(direct::Direct),
+        groups: None,
+        enabled: true,
+        idle_timeout_secs: None,
+    };
+}
+
 pub const fn default_upstream_enabled() -> bool {
     true
 }
@@ -48,6 +89,19 @@ pub struct UpstreamConfig {
     pub groups: Option<HashSet<String>>,
     #[serde(default = "default_upstream_enabled")]
     pub enabled: bool,
+    // Streams through this upstream are closed after this long without traffic either way
+    #[serde(default)]
+    pub idle_timeout_secs: Option<u64>,
+}
+
+impl UpstreamConfig {
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout_secs.map(Duration::from_secs)
+    }
+}
+
+const fn default_tcp_nodelay() -> bool {
+    true
 }
 
 const fn default_socks5_udp_host() -> IpAddr {
@@ -72,14 +126,184 @@ pub struct ClientConfig {
     #[serde(default)]
     pub fwmark: Option<u32>,
 
+    // DSCP (0-63) put on outgoing packets, for routers doing QoS by it
+    #[serde(default)]
+    pub dscp: Option<Dscp>,
+
     #[serde(default)]
     pub udp_tproxy_address: Option<SocketAddr>,
 
+    // Only takes SOCKS5 UDP datagrams from the port the client gave in its UDP ASSOCIATE,
+    // rather than from any port on its IP
+    #[serde(default)]
+    pub udp_associate_strict_port: bool,
+
+    // Takes TLS connections (e.g. redirected here by DNS) and routes them by the server
+    // name in their ClientHello, without decrypting them
+    #[serde(default)]
+    pub sni_proxy_address: Option<SocketAddr>,
+
+    // Where TLS connections rejected by the rules are sent instead, so that a block page
+    // served there can say why. They're closed if it isn't set.
     #[serde(default)]
-    pub traffic_rules: RuleString,
+    pub block_page_address: Option<SocketAddr>,
+
+    // Swapped in place when only the rules change, see `Controller::reload_rules`
+    #[serde(default)]
+    pub traffic_rules: LiveRules,
 
     #[serde(default)]
     pub set_router_rules: bool,
+
+    #[serde(default)]
+    pub connect_timeout_secs: Option<u64>,
+
+    #[serde(default)]
+    pub first_byte_timeout_secs: Option<u64>,
+
+    // How long a SOCKS5 BIND waits for its peer to connect. Defaults to 60s.
+    #[serde(default)]
+    pub socks5_bind_timeout_secs: Option<u64>,
+
+    // Hosts (or `*.suffix` wildcards) the gfw/adblock lists never match
+    #[serde(default)]
+    pub abp_whitelist: Vec<String>,
+
+    // How many times each gfw/adblock list download is tried, backing off in between.
+    // Defaults to 3.
+    #[serde(default)]
+    pub abp_fetch_attempts: Option<usize>,
+
+    // How many redirects a gfw/adblock list download follows. Defaults to 5.
+    #[serde(default)]
+    pub abp_max_redirects: Option<usize>,
+
+    // Where the gfw/adblock lists are cached. Defaults to the user's data directory.
+    #[serde(default)]
+    pub abp_cache_dir: Option<PathBuf>,
+
+    // The lists combined into `list:gfw` and `list:adblock`, replacing the built-in ones,
+    // e.g. `[{url: "https://example.com/list.txt", base64: false}]`. Used from the next update.
+    #[serde(default)]
+    pub gfw_list_sources: Option<Vec<AbpSource>>,
+    #[serde(default)]
+    pub adblock_list_sources: Option<Vec<AbpSource>>,
+
+    // Leaves the bundled gfw list out, so `list:gfw` never matches and it's never updated
+    #[serde(default)]
+    pub disable_gfw_list: bool,
+
+    // Which GeoIP range an address belongs to when ranges overlap
+    #[serde(default)]
+    pub geoip_overlap_policy: OverlapPolicy,
+
+    // Caps the DNS queries in flight at once. No limit is applied if it isn't set.
+    #[serde(default)]
+    pub max_concurrent_dns_queries: Option<usize>,
+
+    // How old a host's last answer can be and still stand in for a failing resolver.
+    // Resolver failures are returned as they are if it isn't set.
+    #[serde(default)]
+    pub dns_serve_stale_secs: Option<u64>,
+
+    // DNS servers by the domain suffix they answer for, as `*.cn`, with `*` for any other
+    // domain. Domains without a server go to the system resolver.
+    #[serde(default)]
+    pub dns_servers: HashMap<String, SocketAddr>,
+
+    // What's done with the client subnet of DNS queries sent through the transparent proxy
+    // or SOCKS5 UDP relays
+    #[serde(default)]
+    pub dns_client_subnet: ClientSubnetPolicy,
+
+    // How many upstreams a request tries before giving up. All of them are tried if it isn't set.
+    #[serde(default)]
+    pub max_upstream_attempts: Option<usize>,
+
+    // Skips upstreams that keep failing for a while, so requests go straight to the next one
+    #[serde(default)]
+    pub upstream_circuit_breaker: Option<CircuitBreakerConfig>,
+
+    // Probes the upstreams through the API, and every so often if it has an interval
+    #[serde(default)]
+    pub health_check: Option<HealthCheckConfig>,
+
+    // One JSON line per finished connection, to `stdout` or appended to a file
+    #[serde(default)]
+    pub access_log: Option<AccessLogSink>,
+
+    // How long a drain waits for active connections before cancelling them
+    #[serde(default)]
+    pub drain_grace_secs: Option<u64>,
+
+    // Split tunneling: destinations here skip the upstreams and connect directly. With
+    // `set_router_rules`, their traffic isn't redirected to the proxy in the first place.
+    #[serde(default)]
+    pub bypass_networks: Vec<IpNetwork>,
+
+    // What to do when the traffic rules can't be loaded at startup
+    #[serde(default)]
+    pub rule_load_policy: RuleLoadPolicy,
+
+    // How long an `external:NAME` rule waits for its decider. Defaults to 1000ms.
+    #[serde(default)]
+    pub external_decision_timeout_ms: Option<u64>,
+
+    // What's done with a connection when its external decider fails or doesn't answer in
+    // time. The connection fails if it isn't set.
+    #[serde(default)]
+    pub external_decision_fallback: Option<ExternalOutcome>,
+
+    // Idle seconds before TCP keepalive probes are sent, set separately for connections to
+    // proxy upstreams and for direct ones. Keepalive is left off when they aren't set.
+    #[serde(default)]
+    pub upstream_keepalive_secs: Option<u64>,
+
+    #[serde(default)]
+    pub direct_keepalive_secs: Option<u64>,
+
+    // Seconds between unanswered keepalive probes, and how many are sent before the
+    // connection is given up. The system's are used when they aren't set.
+    #[serde(default)]
+    pub tcp_keepalive_interval_secs: Option<u64>,
+
+    #[serde(default)]
+    pub tcp_keepalive_count: Option<u32>,
+
+    // Disables Nagle's algorithm on outgoing connections, on by default
+    #[serde(default = "default_tcp_nodelay")]
+    pub tcp_nodelay: bool,
+
+    // Which family's addresses are used first when a name resolves to both
+    #[serde(default)]
+    pub address_family_preference: AddressFamilyPreference,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleLoadPolicy {
+    // Start with rules that parse even if they don't validate, and refuse to start with
+    // rules that don't parse
+    #[default]
+    Lenient,
+    // Refuse to start
+    Strict,
+    // Start with every connection going direct
+    Direct,
+    // Start with every connection rejected
+    Reject,
+}
+
+impl RuleLoadPolicy {
+    // The rules to start with in place of the broken ones
+    pub fn fallback_rules(&self) -> Option<RuleString> {
+        let rules = match self {
+            RuleLoadPolicy::Lenient | RuleLoadPolicy::Strict => return None,
+            RuleLoadPolicy::Direct => "main:\n  fallback -a direct\n",
+            RuleLoadPolicy::Reject => "main:\n  fallback -a reject\n",
+        };
+        Some(rules.parse().expect("Fallback rules to parse"))
+    }
 }
 
 impl Default for ClientConfig {
@@ -89,14 +313,92 @@ impl Default for ClientConfig {
             socks5_udp_host: default_socks5_udp_host(),
             upstreams: Default::default(),
             fwmark: None,
+            dscp: None,
             udp_tproxy_address: None,
+            udp_associate_strict_port: false,
+            sni_proxy_address: None,
+            block_page_address: None,
             traffic_rules: Default::default(),
             set_router_rules: false,
+            connect_timeout_secs: None,
+            first_byte_timeout_secs: None,
+            socks5_bind_timeout_secs: None,
+            abp_whitelist: Default::default(),
+            abp_fetch_attempts: Default::default(),
+            abp_max_redirects: Default::default(),
+            abp_cache_dir: Default::default(),
+            gfw_list_sources: None,
+            adblock_list_sources: None,
+            disable_gfw_list: false,
+            geoip_overlap_policy: Default::default(),
+            max_concurrent_dns_queries: None,
+            dns_serve_stale_secs: None,
+            dns_servers: Default::default(),
+            dns_client_subnet: Default::default(),
+            max_upstream_attempts: None,
+            upstream_circuit_breaker: None,
+            health_check: None,
+            access_log: None,
+            drain_grace_secs: None,
+            bypass_networks: Default::default(),
+            rule_load_policy: Default::default(),
+            external_decision_timeout_ms: None,
+            external_decision_fallback: None,
+            upstream_keepalive_secs: None,
+            direct_keepalive_secs: None,
+            tcp_keepalive_interval_secs: None,
+            tcp_keepalive_count: None,
+            tcp_nodelay: default_tcp_nodelay(),
+            address_family_preference: Default::default(),
         }
     }
 }
 
 impl ClientConfig {
+    pub fn connect_timeout(&self) -> Duration {
+        self.connect_timeout_secs
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_CONNECT_TIMEOUT)
+    }
+
+    pub fn tcp_options(&self) -> TcpOptions {
+        TcpOptions {
+            connect_timeout: self.connect_timeout(),
+            destination_keepalive: self.direct_keepalive_secs.map(Duration::from_secs),
+            upstream_keepalive: self.upstream_keepalive_secs.map(Duration::from_secs),
+            keepalive_interval: self.tcp_keepalive_interval_secs.map(Duration::from_secs),
+            keepalive_count: self.tcp_keepalive_count,
+            nodelay: self.tcp_nodelay,
+            dscp: self.dscp,
+            fwmark: self.fwmark,
+            address_family: self.address_family_preference,
+        }
+    }
+
+    pub fn external_decision_timeout(&self) -> Duration {
+        self.external_decision_timeout_ms
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_EXTERNAL_DECISION_TIMEOUT)
+    }
+
+    pub fn drain_grace_period(&self) -> Duration {
+        self.drain_grace_secs
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_DRAIN_GRACE_PERIOD)
+    }
+
+    // The time to wait for the first response byte after sending the initial data.
+    // No limit is applied if it isn't set.
+    pub fn first_byte_timeout(&self) -> Option<Duration> {
+        self.first_byte_timeout_secs.map(Duration::from_secs)
+    }
+
+    pub fn socks5_bind_timeout(&self) -> Duration {
+        self.socks5_bind_timeout_secs
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_BIND_TIMEOUT)
+    }
+
     fn calc_last_visit_score(stats: &ClientStatistics, upstream_name: &String) -> usize {
         (match stats.upstreams.get(upstream_name) {
             Some(stat) => {
@@ -111,20 +413,59 @@ impl ClientConfig {
         }) as usize
     }
 
+    // Rendezvous (highest random weight) hashing: every destination ranks the members
+    // by the hash of itself and the member's name, so taking a member away only
+    // affects the destinations that ranked it first.
+    fn calc_rendezvous_score(target: &Address<'_>, upstream_name: &str) -> usize {
+        let mut out = [0u8; 8];
+        let mut hasher = Blake2bVar::new(out.len()).unwrap();
+        hasher.update(target.to_string().as_bytes());
+        hasher.update(&[0]);
+        hasher.update(upstream_name.as_bytes());
+        hasher.finalize_variable(&mut out).unwrap();
+        u64::from_le_bytes(out) as usize
+    }
+
+    pub fn validate_rules(&self, rules: &RuleString) -> anyhow::Result<()> {
+        rules.validate(
+            |name| self.upstreams.contains_key(name),
+            // Upstreams without groups take part in every group, same as when routing
+            |group| {
+                self.upstreams.values().any(|u| match &u.groups {
+                    Some(groups) => groups.contains(group),
+                    None => true,
+                })
+            },
+            crate::geoip::has_asn_data(),
+        )
+    }
+
     // Sorted by score MIN -> MAX
-    pub fn find_best_upstream(
+    pub async fn find_best_upstream(
         &self,
         t: TrafficType,
         stats: &ClientStatistics,
-        target: &Address,
+        target: &Address<'_>,
+        src: Option<IpAddr>,
         initial_data: Option<&[u8]>,
     ) -> anyhow::Result<Vec<(&str, &UpstreamConfig)>> {
+        if let Address::IP(addr) = target {
+            let ip = addr.ip().to_canonical();
+            if self.bypass_networks.iter().any(|n| n.contains(ip)) {
+                log::debug!("{target} bypasses the upstreams");
+                return Ok(vec![(BYPASS_UPSTREAM_NAME, &*BYPASS_UPSTREAM)]);
+            }
+        }
+
         let pkt_dst = match target {
-            Address::IP(addr) => PacketDestination::IP {
-                addr: addr.clone(),
-                country_code: find_geoip(&addr.ip()),
-                resolved_host: DnsCache::global().get(&addr.ip()).into_iter().collect(),
-            },
+            Address::IP(addr) => {
+                let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+                PacketDestination::IP {
+                    addr,
+                    country_code: find_geoip(&addr.ip()),
+                    resolved_host: DnsCache::global().get(&addr.ip()).into_iter().collect(),
+                }
+            }
             Address::Name { host, port } => PacketDestination::Domain {
                 hostname: host.as_ref(),
                 port: *port,
@@ -132,15 +473,48 @@ impl ClientConfig {
             },
         };
 
-        let action = self.traffic_rules.execute_rules(
-            &pkt_dst,
-            match t {
-                TrafficType::Datagram => RuleProtocol::Udp,
-                TrafficType::Stream => RuleProtocol::Tcp,
-            },
-            initial_data,
-        )?;
+        let decider = {
+            let rules = self.traffic_rules.load();
+            let started = Instant::now();
+            let action = rules.execute_rules(
+                &pkt_dst,
+                src,
+                match t {
+                    TrafficType::Datagram => RuleProtocol::Udp,
+                    TrafficType::Stream => RuleProtocol::Tcp,
+                },
+                initial_data,
+            );
+            stats.update_rule_evaluation(started.elapsed());
+            match action? {
+                Some(RuleExecutionResult::External(name)) => name.to_string(),
+                action => return self.pick_upstreams(t, stats, target, action),
+            }
+        };
+
+        let query = ExternalQuery {
+            target: target.clone().into_owned(),
+            src,
+            traffic_type: t,
+        };
+        let outcome = external_decision::decide(
+            &decider,
+            query,
+            self.external_decision_timeout(),
+            self.external_decision_fallback.as_ref(),
+        )
+        .await?;
+        log::debug!("External decider {decider} decided {outcome:?} for {target}");
+        self.pick_upstreams(t, stats, target, outcome.as_rule_result())
+    }
 
+    fn pick_upstreams<'a>(
+        &'a self,
+        t: TrafficType,
+        stats: &ClientStatistics,
+        target: &Address,
+        action: Option<RuleExecutionResult<'_>>,
+    ) -> anyhow::Result<Vec<(&'a str, &'a UpstreamConfig)>> {
         let mut upstreams: Vec<(&str, &UpstreamConfig, usize)> = match action {
             None => self
                 .upstreams
@@ -155,12 +529,12 @@ impl ClientConfig {
                 .collect(),
             Some(RuleExecutionResult::Proxy(name)) => self
                 .upstreams
-                .get(name)
+                .get_key_value(name)
                 .into_iter()
-                .filter(|c| c.enabled)
-                .map(move |config| (name, config, 0))
+                .filter(|(_, c)| c.enabled)
+                .map(|(name, config)| (name.as_str(), config, 0))
                 .collect(),
-            Some(RuleExecutionResult::ProxyGroup(name)) => self
+            Some(RuleExecutionResult::ProxyGroup(name, selection)) => self
                 .upstreams
                 .iter()
                 .filter_map(|(n, c)| {
@@ -175,13 +549,30 @@ impl ClientConfig {
                         _ => {}
                     }
 
-                    Some((n.as_str(), c, Self::calc_last_visit_score(stats, n)))
+                    let score = match selection {
+                        GroupSelection::Score => Self::calc_last_visit_score(stats, n),
+                        GroupSelection::Hash => Self::calc_rendezvous_score(target, n),
+                    };
+                    Some((n.as_str(), c, score))
                 })
                 .collect(),
-            Some(RuleExecutionResult::Reject) => Default::default(),
+            Some(RuleExecutionResult::Direct) => {
+                vec![(BYPASS_UPSTREAM_NAME, &*BYPASS_UPSTREAM, 0)]
+            }
+            Some(RuleExecutionResult::Reject) => {
+                return Err(anyhow::Error::new(ConnStatusCode::NOT_ALLOWED)
+                    .context(format!("{target} is rejected by traffic rules")))
+            }
+            Some(RuleExecutionResult::External(name)) => {
+                bail!("External decider {name} can't be asked from another decision")
+            }
         };
 
         upstreams.sort_by_key(|(_, _, score)| *score);
+        if let Some(max) = self.max_upstream_attempts {
+            // Callers try the upstreams from the end
+            upstreams.drain(..upstreams.len().saturating_sub(max));
+        }
         let result = upstreams.into_iter().map(|(n, c, _)| (n, c)).collect();
         Ok(result)
     }
@@ -205,15 +596,15 @@ impl Protocol for UpstreamProtocol {
         dst: &Address<'_>,
         initial_data: Option<&[u8]>,
         stats: &Stats,
-        fwmark: Option<u32>,
+        options: &TcpOptions,
     ) -> anyhow::Result<Box<dyn AsyncStream>> {
         match self {
-            UpstreamProtocol::UdpMan(p) => p.new_stream(dst, initial_data, stats, fwmark).await,
-            UpstreamProtocol::TcpMan(p) => p.new_stream(dst, initial_data, stats, fwmark).await,
-            UpstreamProtocol::Direct(p) => p.new_stream(dst, initial_data, stats, fwmark).await,
-            UpstreamProtocol::Socks5(p) => p.new_stream(dst, initial_data, stats, fwmark).await,
-            UpstreamProtocol::FireTcp(p) => p.new_stream(dst, initial_data, stats, fwmark).await,
-            UpstreamProtocol::Http(p) => p.new_stream(dst, initial_data, stats, fwmark).await,
+            UpstreamProtocol::UdpMan(p) => p.new_stream(dst, initial_data, stats, options).await,
+            UpstreamProtocol::TcpMan(p) => p.new_stream(dst, initial_data, stats, options).await,
+            UpstreamProtocol::Direct(p) => p.new_stream(dst, initial_data, stats, options).await,
+            UpstreamProtocol::Socks5(p) => p.new_stream(dst, initial_data, stats, options).await,
+            UpstreamProtocol::FireTcp(p) => p.new_stream(dst, initial_data, stats, options).await,
+            UpstreamProtocol::Http(p) => p.new_stream(dst, initial_data, stats, options).await,
         }
     }
 
@@ -222,15 +613,199 @@ impl Protocol for UpstreamProtocol {
         dst: &Address<'_>,
         initial_data: Bytes,
         stats: &Stats,
-        fwmark: Option<u32>,
+        options: &TcpOptions,
     ) -> anyhow::Result<(BoxedSink, BoxedStream)> {
         match self {
-            UpstreamProtocol::UdpMan(p) => p.new_datagram(dst, initial_data, stats, fwmark).await,
-            UpstreamProtocol::TcpMan(p) => p.new_datagram(dst, initial_data, stats, fwmark).await,
-            UpstreamProtocol::Direct(p) => p.new_datagram(dst, initial_data, stats, fwmark).await,
-            UpstreamProtocol::Socks5(p) => p.new_datagram(dst, initial_data, stats, fwmark).await,
-            UpstreamProtocol::FireTcp(p) => p.new_datagram(dst, initial_data, stats, fwmark).await,
-            UpstreamProtocol::Http(p) => p.new_datagram(dst, initial_data, stats, fwmark).await,
+            UpstreamProtocol::UdpMan(p) => p.new_datagram(dst, initial_data, stats, options).await,
+            UpstreamProtocol::TcpMan(p) => p.new_datagram(dst, initial_data, stats, options).await,
+            UpstreamProtocol::Direct(p) => p.new_datagram(dst, initial_data, stats, options).await,
+            UpstreamProtocol::Socks5(p) => p.new_datagram(dst, initial_data, stats, options).await,
+            UpstreamProtocol::FireTcp(p) => p.new_datagram(dst, initial_data, stats, options).await,
+            UpstreamProtocol::Http(p) => p.new_datagram(dst, initial_data, stats, options).await,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hashed_group_config(members: &[&str]) -> ClientConfig {
+        let upstreams: String = members
+            .iter()
+            .map(|name| format!("  {name}:\n    protocol:\n      type: direct\n    groups: [g]\n"))
+            .collect();
+        serde_yaml::from_str(&format!(
+            "upstreams:\n{upstreams}traffic_rules: \"main:\\n  hashed -a proxygroup:g:hash\"\n"
+        ))
+        .unwrap()
+    }
+
+    fn pick(config: &ClientConfig, port: u16) -> String {
+        let stats = ClientStatistics::new(config);
+        let target = Address::Name {
+            host: "example.com".into(),
+            port,
+        };
+        let mut upstreams = smol::block_on(config.find_best_upstream(
+            TrafficType::Stream,
+            &stats,
+            &target,
+            None,
+            None,
+        ))
+        .unwrap();
+        assert_eq!(upstreams.len(), config.upstreams.len());
+        upstreams.pop().unwrap().0.to_string()
+    }
+
+    #[test]
+    fn hashed_group_is_consistent() {
+        let config = hashed_group_config(&["a", "b", "c", "d"]);
+        let picked: Vec<_> = (1..200).map(|port| pick(&config, port)).collect();
+        for (port, name) in (1..200).zip(&picked) {
+            assert_eq!(&pick(&config, port), name);
+        }
+
+        // Every member should get its share of the destinations
+        for name in ["a", "b", "c", "d"] {
+            assert!(picked.iter().any(|n| n == name), "{name} is never picked");
+        }
+    }
+
+    #[test]
+    fn hashed_group_member_removal_is_stable() {
+        let before = hashed_group_config(&["a", "b", "c", "d"]);
+        let after = hashed_group_config(&["a", "b", "d"]);
+
+        for port in 1..200 {
+            let old = pick(&before, port);
+            let new = pick(&after, port);
+            if old != "c" {
+                assert_eq!(old, new, "port {port} moved from {old} to {new}");
+            }
+        }
+    }
+
+    #[test]
+    fn external_decision_is_applied() {
+        use crate::external_decision::{
+            register_external_decider, unregister_external_decider, ExternalOutcome,
+        };
+
+        let config: ClientConfig = serde_yaml::from_str(
+            "upstreams:\n  \
+               a:\n    protocol:\n      type: direct\n  \
+               b:\n    protocol:\n      type: direct\n\
+             traffic_rules: \"main:\\n  ask -a external:config_test_stub\"\n",
+        )
+        .unwrap();
+        let stats = ClientStatistics::new(&config);
+        let route = |port: u16| {
+            let target = Address::Name {
+                host: "example.com".into(),
+                port,
+            };
+            smol::block_on(config.find_best_upstream(
+                TrafficType::Stream,
+                &stats,
+                &target,
+                None,
+                None,
+            ))
+            .map(|upstreams| {
+                upstreams
+                    .into_iter()
+                    .map(|(n, _)| n.to_string())
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        // Without a decider registered the connection can't be routed
+        assert!(route(80).is_err());
+
+        register_external_decider("config_test_stub", |query: ExternalQuery| async move {
+            match query.target.get_port() {
+                80 => Ok(ExternalOutcome::Proxy("b".to_string())),
+                _ => Ok(ExternalOutcome::Reject),
+            }
+        });
+        assert_eq!(route(80).unwrap(), vec!["b".to_string()]);
+        let err = route(443).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ConnStatusCode>(),
+            Some(&ConnStatusCode::NOT_ALLOWED)
+        );
+
+        assert!(unregister_external_decider("config_test_stub"));
+    }
+
+    #[test]
+    fn external_decision_falls_back() {
+        use crate::external_decision::{register_external_decider, unregister_external_decider};
+
+        let config: ClientConfig = serde_yaml::from_str(
+            "upstreams:\n  \
+               a:\n    protocol:\n      type: direct\n  \
+               b:\n    protocol:\n      type: direct\n\
+             traffic_rules: \"main:\\n  ask -a external:config_test_slow\"\n\
+             external_decision_timeout_ms: 50\n\
+             external_decision_fallback: proxy:a\n",
+        )
+        .unwrap();
+        let stats = ClientStatistics::new(&config);
+        let route = |port: u16| {
+            let target = Address::Name {
+                host: "example.com".into(),
+                port,
+            };
+            smol::block_on(config.find_best_upstream(
+                TrafficType::Stream,
+                &stats,
+                &target,
+                None,
+                None,
+            ))
+            .map(|upstreams| {
+                upstreams
+                    .into_iter()
+                    .map(|(n, _)| n.to_string())
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        register_external_decider("config_test_slow", |query: ExternalQuery| async move {
+            match query.target.get_port() {
+                80 => Ok(ExternalOutcome::Proxy("b".to_string())),
+                443 => {
+                    smol::Timer::after(Duration::from_secs(5)).await;
+                    Ok(ExternalOutcome::Proxy("b".to_string()))
+                }
+                _ => Err(anyhow::anyhow!("Policy daemon is down")),
+            }
+        });
+        assert_eq!(route(80).unwrap(), vec!["b".to_string()]);
+        assert_eq!(route(443).unwrap(), vec!["a".to_string()]);
+        assert_eq!(route(8080).unwrap(), vec!["a".to_string()]);
+
+        // Without a fallback, a slow decider fails the connection
+        let config = ClientConfig {
+            external_decision_fallback: None,
+            ..config.clone()
+        };
+        let target = Address::Name {
+            host: "example.com".into(),
+            port: 443,
+        };
+        assert!(smol::block_on(config.find_best_upstream(
+            TrafficType::Stream,
+            &stats,
+            &target,
+            None,
+            None,
+        ))
+        .is_err());
+
+        assert!(unregister_external_decider("config_test_slow"));
+    }
+}
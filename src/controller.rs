@@ -1,22 +1,37 @@
 use crate::abp::{adblock_list_engine, gfw_list_engine};
 use crate::broadcast::bounded;
 use crate::buf::RWBuffer;
-use crate::client::{run_client, ClientStatistics};
-use crate::config::{ClientConfig, UpstreamConfig};
+use crate::client::{probe_upstreams, run_client, ClientStatistics};
+use crate::config::{ClientConfig, RuleLoadPolicy, UpstreamConfig, UpstreamProtocol};
+use crate::drain::ConnectionTracker;
 use crate::http::{parse_request, write_http_response, WithHeaders};
 use crate::http_path::HttpPath;
+use crate::iptables as ipt;
+use crate::pac::generate_pac;
+use crate::rule::RuleString;
 use crate::socks5::Address;
 use anyhow::{anyhow, Context};
 use async_broadcast::Sender;
+use async_io::Timer;
 use async_net::TcpListener;
+use async_stream::stream;
 use chrono::{DateTime, Utc};
-use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use futures::{
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Future, FutureExt, Stream, StreamExt,
+};
 use rust_embed::RustEmbed;
 use serde::{Deserialize, Serialize};
 use smol::fs::File;
-use smol::spawn;
-use std::path::PathBuf;
+use smol::{spawn, Task};
+use smol_timeout::TimeoutExt;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+const CONFIG_WATCH_INTERVAL: Duration = Duration::from_secs(2);
+const UPSTREAM_RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+const PROMETHEUS_MIME_TYPE: &str = "text/plain; version=0.0.4";
+const PAC_MIME_TYPE: &str = "application/x-ns-proxy-autoconfig";
 
 #[derive(RustEmbed)]
 #[folder = "web/build"]
@@ -93,10 +108,21 @@ struct RuleResult {
     last_updated: Option<DateTime<Utc>>,
 }
 
+#[derive(Serialize)]
+struct DrainResult {
+    force_closed: usize,
+}
+
 struct Controller {
     current: (Arc<ClientConfig>, Arc<ClientStatistics>),
     broadcaster: Sender<(Arc<ClientConfig>, Arc<ClientStatistics>)>,
     config_file: PathBuf,
+    // Gone once the client has been drained
+    client_task: Option<Task<anyhow::Result<()>>>,
+    connections: ConnectionTracker,
+    // The traffic rules from the config file while fallback rules stand in for them,
+    // written back in their place so the fallback never replaces them on disk
+    unloaded_rules: Option<serde_yaml::Value>,
 }
 
 #[derive(Deserialize)]
@@ -106,6 +132,30 @@ struct UpstreamUpdate {
     config: UpstreamConfig,
 }
 
+// The host part of a `host[:port]` Host header, if it's a plain hostname or IP address
+// that is safe to hand out as the proxy address
+fn pac_proxy_host(host: &str) -> Option<&str> {
+    let host = match host.rsplit_once(':') {
+        Some((h, port)) if port.parse::<u16>().is_ok() => h,
+        _ => host,
+    };
+
+    if let Some(v6) = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        return v6.parse::<std::net::Ipv6Addr>().ok().map(|_| host);
+    }
+
+    let valid = !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        });
+    valid.then_some(host)
+}
+
 impl Controller {
     fn get_stats(&self) -> HttpResult<Arc<ClientStatistics>> {
         Ok(self.current.1.clone())
@@ -115,11 +165,82 @@ impl Controller {
         Ok(self.current.0.clone())
     }
 
+    // `host` is the Host header the PAC file was fetched with, used in place of a
+    // SOCKS5 listener bound to every interface
+    fn get_pac(&self, host: Option<&str>) -> String {
+        let config = &self.current.0;
+        let addr = config.socks5_address;
+        let proxy = match host.map(|h| (h, pac_proxy_host(h))) {
+            Some((_, Some(host))) if addr.ip().is_unspecified() => {
+                format!("{host}:{}", addr.port())
+            }
+            Some((host, None)) if addr.ip().is_unspecified() => {
+                log::warn!("Ignoring invalid Host header {host:?} for PAC");
+                addr.to_string()
+            }
+            _ => addr.to_string(),
+        };
+
+        generate_pac(
+            &config.traffic_rules.load(),
+            |name| {
+                matches!(
+                    config.upstreams.get(name),
+                    Some(UpstreamConfig {
+                        protocol: UpstreamProtocol::Direct(_),
+                        ..
+                    })
+                )
+            },
+            &proxy,
+        )
+    }
+
+    // Stops accepting connections, then gives the active ones the configured grace
+    // period to finish before cancelling them
+    async fn drain(&mut self) -> HttpResult<DrainResult> {
+        if let Some(task) = self.client_task.take() {
+            task.cancel().await;
+        }
+
+        // Nothing is listening for the redirected traffic any more
+        if self.current.0.set_router_rules {
+            if let Err(e) = ipt::clean_up() {
+                log::error!("Error removing router rules: {e:?}");
+            }
+        }
+
+        let grace = self.current.0.drain_grace_period();
+        log::info!(
+            "Draining {} connections, waiting up to {grace:?}",
+            self.connections.active_connections()
+        );
+        let force_closed = self.connections.drain(grace).await;
+        log::info!("Drained, {force_closed} connections were force-closed");
+        Ok(DrainResult { force_closed })
+    }
+
+    async fn apply_config(&mut self, config: Arc<ClientConfig>, stats: Arc<ClientStatistics>) {
+        self.current = (config.clone(), stats.clone());
+        let _ = self.broadcaster.broadcast((config, stats)).await;
+    }
+
     async fn set_current_config(&mut self, c: ClientConfig, s: ClientStatistics) -> HttpResult<()> {
         let stats = Arc::new(s);
         let config = Arc::new(c);
 
-        let config_text = serde_yaml::to_string(config.as_ref())
+        self.write_config_file(&config).await?;
+        self.apply_config(config, stats).await;
+        Ok(())
+    }
+
+    async fn write_config_file(&self, config: &ClientConfig) -> anyhow::Result<()> {
+        let mut value = serde_yaml::to_value(config)
+            .with_context(|| format!("Writing YAML file: {:?}", self.config_file))?;
+        if let (Some(rules), Some(m)) = (&self.unloaded_rules, value.as_mapping_mut()) {
+            m.insert("traffic_rules".into(), rules.clone());
+        }
+        let config_text = serde_yaml::to_string(&value)
             .with_context(|| format!("Writing YAML file: {:?}", self.config_file))?;
 
         let mut file = File::create(&self.config_file)
@@ -136,11 +257,84 @@ impl Controller {
             .with_context(|| format!("Flushing file: {:?}", self.config_file))?;
 
         log::info!("Config written successfully to {:?}", self.config_file);
-        self.current = (config.clone(), stats.clone());
-        let _ = self.broadcaster.broadcast((config.clone(), stats)).await;
         Ok(())
     }
 
+    // Swaps the rules into the running client without restarting it. Lookups already
+    // in progress finish with the rules they started with.
+    async fn reload_rules(&mut self, rules: RuleString) -> HttpResult<()> {
+        self.current
+            .0
+            .validate_rules(&rules)
+            .map_err(ErrorResponse::InvalidRequest)?;
+
+        self.current.0.traffic_rules.store(rules);
+        self.unloaded_rules = None;
+        log::info!("Reloaded traffic rules");
+        self.write_config_file(&self.current.0).await?;
+        Ok(())
+    }
+
+    // Applies a config that was changed on disk. Existing connections are kept as they are.
+    async fn reload_config(&mut self, c: ClientConfig) {
+        // Our own writes come back here too
+        if serde_json::to_value(&c).ok() == serde_json::to_value(self.current.0.as_ref()).ok() {
+            log::debug!("Config file {:?} is unchanged", self.config_file);
+            return;
+        }
+
+        let rules = c.traffic_rules.load();
+        if let Err(e) = c.validate_rules(&rules) {
+            log::error!("Error reloading config, keeping the current one: {e:?}");
+            return;
+        }
+        self.unloaded_rules = None;
+
+        // Only the rules changed, so there's no need to restart the client
+        let without_rules = |c: &ClientConfig| {
+            let mut c = c.clone();
+            c.traffic_rules = Default::default();
+            serde_json::to_value(c).ok()
+        };
+        if without_rules(&c) == without_rules(&self.current.0) {
+            self.current.0.traffic_rules.store(rules.as_ref().clone());
+            log::info!("Reloaded traffic rules from {:?}", self.config_file);
+            return;
+        }
+
+        for (name, upstream) in c.upstreams.iter().filter(|(_, u)| u.enabled) {
+            let address = match upstream.protocol.address() {
+                Some(v) => v,
+                None => continue,
+            };
+
+            match address
+                .resolve_first(c.address_family_preference)
+                .timeout(UPSTREAM_RESOLVE_TIMEOUT)
+                .await
+            {
+                Some(Ok(_)) => {}
+                Some(Err(e)) => log::warn!("Upstream {name} at {address} is unreachable: {e:?}"),
+                None => log::warn!("Timeout resolving upstream {name} at {address}"),
+            }
+        }
+
+        // Keep the stats of the upstreams that haven't changed
+        let (old_config, mut stats) = (self.current.0.clone(), self.current.1.as_ref().clone());
+        stats.upstreams.retain(|name, _| {
+            matches!(
+                (old_config.upstreams.get(name), c.upstreams.get(name)),
+                (Some(old), Some(new)) if old.protocol == new.protocol
+            )
+        });
+        for name in c.upstreams.keys() {
+            stats.upstreams.entry(name.clone()).or_default();
+        }
+
+        log::info!("Reloaded config from {:?}", self.config_file);
+        self.apply_config(Arc::new(c), Arc::new(stats)).await;
+    }
+
     async fn set_config(&mut self, mut c: ClientConfig, replace_upstreams: bool) -> HttpResult<()> {
         let (ClientConfig { upstreams, .. }, new_stats) = (
             self.current.0.as_ref().clone(),
@@ -225,7 +419,22 @@ impl Controller {
                         )
                         .await
                         .and_then(Response::mapper(mime_type)),
+                    ("POST", "/api/rules") => self
+                        .reload_rules(r.body_json_or_yaml().await?)
+                        .await
+                        .and_then(Response::mapper(mime_type)),
+                    ("POST", "/api/drain") => {
+                        self.drain().await.and_then(Response::mapper(mime_type))
+                    }
                     ("GET", "/api/stats") => self.get_stats().and_then(Response::mapper(mime_type)),
+                    ("GET", "/api/metrics") => Ok(Response::Regular {
+                        data: self.current.1.to_prometheus().into_bytes(),
+                        mime_type: PROMETHEUS_MIME_TYPE.to_string(),
+                    }),
+                    ("GET", "/api/pac") => Ok(Response::Regular {
+                        data: self.get_pac(r.get_header_text("Host")).into_bytes(),
+                        mime_type: PAC_MIME_TYPE.to_string(),
+                    }),
                     (m, "/api/gfwlist") | (m, "/api/adblocklist") => {
                         let engine = if path.path.contains("gfwlist") {
                             gfw_list_engine()
@@ -243,7 +452,11 @@ impl Controller {
                                 .map_err(|e| ErrorResponse::Generic(e))
                                 .and_then(Response::mapper(mime_type)),
                             "POST" => engine
-                                .update(&Address::IP(self.current.0.socks5_address))
+                                .update(
+                                    &Address::IP(self.current.0.socks5_address),
+                                    self.current.0.abp_fetch_attempts,
+                                    self.current.0.abp_max_redirects,
+                                )
                                 .await
                                 .and_then(|num_rules| {
                                     Ok(RuleResult {
@@ -276,6 +489,13 @@ impl Controller {
                         .update_upstreams(r.body_json_or_yaml().await?)
                         .await
                         .and_then(Response::mapper(mime_type)),
+                    ("POST", "/api/upstream/probe") => {
+                        let (config, stats) = self.current.clone();
+                        probe_upstreams(&config, &stats, path.get_query("name"))
+                            .await
+                            .map_err(ErrorResponse::InvalidRequest)
+                            .and_then(Response::mapper(mime_type))
+                    }
                     ("DELETE", "/api/upstream") => self
                         .delete_upstreams(r.body_json_or_yaml().await?)
                         .await
@@ -328,18 +548,114 @@ impl Controller {
     }
 }
 
+// The traffic rules are returned apart from the rest, so a config with broken rules
+// can still be read
+fn read_config_file(
+    config_file: &Path,
+) -> anyhow::Result<(ClientConfig, Option<serde_yaml::Value>)> {
+    let mut value: serde_yaml::Value = serde_yaml::from_reader(
+        std::fs::File::open(config_file)
+            .with_context(|| format!("Opening config file {config_file:?}"))?,
+    )
+    .with_context(|| format!("Parsing config file {config_file:?}"))?;
+
+    let rules = value
+        .as_mapping_mut()
+        .and_then(|m| m.remove("traffic_rules"));
+    let config = serde_yaml::from_value(value)
+        .with_context(|| format!("Parsing config file {config_file:?}"))?;
+    Ok((config, rules))
+}
+
+fn parse_rules(config_file: &Path, rules: Option<serde_yaml::Value>) -> anyhow::Result<RuleString> {
+    match rules {
+        Some(rules) => serde_yaml::from_value(rules)
+            .with_context(|| format!("Parsing traffic rules in {config_file:?}")),
+        None => Ok(Default::default()),
+    }
+}
+
+fn load_config_file(config_file: &Path) -> anyhow::Result<ClientConfig> {
+    let (config, rules) = read_config_file(config_file)?;
+    config.traffic_rules.store(parse_rules(config_file, rules)?);
+    Ok(config)
+}
+
+// Rules that fail to parse or validate are handled as `rule_load_policy` says. When
+// fallback rules are used, the rules from the file are returned as they were read.
+fn load_startup_config(
+    config_file: &Path,
+) -> anyhow::Result<(ClientConfig, Option<serde_yaml::Value>)> {
+    let (config, raw_rules) = read_config_file(config_file)?;
+    let policy = config.rule_load_policy;
+    let rules = parse_rules(config_file, raw_rules.clone()).and_then(|rules| {
+        match config.validate_rules(&rules) {
+            Err(e) if policy == RuleLoadPolicy::Lenient => {
+                log::warn!("Starting with traffic rules that don't validate: {e:?}");
+            }
+            r => r?,
+        }
+        Ok(rules)
+    });
+
+    match rules {
+        Ok(rules) => config.traffic_rules.store(rules),
+        Err(e) => match policy.fallback_rules() {
+            Some(fallback) => {
+                log::error!(
+                    "Starting with {policy:?} rules as the traffic rules can't be loaded: {e:?}"
+                );
+                config.traffic_rules.store(fallback);
+                return Ok((config, raw_rules));
+            }
+            None => return Err(e.context("Refusing to start with invalid traffic rules")),
+        },
+    }
+    Ok((config, None))
+}
+
+fn config_file_version(config_file: &Path) -> Option<(SystemTime, u64)> {
+    let meta = std::fs::metadata(config_file).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+// Yields the new config every time the file changes on disk. Files that fail to parse are skipped.
+fn watch_config_file(config_file: PathBuf, interval: Duration) -> impl Stream<Item = ClientConfig> {
+    stream! {
+        let mut last_version = config_file_version(&config_file);
+        loop {
+            Timer::after(interval).await;
+
+            let version = config_file_version(&config_file);
+            if version.is_none() || version == last_version {
+                continue;
+            }
+            last_version = version;
+
+            match load_config_file(&config_file) {
+                Ok(c) => yield c,
+                Err(e) => log::error!("Error reloading config, keeping the current one: {e:?}"),
+            }
+        }
+    }
+}
+
+enum ControllerEvent<C> {
+    Client(std::io::Result<C>),
+    ConfigChanged(Option<Box<ClientConfig>>),
+    Shutdown,
+}
+
+// Runs until drained through the API, or until `shutdown` resolves, which drains the
+// client the same way
 pub async fn run_controller(
     listener: TcpListener,
     config_file: &std::path::Path,
+    shutdown: impl Future<Output = ()>,
 ) -> anyhow::Result<()> {
-    let config = if config_file.exists() {
-        Arc::new(
-            serde_yaml::from_reader(
-                std::fs::File::open(config_file)
-                    .with_context(|| format!("Opening config file {config_file:?}"))?,
-            )
-            .with_context(|| format!("Parsing config file {config_file:?}"))?,
-        )
+    let (config, unloaded_rules) = if config_file.exists() {
+        let (config, unloaded_rules) = load_startup_config(config_file)?;
+        (Arc::new(config), unloaded_rules)
     } else {
         Default::default()
     };
@@ -347,16 +663,48 @@ pub async fn run_controller(
     let stats = Arc::new(ClientStatistics::new(&config));
     let (broadcaster, rx) = bounded(Some((config.clone(), stats.clone())), 1);
 
+    let connections = ConnectionTracker::default();
     let mut controller = Controller {
         current: (config, stats),
         broadcaster,
         config_file: config_file.to_path_buf(),
+        client_task: Some(spawn(run_client(rx, connections.clone()))),
+        connections,
+        unloaded_rules,
     };
 
-    let _client_task = spawn(run_client(rx));
+    let mut config_changes = Box::pin(watch_config_file(
+        config_file.to_path_buf(),
+        CONFIG_WATCH_INTERVAL,
+    ));
 
+    let mut shutdown = Box::pin(shutdown.fuse());
     loop {
-        let (socket, addr) = listener.accept().await?;
+        let event = smol::future::or(
+            smol::future::or(
+                async { ControllerEvent::Client(listener.accept().await) },
+                async { ControllerEvent::ConfigChanged(config_changes.next().await.map(Box::new)) },
+            ),
+            async {
+                (&mut shutdown).await;
+                ControllerEvent::Shutdown
+            },
+        )
+        .await;
+
+        let (socket, addr) = match event {
+            ControllerEvent::Client(v) => v?,
+            ControllerEvent::ConfigChanged(Some(c)) => {
+                controller.reload_config(*c).await;
+                continue;
+            }
+            ControllerEvent::ConfigChanged(None) => continue,
+            ControllerEvent::Shutdown => {
+                let _ = controller.drain().await;
+                log::info!("Client drained, stopping controller");
+                return Ok(());
+            }
+        };
         log::debug!("Serving controller client: {addr}");
         match controller.handle_client(socket).await {
             Ok(_) => {}
@@ -365,5 +713,479 @@ pub async fn run_controller(
             }
         }
         log::debug!("Client {addr} disconnected");
+
+        if controller.client_task.is_none() {
+            log::info!("Client drained, stopping controller");
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client::run_proxy_with,
+        config::UpstreamProtocol,
+        protocol::direct::Direct,
+        test::{create_tcp_server, duplex, echo_tcp_server},
+    };
+    use futures::AsyncBufReadExt;
+    use maplit::hashmap;
+    use std::collections::HashMap;
+    use std::time::Instant;
+
+    const INTERVAL: Duration = Duration::from_millis(20);
+
+    #[test]
+    fn pac_proxy_host_works() {
+        assert_eq!(pac_proxy_host("router.lan:8080"), Some("router.lan"));
+        assert_eq!(pac_proxy_host("192.168.1.1"), Some("192.168.1.1"));
+        assert_eq!(pac_proxy_host("[::1]:80"), Some("[::1]"));
+        assert_eq!(pac_proxy_host("[nope]:80"), None);
+        assert_eq!(pac_proxy_host(""), None);
+        assert_eq!(pac_proxy_host("a..b"), None);
+        assert_eq!(pac_proxy_host("x\";alert(1);//"), None);
+        assert_eq!(pac_proxy_host("x</script>:80"), None);
+    }
+
+    #[test]
+    fn config_file_reload_works() {
+        smol::block_on(async move {
+            let config_file = std::env::temp_dir().join(format!("{}.yaml", uuid::Uuid::new_v4()));
+            std::fs::write(&config_file, "socks5_address: 127.0.0.1:5000\n").unwrap();
+
+            let config = Arc::new(load_config_file(&config_file).unwrap());
+            let stats = Arc::new(ClientStatistics::new(&config));
+            let (broadcaster, mut rx) = bounded(None, 1);
+            let mut controller = Controller {
+                current: (config, stats),
+                broadcaster,
+                config_file: config_file.clone(),
+                client_task: None,
+                connections: Default::default(),
+                unloaded_rules: None,
+            };
+
+            let mut changes = Box::pin(watch_config_file(config_file.clone(), INTERVAL));
+            assert!(changes.next().timeout(INTERVAL * 5).await.is_none());
+
+            std::fs::write(&config_file, "socks5_address: 127.0.0.1:5001\n").unwrap();
+            let c = changes
+                .next()
+                .timeout(INTERVAL * 50)
+                .await
+                .expect("No timeout")
+                .expect("A new config");
+            assert_eq!(c.socks5_address, "127.0.0.1:5001".parse().unwrap());
+
+            controller.reload_config(c).await;
+            let (c, _) = rx.next().await.unwrap();
+            assert_eq!(c.socks5_address, "127.0.0.1:5001".parse().unwrap());
+
+            // Invalid configs are ignored
+            std::fs::write(&config_file, "socks5_address: [invalid\n").unwrap();
+            assert!(changes.next().timeout(INTERVAL * 10).await.is_none());
+
+            std::fs::write(&config_file, "socks5_address: 127.0.0.1:50020\n").unwrap();
+            let c = changes
+                .next()
+                .timeout(INTERVAL * 50)
+                .await
+                .expect("No timeout")
+                .expect("A new config");
+            assert_eq!(c.socks5_address, "127.0.0.1:50020".parse().unwrap());
+
+            let _ = std::fs::remove_file(&config_file);
+        });
+    }
+
+    #[test]
+    fn rule_load_policy_works() {
+        let config_file = std::env::temp_dir().join(format!("{}.yaml", uuid::Uuid::new_v4()));
+        let route = |config: &ClientConfig| {
+            let stats = ClientStatistics::new(config);
+            smol::block_on(config.find_best_upstream(
+                crate::protocol::TrafficType::Stream,
+                &stats,
+                &"1.2.3.4:80".parse().unwrap(),
+                None,
+                None,
+            ))
+            .map(|upstreams| {
+                upstreams
+                    .into_iter()
+                    .map(|(n, _)| n.to_string())
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        // One rule file that doesn't parse, one that refers to an unknown upstream
+        for rule in ["broken -a nonsense", "broken -a proxy:missing"] {
+            for policy in ["", "lenient", "strict", "direct", "reject"] {
+                let policy_line = match policy {
+                    "" => String::new(),
+                    p => format!("rule_load_policy: {p}\n"),
+                };
+                std::fs::write(
+                    &config_file,
+                    format!(
+                        "{policy_line}\
+                         upstreams:\n  \
+                           remote:\n    \
+                             protocol:\n      \
+                               type: direct\n\
+                         traffic_rules: |\n  \
+                           main:\n    \
+                             {rule}\n"
+                    ),
+                )
+                .unwrap();
+
+                // Reloads keep refusing broken rules whatever the policy
+                let unparsable = rule.contains("nonsense");
+                if unparsable {
+                    assert!(load_config_file(&config_file).is_err());
+                }
+
+                let config = load_startup_config(&config_file);
+                match policy {
+                    "" | "lenient" if unparsable => assert!(config.is_err()),
+                    "" | "lenient" => {
+                        // Rules that parse are started with, as they always were
+                        let (config, unloaded) = config.unwrap();
+                        assert!(unloaded.is_none());
+                        assert!(serde_yaml::to_string(&config.traffic_rules)
+                            .unwrap()
+                            .contains("proxy:missing"));
+                    }
+                    "strict" => assert!(config.is_err(), "{rule} under {policy}"),
+                    _ => {
+                        let (config, unloaded) = config.unwrap();
+                        assert_eq!(
+                            unloaded,
+                            Some(serde_yaml::Value::String(format!("main:\n  {rule}\n")))
+                        );
+                        if policy == "direct" {
+                            assert_eq!(route(&config).unwrap(), vec!["bypass"]);
+                        } else {
+                            let err = route(&config).unwrap_err();
+                            assert_eq!(
+                                crate::socks5::ConnStatusCode::from_error(&err),
+                                crate::socks5::ConnStatusCode::NOT_ALLOWED
+                            );
+                        }
+                    }
+                }
+            }
+        }
+
+        // Good rules are used as they are
+        std::fs::write(
+            &config_file,
+            "rule_load_policy: reject\n\
+             upstreams:\n  remote:\n    protocol:\n      type: direct\n\
+             traffic_rules: |\n  main:\n    all -a proxy:remote\n",
+        )
+        .unwrap();
+        let (config, unloaded) = load_startup_config(&config_file).unwrap();
+        assert!(unloaded.is_none());
+        assert_eq!(route(&config).unwrap(), vec!["remote"]);
+
+        let _ = std::fs::remove_file(&config_file);
+    }
+
+    #[test]
+    fn fallback_rules_are_not_persisted() {
+        smol::block_on(async move {
+            let config_file = std::env::temp_dir().join(format!("{}.yaml", uuid::Uuid::new_v4()));
+            std::fs::write(
+                &config_file,
+                "rule_load_policy: direct\n\
+                 traffic_rules: |\n  main:\n    broken -a nonsense\n",
+            )
+            .unwrap();
+
+            let (config, unloaded_rules) = load_startup_config(&config_file).unwrap();
+            let config = Arc::new(config);
+            let stats = Arc::new(ClientStatistics::new(&config));
+            let (broadcaster, _rx) = bounded(None, 1);
+            let mut controller = Controller {
+                current: (config, stats),
+                broadcaster,
+                config_file: config_file.clone(),
+                client_task: None,
+                connections: Default::default(),
+                unloaded_rules,
+            };
+
+            let mut changed = controller.current.0.as_ref().clone();
+            changed.socks5_address = "127.0.0.1:5001".parse().unwrap();
+            assert!(controller
+                .set_current_config(changed, Default::default())
+                .await
+                .is_ok());
+
+            let written = std::fs::read_to_string(&config_file).unwrap();
+            assert!(written.contains("127.0.0.1:5001"));
+            assert!(written.contains("broken -a nonsense"));
+            assert!(!written.contains("fallback -a"));
+
+            // Rules set through the API replace the ones from the file
+            assert!(controller
+                .reload_rules("main:\n  all -a direct\n".parse().unwrap())
+                .await
+                .is_ok());
+            let written = std::fs::read_to_string(&config_file).unwrap();
+            assert!(written.contains("all -a direct"));
+            assert!(!written.contains("broken"));
+
+            let _ = std::fs::remove_file(&config_file);
+        });
+    }
+
+    async fn scrape_metrics(controller: &mut Controller) -> HashMap<String, usize> {
+        let (mut client, server) = duplex(0).await;
+        let (handled, _) = futures::join!(controller.handle_client(server), async {
+            client
+                .write_all(b"GET /api/metrics HTTP/1.1\r\n\r\n")
+                .await
+                .unwrap();
+        });
+        handled.unwrap();
+
+        let mut response = String::new();
+        futures::io::BufReader::new(client)
+            .read_to_string(&mut response)
+            .await
+            .unwrap();
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+
+        body.lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| {
+                let (name, value) = line.rsplit_once(' ').unwrap();
+                (name.to_string(), value.parse::<f64>().unwrap() as usize)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn metrics_works() {
+        smol::block_on(async move {
+            let (_echo, echo_addr) = echo_tcp_server().await;
+            let (listener, proxy_addr) = create_tcp_server().await;
+
+            let config = Arc::new(ClientConfig {
+                upstreams: hashmap! {
+                    String::from("direct") => UpstreamConfig {
+                        protocol: UpstreamProtocol::Direct(Direct),
+                        enabled: true,
+                        idle_timeout_secs: None,
+                        groups: Default::default(),
+                    }
+                },
+                ..Default::default()
+            });
+            let stats = Arc::new(ClientStatistics::new(&config));
+            let _proxy = spawn(run_proxy_with(
+                listener,
+                config.clone(),
+                stats.clone(),
+                Default::default(),
+            ));
+
+            let (broadcaster, _rx) = bounded(None, 1);
+            let mut controller = Controller {
+                current: (config, stats),
+                broadcaster,
+                config_file: Default::default(),
+                client_task: None,
+                connections: Default::default(),
+                unloaded_rules: None,
+            };
+
+            let metrics = scrape_metrics(&mut controller).await;
+            for name in [
+                "cpxy_upstream_connections_total{upstream=\"direct\"}",
+                "cpxy_upstream_bytes_total{upstream=\"direct\",direction=\"tx\"}",
+                "cpxy_upstream_bytes_total{upstream=\"direct\",direction=\"rx\"}",
+                "cpxy_upstream_last_activity_seconds{upstream=\"direct\"}",
+                "cpxy_upstream_connect_latency_seconds_bucket{upstream=\"direct\",le=\"+Inf\"}",
+                "cpxy_upstream_connect_latency_seconds_count{upstream=\"direct\"}",
+                "cpxy_rule_evaluation_seconds_count",
+            ] {
+                assert_eq!(metrics.get(name), Some(&0), "Metric {name}");
+            }
+
+            let tx_name = "cpxy_upstream_bytes_total{upstream=\"direct\",direction=\"tx\"}";
+            let rx_name = "cpxy_upstream_bytes_total{upstream=\"direct\",direction=\"rx\"}";
+            let mut last = metrics;
+            for i in 1..=3 {
+                let mut stream = async_net::TcpStream::connect(proxy_addr).await.unwrap();
+                stream
+                    .write_all(format!("CONNECT {echo_addr} HTTP/1.1\r\n\r\n").as_bytes())
+                    .await
+                    .unwrap();
+                let mut reader = futures::io::BufReader::new(stream);
+                let mut line = String::new();
+                while line != "\r\n" {
+                    line.clear();
+                    reader.read_line(&mut line).await.unwrap();
+                }
+
+                let msg = b"hello, world";
+                reader.get_mut().write_all(msg).await.unwrap();
+                let mut buf = [0u8; 12];
+                reader.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, msg);
+
+                let metrics = scrape_metrics(&mut controller).await;
+                assert_eq!(
+                    metrics["cpxy_upstream_connections_total{upstream=\"direct\"}"],
+                    i
+                );
+                assert_eq!(
+                    metrics["cpxy_upstream_connect_latency_seconds_count{upstream=\"direct\"}"],
+                    i
+                );
+                assert_eq!(metrics["cpxy_rule_evaluation_seconds_count"], i);
+                assert!(metrics[tx_name] > last[tx_name]);
+                assert!(metrics[rx_name] > last[rx_name]);
+                last = metrics;
+            }
+        });
+    }
+
+    #[test]
+    fn drain_lets_connections_finish() {
+        smol::block_on(async move {
+            let (_echo, echo_addr) = echo_tcp_server().await;
+            let (listener, proxy_addr) = create_tcp_server().await;
+
+            let config = Arc::new(ClientConfig {
+                upstreams: hashmap! {
+                    String::from("direct") => UpstreamConfig {
+                        protocol: UpstreamProtocol::Direct(Direct),
+                        enabled: true,
+                        idle_timeout_secs: None,
+                        groups: Default::default(),
+                    }
+                },
+                drain_grace_secs: Some(5),
+                ..Default::default()
+            });
+            let stats = Arc::new(ClientStatistics::new(&config));
+            let connections = ConnectionTracker::default();
+            let (broadcaster, _rx) = bounded(None, 1);
+            let mut controller = Controller {
+                current: (config.clone(), stats.clone()),
+                broadcaster,
+                config_file: Default::default(),
+                client_task: Some(spawn(run_proxy_with(
+                    listener,
+                    config,
+                    stats,
+                    connections.clone(),
+                ))),
+                connections,
+                unloaded_rules: None,
+            };
+
+            // A connection that is still going when the drain starts
+            let mut stream = async_net::TcpStream::connect(proxy_addr).await.unwrap();
+            stream
+                .write_all(format!("CONNECT {echo_addr} HTTP/1.1\r\n\r\n").as_bytes())
+                .await
+                .unwrap();
+            let mut reader = futures::io::BufReader::new(stream);
+            let mut line = String::new();
+            while line != "\r\n" {
+                line.clear();
+                reader.read_line(&mut line).await.unwrap();
+            }
+
+            let started = Instant::now();
+            let (mut client, server) = duplex(0).await;
+            let (handled, _, _) = futures::join!(
+                controller.handle_client(server),
+                async {
+                    client
+                        .write_all(b"POST /api/drain HTTP/1.1\r\n\r\n")
+                        .await
+                        .unwrap();
+                },
+                async {
+                    Timer::after(Duration::from_millis(300)).await;
+                    assert!(
+                        async_net::TcpStream::connect(proxy_addr).await.is_err(),
+                        "No new connections while draining"
+                    );
+
+                    let msg = b"hello, world";
+                    reader.get_mut().write_all(msg).await.unwrap();
+                    let mut buf = [0u8; 12];
+                    reader.read_exact(&mut buf).await.unwrap();
+                    assert_eq!(&buf, msg);
+                    drop(reader);
+                }
+            );
+            handled.unwrap();
+            assert!(started.elapsed() < Duration::from_secs(5));
+            assert!(controller.client_task.is_none());
+
+            let mut response = String::new();
+            futures::io::BufReader::new(client)
+                .read_to_string(&mut response)
+                .await
+                .unwrap();
+            let (_, body) = response.split_once("\r\n\r\n").unwrap();
+            assert_eq!(body, r#"{"force_closed":0}"#);
+        });
+    }
+
+    #[test]
+    fn shutdown_stops_accepting() {
+        smol::block_on(async move {
+            let (controller_listener, controller_addr) = create_tcp_server().await;
+            let proxy_addr = create_tcp_server().await.1;
+            let config_file = std::env::temp_dir().join(format!("{}.yaml", uuid::Uuid::new_v4()));
+            std::fs::write(
+                &config_file,
+                format!("socks5_address: {proxy_addr}\ndrain_grace_secs: 1\n"),
+            )
+            .unwrap();
+
+            let (trigger, shutdown) = futures::channel::oneshot::channel::<()>();
+            let controller = spawn({
+                let config_file = config_file.clone();
+                async move {
+                    run_controller(controller_listener, &config_file, async {
+                        let _ = shutdown.await;
+                    })
+                    .await
+                }
+            });
+
+            // Wait for the proxy to come up
+            let started = Instant::now();
+            while async_net::TcpStream::connect(proxy_addr).await.is_err() {
+                assert!(started.elapsed() < Duration::from_secs(5));
+                Timer::after(INTERVAL).await;
+            }
+
+            trigger.send(()).unwrap();
+            controller
+                .timeout(Duration::from_secs(5))
+                .await
+                .expect("No timeout")
+                .unwrap();
+
+            assert!(async_net::TcpStream::connect(proxy_addr).await.is_err());
+            assert!(async_net::TcpStream::connect(controller_addr)
+                .await
+                .is_err());
+            let _ = std::fs::remove_file(&config_file);
+        });
     }
 }
@@ -19,6 +19,46 @@ impl Counter {
     }
 }
 
+// Upper bounds of the histogram buckets, in milliseconds
+pub const HISTOGRAM_BUCKETS_MS: [usize; 11] =
+    [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+#[derive(Debug, Default)]
+pub struct Histogram {
+    buckets: [Counter; HISTOGRAM_BUCKETS_MS.len()],
+    sum_ms: Counter,
+    count: Counter,
+}
+
+impl Histogram {
+    pub fn observe(&self, value_ms: usize) {
+        if let Some(i) = HISTOGRAM_BUCKETS_MS.iter().position(|b| value_ms <= *b) {
+            self.buckets[i].inc(1);
+        }
+        self.sum_ms.inc(value_ms);
+        self.count.inc(1);
+    }
+
+    // Returns the cumulative count of each bucket, as in Prometheus' `le` semantics
+    pub fn cumulative_buckets(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        HISTOGRAM_BUCKETS_MS
+            .iter()
+            .zip(self.buckets.iter())
+            .scan(0, |total, (bound, c)| {
+                *total += c.get();
+                Some((*bound, *total))
+            })
+    }
+
+    pub fn sum_ms(&self) -> usize {
+        self.sum_ms.get()
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.get()
+    }
+}
+
 impl<'de> Deserialize<'de> for Counter {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -36,3 +76,26 @@ impl Serialize for Counter {
         serializer.serialize_u64(self.get() as u64)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_works() {
+        let h = Histogram::default();
+        h.observe(3);
+        h.observe(30);
+        h.observe(30);
+        h.observe(20000);
+
+        let buckets: Vec<_> = h.cumulative_buckets().collect();
+        assert_eq!(buckets.len(), HISTOGRAM_BUCKETS_MS.len());
+        assert_eq!(buckets[0], (5, 1));
+        assert_eq!(buckets[2], (25, 1));
+        assert_eq!(buckets[3], (50, 3));
+        assert_eq!(buckets.last(), Some(&(10000, 3)));
+        assert_eq!(h.count(), 4);
+        assert_eq!(h.sum_ms(), 20063);
+    }
+}
@@ -0,0 +1,229 @@
+use std::net::IpAddr;
+
+use anyhow::{bail, Context};
+use bytes::{Buf, BufMut, Bytes};
+use ipnetwork::IpNetwork;
+use serde::{Deserialize, Serialize};
+
+use super::https::{read_name, read_u16, take, HEADER_LEN};
+
+const TYPE_OPT: u16 = 41;
+const OPTION_CLIENT_SUBNET: u16 = 8;
+// The UDP payload size advertised by an OPT record we add
+const EDNS_UDP_PAYLOAD_SIZE: u16 = 1232;
+
+// What's done with the EDNS Client Subnet option (RFC 7871) of DNS queries passing
+// through, which tells the resolver where the client is
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClientSubnetPolicy {
+    // Forward queries as they are
+    #[default]
+    Keep,
+    // Remove the option, so the resolver only sees where the query exits
+    Strip,
+    // Replace the option with the given subnet, adding it to queries without one, e.g.
+    // so CDNs answer for the exit region
+    Rewrite(IpNetwork),
+}
+
+impl ClientSubnetPolicy {
+    // The query to forward in place of `query`, if it has to change
+    pub fn apply(&self, query: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        match self {
+            Self::Keep => Ok(None),
+            Self::Strip => rewrite_client_subnet(query, None).map(Some),
+            Self::Rewrite(subnet) => rewrite_client_subnet(query, Some(subnet)).map(Some),
+        }
+    }
+
+    // Applies the policy to a datagram sent to `port`, which is a DNS query when that's 53.
+    // Queries that can't be parsed are sent as they are.
+    pub fn apply_to_datagram(&self, port: u16, payload: Bytes) -> Bytes {
+        if port != 53 {
+            return payload;
+        }
+
+        match self.apply(&payload) {
+            Ok(Some(query)) => query.into(),
+            Ok(None) => payload,
+            Err(e) => {
+                log::warn!("Error rewriting client subnet of DNS query: {e:?}");
+                payload
+            }
+        }
+    }
+}
+
+// Reads the record at `offset`, returning its type, where its rdata starts and where it ends
+fn read_record(pkt: &[u8], offset: usize) -> anyhow::Result<(u16, usize, usize)> {
+    let (_, end) = read_name(pkt, offset)?;
+    let mut fixed = pkt.get(end..end + 10).context("Reading record")?;
+    let rtype = fixed.get_u16();
+    fixed.advance(6);
+    let len = fixed.get_u16() as usize;
+
+    let rdata = end + 10;
+    if pkt.len() < rdata + len {
+        bail!("Unexpected end of DNS packet");
+    }
+    Ok((rtype, rdata, rdata + len))
+}
+
+fn put_client_subnet(buf: &mut Vec<u8>, subnet: &IpNetwork) {
+    let (family, addr) = match subnet.network() {
+        IpAddr::V4(addr) => (1, addr.octets().to_vec()),
+        IpAddr::V6(addr) => (2, addr.octets().to_vec()),
+    };
+    // Only as many bytes of the address as the prefix covers are sent
+    let addr = &addr[..(subnet.prefix() as usize).div_ceil(8)];
+
+    buf.put_u16(OPTION_CLIENT_SUBNET);
+    buf.put_u16(4 + addr.len() as u16);
+    buf.put_u16(family);
+    buf.put_u8(subnet.prefix());
+    // The scope prefix length is always 0 in queries
+    buf.put_u8(0);
+    buf.put_slice(addr);
+}
+
+// Removes the Client Subnet option from the query's OPT record, and puts `subnet` in its
+// place if given. An OPT record is added for `subnet` if the query has none.
+pub fn rewrite_client_subnet(query: &[u8], subnet: Option<&IpNetwork>) -> anyhow::Result<Vec<u8>> {
+    let mut header = query.get(..HEADER_LEN).context("Reading DNS header")?;
+    header.advance(4);
+    let questions = header.get_u16();
+    let records = header.get_u16() as usize + header.get_u16() as usize;
+    let additional = header.get_u16();
+
+    let mut offset = HEADER_LEN;
+    for _ in 0..questions {
+        let (_, end) = read_name(query, offset)?;
+        offset = end + 4;
+    }
+    for _ in 0..records {
+        offset = read_record(query, offset)?.2;
+    }
+
+    let mut out = query.get(..offset).context("Reading questions")?.to_vec();
+    let mut has_opt = false;
+    for _ in 0..additional {
+        let (rtype, rdata, end) = read_record(query, offset)?;
+        if rtype != TYPE_OPT {
+            out.extend_from_slice(&query[offset..end]);
+            offset = end;
+            continue;
+        }
+
+        has_opt = true;
+        let mut options = Vec::new();
+        let mut rest = &query[rdata..end];
+        while !rest.is_empty() {
+            let code = read_u16(&mut rest)?;
+            let len = read_u16(&mut rest)?;
+            let value = take(&mut rest, len as usize)?;
+            if code != OPTION_CLIENT_SUBNET {
+                options.put_u16(code);
+                options.put_u16(len);
+                options.put_slice(value);
+            }
+        }
+        if let Some(subnet) = subnet {
+            put_client_subnet(&mut options, subnet);
+        }
+
+        // Everything up to the rdata length stays as it was
+        out.extend_from_slice(&query[offset..rdata - 2]);
+        out.put_u16(options.len() as u16);
+        out.extend_from_slice(&options);
+        offset = end;
+    }
+
+    if let (false, Some(subnet)) = (has_opt, subnet) {
+        let mut options = Vec::new();
+        put_client_subnet(&mut options, subnet);
+
+        out.put_u8(0);
+        out.put_u16(TYPE_OPT);
+        out.put_u16(EDNS_UDP_PAYLOAD_SIZE);
+        out.put_u32(0);
+        out.put_u16(options.len() as u16);
+        out.extend_from_slice(&options);
+
+        let additional = additional.checked_add(1).context("Too many records")?;
+        out[10..12].copy_from_slice(&additional.to_be_bytes());
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{build_query, DnsQueryType};
+
+    // A query for example.com with an OPT record holding `options`
+    fn query_with_options(options: &[u8]) -> Vec<u8> {
+        let mut pkt = build_query(1, "example.com", DnsQueryType::A).unwrap();
+        pkt[11] = 1;
+        pkt.put_u8(0);
+        pkt.put_u16(TYPE_OPT);
+        pkt.put_u16(4096);
+        pkt.put_u32(0);
+        pkt.put_u16(options.len() as u16);
+        pkt.put_slice(options);
+        pkt
+    }
+
+    // A cookie option, which must survive the rewriting
+    const COOKIE: &[u8] = &[0, 10, 0, 8, 1, 2, 3, 4, 5, 6, 7, 8];
+    // The client's subnet, 192.168.1.0/24
+    const CLIENT_SUBNET: &[u8] = &[0, 8, 0, 7, 0, 1, 24, 0, 192, 168, 1];
+
+    #[test]
+    fn strips_client_subnet() {
+        let query = query_with_options(&[COOKIE, CLIENT_SUBNET].concat());
+        assert_eq!(
+            ClientSubnetPolicy::Strip.apply(&query).unwrap(),
+            Some(query_with_options(COOKIE))
+        );
+        assert_eq!(ClientSubnetPolicy::Keep.apply(&query).unwrap(), None);
+
+        // Queries without EDNS are left alone
+        let plain = build_query(1, "example.com", DnsQueryType::A).unwrap();
+        assert_eq!(
+            ClientSubnetPolicy::Strip.apply(&plain).unwrap(),
+            Some(plain)
+        );
+    }
+
+    #[test]
+    fn rewrites_client_subnet() {
+        let policy = ClientSubnetPolicy::Rewrite("203.0.113.77/20".parse().unwrap());
+        let exit_subnet = [0, 8, 0, 7, 0, 1, 20, 0, 203, 0, 112];
+
+        let query = query_with_options(&[CLIENT_SUBNET, COOKIE].concat());
+        assert_eq!(
+            policy.apply(&query).unwrap(),
+            Some(query_with_options(&[COOKIE, &exit_subnet].concat()))
+        );
+
+        // An OPT record is added for the subnet when there's none
+        let plain = build_query(1, "example.com", DnsQueryType::A).unwrap();
+        let rewritten = policy.apply(&plain).unwrap().unwrap();
+        let mut expected = query_with_options(&exit_subnet);
+        expected[plain.len() + 3..plain.len() + 5]
+            .copy_from_slice(&EDNS_UDP_PAYLOAD_SIZE.to_be_bytes());
+        assert_eq!(rewritten, expected);
+
+        let v6 = ClientSubnetPolicy::Rewrite("2001:db8::/32".parse().unwrap());
+        assert_eq!(
+            v6.apply(&query).unwrap(),
+            Some(query_with_options(
+                &[COOKIE, &[0, 8, 0, 8, 0, 2, 32, 0, 0x20, 0x01, 0x0d, 0xb8]].concat()
+            ))
+        );
+
+        assert!(policy.apply(&query[..query.len() - 3]).is_err());
+    }
+}
@@ -0,0 +1,314 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use anyhow::{bail, Context};
+use bytes::{Buf, BufMut};
+
+pub(super) const HEADER_LEN: usize = 12;
+const CLASS_IN: u16 = 1;
+
+const SVC_PARAM_ALPN: u16 = 1;
+const SVC_PARAM_PORT: u16 = 3;
+const SVC_PARAM_IPV4_HINT: u16 = 4;
+const SVC_PARAM_ECH: u16 = 5;
+const SVC_PARAM_IPV6_HINT: u16 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsQueryType {
+    A,
+    Aaaa,
+    Https,
+}
+
+impl DnsQueryType {
+    pub fn code(self) -> u16 {
+        match self {
+            DnsQueryType::A => 1,
+            DnsQueryType::Aaaa => 28,
+            DnsQueryType::Https => 65,
+        }
+    }
+}
+
+// The service binding hints of an HTTPS resource record (RFC 9460)
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct HttpsRecord {
+    pub priority: u16,
+    pub target: String,
+    pub alpn: Vec<String>,
+    pub port: Option<u16>,
+    pub ip_hints: Vec<IpAddr>,
+    pub ech_config: Option<Vec<u8>>,
+}
+
+pub fn build_query(id: u16, host: &str, query_type: DnsQueryType) -> anyhow::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(HEADER_LEN + host.len() + 6);
+    buf.put_u16(id);
+    // Standard query with recursion desired
+    buf.put_u16(0x0100);
+    buf.put_u16(1);
+    buf.put_u16(0);
+    buf.put_u16(0);
+    buf.put_u16(0);
+
+    for label in host.trim_end_matches('.').split('.') {
+        let len: u8 = match label.len() {
+            1..=63 => label.len() as u8,
+            _ => bail!("Invalid label in host name {host}"),
+        };
+        buf.put_u8(len);
+        buf.put_slice(label.as_bytes());
+    }
+    buf.put_u8(0);
+    buf.put_u16(query_type.code());
+    buf.put_u16(CLASS_IN);
+    Ok(buf)
+}
+
+pub(super) fn take<'a>(buf: &mut &'a [u8], len: usize) -> anyhow::Result<&'a [u8]> {
+    if buf.len() < len {
+        bail!("Unexpected end of DNS packet");
+    }
+    let (head, tail) = buf.split_at(len);
+    *buf = tail;
+    Ok(head)
+}
+
+pub(super) fn read_u16(buf: &mut &[u8]) -> anyhow::Result<u16> {
+    Ok(take(buf, 2)?.get_u16())
+}
+
+// Reads a possibly compressed name starting at `offset`, returning the name and
+// the offset right after it
+pub(super) fn read_name(pkt: &[u8], mut offset: usize) -> anyhow::Result<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Bounds the number of pointers we follow, so a looping packet can't hang us
+    for _ in 0..pkt.len() {
+        let len = *pkt.get(offset).context("Unexpected end of name")? as usize;
+        match len {
+            0 => {
+                let name = labels.join(".");
+                return Ok((name, end.unwrap_or(offset + 1)));
+            }
+            l if l & 0xC0 == 0xC0 => {
+                let mut ptr = pkt
+                    .get(offset..offset + 2)
+                    .context("Reading name pointer")?;
+                end.get_or_insert(offset + 2);
+                offset = (ptr.get_u16() & 0x3FFF) as usize;
+            }
+            l => {
+                let label = pkt
+                    .get(offset + 1..offset + 1 + l)
+                    .context("Reading name label")?;
+                labels.push(std::str::from_utf8(label).context("Decoding label")?);
+                offset += 1 + l;
+            }
+        }
+    }
+    bail!("Too many pointers in name")
+}
+
+fn parse_record(owner: &str, pkt: &[u8], offset: usize, len: usize) -> anyhow::Result<HttpsRecord> {
+    let mut rdata = pkt.get(offset..offset + len).context("Reading rdata")?;
+    let priority = read_u16(&mut rdata)?;
+    let (target, target_end) = read_name(pkt, offset + 2)?;
+    rdata = pkt
+        .get(target_end..offset + len)
+        .context("Reading svc params")?;
+
+    let mut record = HttpsRecord {
+        priority,
+        // An empty target means the service is at the owner name itself
+        target: if target.is_empty() {
+            owner.to_string()
+        } else {
+            target
+        },
+        ..Default::default()
+    };
+
+    while !rdata.is_empty() {
+        let key = read_u16(&mut rdata)?;
+        let value_len = read_u16(&mut rdata)? as usize;
+        let mut value = take(&mut rdata, value_len)?;
+        match key {
+            SVC_PARAM_ALPN => {
+                while !value.is_empty() {
+                    let id_len = take(&mut value, 1)?[0] as usize;
+                    let id = take(&mut value, id_len)?;
+                    record
+                        .alpn
+                        .push(String::from_utf8(id.to_vec()).context("Decoding alpn")?);
+                }
+            }
+            SVC_PARAM_PORT => record.port = Some(read_u16(&mut value)?),
+            SVC_PARAM_IPV4_HINT => {
+                while !value.is_empty() {
+                    let ip: [u8; 4] = take(&mut value, 4)?.try_into().unwrap();
+                    record.ip_hints.push(Ipv4Addr::from(ip).into());
+                }
+            }
+            SVC_PARAM_IPV6_HINT => {
+                while !value.is_empty() {
+                    let ip: [u8; 16] = take(&mut value, 16)?.try_into().unwrap();
+                    record.ip_hints.push(Ipv6Addr::from(ip).into());
+                }
+            }
+            SVC_PARAM_ECH => record.ech_config = Some(value.to_vec()),
+            _ => {}
+        }
+    }
+
+    Ok(record)
+}
+
+// Returns the HTTPS records in the answers of a DNS response, keyed by their owner name
+pub fn parse_https_records(pkt: &[u8]) -> anyhow::Result<Vec<(String, HttpsRecord)>> {
+    let mut header = pkt.get(..HEADER_LEN).context("Reading DNS header")?;
+    header.advance(4);
+    let questions = header.get_u16();
+    let answers = header.get_u16();
+
+    let mut offset = HEADER_LEN;
+    for _ in 0..questions {
+        let (_, end) = read_name(pkt, offset)?;
+        offset = end + 4;
+    }
+
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        let (owner, end) = read_name(pkt, offset)?;
+        let mut fixed = pkt.get(end..end + 10).context("Reading answer")?;
+        let rtype = fixed.get_u16();
+        let class = fixed.get_u16();
+        fixed.advance(4);
+        let len = fixed.get_u16() as usize;
+        offset = end + 10;
+
+        // A record we can't make sense of doesn't spoil the others
+        if rtype == DnsQueryType::Https.code() && class == CLASS_IN {
+            match parse_record(&owner, pkt, offset, len) {
+                Ok(record) => records.push((owner, record)),
+                Err(e) => log::warn!("Skipping HTTPS record for {owner}: {e:?}"),
+            }
+        }
+        offset += len;
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    // Builds a response to `build_query(.., HTTPS)` the way a resolver would,
+    // with the answer's owner name compressed to point at the question
+    pub fn mock_https_response(host: &str, records: &[HttpsRecord]) -> Vec<u8> {
+        let mut pkt = build_query(1, host, DnsQueryType::Https).unwrap();
+        pkt[2] = 0x81;
+        pkt[3] = 0x80;
+        pkt[6..8].copy_from_slice(&(records.len() as u16).to_be_bytes());
+
+        for record in records {
+            let mut rdata = Vec::new();
+            rdata.put_u16(record.priority);
+            for label in record.target.split('.').filter(|l| !l.is_empty()) {
+                rdata.put_u8(label.len() as u8);
+                rdata.put_slice(label.as_bytes());
+            }
+            rdata.put_u8(0);
+
+            if !record.alpn.is_empty() {
+                rdata.put_u16(SVC_PARAM_ALPN);
+                rdata.put_u16(record.alpn.iter().map(|a| a.len() as u16 + 1).sum());
+                for alpn in &record.alpn {
+                    rdata.put_u8(alpn.len() as u8);
+                    rdata.put_slice(alpn.as_bytes());
+                }
+            }
+            if let Some(port) = record.port {
+                rdata.put_u16(SVC_PARAM_PORT);
+                rdata.put_u16(2);
+                rdata.put_u16(port);
+            }
+            let v4_hints: Vec<_> = record
+                .ip_hints
+                .iter()
+                .filter_map(|ip| match ip {
+                    IpAddr::V4(ip) => Some(ip.octets()),
+                    IpAddr::V6(_) => None,
+                })
+                .collect();
+            if !v4_hints.is_empty() {
+                rdata.put_u16(SVC_PARAM_IPV4_HINT);
+                rdata.put_u16(v4_hints.len() as u16 * 4);
+                v4_hints.iter().for_each(|ip| rdata.put_slice(ip));
+            }
+
+            pkt.put_u16(0xC000 | HEADER_LEN as u16);
+            pkt.put_u16(DnsQueryType::Https.code());
+            pkt.put_u16(CLASS_IN);
+            pkt.put_u32(300);
+            pkt.put_u16(rdata.len() as u16);
+            pkt.put_slice(&rdata);
+        }
+        pkt
+    }
+
+    #[test]
+    fn query_types_work() {
+        for query_type in [DnsQueryType::A, DnsQueryType::Aaaa] {
+            let pkt = build_query(1, "www.example.com", query_type).unwrap();
+            let parsed = dns_parser::Packet::parse(&pkt).unwrap();
+            assert_eq!(parsed.questions[0].qname.to_string(), "www.example.com");
+            assert_eq!(parsed.questions[0].qtype as u16, query_type.code());
+        }
+
+        let pkt = build_query(1, "www.example.com.", DnsQueryType::Https).unwrap();
+        assert_eq!(&pkt[pkt.len() - 4..], &[0, 65, 0, 1]);
+        assert!(build_query(1, "www..com", DnsQueryType::Https).is_err());
+    }
+
+    #[test]
+    fn https_records_parse() {
+        let record = HttpsRecord {
+            priority: 1,
+            target: "svc.example.com".to_string(),
+            alpn: vec!["h3".to_string(), "h2".to_string()],
+            port: Some(8443),
+            ip_hints: vec!["10.0.0.1".parse().unwrap()],
+            ..Default::default()
+        };
+        let alias = HttpsRecord {
+            priority: 2,
+            target: "".to_string(),
+            ..Default::default()
+        };
+
+        let pkt = mock_https_response("example.com", &[record.clone(), alias]);
+        let parsed = parse_https_records(&pkt).unwrap();
+        assert_eq!(
+            parsed,
+            vec![
+                ("example.com".to_string(), record.clone()),
+                (
+                    "example.com".to_string(),
+                    HttpsRecord {
+                        priority: 2,
+                        target: "example.com".to_string(),
+                        ..Default::default()
+                    }
+                ),
+            ]
+        );
+
+        // The broken alias is skipped but the record before it is kept
+        assert_eq!(
+            parse_https_records(&pkt[..pkt.len() - 1]).unwrap(),
+            vec![("example.com".to_string(), record)]
+        );
+        assert!(parse_https_records(&pkt[..HEADER_LEN - 1]).is_err());
+    }
+}
@@ -0,0 +1,68 @@
+use std::{future::Future, sync::Arc};
+
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+use smol::lock::Semaphore;
+
+// Caps the number of DNS queries in flight, queuing the rest, so a small resolver
+// (e.g. on a router) isn't flooded. No limit is applied until one is set.
+#[derive(Default)]
+pub struct QueryLimiter {
+    slots: RwLock<Option<Arc<Semaphore>>>,
+}
+
+impl QueryLimiter {
+    // Queries already in flight keep the slots of the limit they started under
+    pub fn set_limit(&self, max_queries: Option<usize>) {
+        *self.slots.write() = max_queries
+            .filter(|max| *max > 0)
+            .map(|max| Arc::new(Semaphore::new(max)));
+    }
+
+    pub async fn run<T>(&self, query: impl Future<Output = T>) -> T {
+        let slots = self.slots.read().clone();
+        let _guard = match &slots {
+            Some(slots) => Some(slots.acquire().await),
+            None => None,
+        };
+        query.await
+    }
+}
+
+lazy_static! {
+    pub static ref QUERY_LIMITER: QueryLimiter = Default::default();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::join_all;
+    use smol::Timer;
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    #[test]
+    fn limits_queries_in_flight() {
+        smol::block_on(async move {
+            let limiter = QueryLimiter::default();
+            limiter.set_limit(Some(2));
+
+            let in_flight = AtomicUsize::new(0);
+            let max_in_flight = AtomicUsize::new(0);
+            let completed = join_all((0..20).map(|_| {
+                limiter.run(async {
+                    let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_in_flight.fetch_max(current, Ordering::SeqCst);
+                    Timer::after(Duration::from_millis(5)).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                })
+            }))
+            .await;
+
+            assert_eq!(completed.len(), 20);
+            assert_eq!(max_in_flight.load(Ordering::SeqCst), 2);
+        });
+    }
+}
@@ -0,0 +1,294 @@
+mod ecs;
+mod https;
+mod limit;
+mod split;
+mod stale;
+
+pub use ecs::{rewrite_client_subnet, ClientSubnetPolicy};
+pub use https::{build_query, parse_https_records, DnsQueryType, HttpsRecord};
+pub use limit::{QueryLimiter, QUERY_LIMITER};
+pub use split::{resolve_host, set_split_dns, SuffixMatchProvider};
+pub use stale::{StaleCache, STALE_CACHE};
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use async_io::Timer;
+use dns_parser::{Packet, QueryClass, RData};
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+use smol::spawn;
+use std::net::IpAddr;
+
+#[derive(Debug)]
+struct Entry {
+    host: Arc<str>,
+    created: Instant,
+}
+
+// Addresses indexed both ways, so a host lookup doesn't have to scan every IP
+#[derive(Default)]
+struct AddressMap {
+    by_ip: HashMap<IpAddr, Entry>,
+    by_host: HashMap<Arc<str>, Vec<IpAddr>>,
+}
+
+fn host_key(host: &str) -> Arc<str> {
+    host.trim_end_matches('.').to_ascii_lowercase().into()
+}
+
+impl AddressMap {
+    fn insert(&mut self, ip: IpAddr, entry: Entry) {
+        let key = host_key(&entry.host);
+        if let Some(old) = self.by_ip.insert(ip, entry) {
+            self.unlink(&host_key(&old.host), &ip);
+        }
+
+        let ips = self.by_host.entry(key).or_default();
+        if !ips.contains(&ip) {
+            ips.push(ip);
+        }
+    }
+
+    fn unlink(&mut self, key: &str, ip: &IpAddr) {
+        if let Some(ips) = self.by_host.get_mut(key) {
+            ips.retain(|i| i != ip);
+            if ips.is_empty() {
+                self.by_host.remove(key);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct HttpsEntry {
+    records: Arc<[HttpsRecord]>,
+    created: Instant,
+}
+
+pub struct DnsCache {
+    address_map: RwLock<AddressMap>,
+    https_map: RwLock<HashMap<Arc<str>, HttpsEntry>>,
+}
+
+const ENTRY_TIMEOUT: Duration = Duration::from_secs(120);
+
+impl DnsCache {
+    pub fn new() -> Arc<DnsCache> {
+        let s = Arc::new(Self {
+            address_map: Default::default(),
+            https_map: Default::default(),
+        });
+
+        let r = Arc::downgrade(&s);
+        spawn(async move {
+            loop {
+                Timer::after(Duration::from_secs(60)).await;
+                if let Some(c) = r.upgrade() {
+                    c.clean_up();
+                } else {
+                    break;
+                }
+            }
+        })
+        .detach();
+
+        s
+    }
+
+    pub fn global() -> &'static Self {
+        lazy_static! {
+            static ref CACHE: Arc<DnsCache> = DnsCache::new();
+        }
+
+        CACHE.as_ref()
+    }
+
+    fn clean_up(&self) {
+        let now = Instant::now();
+        let mut guard = self.address_map.write();
+        let mut expired = Vec::new();
+        guard.by_ip.retain(|ip, entry| {
+            if now.duration_since(entry.created) > ENTRY_TIMEOUT {
+                log::info!("Removing IP = {ip}, Entry = {entry:?}");
+                expired.push((host_key(&entry.host), *ip));
+                false
+            } else {
+                true
+            }
+        });
+        for (key, ip) in expired {
+            guard.unlink(&key, &ip);
+        }
+        drop(guard);
+
+        self.https_map
+            .write()
+            .retain(|_, entry| now.duration_since(entry.created) <= ENTRY_TIMEOUT);
+    }
+
+    pub fn cache(&self, dns_response: &[u8]) -> anyhow::Result<()> {
+        // dns_parser rejects HTTPS records, so they have to be picked out first
+        let https_records =
+            parse_https_records(dns_response).context("Error parsing HTTPS records")?;
+        if !https_records.is_empty() {
+            self.cache_https(https_records);
+            return Ok(());
+        }
+
+        let pkt = Packet::parse(dns_response).context("Error parsing DNS packet")?;
+
+        for answer in &pkt.answers {
+            let addr = match answer.data {
+                RData::A(addr) => IpAddr::V4(addr.0),
+                RData::AAAA(addr) => IpAddr::V6(addr.0),
+                _ => return Ok(()),
+            };
+
+            let entry = Entry {
+                host: answer.name.to_string().into(),
+                created: Instant::now(),
+            };
+            log::info!("Caching DNS record: {entry:?}");
+
+            self.address_map.write().insert(addr, entry);
+        }
+
+        Ok(())
+    }
+
+    fn cache_https(&self, records: Vec<(String, HttpsRecord)>) {
+        let mut by_host = HashMap::<String, Vec<HttpsRecord>>::new();
+        for (host, record) in records {
+            by_host.entry(host).or_default().push(record);
+        }
+
+        let mut guard = self.https_map.write();
+        for (host, mut records) in by_host {
+            records.sort_by_key(|r| r.priority);
+            log::info!("Caching HTTPS records for {host}: {records:?}");
+            guard.insert(
+                host.into(),
+                HttpsEntry {
+                    records: records.into(),
+                    created: Instant::now(),
+                },
+            );
+        }
+    }
+
+    pub fn get(&self, ip: &IpAddr) -> Option<Arc<str>> {
+        self.address_map
+            .read()
+            .by_ip
+            .get(ip)
+            .map(|e| e.host.clone())
+    }
+
+    // The IPs the host was last seen resolving to
+    pub fn lookup(&self, host: &str) -> Vec<IpAddr> {
+        self.address_map
+            .read()
+            .by_host
+            .get(&host_key(host))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    // The HTTPS records last seen for the host, ordered by priority
+    pub fn get_https_records(&self, host: &str) -> Option<Arc<[HttpsRecord]>> {
+        self.https_map
+            .read()
+            .get(host.trim_end_matches('.'))
+            .map(|e| e.records.clone())
+    }
+}
+
+pub fn dns_get_host_names<'a>(pkt: &'a [u8]) -> Option<impl Iterator<Item = String> + 'a> {
+    Some(
+        Packet::parse(pkt)
+            .ok()?
+            .questions
+            .into_iter()
+            .map_while(|q| {
+                if q.qclass == QueryClass::IN {
+                    Some(q.qname.to_string())
+                } else {
+                    None
+                }
+            }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caches_https_hints() {
+        let cache = DnsCache::new();
+        let pkt = https::tests::mock_https_response(
+            "example.com",
+            &[
+                HttpsRecord {
+                    priority: 2,
+                    target: "backup.example.com".to_string(),
+                    ..Default::default()
+                },
+                HttpsRecord {
+                    priority: 1,
+                    target: "".to_string(),
+                    alpn: vec!["h2".to_string()],
+                    port: Some(8443),
+                    ..Default::default()
+                },
+            ],
+        );
+        cache.cache(&pkt).unwrap();
+
+        let records = cache.get_https_records("example.com.").unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].target, "example.com");
+        assert_eq!(records[0].alpn, vec!["h2".to_string()]);
+        assert_eq!(records[0].port, Some(8443));
+        assert_eq!(records[1].target, "backup.example.com");
+        assert!(cache.get_https_records("www.example.com").is_none());
+    }
+
+    #[test]
+    fn lookup_follows_reassigned_ips() {
+        let cache = DnsCache::new();
+        let entry = |host: &str| Entry {
+            host: host.into(),
+            created: Instant::now(),
+        };
+        let ip1: IpAddr = "1.1.1.1".parse().unwrap();
+        let ip2: IpAddr = "1.1.1.2".parse().unwrap();
+
+        {
+            let mut map = cache.address_map.write();
+            map.insert(ip1, entry("Example.com."));
+            map.insert(ip2, entry("example.com"));
+            map.insert(ip2, entry("other.com"));
+        }
+
+        assert_eq!(cache.lookup("example.com"), vec![ip1]);
+        assert_eq!(cache.lookup("EXAMPLE.COM."), vec![ip1]);
+        assert_eq!(cache.lookup("other.com"), vec![ip2]);
+        assert_eq!(cache.get(&ip2).as_deref(), Some("other.com"));
+
+        cache
+            .address_map
+            .write()
+            .by_ip
+            .values_mut()
+            .for_each(|e| e.created = Instant::now() - ENTRY_TIMEOUT - Duration::from_secs(1));
+        cache.clean_up();
+        assert!(cache.lookup("example.com").is_empty());
+        assert!(cache.address_map.read().by_host.is_empty());
+    }
+}
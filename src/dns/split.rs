@@ -0,0 +1,406 @@
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::bail;
+use async_net::{resolve, TcpStream};
+use dns_parser::{Packet, RData};
+use futures::{AsyncReadExt, AsyncWriteExt};
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+use smol_timeout::TimeoutExt;
+
+use super::{build_query, https::HEADER_LEN, DnsCache, DnsQueryType, QUERY_LIMITER};
+use crate::io::bind_udp;
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Node {
+    children: HashMap<String, Node>,
+    server: Option<SocketAddr>,
+}
+
+// Picks the DNS server for a host by the longest domain suffix it's configured for, e.g.
+// `*.cn` to a domestic resolver and `*` for everything else. Hosts matching nothing are
+// left to the system resolver.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SuffixMatchProvider {
+    // Keyed by label, starting from the top level domain
+    root: Node,
+}
+
+impl SuffixMatchProvider {
+    // Takes suffixes as `*.example.com`, `.example.com` or `example.com`, and `*` for the
+    // default server
+    pub fn new(servers: &HashMap<String, SocketAddr>) -> anyhow::Result<Self> {
+        let mut root = Node::default();
+        for (suffix, server) in servers {
+            let suffix = suffix.trim_start_matches('*').trim_start_matches('.');
+            let mut node = &mut root;
+            if !suffix.is_empty() {
+                for label in suffix.trim_end_matches('.').rsplit('.') {
+                    if label.is_empty() {
+                        bail!("Invalid domain suffix for DNS server {server}: {suffix}");
+                    }
+                    node = node.children.entry(label.to_ascii_lowercase()).or_default();
+                }
+            }
+            node.server = Some(*server);
+        }
+        Ok(Self { root })
+    }
+
+    pub fn dns_server_for_domain(&self, host: &str) -> Option<SocketAddr> {
+        let mut node = &self.root;
+        let mut server = node.server;
+        for label in host.trim_end_matches('.').rsplit('.') {
+            match node.children.get(label.to_ascii_lowercase().as_str()) {
+                Some(child) => node = child,
+                None => break,
+            }
+            server = node.server.or(server);
+        }
+        server
+    }
+}
+
+lazy_static! {
+    static ref SPLIT_DNS: RwLock<SuffixMatchProvider> = Default::default();
+}
+
+// Replaces the DNS servers picked by domain suffix. The servers set before are kept if
+// the new ones can't be loaded.
+pub fn set_split_dns(servers: &HashMap<String, SocketAddr>) -> anyhow::Result<()> {
+    *SPLIT_DNS.write() = SuffixMatchProvider::new(servers)?;
+    Ok(())
+}
+
+// Sends a query over TCP, for answers too large for a datagram
+async fn query_server_tcp(server: SocketAddr, query: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(server).await?;
+    let mut request = Vec::with_capacity(query.len() + 2);
+    request.extend_from_slice(&(query.len() as u16).to_be_bytes());
+    request.extend_from_slice(query);
+    stream.write_all(&request).await?;
+
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len).await?;
+    let mut response = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut response).await?;
+    Ok(response)
+}
+
+// The addresses the host's cached HTTPS records hint at, for when it has no A or AAAA
+// records of its own
+fn https_hints(host: &str) -> Vec<IpAddr> {
+    let host = host.trim_end_matches('.');
+    DnsCache::global()
+        .get_https_records(host)
+        .map(|records| {
+            records
+                .iter()
+                .filter(|r| r.target.eq_ignore_ascii_case(host))
+                .flat_map(|r| r.ip_hints.iter().copied())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Asks `server` for the A, AAAA and HTTPS records of the host. Truncated answers are asked
+// for again over TCP.
+async fn query_server(
+    server: SocketAddr,
+    host: &str,
+    port: u16,
+) -> std::io::Result<Vec<SocketAddr>> {
+    let socket = bind_udp(server.is_ipv4()).await?;
+    socket.connect(server).await?;
+
+    let mut queries = Vec::new();
+    for query_type in [DnsQueryType::A, DnsQueryType::Aaaa, DnsQueryType::Https] {
+        let id = rand::random();
+        let query = build_query(id, host, query_type)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        socket.send(&query).await?;
+        queries.push((id, query_type, query));
+    }
+
+    let mut ips = Vec::new();
+    let mut buf = [0u8; 4096];
+    while !queries.is_empty() {
+        // The HTTPS records are only a fallback, so they aren't waited for once there are
+        // addresses
+        if !ips.is_empty() && queries.iter().all(|(_, t, _)| *t == DnsQueryType::Https) {
+            break;
+        }
+
+        let len = match socket.recv(&mut buf).timeout(QUERY_TIMEOUT).await {
+            Some(v) => v?,
+            None => break,
+        };
+
+        // dns_parser rejects HTTPS answers, so the header is read by hand
+        if len < HEADER_LEN {
+            continue;
+        }
+        let id = u16::from_be_bytes([buf[0], buf[1]]);
+        let truncated = buf[2] & 0x02 != 0;
+        let Some(i) = queries.iter().position(|(qid, ..)| *qid == id) else {
+            continue;
+        };
+        let (_, _, query) = queries.swap_remove(i);
+
+        let tcp_response;
+        let response = if truncated {
+            tcp_response = match query_server_tcp(server, &query)
+                .timeout(QUERY_TIMEOUT)
+                .await
+            {
+                Some(Ok(v)) => v,
+                Some(Err(e)) => {
+                    log::warn!("Error querying {host} over TCP from DNS server {server}: {e:?}");
+                    continue;
+                }
+                None => continue,
+            };
+            tcp_response.as_slice()
+        } else {
+            &buf[..len]
+        };
+        let _ = DnsCache::global().cache(response);
+        let Ok(pkt) = Packet::parse(response) else {
+            continue;
+        };
+
+        ips.extend(pkt.answers.iter().filter_map(|answer| match answer.data {
+            RData::A(addr) => Some(IpAddr::V4(addr.0)),
+            RData::AAAA(addr) => Some(IpAddr::V6(addr.0)),
+            _ => None,
+        }));
+    }
+
+    if ips.is_empty() {
+        ips = https_hints(host);
+    }
+    if ips.is_empty() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("No address for {host} from DNS server {server}"),
+        ));
+    }
+    Ok(ips
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect())
+}
+
+// Looks the host up with the DNS server configured for it, or the system resolver. IP
+// literals are taken as they are.
+pub async fn resolve_host(host: &str, port: u16) -> std::io::Result<Vec<SocketAddr>> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+
+    let server = SPLIT_DNS.read().dns_server_for_domain(host);
+    match server {
+        Some(server) => QUERY_LIMITER.run(query_server(server, host, port)).await,
+        None => QUERY_LIMITER.run(resolve((host, port))).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use maplit::hashmap;
+    use smol::spawn;
+
+    use super::*;
+    use crate::dns::{https::tests::mock_https_response, HttpsRecord};
+
+    #[test]
+    fn picks_longest_suffix() {
+        let domestic: SocketAddr = "114.114.114.114:53".parse().unwrap();
+        let corp: SocketAddr = "10.0.0.53:53".parse().unwrap();
+        let default: SocketAddr = "1.1.1.1:53".parse().unwrap();
+        let provider = SuffixMatchProvider::new(&hashmap! {
+            String::from("*.cn") => domestic,
+            String::from("corp.example.cn") => corp,
+            String::from("*") => default,
+        })
+        .unwrap();
+
+        // Exact suffix match
+        assert_eq!(provider.dns_server_for_domain("cn"), Some(domestic));
+        assert_eq!(provider.dns_server_for_domain("baidu.CN."), Some(domestic));
+
+        // Longest match wins
+        assert_eq!(
+            provider.dns_server_for_domain("corp.example.cn"),
+            Some(corp)
+        );
+        assert_eq!(
+            provider.dns_server_for_domain("git.corp.example.cn"),
+            Some(corp)
+        );
+        assert_eq!(provider.dns_server_for_domain("example.cn"), Some(domestic));
+
+        // Anything else falls back to the default, or to the system resolver without one
+        assert_eq!(provider.dns_server_for_domain("notcn"), Some(default));
+        assert_eq!(provider.dns_server_for_domain("google.com"), Some(default));
+        let provider = SuffixMatchProvider::new(&hashmap! {
+            String::from(".cn") => domestic,
+        })
+        .unwrap();
+        assert_eq!(provider.dns_server_for_domain("google.com"), None);
+
+        assert!(SuffixMatchProvider::new(&hashmap! {
+            String::from("a..cn") => domestic,
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn queries_configured_server() {
+        smol::block_on(async move {
+            // Answers every query with 10.0.0.1
+            let server = bind_udp(true).await.unwrap();
+            let server_addr = SocketAddr::new(
+                "127.0.0.1".parse().unwrap(),
+                server.local_addr().unwrap().port(),
+            );
+            let _task = spawn(async move {
+                let mut buf = [0u8; 512];
+                loop {
+                    let (len, from) = server.recv_from(&mut buf).await.unwrap();
+                    let mut response = buf[..len].to_vec();
+                    response[2] = 0x81;
+                    response[3] = 0x80;
+                    if response[len - 3] == DnsQueryType::A.code() as u8 {
+                        response[7] = 1;
+                        response.extend_from_slice(&[
+                            0xC0, 12, 0, 1, 0, 1, 0, 0, 1, 0, 0, 4, 10, 0, 0, 1,
+                        ]);
+                    }
+                    server.send_to(&response, from).await.unwrap();
+                }
+            });
+
+            assert_eq!(
+                query_server(server_addr, "www.split-dns.test", 443)
+                    .await
+                    .unwrap(),
+                vec!["10.0.0.1:443".parse::<SocketAddr>().unwrap()]
+            );
+            assert_eq!(
+                DnsCache::global()
+                    .get(&"10.0.0.1".parse().unwrap())
+                    .as_deref(),
+                Some("www.split-dns.test")
+            );
+        });
+    }
+
+    #[test]
+    fn truncated_answers_are_retried_over_tcp() {
+        smol::block_on(async move {
+            // Over UDP, every answer is truncated. Over TCP, A queries get 10.0.0.2.
+            let tcp = async_net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let server_addr = tcp.local_addr().unwrap();
+            let udp = async_net::UdpSocket::bind(server_addr).await.unwrap();
+            let _udp_task = spawn(async move {
+                let mut buf = [0u8; 512];
+                loop {
+                    let (len, from) = udp.recv_from(&mut buf).await.unwrap();
+                    let mut response = buf[..len].to_vec();
+                    response[2] = 0x83;
+                    response[3] = 0x80;
+                    udp.send_to(&response, from).await.unwrap();
+                }
+            });
+            let _tcp_task = spawn(async move {
+                loop {
+                    let (mut stream, _) = tcp.accept().await.unwrap();
+                    let mut len = [0u8; 2];
+                    stream.read_exact(&mut len).await.unwrap();
+                    let mut response = vec![0u8; u16::from_be_bytes(len) as usize];
+                    stream.read_exact(&mut response).await.unwrap();
+                    response[2] = 0x81;
+                    response[3] = 0x80;
+                    if response[response.len() - 3] == DnsQueryType::A.code() as u8 {
+                        response[7] = 1;
+                        response.extend_from_slice(&[
+                            0xC0, 12, 0, 1, 0, 1, 0, 0, 1, 0, 0, 4, 10, 0, 0, 2,
+                        ]);
+                    }
+                    let mut framed = (response.len() as u16).to_be_bytes().to_vec();
+                    framed.extend_from_slice(&response);
+                    stream.write_all(&framed).await.unwrap();
+                }
+            });
+
+            assert_eq!(
+                query_server(server_addr, "big.split-dns.test", 443)
+                    .await
+                    .unwrap(),
+                vec!["10.0.0.2:443".parse::<SocketAddr>().unwrap()]
+            );
+        });
+    }
+
+    #[test]
+    fn https_hints_are_used_without_addresses() {
+        smol::block_on(async move {
+            // Only has an HTTPS record, hinting at 10.0.0.3
+            let server = bind_udp(true).await.unwrap();
+            let server_addr = SocketAddr::new(
+                "127.0.0.1".parse().unwrap(),
+                server.local_addr().unwrap().port(),
+            );
+            let _task = spawn(async move {
+                let mut buf = [0u8; 512];
+                loop {
+                    let (len, from) = server.recv_from(&mut buf).await.unwrap();
+                    let mut response = buf[..len].to_vec();
+                    response[2] = 0x81;
+                    response[3] = 0x80;
+                    if response[len - 3] == DnsQueryType::Https.code() as u8 {
+                        response = mock_https_response(
+                            "hinted.split-dns.test",
+                            &[HttpsRecord {
+                                priority: 1,
+                                ip_hints: vec!["10.0.0.3".parse().unwrap()],
+                                ..Default::default()
+                            }],
+                        );
+                        response[..2].copy_from_slice(&buf[..2]);
+                    }
+                    server.send_to(&response, from).await.unwrap();
+                }
+            });
+
+            assert_eq!(
+                query_server(server_addr, "hinted.split-dns.test", 443)
+                    .await
+                    .unwrap(),
+                vec!["10.0.0.3:443".parse::<SocketAddr>().unwrap()]
+            );
+        });
+    }
+
+    #[test]
+    fn ip_literals_are_not_looked_up() {
+        smol::block_on(async move {
+            assert_eq!(
+                resolve_host("10.1.2.3", 80).await.unwrap(),
+                vec!["10.1.2.3:80".parse::<SocketAddr>().unwrap()]
+            );
+            assert_eq!(
+                resolve_host("::1", 80).await.unwrap(),
+                vec!["[::1]:80".parse::<SocketAddr>().unwrap()]
+            );
+        });
+    }
+}
@@ -0,0 +1,235 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_io::Timer;
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+use smol::spawn;
+
+use super::resolve_host;
+
+// How long after serving a stale answer the host is looked up again
+const STALE_REFRESH_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+struct Answer {
+    ips: Arc<[IpAddr]>,
+    resolved: Instant,
+    refreshing: bool,
+}
+
+// Remembers the last answer for each host, so that when the resolver fails, an answer
+// up to `max_stale` old is used instead while the host is looked up again in the
+// background. Nothing is remembered until `max_stale` is set.
+#[derive(Default)]
+pub struct StaleCache {
+    max_stale: RwLock<Option<Duration>>,
+    answers: RwLock<HashMap<Arc<str>, Answer>>,
+}
+
+impl StaleCache {
+    pub fn new() -> Arc<Self> {
+        let s = Arc::new(Self::default());
+
+        let r = Arc::downgrade(&s);
+        spawn(async move {
+            loop {
+                Timer::after(Duration::from_secs(60)).await;
+                if let Some(c) = r.upgrade() {
+                    c.clean_up();
+                } else {
+                    break;
+                }
+            }
+        })
+        .detach();
+
+        s
+    }
+
+    pub fn set_max_stale(&self, max_stale: Option<Duration>) {
+        *self.max_stale.write() = max_stale;
+        if max_stale.is_none() {
+            self.answers.write().clear();
+        }
+    }
+
+    fn clean_up(&self) {
+        let max_stale = *self.max_stale.read();
+        let now = Instant::now();
+        self.answers.write().retain(|_, answer| {
+            max_stale.is_some_and(|max| now.duration_since(answer.resolved) <= max)
+        });
+    }
+
+    fn record(&self, host: &str, addrs: &[SocketAddr]) {
+        if addrs.is_empty() {
+            return;
+        }
+
+        self.answers.write().insert(
+            host.into(),
+            Answer {
+                ips: addrs.iter().map(SocketAddr::ip).collect(),
+                resolved: Instant::now(),
+                refreshing: false,
+            },
+        );
+    }
+
+    // The last answer for the host if it's recent enough, along with whether a refresh
+    // should be started for it
+    fn stale_answer(&self, host: &str, max_stale: Duration) -> Option<(Arc<[IpAddr]>, bool)> {
+        let mut answers = self.answers.write();
+        let answer = answers.get_mut(host)?;
+        if answer.resolved.elapsed() > max_stale {
+            return None;
+        }
+
+        let start_refresh = !answer.refreshing;
+        answer.refreshing = true;
+        Some((answer.ips.clone(), start_refresh))
+    }
+
+    pub async fn resolve_with<F, Fut>(
+        self: &Arc<Self>,
+        host: &str,
+        port: u16,
+        lookup: F,
+    ) -> std::io::Result<Vec<SocketAddr>>
+    where
+        F: Fn(String, u16) -> Fut + Send + 'static,
+        Fut: Future<Output = std::io::Result<Vec<SocketAddr>>> + Send + 'static,
+    {
+        let Some(max_stale) = *self.max_stale.read() else {
+            return lookup(host.to_string(), port).await;
+        };
+
+        let err = match lookup(host.to_string(), port).await {
+            Ok(addrs) => {
+                self.record(host, &addrs);
+                return Ok(addrs);
+            }
+            Err(e) => e,
+        };
+
+        let Some((ips, start_refresh)) = self.stale_answer(host, max_stale) else {
+            return Err(err);
+        };
+        log::warn!("Error resolving {host}, using its last answer {ips:?}: {err}");
+
+        if start_refresh {
+            let cache = self.clone();
+            let host = host.to_string();
+            spawn(async move {
+                Timer::after(STALE_REFRESH_DELAY).await;
+                match lookup(host.clone(), port).await {
+                    Ok(addrs) => cache.record(&host, &addrs),
+                    Err(e) => {
+                        log::debug!("Error refreshing {host}: {e}");
+                        if let Some(answer) = cache.answers.write().get_mut(host.as_str()) {
+                            answer.refreshing = false;
+                        }
+                    }
+                }
+            })
+            .detach();
+        }
+
+        Ok(ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect())
+    }
+
+    // Looks the host up with the DNS server configured for it, or the system resolver
+    pub async fn resolve(
+        self: &Arc<Self>,
+        host: &str,
+        port: u16,
+    ) -> std::io::Result<Vec<SocketAddr>> {
+        self.resolve_with(host, port, |host, port| async move {
+            resolve_host(&host, port).await
+        })
+        .await
+    }
+}
+
+lazy_static! {
+    pub static ref STALE_CACHE: Arc<StaleCache> = StaleCache::new();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::ErrorKind,
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    use super::*;
+
+    #[test]
+    fn serves_stale_answer_when_resolver_fails() {
+        smol::block_on(async move {
+            let cache = StaleCache::new();
+            cache.set_max_stale(Some(Duration::from_secs(60)));
+            let stale_ip: IpAddr = "10.0.0.1".parse().unwrap();
+            let fresh_ip: IpAddr = "10.0.0.2".parse().unwrap();
+            cache.answers.write().insert(
+                "example.com".into(),
+                Answer {
+                    ips: [stale_ip].into(),
+                    resolved: Instant::now() - Duration::from_secs(30),
+                    refreshing: false,
+                },
+            );
+
+            // Fails until it's back up
+            let resolver_up = Arc::new(AtomicBool::new(false));
+            let lookup = {
+                let resolver_up = resolver_up.clone();
+                move |_: String, port: u16| {
+                    let up = resolver_up.load(Ordering::SeqCst);
+                    async move {
+                        match up {
+                            true => Ok(vec![SocketAddr::new(fresh_ip, port)]),
+                            false => Err(std::io::Error::new(ErrorKind::Other, "Resolver down")),
+                        }
+                    }
+                }
+            };
+
+            assert_eq!(
+                cache
+                    .resolve_with("example.com", 443, lookup.clone())
+                    .await
+                    .unwrap(),
+                vec![SocketAddr::new(stale_ip, 443)]
+            );
+            assert!(cache
+                .resolve_with("www.example.com", 443, lookup.clone())
+                .await
+                .is_err());
+
+            // The background refresh picks up the new answer once the resolver is back
+            resolver_up.store(true, Ordering::SeqCst);
+            Timer::after(STALE_REFRESH_DELAY + Duration::from_millis(200)).await;
+            assert_eq!(&*cache.answers.read()["example.com"].ips, &[fresh_ip]);
+
+            // Answers older than the bound aren't used
+            cache
+                .answers
+                .write()
+                .get_mut("example.com")
+                .unwrap()
+                .resolved = Instant::now() - Duration::from_secs(120);
+            resolver_up.store(false, Ordering::SeqCst);
+            assert!(cache
+                .resolve_with("example.com", 443, lookup)
+                .await
+                .is_err());
+        });
+    }
+}
@@ -0,0 +1,99 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use smol::{spawn, Task, Timer};
+
+// How often draining checks whether the connections have all finished
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Default)]
+struct Tracked {
+    next_id: usize,
+    tasks: HashMap<usize, Task<()>>,
+}
+
+// Keeps hold of the tasks serving connections, so that on shutdown they can be given
+// a grace period to finish before the rest are cancelled. Clones share the same tasks.
+#[derive(Clone, Default)]
+pub struct ConnectionTracker(Arc<Mutex<Tracked>>);
+
+impl ConnectionTracker {
+    pub fn spawn(&self, conn: impl Future<Output = ()> + Send + 'static) {
+        // Held until the task is in the map, so it can't remove itself before that
+        let mut tracked = self.0.lock();
+        let id = tracked.next_id;
+        tracked.next_id += 1;
+
+        let tracker = Arc::downgrade(&self.0);
+        let task = spawn(async move {
+            conn.await;
+            if let Some(tracker) = tracker.upgrade() {
+                if let Some(task) = tracker.lock().tasks.remove(&id) {
+                    task.detach();
+                }
+            }
+        });
+        tracked.tasks.insert(id, task);
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.0.lock().tasks.len()
+    }
+
+    // Waits up to `grace` for the connections to finish, then cancels the ones left.
+    // Returns how many had to be cancelled.
+    pub async fn drain(&self, grace: Duration) -> usize {
+        let deadline = Instant::now() + grace;
+        while self.active_connections() > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            Timer::after(DRAIN_POLL_INTERVAL.min(deadline - now)).await;
+        }
+
+        let remaining: Vec<_> = self.0.lock().tasks.drain().map(|(_, t)| t).collect();
+        let force_closed = remaining.len();
+        for task in remaining {
+            task.cancel().await;
+        }
+        force_closed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn drain_waits_then_cancels() {
+        smol::block_on(async move {
+            let tracker = ConnectionTracker::default();
+            let finished = Arc::new(AtomicBool::new(false));
+            tracker.spawn({
+                let finished = finished.clone();
+                async move {
+                    Timer::after(Duration::from_millis(100)).await;
+                    finished.store(true, Ordering::SeqCst);
+                }
+            });
+            assert_eq!(tracker.active_connections(), 1);
+
+            assert_eq!(tracker.drain(Duration::from_secs(5)).await, 0);
+            assert!(finished.load(Ordering::SeqCst));
+
+            tracker.spawn(futures::future::pending());
+            let started = Instant::now();
+            assert_eq!(tracker.drain(Duration::from_millis(100)).await, 1);
+            assert!(started.elapsed() < Duration::from_secs(1));
+            assert_eq!(tracker.active_connections(), 0);
+        });
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use futures::future::BoxFuture;
+use futures::{Future, FutureExt};
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+use serde_with::{DeserializeFromStr, SerializeDisplay};
+use smol_timeout::TimeoutExt;
+
+use crate::protocol::TrafficType;
+use crate::rule::{GroupSelection, RuleExecutionResult};
+use crate::socks5::Address;
+
+// The connection an external decider is asked about
+#[derive(Debug, Clone)]
+pub struct ExternalQuery {
+    pub target: Address<'static>,
+    pub src: Option<IpAddr>,
+    pub traffic_type: TrafficType,
+}
+
+// What an external decider answers with, in place of a rule's action. Written in config
+// as the rule actions are, e.g. `proxy:NAME` or `direct`.
+#[derive(Debug, Clone, PartialEq, Eq, SerializeDisplay, DeserializeFromStr)]
+pub enum ExternalOutcome {
+    Proxy(String),
+    ProxyGroup(String),
+    Direct,
+    Reject,
+    // Picks from all the upstreams, as when no rule matches
+    Default,
+}
+
+impl FromStr for ExternalOutcome {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((n, v)) if n.eq_ignore_ascii_case("proxy") => Ok(Self::Proxy(v.to_string())),
+            Some((n, v)) if n.eq_ignore_ascii_case("proxygroup") => {
+                Ok(Self::ProxyGroup(v.to_string()))
+            }
+            None if s.eq_ignore_ascii_case("direct") => Ok(Self::Direct),
+            None if s.eq_ignore_ascii_case("reject") => Ok(Self::Reject),
+            None if s.eq_ignore_ascii_case("default") => Ok(Self::Default),
+            _ => anyhow::bail!("Unknown external decision outcome {s}"),
+        }
+    }
+}
+
+impl Display for ExternalOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Proxy(name) => write!(f, "proxy:{name}"),
+            Self::ProxyGroup(name) => write!(f, "proxygroup:{name}"),
+            Self::Direct => f.write_str("direct"),
+            Self::Reject => f.write_str("reject"),
+            Self::Default => f.write_str("default"),
+        }
+    }
+}
+
+impl ExternalOutcome {
+    pub(crate) fn as_rule_result(&self) -> Option<RuleExecutionResult<'_>> {
+        match self {
+            Self::Proxy(name) => Some(RuleExecutionResult::Proxy(name)),
+            Self::ProxyGroup(name) => Some(RuleExecutionResult::ProxyGroup(
+                name,
+                GroupSelection::default(),
+            )),
+            Self::Direct => Some(RuleExecutionResult::Direct),
+            Self::Reject => Some(RuleExecutionResult::Reject),
+            Self::Default => None,
+        }
+    }
+}
+
+type Decider =
+    Arc<dyn Fn(ExternalQuery) -> BoxFuture<'static, anyhow::Result<ExternalOutcome>> + Send + Sync>;
+
+lazy_static! {
+    static ref DECIDERS: RwLock<HashMap<String, Decider>> = Default::default();
+}
+
+// Makes `decider` answer for the rules with the action `external:NAME`, e.g. by asking a
+// policy daemon, so the policy can change without reloading the rules. A decider already
+// registered under the name is replaced.
+pub fn register_external_decider<F, Fut>(name: &str, decider: F)
+where
+    F: Fn(ExternalQuery) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = anyhow::Result<ExternalOutcome>> + Send + 'static,
+{
+    DECIDERS
+        .write()
+        .insert(name.to_string(), Arc::new(move |q| decider(q).boxed()));
+}
+
+pub fn unregister_external_decider(name: &str) -> bool {
+    DECIDERS.write().remove(name).is_some()
+}
+
+// Asks the decider `name`, giving up on it after `timeout`. A decider that fails or times
+// out is answered for by `fallback` if there's one.
+pub(crate) async fn decide(
+    name: &str,
+    query: ExternalQuery,
+    timeout: Duration,
+    fallback: Option<&ExternalOutcome>,
+) -> anyhow::Result<ExternalOutcome> {
+    let decider = DECIDERS
+        .read()
+        .get(name)
+        .cloned()
+        .with_context(|| format!("No external decider named {name}"))?;
+    let outcome = decider(query)
+        .timeout(timeout)
+        .await
+        .unwrap_or_else(|| Err(anyhow::anyhow!("No answer after {timeout:?}")))
+        .with_context(|| format!("Asking external decider {name}"));
+
+    match (outcome, fallback) {
+        (Err(e), Some(fallback)) => {
+            log::warn!("{e:?}. Falling back to {fallback:?}");
+            Ok(fallback.clone())
+        }
+        (outcome, _) => outcome,
+    }
+}
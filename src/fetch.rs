@@ -1,14 +1,22 @@
-use std::{borrow::Cow, pin::Pin};
+use std::{
+    borrow::Cow,
+    future::Future,
+    io::ErrorKind,
+    pin::Pin,
+    time::{Duration, Instant},
+};
 
-use anyhow::Context;
-use async_native_tls::{TlsConnector, TlsStream};
+use anyhow::{bail, Context};
 use futures::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use parking_lot::Mutex;
+use smol::net::TcpStream;
 
 use crate::{
     buf::RWBuffer,
-    http::{AsyncHttpStream, HttpRequest, HttpResponse, WithHeaders},
+    http::{AsyncHttpStream, HttpRequest, HttpResponse, WithHeaders, ACCEPT_ENCODING},
     io::connect_tcp,
     socks5::Address,
+    tls::{connect_tls, is_tls_handshake_error, TlsOptions, TlsStream},
     url::HttpUrl,
 };
 
@@ -17,7 +25,7 @@ pub async fn send_http_with_proxy(
     address: &Address<'_>,
     mut req: HttpRequest<'_>,
     http_proxy: &Address<'_>,
-) -> anyhow::Result<impl AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static> {
+) -> anyhow::Result<TcpStream> {
     let mut client = connect_tcp(http_proxy)
         .await
         .with_context(|| format!("Connecting to proxy server: {http_proxy}"))?;
@@ -40,20 +48,126 @@ pub async fn connect_http_stream<T: AsyncRead + AsyncWrite + Unpin + Send + Sync
     tls: bool,
     address: &Address<'_>,
     client: T,
+    options: TlsOptions<'_>,
 ) -> anyhow::Result<HttpStream<T>> {
     let client = if tls {
-        HttpStream::SSL(
-            TlsConnector::new()
-                .connect(address.get_host().as_ref(), client)
-                .await
-                .context("TLS handshake")?,
-        )
+        let stream =
+            connect_tls(options.connector()?, &options.server_name(address), client).await?;
+        if options.alpn.is_some() {
+            log::debug!(
+                "Negotiated ALPN protocol with {address}: {:?}",
+                stream
+                    .negotiated_alpn()?
+                    .map(|p| String::from_utf8_lossy(&p).into_owned())
+            );
+        }
+        HttpStream::SSL(stream)
     } else {
         HttpStream::Plain(client)
     };
     Ok(client)
 }
 
+// How long what worked for an upstream is tried first, before going back to what's configured
+const TLS_MODE_TTL: Duration = Duration::from_secs(10 * 60);
+
+// Whether TLS last worked with each upstream, as found by `connect_with_tls_fallback`
+pub struct TlsModes<K>(Mutex<Vec<(K, bool, Instant)>>);
+
+impl<K> Default for TlsModes<K> {
+    fn default() -> Self {
+        Self(Default::default())
+    }
+}
+
+impl<K: PartialEq + Clone> TlsModes<K> {
+    pub fn get(&self, upstream: &K) -> Option<bool> {
+        let mut modes = self.0.lock();
+        modes.retain(|(_, _, since)| since.elapsed() < TLS_MODE_TTL);
+        modes
+            .iter()
+            .find(|(k, _, _)| k == upstream)
+            .map(|(_, tls, _)| *tls)
+    }
+
+    // Only a change restarts the clock, so the configured mode gets tried again in time
+    fn set(&self, upstream: &K, tls: bool) {
+        let mut modes = self.0.lock();
+        match modes.iter_mut().find(|(k, _, _)| k == upstream) {
+            Some((_, t, _)) if *t == tls => {}
+            Some(entry) => *entry = (upstream.clone(), tls, Instant::now()),
+            None => modes.push((upstream.clone(), tls, Instant::now())),
+        }
+    }
+}
+
+// Whether the failure says the server speaks the other protocol. A server expecting plain
+// fails the TLS handshake, and one expecting TLS answers plain requests with something that
+// isn't HTTP. Anything else, e.g. a refused connection, would fail either way.
+fn is_tls_mismatch(tried_tls: bool, e: &anyhow::Error) -> bool {
+    if tried_tls {
+        return is_tls_handshake_error(e);
+    }
+
+    e.chain().any(|e| {
+        e.is::<httparse::Error>()
+            || matches!(
+                e.downcast_ref::<std::io::Error>().map(std::io::Error::kind),
+                Some(
+                    ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::InvalidData
+                )
+            )
+    })
+}
+
+// Runs `attempt` with `tls` and, if `fallback` is set and it fails as if the server speaks
+// the other protocol, again with the opposite. What works is remembered in `modes` for
+// `upstream` and tried first for a while. Falling back to plain only happens when
+// `allow_plain` is set: anyone on the path can fail a TLS handshake, so with credentials
+// involved it would let them have the credentials in plain text.
+// Each attempt must make its own connection and send its initial data afresh: whatever a
+// failed attempt has read or buffered goes with its connection, so nothing from it can
+// leak into, or be missing from, the one that's kept.
+pub async fn connect_with_tls_fallback<K, R, Fut>(
+    (modes, upstream): (&TlsModes<K>, &K),
+    address: &Address<'_>,
+    tls: bool,
+    fallback: bool,
+    allow_plain: bool,
+    attempt: impl Fn(bool) -> Fut,
+) -> anyhow::Result<R>
+where
+    K: PartialEq + Clone,
+    Fut: Future<Output = anyhow::Result<R>>,
+{
+    if !fallback {
+        return attempt(tls).await;
+    }
+
+    let first = match modes.get(upstream) {
+        Some(false) if !allow_plain => true,
+        Some(v) => v,
+        None => tls,
+    };
+    let (tls, result) = match attempt(first).await {
+        Ok(v) => (first, v),
+        Err(e) if (!first || allow_plain) && is_tls_mismatch(first, &e) => {
+            log::info!(
+                "Connecting to {address} with tls = {first} failed, retrying with tls = {}: {e:?}",
+                !first
+            );
+            let v = attempt(!first)
+                .await
+                .with_context(|| format!("Connecting to {address} with tls = {}", !first))?;
+            (!first, v)
+        }
+        Err(e) => return Err(e),
+    };
+
+    modes.set(upstream, tls);
+    Ok(result)
+}
+
 impl<T: AsyncRead + AsyncWrite + Unpin> AsyncRead for HttpStream<T> {
     fn poll_read(
         self: std::pin::Pin<&mut Self>,
@@ -138,9 +252,7 @@ pub async fn fetch_http_with_proxy<'a, 'b>(
     headers: impl Iterator<Item = (Cow<'b, str>, Cow<'b, [u8]>)> + Send + Sync + 'b,
     http_proxy: &Address<'_>,
     body: Option<(&str, &[u8])>,
-) -> anyhow::Result<
-    AsyncHttpStream<HttpResponse<'static>, impl AsyncRead + AsyncWrite + Unpin + Send + Sync + 'a>,
-> {
+) -> anyhow::Result<AsyncHttpStream<HttpResponse<'static>, TcpStream>> {
     let mut headers = headers.collect::<Vec<_>>();
 
     let body = if let Some((content_type, body)) = body {
@@ -175,6 +287,12 @@ pub async fn fetch_http_with_proxy<'a, 'b>(
             Cow::Owned(address.to_string().into_bytes()),
         ));
     }
+    if request.get_header("accept-encoding").is_none() {
+        request.headers.push((
+            Cow::Borrowed("Accept-Encoding"),
+            Cow::Borrowed(ACCEPT_ENCODING.as_bytes()),
+        ));
+    }
 
     let mut client = send_http_with_proxy(is_https, &address, request, http_proxy).await?;
 
@@ -184,3 +302,74 @@ pub async fn fetch_http_with_proxy<'a, 'b>(
 
     super::http::parse_response(client, RWBuffer::new_vec_uninitialised(512)).await
 }
+
+// How many redirects `fetch_http_following_redirects` follows if not told otherwise
+pub const DEFAULT_MAX_REDIRECTS: usize = 5;
+
+// Headers that make a request conditional on what the client has of the original URL,
+// which mean nothing to where it's redirected
+const CONDITIONAL_HEADERS: [&str; 2] = ["If-Modified-Since", "If-None-Match"];
+
+// Like `fetch_http_with_proxy`, but follows up to `max_redirects` redirects. The method
+// and body are kept through 307 and 308, while 303 and, for POSTs, 301 and 302 turn the
+// request into a GET. Redirects from https to http aren't followed.
+pub async fn fetch_http_following_redirects(
+    url: &str,
+    method: &str,
+    headers: Vec<(Cow<'static, str>, Cow<'static, [u8]>)>,
+    http_proxy: &Address<'_>,
+    mut body: Option<(&str, &[u8])>,
+    max_redirects: usize,
+) -> anyhow::Result<AsyncHttpStream<HttpResponse<'static>, TcpStream>> {
+    let mut url = HttpUrl::try_from(url)?.to_owned();
+    let mut method = method.to_string();
+    let mut visited = vec![url.to_string()];
+
+    loop {
+        let first = visited.len() == 1;
+        let hop_headers = headers
+            .iter()
+            .filter(|(name, _)| {
+                first
+                    || !CONDITIONAL_HEADERS
+                        .iter()
+                        .any(|h| name.eq_ignore_ascii_case(h))
+            })
+            .cloned();
+
+        let res =
+            fetch_http_with_proxy(&url.to_string(), &method, hop_headers, http_proxy, body).await?;
+
+        if !matches!(res.status_code, 301 | 302 | 303 | 307 | 308) {
+            return Ok(res);
+        }
+
+        let location = res
+            .get_header_text("Location")
+            .with_context(|| format!("Redirect from {url} without a Location"))?;
+        let next = url
+            .join(location)
+            .with_context(|| format!("Invalid redirect from {url} to {location}"))?;
+        if url.is_https && !next.is_https {
+            bail!("Refusing to redirect from {url} to {next}");
+        }
+        if visited.len() > max_redirects {
+            bail!("Too many redirects fetching {}", visited[0]);
+        }
+        if visited.contains(&next.to_string()) {
+            bail!("Redirect loop fetching {}: {next} again", visited[0]);
+        }
+
+        let status_code = res.status_code;
+        if status_code == 303 && method != "HEAD"
+            || matches!(status_code, 301 | 302) && method == "POST"
+        {
+            method = String::from("GET");
+            body = None;
+        }
+
+        log::debug!("Following redirect ({status_code}) from {url} to {next}");
+        visited.push(next.to_string());
+        url = next;
+    }
+}
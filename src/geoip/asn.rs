@@ -0,0 +1,163 @@
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use anyhow::bail;
+use bytes::Buf;
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+
+// Record structure, all big endian:
+// |start(u32)|end(u32)|asn(u32)|
+const RECORD_LEN: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct AsnRecord {
+    start: u32,
+    end: u32,
+    asn: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct AsnDatabase {
+    records_v4: Vec<AsnRecord>,
+}
+
+impl AsnDatabase {
+    pub fn parse(mut raw: &[u8]) -> anyhow::Result<Self> {
+        if !raw.len().is_multiple_of(RECORD_LEN) {
+            bail!("Invalid ASN data length: {}", raw.len());
+        }
+
+        let mut records_v4 = Vec::with_capacity(raw.len() / RECORD_LEN);
+        while raw.has_remaining() {
+            records_v4.push(AsnRecord {
+                start: raw.get_u32(),
+                end: raw.get_u32(),
+                asn: raw.get_u32(),
+            });
+        }
+
+        // Lookups binary search by start, so don't rely on the file being sorted
+        records_v4.sort_unstable_by_key(|r| r.start);
+        records_v4.dedup();
+
+        Ok(Self { records_v4 })
+    }
+
+    pub fn from_file(p: &Path) -> anyhow::Result<Self> {
+        Self::parse(&std::fs::read(p)?)
+    }
+
+    pub fn find(&self, ip: &IpAddr) -> Option<u32> {
+        match ip.to_canonical() {
+            IpAddr::V4(addr) => {
+                let needle = u32::from(addr);
+                match self.records_v4.binary_search_by_key(&needle, |r| r.start) {
+                    Ok(index) => Some(self.records_v4[index].asn),
+                    Err(index)
+                        if index > 0
+                            && needle >= self.records_v4[index - 1].start
+                            && needle <= self.records_v4[index - 1].end =>
+                    {
+                        Some(self.records_v4[index - 1].asn)
+                    }
+                    _ => None,
+                }
+            }
+            IpAddr::V6(_) => None,
+        }
+    }
+}
+
+fn asn_file_path() -> Option<PathBuf> {
+    dirs::data_dir().map(|mut r| {
+        r.push("cjk_proxy");
+        r.push("geoip");
+        r.push("asn_ipv4.dat");
+        r
+    })
+}
+
+fn asn_database() -> &'static RwLock<AsnDatabase> {
+    lazy_static! {
+        static ref DATABASE: RwLock<AsnDatabase> = RwLock::new(
+            match asn_file_path().map(|p| (AsnDatabase::from_file(&p), p)) {
+                Some((Ok(db), _)) => db,
+                Some((Err(e), p)) => {
+                    log::warn!("Error loading ASN data from {p:?}: {e:#}");
+                    Default::default()
+                }
+                None => Default::default(),
+            }
+        );
+    }
+    &DATABASE
+}
+
+#[cfg(test)]
+pub fn set_asn_database(db: AsnDatabase) {
+    *asn_database().write() = db;
+}
+
+// Whether any ASN data was loaded, without which `asn:` rules never match
+pub fn has_asn_data() -> bool {
+    !asn_database().read().records_v4.is_empty()
+}
+
+pub fn find_asn(ip: &IpAddr) -> Option<u32> {
+    asn_database().read().find(ip)
+}
+
+#[cfg(test)]
+fn encode_records(records: &[(&str, &str, u32)]) -> Vec<u8> {
+    let mut raw = Vec::new();
+    for (start, end, asn) in records {
+        raw.extend_from_slice(&start.parse::<std::net::Ipv4Addr>().unwrap().octets());
+        raw.extend_from_slice(&end.parse::<std::net::Ipv4Addr>().unwrap().octets());
+        raw.extend_from_slice(&asn.to_be_bytes());
+    }
+    raw
+}
+
+#[cfg(test)]
+pub fn test_asn_database() -> AsnDatabase {
+    AsnDatabase::parse(&encode_records(&[
+        ("1.1.1.0", "1.1.1.255", 13335),
+        ("8.8.8.0", "8.8.8.255", 15169),
+    ]))
+    .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_find_asn() {
+        let db = test_asn_database();
+        assert_eq!(db.find(&"1.1.1.1".parse().unwrap()), Some(13335));
+        assert_eq!(db.find(&"8.8.8.0".parse().unwrap()), Some(15169));
+        assert_eq!(db.find(&"8.8.9.1".parse().unwrap()), None);
+        assert_eq!(db.find(&"2001:4860:4860::8888".parse().unwrap()), None);
+
+        assert!(AsnDatabase::parse(&[0u8; 5]).is_err());
+    }
+
+    #[test]
+    fn unsorted_records_are_found() {
+        let db = AsnDatabase::parse(&encode_records(&[
+            ("9.9.9.0", "9.9.9.255", 19281),
+            ("1.1.1.0", "1.1.1.255", 13335),
+            ("8.8.8.0", "8.8.8.255", 15169),
+            ("1.1.1.0", "1.1.1.255", 13335),
+            ("4.4.4.0", "4.4.4.255", 3356),
+        ]))
+        .unwrap();
+        assert_eq!(db.records_v4.len(), 4);
+        assert_eq!(db.find(&"1.1.1.1".parse().unwrap()), Some(13335));
+        assert_eq!(db.find(&"4.4.4.4".parse().unwrap()), Some(3356));
+        assert_eq!(db.find(&"8.8.8.8".parse().unwrap()), Some(15169));
+        assert_eq!(db.find(&"9.9.9.9".parse().unwrap()), Some(19281));
+        assert_eq!(db.find(&"5.5.5.5".parse().unwrap()), None);
+    }
+}
@@ -1,11 +1,16 @@
+mod asn;
 mod country_code;
 
+pub use asn::*;
 pub use country_code::CountryCode;
+use std::collections::BTreeMap;
 use std::mem::size_of;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr};
 
-use bytes::Buf;
+use anyhow::bail;
 use lazy_static::lazy_static;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
 use std::slice::from_raw_parts;
 
 #[repr(C)]
@@ -15,35 +20,188 @@ struct Record<const N: usize> {
     c: CountryCode,
 }
 
-fn load_ip_dat<const N: usize>(raw: &[u8]) -> &'static [Record<N>] {
+impl Record<4> {
+    fn range(&self) -> (u32, u32) {
+        (u32::from_be_bytes(self.start), u32::from_be_bytes(self.end))
+    }
+}
+
+fn load_ip_dat<const N: usize>(raw: &[u8]) -> &[Record<N>] {
     let len = raw.len() / size_of::<Record<N>>();
     unsafe { from_raw_parts(raw.as_ptr() as *const Record<N>, len) }
 }
 
-pub fn find_geoip(ip: &IpAddr) -> Option<CountryCode> {
-    match ip {
-        IpAddr::V4(addr) => {
-            let needle = addr.octets().as_slice().get_u32();
-            lazy_static! {
-                static ref RECORDS_V4: &'static [Record<4>] =
-                    load_ip_dat(include_bytes!("ipv4.dat"));
+// Which range an address belongs to when the ranges listing it overlap
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverlapPolicy {
+    // The range listed first
+    FirstWins,
+    // The range listed last, so a more specific range following a broader one wins
+    #[default]
+    LastWins,
+    // Overlapping ranges are rejected when the data is loaded
+    Error,
+}
+
+// The range in `ranges` (sorted by start, not overlapping) containing `needle`
+fn search<T>(ranges: &[T], needle: u32, range: impl Fn(&T) -> (u32, u32)) -> Option<&T> {
+    let index = ranges.partition_point(|r| range(r).0 <= needle);
+    ranges[..index].last().filter(|r| range(r).1 >= needle)
+}
+
+// Splits a run of overlapping records into ranges that don't overlap, with each address
+// going to the record the policy prefers
+fn resolve_overlaps(run: &[Record<4>], policy: OverlapPolicy) -> Vec<(u32, u32, CountryCode)> {
+    let mut ordered: Vec<_> = run.iter().collect();
+    if policy == OverlapPolicy::LastWins {
+        ordered.reverse();
+    }
+
+    // Preferred records take their ranges first, the rest only fill the gaps left
+    let mut taken = BTreeMap::<u32, (u32, CountryCode)>::new();
+    for r in ordered {
+        let (start, end) = r.range();
+        let mut gaps = Vec::new();
+        let mut cursor = start as u64;
+        for (s, (e, _)) in taken.range(..=end).filter(|(_, (e, _))| *e >= start) {
+            if *s as u64 > cursor {
+                gaps.push((cursor as u32, s - 1));
+            }
+            cursor = cursor.max(*e as u64 + 1);
+        }
+        if cursor <= end as u64 {
+            gaps.push((cursor as u32, end));
+        }
+
+        for (s, e) in gaps {
+            taken.insert(s, (e, r.c));
+        }
+    }
+
+    taken.into_iter().map(|(s, (e, c))| (s, e, c)).collect()
+}
+
+struct GeoIpRanges<'a> {
+    records: &'a [Record<4>],
+    policy: OverlapPolicy,
+    // Where runs of records overlap, and what they were resolved to
+    overlap_spans: Vec<(u32, u32)>,
+    resolved: Vec<(u32, u32, CountryCode)>,
+}
+
+impl<'a> GeoIpRanges<'a> {
+    // The records must be sorted by their start
+    fn load(raw: &'a [u8], policy: OverlapPolicy) -> anyhow::Result<Self> {
+        let records = load_ip_dat::<4>(raw);
+        let mut overlap_spans = Vec::new();
+        let mut resolved = Vec::new();
+
+        let mut i = 0;
+        while i < records.len() {
+            let (start, mut end) = records[i].range();
+            let mut j = i + 1;
+            while j < records.len() && records[j].range().0 <= end {
+                if records[j].range().0 < records[j - 1].range().0 {
+                    bail!(
+                        "GeoIP ranges aren't sorted at {}",
+                        Ipv4Addr::from(records[j].range().0)
+                    );
+                }
+                end = end.max(records[j].range().1);
+                j += 1;
             }
-            match RECORDS_V4.binary_search_by_key(&needle, |r| u32::from_be_bytes(r.start)) {
-                Ok(index) => Some(RECORDS_V4[index].c),
-                Err(index)
-                    if index > 0
-                        && needle >= u32::from_be_bytes(RECORDS_V4[index - 1].start)
-                        && needle <= u32::from_be_bytes(RECORDS_V4[index - 1].end) =>
-                {
-                    Some(RECORDS_V4[index - 1].c)
+
+            if j - i > 1 {
+                if policy == OverlapPolicy::Error {
+                    bail!(
+                        "GeoIP ranges overlap between {} and {}",
+                        Ipv4Addr::from(start),
+                        Ipv4Addr::from(end)
+                    );
                 }
-                _ => None,
+                overlap_spans.push((start, end));
+                resolved.extend(resolve_overlaps(&records[i..j], policy));
             }
+            i = j;
+        }
+
+        if !overlap_spans.is_empty() {
+            log::debug!(
+                "Resolved {} runs of overlapping GeoIP ranges ({policy:?})",
+                overlap_spans.len()
+            );
+        }
+
+        Ok(Self {
+            records,
+            policy,
+            overlap_spans,
+            resolved,
+        })
+    }
+
+    fn is_overlapping(&self, addr: u32) -> bool {
+        search(&self.overlap_spans, addr, |s| *s).is_some()
+    }
+
+    fn find(&self, needle: u32) -> Option<CountryCode> {
+        if self.is_overlapping(needle) {
+            return search(&self.resolved, needle, |r| (r.0, r.1)).map(|r| r.2);
         }
+        search(self.records, needle, Record::range).map(|r| r.c)
+    }
+
+    fn ranges(&self, c: CountryCode) -> Vec<(u32, u32)> {
+        let mut ranges: Vec<_> = self
+            .records
+            .iter()
+            .filter(|r| r.c == c && !self.is_overlapping(r.range().0))
+            .map(Record::range)
+            .chain(
+                self.resolved
+                    .iter()
+                    .filter(|r| r.2 == c)
+                    .map(|(s, e, _)| (*s, *e)),
+            )
+            .collect();
+        ranges.sort_unstable();
+        ranges
+    }
+}
+
+const IPV4_DAT: &[u8] = include_bytes!("ipv4.dat");
+
+lazy_static! {
+    static ref RANGES_V4: RwLock<GeoIpRanges<'static>> = RwLock::new(
+        GeoIpRanges::load(IPV4_DAT, Default::default()).expect("Embedded GeoIP data to load")
+    );
+}
+
+// Reloads the GeoIP data if the policy has changed. The data loaded before is kept if
+// it can't be loaded with the new policy.
+pub fn set_geoip_overlap_policy(policy: OverlapPolicy) -> anyhow::Result<()> {
+    if RANGES_V4.read().policy == policy {
+        return Ok(());
+    }
+
+    *RANGES_V4.write() = GeoIpRanges::load(IPV4_DAT, policy)?;
+    Ok(())
+}
+
+pub fn find_geoip(ip: &IpAddr) -> Option<CountryCode> {
+    // IPv4-mapped IPv6 addresses are looked up as their IPv4 form
+    match ip.to_canonical() {
+        IpAddr::V4(addr) => RANGES_V4.read().find(addr.into()),
         IpAddr::V6(_) => None,
     }
 }
 
+// The inclusive IPv4 ranges assigned to the country, in ascending order
+pub fn geoip_ranges_v4(c: CountryCode) -> impl Iterator<Item = (u32, u32)> {
+    RANGES_V4.read().ranges(c).into_iter()
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -62,5 +220,64 @@ mod test {
             Some("NZ".parse().unwrap())
         );
         assert_eq!(find_geoip(&"2001:4860:4860::8888".parse().unwrap()), None);
+        assert_eq!(
+            find_geoip(&"::ffff:219.159.81.138".parse().unwrap()),
+            Some("cn".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn ranges_match_lookups() {
+        let nz: CountryCode = "NZ".parse().unwrap();
+        let ranges: Vec<_> = geoip_ranges_v4(nz).collect();
+        assert!(!ranges.is_empty());
+        assert!(ranges.windows(2).all(|w| w[0].1 < w[1].0));
+
+        let needle = u32::from("122.61.248.102".parse::<std::net::Ipv4Addr>().unwrap());
+        assert!(ranges
+            .iter()
+            .any(|(start, end)| (*start..=*end).contains(&needle)));
+    }
+
+    #[test]
+    fn overlap_policy_decides_lookups() {
+        let mut raw = Vec::new();
+        for (start, end, c) in [
+            ("10.0.0.0", "10.0.3.255", "FR"),
+            ("10.0.1.0", "10.0.1.255", "CH"),
+            ("10.0.2.0", "10.0.5.255", "NO"),
+            ("10.1.0.0", "10.1.0.255", "NZ"),
+        ] {
+            raw.extend_from_slice(&start.parse::<Ipv4Addr>().unwrap().octets());
+            raw.extend_from_slice(&end.parse::<Ipv4Addr>().unwrap().octets());
+            raw.extend_from_slice(c.as_bytes());
+        }
+        let find = |ranges: &GeoIpRanges, ip: &str| {
+            ranges
+                .find(ip.parse::<Ipv4Addr>().unwrap().into())
+                .map(|c| c.to_string())
+        };
+
+        let first = GeoIpRanges::load(&raw, OverlapPolicy::FirstWins).unwrap();
+        assert_eq!(find(&first, "10.0.1.1").as_deref(), Some("FR"));
+        assert_eq!(find(&first, "10.0.3.1").as_deref(), Some("FR"));
+        assert_eq!(find(&first, "10.0.4.1").as_deref(), Some("NO"));
+
+        let last = GeoIpRanges::load(&raw, OverlapPolicy::LastWins).unwrap();
+        assert_eq!(find(&last, "10.0.0.1").as_deref(), Some("FR"));
+        assert_eq!(find(&last, "10.0.1.1").as_deref(), Some("CH"));
+        assert_eq!(find(&last, "10.0.3.1").as_deref(), Some("NO"));
+
+        for ranges in [&first, &last] {
+            assert_eq!(find(ranges, "10.1.0.1").as_deref(), Some("NZ"));
+            assert_eq!(find(ranges, "10.0.6.1"), None);
+        }
+        let nz: CountryCode = "NZ".parse().unwrap();
+        assert_eq!(last.ranges(nz), vec![(0x0a010000, 0x0a0100ff)]);
+        let fr: CountryCode = "FR".parse().unwrap();
+        assert_eq!(last.ranges(fr), vec![(0x0a000000, 0x0a0000ff)]);
+
+        assert!(GeoIpRanges::load(&raw, OverlapPolicy::Error).is_err());
+        assert!(GeoIpRanges::load(&raw[30..], OverlapPolicy::Error).is_ok());
     }
 }
@@ -61,6 +61,10 @@ pub enum HandshakeRequest<'a> {
     UDP {
         dst: Option<Address<'a>>,
     },
+    // SOCKS5 only, `dst` is the peer expected to connect back
+    Bind {
+        dst: Address<'a>,
+    },
     HTTP {
         dst: Address<'a>,
         https: bool,
@@ -199,14 +203,36 @@ impl Handshaker {
         }
     }
 
+    // The first of a BIND's two replies, telling the client where its peer should connect.
+    // The second one is `respond_ok` with the peer's address.
+    pub async fn respond_bind_listening(
+        &self,
+        stream: &mut (impl AsyncWrite + Send + Sync + Unpin),
+        listening_address: SocketAddr,
+    ) -> anyhow::Result<()> {
+        match self.0 {
+            HandshakeType::Socks5 => {
+                ClientConnRequest::respond(
+                    stream,
+                    ConnStatusCode::GRANTED,
+                    &listening_address.into(),
+                )
+                .await
+            }
+            _ => bail!("BIND is only supported by SOCKS5"),
+        }
+    }
+
     pub async fn respond_err(
         self,
         stream: &mut (impl AsyncWrite + Unpin + Send + Sync),
+        err: &anyhow::Error,
     ) -> anyhow::Result<()> {
         match self.0 {
             HandshakeType::Socks5 => {
-                ClientConnRequest::respond(stream, ConnStatusCode::FAILED, &Default::default())
-                    .await
+                let code = ConnStatusCode::from_error(err);
+                log::debug!("Replying SOCKS5 status {code} for error: {err:#}");
+                ClientConnRequest::respond(stream, code, &Default::default()).await
             }
             HandshakeType::Socks4 => {
                 respond_socks4(
@@ -217,9 +243,11 @@ impl Handshaker {
                 .await
             }
             HandshakeType::Http | HandshakeType::HttpTcpChannel => {
-                stream
-                    .write_all(b"HTTP/1.1 500 Internal server error\r\n\r\n")
-                    .await?;
+                let response: &[u8] = match ConnStatusCode::from_error(err) {
+                    ConnStatusCode::NOT_ALLOWED => b"HTTP/1.1 403 Forbidden\r\n\r\n",
+                    _ => b"HTTP/1.1 500 Internal server error\r\n\r\n",
+                };
+                stream.write_all(response).await?;
                 Ok(())
             }
         }
@@ -251,14 +279,16 @@ fn handshake_http(r: HttpRequest<'static>) -> anyhow::Result<HandshakeRequest> {
                 address,
                 path,
             } = HttpUrl::try_from(path.as_ref()).context("Parsing HTTP request path")?;
+            let mut req = HttpRequest {
+                headers,
+                method,
+                path: Cow::Owned(path.to_string()),
+            };
+            req.strip_hop_by_hop_headers();
             Ok(HandshakeRequest::HTTP {
                 dst: address.into_owned(),
                 https: is_https,
-                req: HttpRequest {
-                    headers,
-                    method,
-                    path: Cow::Owned(path.to_string()),
-                },
+                req,
             })
         }
     }
@@ -294,6 +324,11 @@ async fn handshake_socks5(
                     buf.advance_read(offset);
                     return Ok(HandshakeRequest::UDP { dst });
                 }
+                Command::BIND_TCP => {
+                    let dst = address.into_owned();
+                    buf.advance_read(offset);
+                    return Ok(HandshakeRequest::Bind { dst });
+                }
                 _ => {
                     ClientConnRequest::respond(
                         socket,
@@ -312,3 +347,62 @@ async fn handshake_socks5(
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::duplex;
+    use async_io::Timer;
+    use smol::spawn;
+    use std::time::Duration;
+
+    #[test]
+    fn socks5_handshake_dribbled_byte_by_byte() {
+        smol::block_on(async move {
+            let (mut client, mut server) = duplex(0).await;
+
+            let client_task = spawn(async move {
+                // Greeting, then CONNECT to 1.2.3.4:80
+                let bytes = [
+                    0x05, 0x01, 0x00, 0x05, 0x01, 0x00, 0x01, 1, 2, 3, 4, 0x00, 0x50,
+                ];
+                for b in bytes {
+                    client.write_all(&[b]).await.unwrap();
+                    client.flush().await.unwrap();
+                    Timer::after(Duration::from_millis(5)).await;
+                }
+
+                let mut reply = [0u8; 2];
+                client.read_exact(&mut reply).await.unwrap();
+                reply
+            });
+
+            // Start tiny so the buffer has to grow as the bytes come in
+            let mut buf = RWBuffer::new_vec_uninitialised(1);
+            let (_, req) = Handshaker::start(&mut server, &mut buf).await.unwrap();
+            match req {
+                HandshakeRequest::TCP { dst } => {
+                    assert_eq!(dst, "1.2.3.4:80".parse::<Address>().unwrap())
+                }
+                r => panic!("Unexpected request: {r:?}"),
+            }
+            assert_eq!(client_task.await, [0x05, AUTH_NO_PASSWORD]);
+        });
+    }
+
+    #[test]
+    fn socks5_handshake_fails_on_eof_mid_greeting() {
+        smol::block_on(async move {
+            let (mut client, mut server) = duplex(0).await;
+            client.write_all(&[0x05, 0x02]).await.unwrap();
+            drop(client);
+
+            let mut buf = RWBuffer::new_vec_uninitialised(512);
+            let err = Handshaker::start(&mut server, &mut buf)
+                .await
+                .err()
+                .expect("To fail");
+            assert!(err.to_string().contains("Unexpected EOF"));
+        });
+    }
+}
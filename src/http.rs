@@ -6,12 +6,24 @@ use std::{
 };
 
 use anyhow::{anyhow, bail, Context};
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
 use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use pin_project_lite::pin_project;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use crate::buf::RWBuffer;
 
+// Headers that only apply to the connection they arrive on, never passed on by a proxy
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "proxy-connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+];
+
 pub trait WithHeaders {
     fn headers(&self) -> &Vec<(Cow<str>, Cow<[u8]>)>;
 
@@ -140,6 +152,32 @@ impl<'a> HttpRequest<'a> {
         }
     }
 
+    // Removes the headers meant for the proxy, along with those the client listed in
+    // `Connection`. An upgrade is left in place, as the connection is relayed as it is
+    // once the request has been forwarded.
+    pub fn strip_hop_by_hop_headers(&mut self) {
+        let listed: Vec<String> = self
+            .headers
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case("connection"))
+            .flat_map(|(_, v)| {
+                String::from_utf8_lossy(v)
+                    .split(',')
+                    .map(|h| h.trim().to_ascii_lowercase())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        let upgrading = listed.iter().any(|h| h == "upgrade");
+
+        self.headers.retain(|(k, _)| {
+            let name = k.to_ascii_lowercase();
+            if upgrading && (name == "connection" || name == "upgrade") {
+                return true;
+            }
+            !HOP_BY_HOP_HEADERS.contains(&name.as_str()) && !listed.contains(&name)
+        });
+    }
+
     pub fn to_builder(&self) -> HttpRequestBuilder {
         let mut builder = HttpRequestBuilder::new(&self.method, &self.path).unwrap();
         for (k, v) in &self.headers {
@@ -176,8 +214,45 @@ impl<I: Debug, T> Debug for AsyncHttpStream<I, T> {
     }
 }
 
+// The content codings `fetch_http_with_proxy` asks for, all of which `body` undoes
+pub const ACCEPT_ENCODING: &str = "gzip, deflate, br";
+
+// Undoes a Content-Encoding, which lists the codings in the order they were applied
+fn decode_content(encoding: &str, mut body: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    use std::io::Read;
+
+    for coding in encoding.rsplit(',').map(str::trim) {
+        let mut decoded = Vec::new();
+        match coding.to_ascii_lowercase().as_str() {
+            "" | "identity" => continue,
+            "gzip" | "x-gzip" => GzDecoder::new(body.as_slice()).read_to_end(&mut decoded),
+            // Meant to be zlib wrapped, but some servers send it raw
+            "deflate" => ZlibDecoder::new(body.as_slice())
+                .read_to_end(&mut decoded)
+                .or_else(|_| {
+                    decoded.clear();
+                    DeflateDecoder::new(body.as_slice()).read_to_end(&mut decoded)
+                }),
+            "br" => brotli::Decompressor::new(body.as_slice(), 4096).read_to_end(&mut decoded),
+            c => bail!("Unsupported content encoding: {c}"),
+        }
+        .with_context(|| format!("Decoding {coding} body"))?;
+        body = decoded;
+    }
+    Ok(body)
+}
+
 impl<I: WithHeaders, T: AsyncRead + Unpin + Send + Sync> AsyncHttpStream<I, T> {
+    // The body with its transfer and content encodings undone
     pub async fn body(&mut self) -> anyhow::Result<Vec<u8>> {
+        let body = self.raw_body().await?;
+        match self.init.get_header_text("content-encoding") {
+            Some(encoding) => decode_content(encoding, body),
+            None => Ok(body),
+        }
+    }
+
+    async fn raw_body(&mut self) -> anyhow::Result<Vec<u8>> {
         let content_len = self.init.get_content_length();
         let transfer_encoding = self.init.get_header_text("transfer-encoding");
         match (content_len, transfer_encoding) {
@@ -302,10 +377,16 @@ pub async fn parse_response<T: AsyncRead + Unpin + Send + Sync>(
 ) -> anyhow::Result<AsyncHttpStream<HttpResponse<'static>, T>> {
     loop {
         match stream.read(buf.write_buf()).await? {
-            0 => bail!(
-                "Unexpected EOF while parsing HTTP response: write_buf_len = {}",
-                buf.remaining_write()
-            ),
+            0 => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!(
+                        "Unexpected EOF while parsing HTTP response: write_buf_len = {}",
+                        buf.remaining_write()
+                    ),
+                )
+                .into())
+            }
             v => buf.advance_write(v),
         }
 
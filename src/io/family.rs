@@ -0,0 +1,195 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+// Which address family is tried first when a name resolves to both
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressFamilyPreference {
+    // Keeps the order the resolver gave
+    #[default]
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+// Whether the host can reach the internet over each family
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Connectivity {
+    pub ipv4: bool,
+    pub ipv6: bool,
+}
+
+// How long a probe's result is used before the routes are checked again
+const CONNECTIVITY_TTL: Duration = Duration::from_secs(30);
+
+// Public resolvers, only used to look up the route to them. Nothing is sent.
+const PROBE_V4: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)), 53);
+const PROBE_V6: SocketAddr = SocketAddr::new(
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888)),
+    53,
+);
+
+lazy_static! {
+    static ref CONNECTIVITY: Mutex<Option<(Instant, Connectivity)>> = Default::default();
+}
+
+impl Connectivity {
+    pub fn current() -> Self {
+        let mut cached = CONNECTIVITY.lock();
+        match *cached {
+            Some((probed, connectivity)) if probed.elapsed() < CONNECTIVITY_TTL => connectivity,
+            _ => {
+                let connectivity = Self::probe();
+                cached.replace((Instant::now(), connectivity));
+                connectivity
+            }
+        }
+    }
+
+    fn probe() -> Self {
+        Self {
+            ipv4: has_route_to(PROBE_V4),
+            ipv6: has_route_to(PROBE_V6),
+        }
+    }
+
+    fn reaches(&self, ip: &IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(_) => self.ipv4,
+            IpAddr::V6(_) => self.ipv6,
+        }
+    }
+}
+
+// Connecting a UDP socket only picks the route and the source address. A host without
+// IPv6 connectivity usually has no route at all, or only a link-local address to send from.
+fn has_route_to(addr: SocketAddr) -> bool {
+    let local = match addr {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let Ok(socket) = UdpSocket::bind(local) else {
+        return false;
+    };
+    if socket.connect(addr).is_err() {
+        return false;
+    }
+    match socket.local_addr().map(|a| a.ip()) {
+        Ok(IpAddr::V4(ip)) => !ip.is_unspecified() && !ip.is_loopback() && !ip.is_link_local(),
+        Ok(IpAddr::V6(ip)) => {
+            !ip.is_unspecified() && !ip.is_loopback() && !ip.is_unicast_link_local()
+        }
+        Err(_) => false,
+    }
+}
+
+// Addresses on the host or its networks are reachable whatever the internet connectivity
+fn is_local(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unique_local() || ip.is_unicast_link_local(),
+    }
+}
+
+// Puts the preferred family first, keeping the order within each family, and leaves out
+// internet addresses of a family the host can't reach the internet over. Nothing is left
+// out if that would leave no address at all.
+pub fn order_by_family(
+    addrs: impl IntoIterator<Item = SocketAddr>,
+    preference: AddressFamilyPreference,
+    connectivity: &Connectivity,
+) -> Vec<SocketAddr> {
+    let mut addrs: Vec<_> = addrs.into_iter().collect();
+    match preference {
+        AddressFamilyPreference::Any => {}
+        AddressFamilyPreference::Ipv4 => addrs.sort_by_key(SocketAddr::is_ipv6),
+        AddressFamilyPreference::Ipv6 => addrs.sort_by_key(SocketAddr::is_ipv4),
+    }
+
+    let reachable: Vec<_> = addrs
+        .iter()
+        .filter(|a| is_local(&a.ip()) || connectivity.reaches(&a.ip()))
+        .copied()
+        .collect();
+    if reachable.is_empty() {
+        addrs
+    } else {
+        reachable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::StaleCache;
+
+    #[test]
+    fn orders_by_family_preference() {
+        smol::block_on(async move {
+            // Answers with a mix of both families
+            let resolved = StaleCache::new()
+                .resolve_with("example.com", 443, |_, port| async move {
+                    Ok(["2001:db8::1", "192.0.2.1", "2001:db8::2", "192.0.2.2"]
+                        .into_iter()
+                        .map(|ip| SocketAddr::new(ip.parse().unwrap(), port))
+                        .collect())
+                })
+                .await
+                .unwrap();
+            let addrs = |ips: &[&str]| -> Vec<SocketAddr> {
+                ips.iter()
+                    .map(|ip| SocketAddr::new(ip.parse().unwrap(), 443))
+                    .collect()
+            };
+            let both = Connectivity {
+                ipv4: true,
+                ipv6: true,
+            };
+
+            assert_eq!(
+                order_by_family(resolved.clone(), AddressFamilyPreference::Any, &both),
+                resolved
+            );
+            assert_eq!(
+                order_by_family(resolved.clone(), AddressFamilyPreference::Ipv4, &both),
+                addrs(&["192.0.2.1", "192.0.2.2", "2001:db8::1", "2001:db8::2"])
+            );
+            assert_eq!(
+                order_by_family(resolved.clone(), AddressFamilyPreference::Ipv6, &both),
+                addrs(&["2001:db8::1", "2001:db8::2", "192.0.2.1", "192.0.2.2"])
+            );
+
+            // IPv6 is left out when it goes nowhere, even when it's preferred
+            let v4_only = Connectivity {
+                ipv4: true,
+                ipv6: false,
+            };
+            assert_eq!(
+                order_by_family(resolved.clone(), AddressFamilyPreference::Ipv6, &v4_only),
+                addrs(&["192.0.2.1", "192.0.2.2"])
+            );
+
+            // Unless nothing would be left, or the address is a local one
+            assert_eq!(
+                order_by_family(
+                    addrs(&["2001:db8::1"]),
+                    AddressFamilyPreference::Any,
+                    &v4_only
+                ),
+                addrs(&["2001:db8::1"])
+            );
+            assert_eq!(
+                order_by_family(
+                    addrs(&["::1", "fd00::1", "192.0.2.1"]),
+                    AddressFamilyPreference::Any,
+                    &v4_only
+                ),
+                addrs(&["::1", "fd00::1", "192.0.2.1"])
+            );
+        });
+    }
+}
@@ -1,4 +1,7 @@
 mod bytes_ref;
+mod family;
+#[cfg(target_os = "linux")]
+mod splice;
 mod stream;
 mod tcp;
 mod timer;
@@ -6,6 +9,9 @@ mod udp;
 mod utils;
 
 pub use bytes_ref::*;
+pub use family::*;
+#[cfg(target_os = "linux")]
+pub use splice::*;
 pub use stream::*;
 pub use tcp::*;
 pub use timer::*;
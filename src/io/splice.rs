@@ -0,0 +1,196 @@
+use std::io::ErrorKind;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::Arc;
+
+use async_io::Async;
+use async_net::TcpStream;
+use nix::fcntl::{splice, OFlag, SpliceFFlags};
+use nix::unistd::pipe2;
+
+use super::AsyncStreamCounter;
+use crate::counter::Counter;
+use crate::protocol::AsyncStream;
+use crate::utils::race;
+
+// How much is moved through the pipe at a time, the default capacity of a pipe
+const PIPE_CHUNK: usize = 64 * 1024;
+
+// A stream that is a TCP socket underneath, with nothing changing the bytes on the way,
+// along with the counters reading and writing it would have updated
+pub struct SpliceEnd<'a> {
+    socket: Arc<Async<std::net::TcpStream>>,
+    rx: Vec<&'a Counter>,
+    tx: Vec<&'a Counter>,
+}
+
+impl<'a> SpliceEnd<'a> {
+    pub fn of(stream: &'a dyn AsyncStream) -> Option<Self> {
+        let any = stream.as_any();
+        if let Some(stream) = any.downcast_ref::<Box<dyn AsyncStream>>() {
+            return Self::of(stream.as_ref());
+        }
+        if let Some(stream) = any.downcast_ref::<TcpStream>() {
+            return Some(Self {
+                socket: stream.clone().into(),
+                rx: Vec::new(),
+                tx: Vec::new(),
+            });
+        }
+        if let Some(counter) = any.downcast_ref::<AsyncStreamCounter<TcpStream>>() {
+            let (stream, rx, tx) = counter.parts();
+            return Some(Self {
+                socket: stream.clone().into(),
+                rx: vec![rx],
+                tx: vec![tx],
+            });
+        }
+
+        // Counted on top of another stream, e.g. for the payload relayed through an upstream
+        let (stream, rx, tx) = any
+            .downcast_ref::<AsyncStreamCounter<Box<dyn AsyncStream>>>()?
+            .parts();
+        let mut end = Self::of(stream.as_ref())?;
+        end.rx.push(rx);
+        end.tx.push(tx);
+        Some(end)
+    }
+}
+
+fn new_pipe() -> std::io::Result<(OwnedFd, OwnedFd)> {
+    let (r, w) = pipe2(OFlag::O_NONBLOCK | OFlag::O_CLOEXEC)?;
+    // Safety: both were just opened and aren't owned by anything else
+    Ok(unsafe { (OwnedFd::from_raw_fd(r), OwnedFd::from_raw_fd(w)) })
+}
+
+async fn splice_one_way(
+    from: &SpliceEnd<'_>,
+    to: &SpliceEnd<'_>,
+    count: Option<&Counter>,
+) -> anyhow::Result<()> {
+    let (pipe_r, pipe_w) = new_pipe()?;
+    let flags = SpliceFFlags::SPLICE_F_MOVE | SpliceFFlags::SPLICE_F_NONBLOCK;
+    loop {
+        // The pipe is always emptied before the next read, so it's only the socket that can
+        // have nothing to give
+        let len = from
+            .socket
+            .read_with(|s| {
+                Ok(splice(
+                    s.as_raw_fd(),
+                    None,
+                    pipe_w.as_raw_fd(),
+                    None,
+                    PIPE_CHUNK,
+                    flags,
+                )?)
+            })
+            .await?;
+        if len == 0 {
+            return Ok(());
+        }
+        // Counted as soon as they're read, as copying would
+        for counter in from.rx.iter().copied().chain(count) {
+            counter.inc(len);
+        }
+
+        let mut remaining = len;
+        while remaining > 0 {
+            let written = to
+                .socket
+                .write_with(|s| {
+                    Ok(splice(
+                        pipe_r.as_raw_fd(),
+                        None,
+                        s.as_raw_fd(),
+                        None,
+                        remaining,
+                        flags,
+                    )?)
+                })
+                .await?;
+            if written == 0 {
+                return Err(std::io::Error::from(ErrorKind::WriteZero).into());
+            }
+            remaining -= written;
+            for counter in &to.tx {
+                counter.inc(written);
+            }
+        }
+    }
+}
+
+// Relays between two TCP sockets with splice(2), so the bytes go from one to the other
+// without being copied through userspace. Stops when either direction is done.
+pub async fn splice_duplex(
+    d1: SpliceEnd<'_>,
+    d2: SpliceEnd<'_>,
+    d1d2_count: Option<&Counter>,
+    d2d1_count: Option<&Counter>,
+) -> anyhow::Result<()> {
+    race(
+        splice_one_way(&d1, &d2, d1d2_count),
+        splice_one_way(&d2, &d1, d2d1_count),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{AsyncReadExt, AsyncWriteExt};
+    use smol::spawn;
+
+    use super::*;
+    use crate::test::{create_tcp_server, echo_tcp_server};
+    use crate::utils::relay_duplex;
+
+    #[test]
+    fn large_transfer_through_splice() {
+        smol::block_on(async move {
+            let (_echo, echo_addr) = echo_tcp_server().await;
+            let (listener, relay_addr) = create_tcp_server().await;
+            let rx = Arc::new(Counter::default());
+            let tx = Arc::new(Counter::default());
+            let relayed = Arc::new(Counter::default());
+
+            let relay = spawn({
+                let (rx, tx, relayed) = (rx.clone(), tx.clone(), relayed.clone());
+                async move {
+                    let (downstream, _) = listener.accept().await.unwrap();
+                    let upstream: Box<dyn AsyncStream> = Box::new(AsyncStreamCounter::new(
+                        TcpStream::connect(echo_addr).await.unwrap(),
+                        rx.clone(),
+                        tx.clone(),
+                    ));
+                    // Counted again on top, as the client does for the payload
+                    let upstream: Box<dyn AsyncStream> =
+                        Box::new(AsyncStreamCounter::new(upstream, rx, tx));
+                    assert!(SpliceEnd::of(&downstream).is_some());
+                    assert!(SpliceEnd::of(&upstream).is_some());
+                    relay_duplex(downstream, upstream, Some(relayed), None).await
+                }
+            });
+
+            let data: Vec<u8> = (0..8 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+            let client = TcpStream::connect(relay_addr).await.unwrap();
+            let (mut reader, mut writer) = (client.clone(), client);
+            let (_, echoed) = futures::join!(
+                async {
+                    writer.write_all(&data).await.unwrap();
+                },
+                async {
+                    let mut echoed = vec![0u8; data.len()];
+                    reader.read_exact(&mut echoed).await.unwrap();
+                    echoed
+                }
+            );
+            assert!(echoed == data, "Echoed data differs");
+            assert_eq!(relayed.get(), data.len());
+            assert_eq!(tx.get(), data.len() * 2);
+            assert_eq!(rx.get(), data.len() * 2);
+
+            // Closing one end stops the relay
+            drop((reader, writer));
+            relay.await.unwrap();
+        });
+    }
+}
@@ -1,5 +1,8 @@
-use futures::{AsyncRead, AsyncWrite};
+use std::time::Duration;
+
+use futures::{future::Either, io::BufReader, AsyncRead, AsyncReadExt, AsyncWrite};
 use pin_project_lite::pin_project;
+use smol_timeout::TimeoutExt;
 
 pin_project! {
     pub struct StreamUnion<R, W> {
@@ -63,3 +66,163 @@ impl<R, W: AsyncWrite> AsyncWrite for StreamUnion<R, W> {
         self.project().w.poll_write_vectored(cx, bufs)
     }
 }
+
+pub type ReadAhead<S> = Either<BufReader<S>, S>;
+
+// Reads ahead up to `capacity` bytes at a time, so a bulk transfer needs fewer reads.
+// Without a capacity the stream is left as it is.
+pub fn read_ahead<S: AsyncRead>(stream: S, capacity: Option<usize>) -> ReadAhead<S> {
+    match capacity {
+        Some(capacity) => Either::Left(BufReader::with_capacity(capacity, stream)),
+        None => Either::Right(stream),
+    }
+}
+
+pin_project! {
+    // Replays the given prefix before reading from the stream
+    pub struct PrefixedStream<S> {
+        #[pin]
+        stream: S,
+        prefix: Vec<u8>,
+        offset: usize,
+    }
+}
+
+impl<S> PrefixedStream<S> {
+    pub fn new(stream: S, prefix: Vec<u8>) -> Self {
+        Self {
+            stream,
+            prefix,
+            offset: 0,
+        }
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for PrefixedStream<S> {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        let this = self.project();
+        let remaining = &this.prefix[*this.offset..];
+        if remaining.is_empty() {
+            return this.stream.poll_read(cx, buf);
+        }
+
+        let len = remaining.len().min(buf.len());
+        buf[..len].copy_from_slice(&remaining[..len]);
+        *this.offset += len;
+        std::task::Poll::Ready(Ok(len))
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for PrefixedStream<S> {
+    fn poll_close(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.project().stream.poll_close(cx)
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.project().stream.poll_flush(cx)
+    }
+
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        self.project().stream.poll_write(cx, buf)
+    }
+}
+
+// Waits for the first bytes to arrive from the stream. The bytes read are replayed by
+// the returned stream.
+pub async fn read_first_bytes<S: AsyncRead + Unpin>(
+    mut stream: S,
+    timeout: Duration,
+) -> std::io::Result<PrefixedStream<S>> {
+    let mut buf = vec![0u8; 4096];
+    match stream.read(&mut buf).timeout(timeout).await {
+        Some(Ok(len)) => {
+            buf.truncate(len);
+            Ok(PrefixedStream::new(stream, buf))
+        }
+        Some(Err(e)) => Err(e),
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("No response received after {timeout:?}"),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    // Counts how many times the wrapped reader is read from
+    struct CountingReader<R> {
+        inner: R,
+        reads: usize,
+    }
+
+    impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<R> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            self.reads += 1;
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    fn count_reads(capacity: Option<usize>) -> usize {
+        smol::block_on(async move {
+            let data = vec![1u8; 1024 * 1024];
+            let mut reader = read_ahead(
+                CountingReader {
+                    inner: futures::io::Cursor::new(data.clone()),
+                    reads: 0,
+                },
+                capacity,
+            );
+
+            let mut received = Vec::new();
+            let mut buf = [0u8; 1024];
+            loop {
+                match reader.read(&mut buf).await.unwrap() {
+                    0 => break,
+                    n => received.extend_from_slice(&buf[..n]),
+                }
+            }
+            assert_eq!(received, data);
+            match reader {
+                Either::Left(reader) => reader.into_inner().reads,
+                Either::Right(reader) => reader.reads,
+            }
+        })
+    }
+
+    #[test]
+    fn read_ahead_reduces_reads() {
+        let unbuffered = count_reads(None);
+        let small = count_reads(Some(8 * 1024));
+        let large = count_reads(Some(64 * 1024));
+
+        assert_eq!(unbuffered, 1024 + 1);
+        assert!(small < unbuffered, "{small} >= {unbuffered}");
+        assert!(large < small, "{large} >= {small}");
+        assert_eq!(large, 16 + 1);
+    }
+}
@@ -1,10 +1,17 @@
+use std::io::ErrorKind;
 use std::net::SocketAddr;
+use std::time::Duration;
 
+use async_io::Timer;
+use futures::channel::oneshot;
+use futures::{pin_mut, select, Future, FutureExt};
 use smol::net::{TcpListener, TcpStream};
+use smol_timeout::TimeoutExt;
 
 use crate::socks5::Address;
+use crate::utils::race;
 
-use super::AsRawFdExt;
+use super::{mark_dscp, AddressFamilyPreference, AsRawFdExt, Dscp};
 
 pub trait TcpStreamExt {
     fn is_v4(&self) -> bool;
@@ -55,20 +62,225 @@ impl TcpStreamExt for TcpStream {
 }
 
 pub async fn connect_tcp(a: &Address<'_>) -> std::io::Result<TcpStream> {
+    let stream = match a {
+        Address::IP(addr) => TcpStream::connect(addr).await?,
+        Address::Name { .. } => connect_tcp_serially(usable_addrs(a, a.resolve().await?)?).await?,
+    };
+    stream.set_nodelay(TcpOptions::default().nodelay)?;
+    Ok(stream)
+}
+
+fn usable_addrs(
+    a: &Address<'_>,
+    addrs: impl IntoIterator<Item = SocketAddr>,
+) -> std::io::Result<Vec<SocketAddr>> {
+    let addrs: Vec<_> = addrs.into_iter().collect();
+    if addrs.is_empty() {
+        return Err(a.no_usable_addresses());
+    }
+    Ok(addrs)
+}
+
+// How long the IPv6 attempt gets a head start before IPv4 joins in (RFC 8305)
+const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+
+async fn connect_tcp_serially(addrs: Vec<SocketAddr>) -> std::io::Result<TcpStream> {
+    connect_serially(addrs, &TcpStream::connect).await
+}
+
+async fn connect_serially<T, Fut>(
+    addrs: Vec<SocketAddr>,
+    connect: &impl Fn(SocketAddr) -> Fut,
+) -> std::io::Result<T>
+where
+    Fut: Future<Output = std::io::Result<T>>,
+{
+    let mut last_error = None;
+    for addr in addrs {
+        match connect(addr).await {
+            Ok(v) => return Ok(v),
+            Err(e) => {
+                log::debug!("Error connecting to {addr}: {e:?}");
+                last_error.replace(e);
+            }
+        }
+    }
+
+    Err(last_error
+        .unwrap_or_else(|| std::io::Error::new(ErrorKind::NotFound, "No address to connect to")))
+}
+
+pub async fn connect_tcp_addrs(
+    addrs: impl IntoIterator<Item = SocketAddr>,
+) -> std::io::Result<TcpStream> {
+    connect_addrs(addrs, TcpStream::connect).await
+}
+
+// Happy eyeballs over `addrs`, with each attempt made by `connect`
+async fn connect_addrs<T, Fut>(
+    addrs: impl IntoIterator<Item = SocketAddr>,
+    connect: impl Fn(SocketAddr) -> Fut,
+) -> std::io::Result<T>
+where
+    Fut: Future<Output = std::io::Result<T>>,
+{
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(SocketAddr::is_ipv6);
+    if v6.is_empty() || v4.is_empty() {
+        return connect_serially(if v6.is_empty() { v4 } else { v6 }, &connect).await;
+    }
+
+    // Let IPv4 go straight away if all IPv6 attempts fail before the delay is up
+    let (v6_failed_tx, v6_failed_rx) = oneshot::channel::<()>();
+
+    let connect = &connect;
+    let v6_attempt = async move {
+        let result = connect_serially(v6, connect).await;
+        if result.is_err() {
+            let _ = v6_failed_tx.send(());
+        }
+        result
+    }
+    .fuse();
+
+    let v4_attempt = async move {
+        race(
+            async {
+                Timer::after(HAPPY_EYEBALLS_DELAY).await;
+            },
+            async {
+                let _ = v6_failed_rx.await;
+            },
+        )
+        .await;
+        connect_serially(v4, connect).await
+    }
+    .fuse();
+
+    pin_mut!(v6_attempt, v4_attempt);
+
+    select! {
+        r = v6_attempt => match r {
+            Ok(v) => Ok(v),
+            Err(_) => v4_attempt.await,
+        },
+        r = v4_attempt => match r {
+            Ok(v) => Ok(v),
+            Err(_) => v6_attempt.await,
+        },
+    }
+}
+
+pub async fn connect_tcp_happy_eyeballs(a: &Address<'_>) -> std::io::Result<TcpStream> {
     match a {
         Address::IP(addr) => Ok(TcpStream::connect(addr).await?),
-        Address::Name { host, port } => Ok(TcpStream::connect((host.as_ref(), *port)).await?),
+        Address::Name { .. } => connect_tcp_addrs(usable_addrs(a, a.resolve().await?)?).await,
+    }
+}
+
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How outgoing connections are made, see `ClientConfig::tcp_options`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TcpOptions {
+    pub connect_timeout: Duration,
+    // Idle time before keepalive probes are sent to the destination, when connecting
+    // directly. Keepalive is off without it.
+    pub destination_keepalive: Option<Duration>,
+    // Same as `destination_keepalive`, for proxy upstreams
+    pub upstream_keepalive: Option<Duration>,
+    // Time between unanswered keepalive probes and how many go before the connection is
+    // dropped, for both peers. They're left to the system when not set.
+    pub keepalive_interval: Option<Duration>,
+    pub keepalive_count: Option<u32>,
+    // On by default, as Nagle's algorithm holds back the small writes of interactive traffic
+    pub nodelay: bool,
+    // Also used for the UDP sockets of protocols
+    pub dscp: Option<Dscp>,
+    // Put on the sockets of protocols as well, like the dscp
+    pub fwmark: Option<u32>,
+    // Which of the resolved addresses are used first
+    pub address_family: AddressFamilyPreference,
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
+        Self {
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            destination_keepalive: None,
+            upstream_keepalive: None,
+            keepalive_interval: None,
+            keepalive_count: None,
+            nodelay: true,
+            dscp: None,
+            fwmark: None,
+            address_family: Default::default(),
+        }
+    }
+}
+
+impl TcpOptions {
+    pub fn keepalive(&self, peer: TcpPeer) -> Option<Duration> {
+        match peer {
+            TcpPeer::Destination => self.destination_keepalive,
+            TcpPeer::Upstream => self.upstream_keepalive,
+        }
+    }
+}
+
+pub fn is_timeout_error(e: &anyhow::Error) -> bool {
+    e.chain().any(|e| {
+        matches!(e.downcast_ref::<std::io::Error>(), Some(e) if e.kind() == ErrorKind::TimedOut)
+    })
+}
+
+async fn with_connect_timeout<T>(
+    a: &Address<'_>,
+    timeout: Duration,
+    connect: impl Future<Output = std::io::Result<T>>,
+) -> std::io::Result<T> {
+    match connect.timeout(timeout).await {
+        Some(v) => v,
+        None => Err(std::io::Error::new(
+            ErrorKind::TimedOut,
+            format!("Timeout connecting to {a} after {timeout:?}"),
+        )),
     }
 }
 
-pub async fn connect_tcp_marked(
+// What an outgoing connection is made to, as each can have its own keepalive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpPeer {
+    // The destination itself, when connecting directly
+    Destination,
+    // A proxy upstream carrying the traffic
+    Upstream,
+}
+
+pub async fn connect_tcp_with(
     a: &Address<'_>,
-    fwmark: Option<u32>,
+    peer: TcpPeer,
+    options: &TcpOptions,
 ) -> std::io::Result<TcpStream> {
-    let stream = connect_tcp(a).await?;
-    if let Some(mark) = fwmark {
+    connect_tcp_using(a, peer, options, connect_tcp_happy_eyeballs(a)).await
+}
+
+// Same as `connect_tcp_with`, with the connection made by `connect`
+async fn connect_tcp_using(
+    a: &Address<'_>,
+    peer: TcpPeer,
+    options: &TcpOptions,
+    connect: impl Future<Output = std::io::Result<TcpStream>>,
+) -> std::io::Result<TcpStream> {
+    let stream = with_connect_timeout(a, options.connect_timeout, connect).await?;
+    if let Some(mark) = options.fwmark {
         stream.set_sock_mark(mark)?;
     }
+    if let Some(idle) = options.keepalive(peer) {
+        stream.set_tcp_keepalive(idle)?;
+        stream.set_tcp_keepalive_probes(options.keepalive_interval, options.keepalive_count)?;
+    }
+    stream.set_nodelay(options.nodelay)?;
+    mark_dscp(&stream, stream.local_addr(), options.dscp)?;
     Ok(stream)
 }
 
@@ -78,3 +290,174 @@ pub async fn bind_tcp(a: &Address<'_>) -> std::io::Result<TcpListener> {
         Address::Name { host, port } => Ok(TcpListener::bind((host.as_ref(), *port)).await?),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::socks5::ConnStatusCode;
+    use crate::test::create_tcp_server;
+
+    // Connects at once to IPv4 addresses, while IPv6 attempts hang or fail straight away
+    async fn fake_connect(addr: SocketAddr, v6_hangs: bool) -> std::io::Result<SocketAddr> {
+        if addr.is_ipv6() {
+            if v6_hangs {
+                futures::future::pending::<()>().await;
+            }
+            return Err(ErrorKind::ConnectionRefused.into());
+        }
+        Ok(addr)
+    }
+
+    #[test]
+    fn happy_eyeballs_falls_back_to_v4() {
+        smol::block_on(async move {
+            let v6: SocketAddr = "[2001:db8::1]:80".parse().unwrap();
+            let v4: SocketAddr = "192.0.2.1:80".parse().unwrap();
+
+            let start = Instant::now();
+            let connected = connect_addrs([v6, v4], |a| fake_connect(a, true))
+                .await
+                .expect("To connect via IPv4");
+
+            assert_eq!(connected, v4);
+            assert!(start.elapsed() >= HAPPY_EYEBALLS_DELAY);
+            assert!(start.elapsed() < HAPPY_EYEBALLS_DELAY * 2);
+
+            // IPv4 doesn't wait for the delay once IPv6 has failed
+            let start = Instant::now();
+            let connected = connect_addrs([v6, v4], |a| fake_connect(a, false))
+                .await
+                .expect("To connect via IPv4");
+
+            assert_eq!(connected, v4);
+            assert!(start.elapsed() < HAPPY_EYEBALLS_DELAY);
+        });
+    }
+
+    #[test]
+    fn filtered_out_addresses_are_reported() {
+        let addr: Address = "example.com:443".parse().unwrap();
+        let v6_only: Vec<SocketAddr> = vec![
+            "[2001:db8::1]:443".parse().unwrap(),
+            "[2001:db8::2]:443".parse().unwrap(),
+        ];
+
+        // An IPv4-only policy leaves nothing out of an IPv6-only answer
+        let err = usable_addrs(&addr, v6_only.into_iter().filter(SocketAddr::is_ipv4))
+            .expect_err("To have no usable address");
+
+        assert_eq!(err.kind(), ErrorKind::HostUnreachable);
+        assert_eq!(err.to_string(), "No usable addresses for example.com:443");
+        assert_eq!(
+            ConnStatusCode::from_error(&anyhow::Error::from(err).context("Connecting")),
+            ConnStatusCode::HOST_UNREACHABLE
+        );
+    }
+
+    // Stands in for a connection attempt nothing ever answers
+    fn hanging_connect() -> impl Future<Output = std::io::Result<TcpStream>> {
+        futures::future::pending()
+    }
+
+    #[test]
+    fn connect_timeout_works() {
+        smol::block_on(async move {
+            let addr: Address = "10.255.255.1:80".parse().unwrap();
+            let options = TcpOptions {
+                connect_timeout: Duration::from_millis(200),
+                ..Default::default()
+            };
+
+            let start = Instant::now();
+            let err = connect_tcp_using(&addr, TcpPeer::Destination, &options, hanging_connect())
+                .await
+                .expect_err("To time out");
+
+            assert_eq!(err.kind(), ErrorKind::TimedOut);
+            assert!(start.elapsed() >= options.connect_timeout);
+            assert!(start.elapsed() < options.connect_timeout * 2);
+            assert!(is_timeout_error(
+                &anyhow::Error::from(err).context("Connecting")
+            ));
+        });
+    }
+
+    #[test]
+    fn connect_timeout_is_taken_per_connect() {
+        smol::block_on(async move {
+            let addr: Address = "10.255.255.1:80".parse().unwrap();
+            for timeout in [Duration::from_millis(100), Duration::from_millis(400)] {
+                let options = TcpOptions {
+                    connect_timeout: timeout,
+                    ..Default::default()
+                };
+
+                let start = Instant::now();
+                let err = connect_tcp_using(&addr, TcpPeer::Upstream, &options, hanging_connect())
+                    .await
+                    .expect_err("To time out");
+
+                assert_eq!(err.kind(), ErrorKind::TimedOut);
+                assert!(start.elapsed() >= timeout);
+                assert!(start.elapsed() < timeout * 2);
+            }
+        });
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn keepalive_is_set_per_peer() {
+        use nix::sys::socket::{
+            getsockopt,
+            sockopt::{KeepAlive, TcpKeepCount, TcpKeepIdle, TcpKeepInterval, TcpNoDelay},
+        };
+        use std::os::unix::prelude::AsRawFd;
+
+        smol::block_on(async move {
+            let (listener, addr) = create_tcp_server().await;
+            let _accepted = smol::spawn(async move {
+                let mut streams = Vec::new();
+                while let Ok((stream, _)) = listener.accept().await {
+                    streams.push(stream);
+                }
+            });
+
+            let options = TcpOptions {
+                upstream_keepalive: Some(Duration::from_secs(42)),
+                keepalive_interval: Some(Duration::from_secs(7)),
+                keepalive_count: Some(3),
+                ..Default::default()
+            };
+            let addr: Address = addr.into();
+            let upstream = connect_tcp_with(&addr, TcpPeer::Upstream, &options)
+                .await
+                .unwrap();
+            let direct = connect_tcp_with(&addr, TcpPeer::Destination, &options)
+                .await
+                .unwrap();
+
+            assert!(getsockopt(upstream.as_raw_fd(), KeepAlive).unwrap());
+            assert_eq!(getsockopt(upstream.as_raw_fd(), TcpKeepIdle).unwrap(), 42);
+            assert_eq!(
+                getsockopt(upstream.as_raw_fd(), TcpKeepInterval).unwrap(),
+                7
+            );
+            assert_eq!(getsockopt(upstream.as_raw_fd(), TcpKeepCount).unwrap(), 3);
+            assert!(!getsockopt(direct.as_raw_fd(), KeepAlive).unwrap());
+
+            // Nagle's algorithm is off unless asked for
+            assert!(getsockopt(upstream.as_raw_fd(), TcpNoDelay).unwrap());
+            assert!(getsockopt(direct.as_raw_fd(), TcpNoDelay).unwrap());
+            let nagle = TcpOptions {
+                nodelay: false,
+                ..options
+            };
+            let nagle = connect_tcp_with(&addr, TcpPeer::Destination, &nagle)
+                .await
+                .unwrap();
+            assert!(!getsockopt(nagle.as_raw_fd(), TcpNoDelay).unwrap());
+        });
+    }
+}
@@ -1,9 +1,18 @@
-use std::{sync::Arc, task::Poll};
+use std::{
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::Poll,
+    time::{Duration, Instant},
+};
 
-use futures::{AsyncRead, AsyncWrite};
+use anyhow::Context;
+use async_io::Timer;
+use futures::{AsyncRead, AsyncWrite, AsyncWriteExt, Future};
 
-use crate::counter::Counter;
+use crate::{counter::Counter, socks5::Address};
 use pin_project_lite::pin_project;
+use serde::{Deserialize, Serialize};
 
 #[cfg(unix)]
 use std::os::unix::prelude::AsRawFd;
@@ -21,6 +30,11 @@ impl<S> AsyncStreamCounter<S> {
     pub fn new(stream: S, rx: Arc<Counter>, tx: Arc<Counter>) -> Self {
         Self { stream, rx, tx }
     }
+
+    #[cfg(target_os = "linux")]
+    pub(super) fn parts(&self) -> (&S, &Counter, &Counter) {
+        (&self.stream, &self.rx, &self.tx)
+    }
 }
 
 impl<S: AsyncRead> AsyncRead for AsyncStreamCounter<S> {
@@ -93,6 +107,178 @@ impl<S: AsyncWrite> AsyncWrite for AsyncStreamCounter<S> {
     }
 }
 
+pin_project! {
+    // Fails reads and writes with `TimedOut` once no bytes have gone either way for
+    // `timeout`, so relays over a connection whose peer vanished end instead of
+    // waiting on it forever. Being unknown to `SpliceEnd`, it's relayed by copying.
+    #[project = IdleTimeoutStreamProj]
+    pub struct IdleTimeoutStream<S> {
+        #[pin]
+        stream: S,
+        timeout: Duration,
+        last_active: Instant,
+        timer: Timer,
+    }
+}
+
+impl<S> IdleTimeoutStream<S> {
+    pub fn new(stream: S, timeout: Duration) -> Self {
+        Self {
+            stream,
+            timeout,
+            last_active: Instant::now(),
+            timer: Timer::after(timeout),
+        }
+    }
+}
+
+impl<S> IdleTimeoutStreamProj<'_, S> {
+    fn active(&mut self, rc: &Poll<std::io::Result<usize>>) {
+        if let Poll::Ready(Ok(len)) = rc {
+            if *len > 0 {
+                *self.last_active = Instant::now();
+            }
+        }
+    }
+
+    // Only called while the stream is pending. The timer isn't moved on every transfer,
+    // only when it fires early.
+    fn poll_idle(&mut self, cx: &mut std::task::Context<'_>) -> Poll<std::io::Result<usize>> {
+        while Pin::new(&mut *self.timer).poll(cx).is_ready() {
+            let deadline = *self.last_active + *self.timeout;
+            if deadline <= Instant::now() {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("No traffic for {:?}", self.timeout),
+                )));
+            }
+            self.timer.set_at(deadline);
+        }
+        Poll::Pending
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for IdleTimeoutStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let mut this = self.project();
+        let rc = this.stream.as_mut().poll_read(cx, buf);
+        this.active(&rc);
+        match rc {
+            Poll::Pending => this.poll_idle(cx),
+            rc => rc,
+        }
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for IdleTimeoutStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let mut this = self.project();
+        let rc = this.stream.as_mut().poll_write(cx, buf);
+        this.active(&rc);
+        match rc {
+            Poll::Pending => this.poll_idle(cx),
+            rc => rc,
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        let mut this = self.project();
+        let rc = this.stream.as_mut().poll_write_vectored(cx, bufs);
+        this.active(&rc);
+        match rc {
+            Poll::Pending => this.poll_idle(cx),
+            rc => rc,
+        }
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        self.project().stream.poll_flush(cx)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        self.project().stream.poll_close(cx)
+    }
+}
+
+// Writes the initial data to a freshly established tunnel. On failure the stream is closed
+// so the remote end sees a clean shutdown instead of a half written request.
+pub async fn write_initial_data(
+    stream: &mut (impl AsyncWrite + Unpin + ?Sized),
+    initial_data: Option<&[u8]>,
+    dst: &Address<'_>,
+) -> anyhow::Result<()> {
+    let data = match initial_data {
+        Some(b) if !b.is_empty() => b,
+        _ => return Ok(()),
+    };
+
+    if let Err(e) = stream.write_all(data).await {
+        let _ = stream.close().await;
+        return Err(e).with_context(|| format!("Writing initial data to {dst}"));
+    }
+
+    Ok(())
+}
+
+// A DSCP value (0-63), put on outgoing packets for routers doing QoS by it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub struct Dscp(u8);
+
+impl TryFrom<u8> for Dscp {
+    type Error = String;
+
+    fn try_from(dscp: u8) -> Result<Self, Self::Error> {
+        match dscp {
+            0..=0x3f => Ok(Self(dscp)),
+            _ => Err(format!("DSCP {dscp} is out of range 0-63")),
+        }
+    }
+}
+
+impl From<Dscp> for u8 {
+    fn from(dscp: Dscp) -> Self {
+        dscp.0
+    }
+}
+
+impl Dscp {
+    // DSCP takes the upper six bits of the TOS / traffic class byte
+    pub fn tos(self) -> u8 {
+        self.0 << 2
+    }
+}
+
+// Applies the DSCP marking, if there's one, to an outgoing socket
+pub fn mark_dscp(
+    socket: &impl AsRawFdExt,
+    local_addr: std::io::Result<SocketAddr>,
+    dscp: Option<Dscp>,
+) -> std::io::Result<()> {
+    match dscp {
+        Some(dscp) => socket.set_dscp(dscp, local_addr?.is_ipv6()),
+        None => Ok(()),
+    }
+}
+
 #[cfg(unix)]
 pub trait AsRawFdExt: AsRawFd {
     #[cfg(target_os = "linux")]
@@ -107,6 +293,86 @@ pub trait AsRawFdExt: AsRawFd {
     fn set_sock_mark(&self, _: u32) -> std::io::Result<()> {
         Ok(())
     }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn set_tcp_keepalive(&self, idle: Duration) -> std::io::Result<()> {
+        use nix::sys::socket::{setsockopt, sockopt::KeepAlive, sockopt::TcpKeepIdle};
+
+        setsockopt(self.as_raw_fd(), KeepAlive, &true)?;
+        setsockopt(
+            self.as_raw_fd(),
+            TcpKeepIdle,
+            &(idle.as_secs().max(1) as u32),
+        )?;
+        Ok(())
+    }
+
+    // The idle time is left to the system elsewhere
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn set_tcp_keepalive(&self, _: Duration) -> std::io::Result<()> {
+        use nix::sys::socket::{setsockopt, sockopt::KeepAlive};
+
+        setsockopt(self.as_raw_fd(), KeepAlive, &true)?;
+        Ok(())
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn set_tcp_keepalive_probes(
+        &self,
+        interval: Option<Duration>,
+        count: Option<u32>,
+    ) -> std::io::Result<()> {
+        use nix::sys::socket::{setsockopt, sockopt::TcpKeepCount, sockopt::TcpKeepInterval};
+
+        if let Some(interval) = interval {
+            setsockopt(
+                self.as_raw_fd(),
+                TcpKeepInterval,
+                &(interval.as_secs().max(1) as u32),
+            )?;
+        }
+        if let Some(count) = count {
+            setsockopt(self.as_raw_fd(), TcpKeepCount, &count)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn set_tcp_keepalive_probes(&self, _: Option<Duration>, _: Option<u32>) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    // Sets the DSCP bits of the IPv4 TOS or IPv6 traffic class of the outgoing packets. An
+    // IPv6 socket gets both, as what it sends to IPv4-mapped peers goes out as IPv4.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn set_dscp(&self, dscp: Dscp, ipv6: bool) -> std::io::Result<()> {
+        let value = dscp.tos() as libc::c_int;
+        let set = |level, name| {
+            let rc = unsafe {
+                libc::setsockopt(
+                    self.as_raw_fd(),
+                    level,
+                    name,
+                    &value as *const libc::c_int as *const libc::c_void,
+                    std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+                )
+            };
+            match rc {
+                0 => Ok(()),
+                _ => Err(std::io::Error::last_os_error()),
+            }
+        };
+
+        if ipv6 {
+            set(libc::IPPROTO_IPV6, libc::IPV6_TCLASS)?;
+        }
+        set(libc::IPPROTO_IP, libc::IP_TOS)
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn set_dscp(&self, _: Dscp, _: bool) -> std::io::Result<()> {
+        Ok(())
+    }
 }
 
 #[cfg(unix)]
@@ -117,6 +383,22 @@ pub trait AsRawFdExt {
     fn set_sock_mark(&self, _mark: u32) -> std::io::Result<()> {
         Ok(())
     }
+
+    fn set_tcp_keepalive(&self, _idle: Duration) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn set_tcp_keepalive_probes(
+        &self,
+        _interval: Option<Duration>,
+        _count: Option<u32>,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn set_dscp(&self, _dscp: Dscp, _ipv6: bool) -> std::io::Result<()> {
+        Ok(())
+    }
 }
 
 #[cfg(not(unix))]
@@ -124,3 +406,144 @@ impl AsRawFdExt for async_net::TcpStream {}
 
 #[cfg(not(unix))]
 impl AsRawFdExt for async_net::UdpSocket {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io, pin::Pin, task::Context as TaskContext};
+
+    #[derive(Default)]
+    struct FailingWriter {
+        closed: bool,
+    }
+
+    impl AsyncWrite for FailingWriter {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut TaskContext<'_>,
+            _: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, _: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+            self.closed = true;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn write_initial_data_closes_on_failure() {
+        smol::block_on(async move {
+            let dst: Address = "example.com:443".parse().unwrap();
+
+            let mut w = FailingWriter::default();
+            write_initial_data(&mut w, None, &dst).await.unwrap();
+            write_initial_data(&mut w, Some(b""), &dst).await.unwrap();
+            assert!(!w.closed);
+
+            let err = write_initial_data(&mut w, Some(b"hello"), &dst)
+                .await
+                .unwrap_err();
+            assert_eq!(err.to_string(), "Writing initial data to example.com:443");
+            assert_eq!(
+                err.downcast_ref::<io::Error>().map(|e| e.kind()),
+                Some(io::ErrorKind::BrokenPipe)
+            );
+            assert!(w.closed);
+        });
+    }
+
+    #[test]
+    fn idle_streams_time_out() {
+        use async_net::{TcpListener, TcpStream};
+        use futures::AsyncReadExt;
+
+        smol::block_on(async move {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let timeout = Duration::from_millis(300);
+
+            // Traffic every 100ms keeps the stream open well past the timeout
+            let mut active =
+                IdleTimeoutStream::new(TcpStream::connect(addr).await.unwrap(), timeout);
+            let (mut peer, _) = listener.accept().await.unwrap();
+            let started = Instant::now();
+            let mut buf = [0u8; 16];
+            while started.elapsed() < timeout * 3 {
+                peer.write_all(b"ping").await.unwrap();
+                active.read_exact(&mut buf[..4]).await.unwrap();
+                smol::Timer::after(Duration::from_millis(100)).await;
+            }
+
+            // Without traffic it's given up after the timeout, and the peer sees it closed
+            drop(active);
+            let (mut peer, _) = {
+                let stream = TcpStream::connect(addr).await.unwrap();
+                let mut idle = IdleTimeoutStream::new(stream, timeout);
+                let accepted = listener.accept().await.unwrap();
+                let started = Instant::now();
+                let err = idle.read(&mut buf).await.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+                assert!(started.elapsed() >= timeout);
+                assert!(started.elapsed() < timeout * 3);
+                accepted
+            };
+            assert_eq!(peer.read(&mut buf).await.unwrap(), 0);
+        });
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn dscp_is_marked() {
+        use crate::io::{bind_udp, connect_tcp_with, TcpOptions, TcpPeer};
+
+        fn get_tos(socket: &impl AsRawFd, level: libc::c_int, name: libc::c_int) -> libc::c_int {
+            let mut value: libc::c_int = 0;
+            let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+            let rc = unsafe {
+                libc::getsockopt(
+                    socket.as_raw_fd(),
+                    level,
+                    name,
+                    &mut value as *mut libc::c_int as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            assert_eq!(rc, 0);
+            value
+        }
+
+        smol::block_on(async move {
+            let (_task, addr) = crate::test::echo_tcp_server().await;
+
+            // Expedited Forwarding
+            let dscp = Dscp::try_from(46).unwrap();
+            let options = TcpOptions {
+                dscp: Some(dscp),
+                ..Default::default()
+            };
+            let tcp = connect_tcp_with(&addr.into(), TcpPeer::Upstream, &options)
+                .await
+                .unwrap();
+            let udp = bind_udp(false).await.unwrap();
+            mark_dscp(&udp, udp.local_addr(), Some(dscp)).unwrap();
+
+            assert_eq!(get_tos(&tcp, libc::IPPROTO_IP, libc::IP_TOS), 46 << 2);
+            // Both, for IPv4-mapped peers of the dual-stack socket
+            assert_eq!(
+                get_tos(&udp, libc::IPPROTO_IPV6, libc::IPV6_TCLASS),
+                46 << 2
+            );
+            assert_eq!(get_tos(&udp, libc::IPPROTO_IP, libc::IP_TOS), 46 << 2);
+
+            assert!(Dscp::try_from(64).is_err());
+            assert!(serde_yaml::from_str::<Dscp>("64").is_err());
+            assert_eq!(serde_yaml::from_str::<Dscp>("46").unwrap(), dscp);
+        });
+    }
+}
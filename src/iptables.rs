@@ -1,6 +1,7 @@
 #[cfg(target_os = "linux")]
 mod linux {
     use anyhow::{anyhow, Context};
+    use ipnetwork::IpNetwork;
     use std::{error::Error, process::Command};
 
     const CHAIN_NAME: &str = "cpxy";
@@ -60,11 +61,15 @@ mod linux {
         Ok(())
     }
 
-    pub fn add_rules(tcp_port: u16, udp_port: Option<u16>) -> anyhow::Result<()> {
+    pub fn add_rules(
+        tcp_port: u16,
+        udp_port: Option<u16>,
+        bypass_networks: &[IpNetwork],
+    ) -> anyhow::Result<()> {
         execute_command("sysctl", &["-w", "net.ipv4.conf.all.route_localnet=1"])?;
         let ipt = iptables::new(false).context("Create iptable instance")?;
 
-        let networks = [
+        let mut networks: Vec<String> = [
             "10.0.0.0/8",
             "100.64.0.0/10",
             "127.0.0.0/8",
@@ -74,7 +79,18 @@ mod linux {
             "192.168.0.0/16",
             "198.18.0.0/15",
             "255.255.255.255/32",
-        ];
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+
+        // Only IPv4 traffic is redirected
+        networks.extend(
+            bypass_networks
+                .iter()
+                .filter(|n| n.is_ipv4())
+                .map(|n| n.to_string()),
+        );
 
         // TCP rules
         ipt.new_chain("nat", CHAIN_NAME)
@@ -156,7 +172,13 @@ mod linux {
 
 #[cfg(not(target_os = "linux"))]
 mod noop {
-    pub fn add_rules(_tcp_port: u16, _udp_port: Option<u16>) -> anyhow::Result<()> {
+    use ipnetwork::IpNetwork;
+
+    pub fn add_rules(
+        _tcp_port: u16,
+        _udp_port: Option<u16>,
+        _bypass_networks: &[IpNetwork],
+    ) -> anyhow::Result<()> {
         Ok(())
     }
 
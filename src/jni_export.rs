@@ -1,9 +1,9 @@
 use crate::controller::run_controller;
 use crate::io::bind_tcp;
-use smol::{block_on, spawn, Task};
 use jni::objects::{JClass, JString};
 use jni::sys::{jint, jlong};
 use jni::JNIEnv;
+use smol::{block_on, spawn, Task};
 use std::path::Path;
 
 struct Instance(u16, Task<anyhow::Result<()>>);
@@ -15,10 +15,7 @@ pub extern "system" fn Java_dev_fanchao_CJKProxy_start(
     config_path: JString,
 ) -> jlong {
     #[cfg(target_os = "android")]
-    android_logger::init_once(
-        android_logger::Config::default()
-            .with_tag("proxy_rust"),
-    );
+    android_logger::init_once(android_logger::Config::default().with_tag("proxy_rust"));
 
     let config_path: String = env
         .get_string(&config_path)
@@ -49,7 +46,15 @@ pub extern "system" fn Java_dev_fanchao_CJKProxy_start(
 
     Box::leak(Box::new(Instance(
         port,
-        spawn(async move { run_controller(listener, Path::new(&config_path)).await }),
+        // Stopped by cancelling the task
+        spawn(async move {
+            run_controller(
+                listener,
+                Path::new(&config_path),
+                futures::future::pending(),
+            )
+            .await
+        }),
     ))) as *mut Instance as jlong
 }
 
@@ -1,20 +1,28 @@
+pub mod bench_client;
 mod broadcast;
 mod buf;
 mod client;
 mod counter;
-mod dns;
+pub mod dns;
+mod drain;
+pub mod external_decision;
 mod fetch;
 mod handshake;
 mod http;
 mod http_path;
 mod iptables;
+pub mod logging;
+mod pac;
 mod parse;
 mod pattern;
 pub mod protocol;
 mod rule;
+pub mod runtime;
+mod schedule;
 mod sni;
 mod socks4;
 pub mod socks5;
+mod tls;
 pub mod url;
 pub mod utils;
 mod ws;
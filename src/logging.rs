@@ -0,0 +1,168 @@
+use std::{
+    cell::RefCell,
+    future::Future,
+    io::Write,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use parking_lot::Mutex;
+use pin_project_lite::pin_project;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    // env_logger's usual lines
+    #[default]
+    Human,
+    // One JSON object per line, for log aggregation
+    Json,
+}
+
+// Sets up the global logger, configured by `RUST_LOG` as usual
+pub fn init_logger(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if format == LogFormat::Json {
+        builder.format(write_json);
+    }
+    builder.init();
+}
+
+// What's known about the connection a log line comes from
+#[derive(Debug)]
+struct LogContext {
+    connection_id: u64,
+    upstream: Mutex<Option<String>>,
+}
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static CURRENT_CONTEXT: RefCell<Option<Arc<LogContext>>> = const { RefCell::new(None) };
+}
+
+pin_project! {
+    pub struct WithConnectionContext<F> {
+        #[pin]
+        inner: F,
+        context: Arc<LogContext>,
+    }
+}
+
+impl<F: Future> Future for WithConnectionContext<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let previous = CURRENT_CONTEXT.with(|c| c.replace(Some(this.context.clone())));
+        let result = this.inner.poll(cx);
+        CURRENT_CONTEXT.with(|c| *c.borrow_mut() = previous);
+        result
+    }
+}
+
+// Tags what's logged while serving a connection with a new connection id
+pub fn with_connection_context<F: Future>(inner: F) -> WithConnectionContext<F> {
+    WithConnectionContext {
+        inner,
+        context: Arc::new(LogContext {
+            connection_id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            upstream: Default::default(),
+        }),
+    }
+}
+
+// Tags the rest of the current connection's logs with the upstream serving it
+pub fn set_log_upstream(name: &str) {
+    CURRENT_CONTEXT.with(|c| {
+        if let Some(context) = c.borrow().as_ref() {
+            *context.upstream.lock() = Some(name.to_string());
+        }
+    });
+}
+
+#[derive(Serialize)]
+struct JsonLogLine<'a> {
+    timestamp: DateTime<Utc>,
+    level: &'a str,
+    target: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connection_id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upstream: Option<String>,
+    event: String,
+}
+
+fn write_json(w: &mut impl Write, record: &log::Record<'_>) -> std::io::Result<()> {
+    let context = CURRENT_CONTEXT.with(|c| c.borrow().clone());
+    let line = JsonLogLine {
+        timestamp: Utc::now(),
+        level: record.level().as_str(),
+        target: record.target(),
+        connection_id: context.as_ref().map(|c| c.connection_id),
+        upstream: context.and_then(|c| c.upstream.lock().clone()),
+        event: record.args().to_string(),
+    };
+    serde_json::to_writer(&mut *w, &line)?;
+    writeln!(w)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_line(message: &str) -> serde_json::Value {
+        let mut buf = Vec::new();
+        write_json(
+            &mut buf,
+            &log::Record::builder()
+                .args(format_args!("{message}"))
+                .level(log::Level::Warn)
+                .target("cpxy::test")
+                .build(),
+        )
+        .unwrap();
+
+        assert_eq!(buf.last(), Some(&b'\n'));
+        serde_json::from_slice(&buf).expect("Log line to be JSON")
+    }
+
+    #[test]
+    fn json_logs_carry_connection_context() {
+        let line = log_line("Outside a connection");
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["target"], "cpxy::test");
+        assert_eq!(line["event"], "Outside a connection");
+        assert!(line["timestamp"].is_string());
+        assert!(line.get("connection_id").is_none());
+        assert!(line.get("upstream").is_none());
+
+        let (first, second) = smol::block_on(async {
+            let first = with_connection_context(async {
+                let before = log_line("Connected");
+                set_log_upstream("remote");
+                (before, log_line("Proxying \"quoted\""))
+            })
+            .await;
+            let second = with_connection_context(async { log_line("Connected") }).await;
+            (first, second)
+        });
+
+        let (before, after) = first;
+        let id = before["connection_id"].as_u64().expect("A connection id");
+        assert!(before.get("upstream").is_none());
+        assert_eq!(after["connection_id"], id);
+        assert_eq!(after["upstream"], "remote");
+        assert_eq!(after["event"], "Proxying \"quoted\"");
+        assert_ne!(second["connection_id"], id);
+
+        // The context doesn't outlive the connection
+        assert!(log_line("Done").get("connection_id").is_none());
+    }
+}
@@ -0,0 +1,440 @@
+use std::{fmt::Write, net::IpAddr, ops::RangeInclusive};
+
+use ipnetwork::IpNetwork;
+
+use crate::{
+    geoip::geoip_ranges_v4,
+    pattern::Pattern,
+    rule::{HostMatch, RuleAction, RuleDestination, RuleProtocol, RuleString},
+};
+
+// A country with more ranges than this is left to the proxy, as embedding it would
+// bloat the script past what browsers are happy to evaluate on every request
+const MAX_EMBEDDED_RANGES: usize = 10_000;
+
+// Same as `RuleString::execute_table`
+const MAX_JUMP_LEVEL: usize = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PacCondition {
+    // The pattern and its JavaScript translation
+    Host(Pattern, String),
+    // Sorted, non-overlapping inclusive IPv4 ranges
+    Ip(Vec<(u32, u32)>),
+    Port(RangeInclusive<u16>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PacAction {
+    Direct,
+    Proxy,
+    Jump(usize),
+    Return,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PacRule {
+    conditions: Vec<PacCondition>,
+    action: PacAction,
+}
+
+// The rule tables reachable from `main`, reduced to what a PAC script can check:
+// host patterns, IPv4 ranges and ports. A rule that can't be checked there sends the
+// rest of its table to the proxy, which still applies the real rules.
+#[derive(Debug)]
+struct PacTables {
+    // Indexed by `PacAction::Jump`, with `main` first
+    tables: Vec<Vec<PacRule>>,
+}
+
+fn network_range(n: &IpNetwork) -> Option<(u32, u32)> {
+    match (n.network(), n.broadcast()) {
+        (IpAddr::V4(start), IpAddr::V4(end)) => Some((start.into(), end.into())),
+        _ => None,
+    }
+}
+
+fn translate_destination(dest: &RuleDestination) -> Option<PacCondition> {
+    match dest {
+        RuleDestination::Domain(HostMatch::Pattern(p)) => match js_regex(&p.to_string()) {
+            Some(js) => Some(PacCondition::Host(p.clone(), js)),
+            None => {
+                log::warn!("Pattern {p} can't be used in a PAC script");
+                None
+            }
+        },
+        RuleDestination::Network(n) => Some(PacCondition::Ip(vec![network_range(n)?])),
+        RuleDestination::GeoIP(c) => {
+            let ranges: Vec<_> = geoip_ranges_v4(*c).take(MAX_EMBEDDED_RANGES + 1).collect();
+            if ranges.is_empty() || ranges.len() > MAX_EMBEDDED_RANGES {
+                return None;
+            }
+            Some(PacCondition::Ip(ranges))
+        }
+        RuleDestination::Port(p) => Some(PacCondition::Port(p.clone())),
+        _ => None,
+    }
+}
+
+impl PacTables {
+    fn new(rules: &RuleString, is_direct: &impl Fn(&str) -> bool) -> Self {
+        let mut names = vec!["main".to_string()];
+        let mut tables = Vec::new();
+
+        // Tables are appended to `names` as jumps to them are found
+        while let Some(name) = names.get(tables.len()).cloned() {
+            let mut table = Vec::new();
+            for rule in rules.table(&name).unwrap_or_default() {
+                // Browsers only ask about TCP connections
+                if rule.proto == Some(RuleProtocol::Udp) {
+                    continue;
+                }
+
+                let conditions: Option<Vec<_>> =
+                    rule.dest.iter().map(translate_destination).collect();
+                let Some(conditions) = conditions else {
+                    log::debug!("Sending the rest of table {name} to proxy at {rule:?}");
+                    table.push(PacRule {
+                        conditions: vec![],
+                        action: PacAction::Proxy,
+                    });
+                    break;
+                };
+
+                let action = match &rule.action {
+                    RuleAction::Direct => PacAction::Direct,
+                    RuleAction::Proxy(p) if is_direct(p) => PacAction::Direct,
+                    RuleAction::Proxy(_)
+                    | RuleAction::ProxyGroup(..)
+                    | RuleAction::Reject
+                    | RuleAction::External(_) => PacAction::Proxy,
+                    RuleAction::Jump(t) if rules.table(t).is_some() => {
+                        match names.iter().position(|n| n.as_str() == t.as_ref()) {
+                            Some(index) => PacAction::Jump(index),
+                            None => {
+                                names.push(t.to_string());
+                                PacAction::Jump(names.len() - 1)
+                            }
+                        }
+                    }
+                    RuleAction::Jump(_) => continue,
+                    RuleAction::Return => PacAction::Return,
+                };
+
+                let unconditional = conditions.is_empty() && action != PacAction::Return;
+                table.push(PacRule { conditions, action });
+                if unconditional {
+                    break;
+                }
+            }
+            tables.push(table);
+        }
+
+        Self { tables }
+    }
+
+    fn render(&self, proxy: &str) -> String {
+        let mut js = String::new();
+        let proxy = serde_json::to_string(&format!("SOCKS5 {proxy}; SOCKS {proxy}"))
+            .expect("a string serialises");
+        let _ = writeln!(js, "var PROXY = {proxy};");
+        js.push_str(PAC_HELPERS);
+
+        for (index, table) in self.tables.iter().enumerate() {
+            let _ = writeln!(js, "\nfunction table{index}(d, level) {{");
+            let _ = writeln!(js, "    if (level > {MAX_JUMP_LEVEL}) return null;");
+            for rule in table {
+                let action = match rule.action {
+                    PacAction::Direct => "return \"DIRECT\";".to_string(),
+                    PacAction::Proxy => "return PROXY;".to_string(),
+                    PacAction::Jump(t) => {
+                        format!("{{ var r = table{t}(d, level + 1); if (r) return r; }}")
+                    }
+                    PacAction::Return => "return null;".to_string(),
+                };
+
+                if rule.conditions.is_empty() {
+                    let _ = writeln!(js, "    {action}");
+                    continue;
+                }
+
+                let conditions: Vec<_> = rule.conditions.iter().map(render_condition).collect();
+                let _ = writeln!(js, "    if ({}) {action}", conditions.join(" && "));
+            }
+            js.push_str("    return null;\n}\n");
+        }
+
+        js.push_str(
+            r#"
+function FindProxyForURL(url, host) {
+    var ip;
+    var d = {
+        host: host,
+        port: portOf(url),
+        ip: function () {
+            if (ip === undefined) ip = ipToNum(dnsResolve(host) || "");
+            return ip;
+        }
+    };
+    return table0(d, 0) || PROXY;
+}
+"#,
+        );
+        js
+    }
+}
+
+fn render_condition(c: &PacCondition) -> String {
+    match c {
+        PacCondition::Host(_, js) => format!("/{js}/.test(d.host)"),
+        PacCondition::Ip(ranges) => {
+            let mut flat = String::new();
+            for (start, end) in ranges {
+                if !flat.is_empty() {
+                    flat.push(',');
+                }
+                let _ = write!(flat, "{start},{end}");
+            }
+            format!("inRanges([{flat}], d.ip())")
+        }
+        PacCondition::Port(p) => format!("d.port >= {} && d.port <= {}", p.start(), p.end()),
+    }
+}
+
+// Translates a Rust regex into the body of an equivalent JavaScript regex literal. Syntax
+// the two disagree on (inline flags, named groups, Unicode and POSIX classes, nested or
+// set-operation classes, `\A`/`\z` anchors...) gives `None`, as one pattern JavaScript
+// rejects would break the whole script.
+fn js_regex(pattern: &str) -> Option<String> {
+    let mut js = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars().peekable();
+    let mut in_class = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                let escaped = chars.next()?;
+                let supported = match escaped {
+                    'd' | 'D' | 'w' | 'W' | 's' | 'S' | 'n' | 'r' | 't' | 'f' | 'v' => true,
+                    'b' | 'B' => !in_class,
+                    'x' => chars.peek().is_some_and(|c| c.is_ascii_hexdigit()),
+                    c => c.is_ascii_punctuation(),
+                };
+                if !supported {
+                    return None;
+                }
+                js.push(c);
+                js.push(escaped);
+            }
+            '[' if in_class => return None,
+            '[' => {
+                in_class = true;
+                js.push(c);
+                if chars.next_if_eq(&'^').is_some() {
+                    js.push('^');
+                }
+                // A leading `]` is a literal in Rust but closes an empty class in JavaScript
+                if chars.next_if_eq(&']').is_some() {
+                    js.push_str("\\]");
+                }
+            }
+            ']' if in_class => {
+                in_class = false;
+                js.push(c);
+            }
+            '&' | '-' | '~' if in_class && chars.peek() == Some(&c) => return None,
+            '(' if !in_class && chars.peek() == Some(&'?') => {
+                chars.next();
+                if chars.next()? != ':' {
+                    return None;
+                }
+                js.push_str("(?:");
+            }
+            '/' => js.push_str("\\/"),
+            c if c.is_control() => return None,
+            c => js.push(c),
+        }
+    }
+
+    (!in_class).then_some(js)
+}
+
+const PAC_HELPERS: &str = r#"
+function ipToNum(ip) {
+    var p = ip.split(".");
+    if (p.length != 4) return -1;
+    return ((+p[0]) * 16777216) + ((+p[1]) << 16) + ((+p[2]) << 8) + (+p[3]);
+}
+
+function inRanges(ranges, ip) {
+    var lo = 0, hi = ranges.length / 2 - 1;
+    if (ip < 0) return false;
+    while (lo <= hi) {
+        var mid = (lo + hi) >> 1;
+        if (ip < ranges[mid * 2]) hi = mid - 1;
+        else if (ip > ranges[mid * 2 + 1]) lo = mid + 1;
+        else return true;
+    }
+    return false;
+}
+
+function portOf(url) {
+    var m = /^[a-z][a-z0-9+.-]*:\/\/(?:[^\/@]*@)?(?:\[[^\]]*\]|[^:\/]*):(\d+)/i.exec(url);
+    if (m) return +m[1];
+    return url.substring(0, 6).toLowerCase() == "https:" ? 443 : 80;
+}
+"#;
+
+// Generates a proxy auto-config script that mirrors the rules: DIRECT where they pick
+// a direct upstream, and the SOCKS5 proxy at `proxy` for everything else.
+pub fn generate_pac(rules: &RuleString, is_direct: impl Fn(&str) -> bool, proxy: &str) -> String {
+    PacTables::new(rules, &is_direct).render(proxy)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    // Walks the tables the way the rendered script does
+    fn evaluate(tables: &PacTables, host: &str, ip: Option<Ipv4Addr>, port: u16) -> &'static str {
+        fn run(
+            tables: &PacTables,
+            index: usize,
+            level: usize,
+            host: &str,
+            ip: Option<u32>,
+            port: u16,
+        ) -> Option<&'static str> {
+            if level > MAX_JUMP_LEVEL {
+                return None;
+            }
+
+            for rule in &tables.tables[index] {
+                let matches = rule.conditions.iter().all(|c| match c {
+                    PacCondition::Host(p, _) => p.matches(host),
+                    PacCondition::Ip(ranges) => ip.map_or(false, |ip| {
+                        ranges
+                            .iter()
+                            .any(|(start, end)| (*start..=*end).contains(&ip))
+                    }),
+                    PacCondition::Port(p) => p.contains(&port),
+                });
+                if !matches {
+                    continue;
+                }
+
+                match rule.action {
+                    PacAction::Direct => return Some("DIRECT"),
+                    PacAction::Proxy => return Some("PROXY"),
+                    PacAction::Jump(t) => {
+                        if let Some(r) = run(tables, t, level + 1, host, ip, port) {
+                            return Some(r);
+                        }
+                    }
+                    PacAction::Return => return None,
+                }
+            }
+            None
+        }
+
+        run(tables, 0, 0, host, ip.map(u32::from), port).unwrap_or("PROXY")
+    }
+
+    #[test]
+    fn pac_follows_rules() {
+        let rules: RuleString = serde_json::from_value(serde_json::Value::String(
+            r#"
+main:
+  local -d domain:matches:\.example\.com -a proxy:direct
+  private -d network:192.168.0.0/16 -a proxy:direct
+  udp -p udp -a proxy:direct
+  nz -d geoip:nz -a jump:nz
+  blocked -d domain:matches:^ads\. -a reject
+  source -d src_ip:10.0.0.0/8 -a proxy:direct
+  rest -a proxy:direct
+nz:
+  https -d port:443 -a proxy:direct
+  all -a return
+"#
+            .to_string(),
+        ))
+        .unwrap();
+
+        let tables = PacTables::new(&rules, &|name| name == "direct");
+        let nz_ip = Some("122.61.248.102".parse().unwrap());
+
+        assert_eq!(evaluate(&tables, "www.example.com", None, 80), "DIRECT");
+        assert_eq!(evaluate(&tables, "www.example.org", None, 80), "PROXY");
+        assert_eq!(
+            evaluate(&tables, "nas", Some(Ipv4Addr::new(192, 168, 1, 2)), 80),
+            "DIRECT"
+        );
+        assert_eq!(evaluate(&tables, "kiwi.nz", nz_ip, 443), "DIRECT");
+        assert_eq!(evaluate(&tables, "kiwi.nz", nz_ip, 80), "PROXY");
+        // The src_ip rule can't be checked in a PAC script, so nothing after it goes direct
+        assert_eq!(evaluate(&tables, "ads.example.org", None, 80), "PROXY");
+        assert_eq!(tables.tables[0].last().unwrap().action, PacAction::Proxy);
+
+        let js = generate_pac(&rules, |name| name == "direct", "127.0.0.1:5000");
+        assert!(js.starts_with("var PROXY = \"SOCKS5 127.0.0.1:5000; SOCKS 127.0.0.1:5000\";"));
+
+        let escaped = generate_pac(&rules, |_| false, "\";alert(1);//");
+        assert!(
+            escaped.starts_with(r#"var PROXY = "SOCKS5 \";alert(1);//; SOCKS \";alert(1);//";"#)
+        );
+        assert!(js.contains("function FindProxyForURL(url, host)"));
+        assert!(js.contains(r#"if (/\.example\.com/.test(d.host)) return "DIRECT";"#));
+        assert!(js.contains("if (inRanges([3232235520,3232301055], d.ip())) return \"DIRECT\";"));
+        assert!(js.contains("{ var r = table1(d, level + 1); if (r) return r; }"));
+        assert!(js.contains("if (d.port >= 443 && d.port <= 443) return \"DIRECT\";"));
+    }
+
+    #[test]
+    fn js_regex_works() {
+        assert_eq!(js_regex(r"^ads\.").as_deref(), Some(r"^ads\."));
+        assert_eq!(js_regex(r"a/b\/c").as_deref(), Some(r"a\/b\/c"));
+        assert_eq!(
+            js_regex(r"(?:www\.)?x[^/.]\d+$").as_deref(),
+            Some(r"(?:www\.)?x[^\/.]\d+$")
+        );
+        assert_eq!(js_regex(r"[]a]").as_deref(), Some(r"[\]a]"));
+        assert_eq!(js_regex(r"\x41").as_deref(), Some(r"\x41"));
+
+        for pattern in [
+            r"(?i)example",
+            r"(?P<name>a)",
+            r"\Aexample\z",
+            r"\pL",
+            r"[[:alpha:]]",
+            r"[a-z&&[^x]]",
+            r"\x{41}",
+            "a\nb",
+        ] {
+            assert_eq!(js_regex(pattern), None, "{pattern}");
+        }
+    }
+
+    #[test]
+    fn incompatible_patterns_go_to_proxy() {
+        let rules: RuleString = serde_json::from_value(serde_json::Value::String(
+            r#"
+main:
+  ok -d domain:matches:^good\. -a proxy:direct
+  rust -d domain:matches:(?i)^bad\. -a proxy:direct
+  rest -a proxy:direct
+"#
+            .to_string(),
+        ))
+        .unwrap();
+
+        let tables = PacTables::new(&rules, &|name| name == "direct");
+        assert_eq!(evaluate(&tables, "good.example", None, 80), "DIRECT");
+        assert_eq!(evaluate(&tables, "other.example", None, 80), "PROXY");
+
+        let js = tables.render("127.0.0.1:5000");
+        assert!(js.contains(r#"if (/^good\./.test(d.host)) return "DIRECT";"#));
+        assert!(!js.contains("(?i)"));
+    }
+}
@@ -0,0 +1,69 @@
+use std::{ops::RangeInclusive, str::FromStr};
+
+use anyhow::{bail, Context};
+
+// A list of destination ports the server will connect to. An empty list allows all ports.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AllowedPorts(Vec<RangeInclusive<u16>>);
+
+impl AllowedPorts {
+    pub fn allows(&self, port: u16) -> bool {
+        self.0.is_empty() || self.0.iter().any(|r| r.contains(&port))
+    }
+
+    pub fn check(&self, port: u16) -> anyhow::Result<()> {
+        if !self.allows(port) {
+            bail!("Destination port {port} is not allowed");
+        }
+        Ok(())
+    }
+}
+
+// Parses a list like "80,443,8000-9000"
+impl FromStr for AllowedPorts {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut ranges = Vec::new();
+        for item in s.split(',').map(str::trim).filter(|v| !v.is_empty()) {
+            let (start, end) = item.split_once('-').unwrap_or((item, item));
+            let start: u16 = start
+                .trim()
+                .parse()
+                .with_context(|| format!("Parsing port range {item}"))?;
+            let end: u16 = end
+                .trim()
+                .parse()
+                .with_context(|| format!("Parsing port range {item}"))?;
+
+            if start > end {
+                bail!("Invalid port range {item}");
+            }
+
+            ranges.push(start..=end);
+        }
+
+        Ok(Self(ranges))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allowed_ports_works() {
+        let ports: AllowedPorts = "80, 443,8000-9000".parse().unwrap();
+        assert!(ports.allows(443));
+        assert!(ports.allows(80));
+        assert!(ports.allows(8000));
+        assert!(ports.allows(9000));
+        assert!(!ports.allows(25));
+        assert!(!ports.allows(9001));
+        assert!(ports.check(25).is_err());
+
+        assert!(AllowedPorts::default().allows(25));
+        assert!("9000-8000".parse::<AllowedPorts>().is_err());
+        assert!("http".parse::<AllowedPorts>().is_err());
+    }
+}
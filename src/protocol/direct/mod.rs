@@ -1,13 +1,14 @@
 use super::{Protocol, Stats, TrafficType};
 use crate::io::{
-    bind_udp, connect_tcp, send_to_addr, AsRawFdExt, AsyncStreamCounter, UdpSocketExt,
+    bind_udp, connect_tcp_with, mark_dscp, send_to_addr, write_initial_data, AsRawFdExt,
+    AsyncStreamCounter, TcpOptions, TcpPeer, UdpSocketExt,
 };
 use crate::protocol::{AsyncStream, BoxedSink, BoxedStream};
 use crate::socks5::Address;
 use anyhow::Context;
 use async_trait::async_trait;
 use bytes::Bytes;
-use futures::{AsyncWriteExt, TryStreamExt};
+use futures::TryStreamExt;
 use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
@@ -26,19 +27,11 @@ impl Protocol for Direct {
         dst: &Address<'_>,
         initial_data: Option<&[u8]>,
         stats: &Stats,
-        fwmark: Option<u32>,
+        options: &TcpOptions,
     ) -> anyhow::Result<Box<dyn AsyncStream>> {
-        let mut stream = connect_tcp(dst).await?;
-        if let Some(fwmark) = fwmark {
-            stream.set_sock_mark(fwmark)?;
-        }
+        let mut stream = connect_tcp_with(dst, TcpPeer::Destination, options).await?;
 
-        match initial_data {
-            Some(b) if b.len() > 0 => {
-                stream.write_all(b).await.context("Writing initial data")?;
-            }
-            _ => {}
-        };
+        write_initial_data(&mut stream, initial_data, dst).await?;
 
         Ok(Box::new(AsyncStreamCounter::new(
             stream,
@@ -52,15 +45,16 @@ impl Protocol for Direct {
         dst: &Address<'_>,
         initial_data: Bytes,
         stats: &Stats,
-        fwmark: Option<u32>,
+        options: &TcpOptions,
     ) -> anyhow::Result<(BoxedSink, BoxedStream)> {
         let socket = bind_udp(!matches!(dst, Address::IP(SocketAddr::V4(_))))
             .await
             .context("Binding UDP socket")?;
 
-        if let Some(m) = fwmark {
+        if let Some(m) = options.fwmark {
             socket.set_sock_mark(m)?;
         }
+        mark_dscp(&socket, socket.local_addr(), options.dscp)?;
 
         send_to_addr(&socket, initial_data.as_ref(), dst)
             .await
@@ -69,10 +63,11 @@ impl Protocol for Direct {
         let (sink, stream) = socket.to_sink_stream().split();
         let tx = stats.tx.clone();
         let rx = stats.rx.clone();
+        let family = options.address_family;
         Ok((
             Box::pin(sink.with(move |(data, addr): (Bytes, Address<'static>)| {
                 tx.inc(data.len());
-                async move { Ok((data, addr.resolve_first().await?)) }
+                async move { Ok((data, addr.resolve_first(family).await?)) }
             })),
             Box::pin(stream.map_ok(move |(data, addr)| {
                 rx.inc(data.len());
@@ -21,7 +21,12 @@ mod test {
         block_on(async move {
             let (server, server_addr) = create_tcp_server().await;
             let pw = PasswordedKey::new("123456");
-            let _task = spawn(run_server(server, pw.clone()));
+            let _task = spawn(run_server(
+                server,
+                pw.clone(),
+                Default::default(),
+                Default::default(),
+            ));
 
             let protocol = FireTcp::new(server_addr.into(), pw);
 
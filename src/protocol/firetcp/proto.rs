@@ -2,7 +2,7 @@ use anyhow::Context;
 use async_trait::async_trait;
 use chacha20::ChaCha20;
 use cipher::KeyIvInit;
-use futures::{AsyncReadExt, AsyncWriteExt};
+use futures::AsyncReadExt;
 use serde::{Deserialize, Serialize};
 
 use super::{
@@ -10,7 +10,7 @@ use super::{
     pw::PasswordedKey,
 };
 use crate::{
-    io::{connect_tcp_marked, union, AsyncStreamCounter},
+    io::{connect_tcp_with, union, write_initial_data, AsyncStreamCounter, TcpOptions, TcpPeer},
     socks5::Address,
     utils::write_bincode_lengthed_async,
 };
@@ -30,6 +30,10 @@ impl FireTcp {
     pub fn new(address: Address<'static>, password: PasswordedKey) -> Self {
         Self { address, password }
     }
+
+    pub fn address(&self) -> &Address<'static> {
+        &self.address
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -58,10 +62,10 @@ impl Protocol for FireTcp {
         dst: &Address<'_>,
         initial_data: Option<&[u8]>,
         stats: &Stats,
-        fwmark: Option<u32>,
+        options: &TcpOptions,
     ) -> anyhow::Result<Box<dyn AsyncStream>> {
         let (r, w) = AsyncStreamCounter::new(
-            connect_tcp_marked(&self.address, fwmark)
+            connect_tcp_with(&self.address, TcpPeer::Upstream, options)
                 .await
                 .context("Connecting to firetcp server")?,
             stats.rx.clone(),
@@ -101,9 +105,7 @@ impl Protocol for FireTcp {
         .await
         .context("Unable to send request")?;
 
-        if let Some(b) = initial_data {
-            w.write_all(b).await.context("Sending initial data")?;
-        }
+        write_initial_data(&mut w, initial_data, dst).await?;
 
         Ok(Box::new(union(r, w)))
     }
@@ -10,27 +10,54 @@ use futures::{AsyncRead, AsyncReadExt, AsyncWrite};
 use parking_lot::Mutex;
 use smol::{spawn, Task};
 use std::net::SocketAddr;
+use std::time::Instant;
 
 use crate::io::connect_tcp;
+use crate::protocol::allowed_ports::AllowedPorts;
+use crate::protocol::load_shed::LoadShedder;
+use crate::protocol::log_sampler::CONNECTION_LOG_SAMPLER;
 use crate::utils::{race, read_bincode_lengthed_async};
 
 use super::cipher::CipherRead;
 use super::proto::{CipherOption, Request, INITIAL_CIPHER_LEN};
 use super::pw::PasswordedKey;
 
-pub async fn run_server(listener: TcpListener, password: PasswordedKey) -> anyhow::Result<()> {
+pub async fn run_server(
+    listener: TcpListener,
+    password: PasswordedKey,
+    allowed_ports: AllowedPorts,
+    load_shedder: LoadShedder,
+) -> anyhow::Result<()> {
     let clients: Arc<Mutex<BTreeMap<SocketAddr, Task<()>>>> = Default::default();
     let password = Arc::new(password);
+    let allowed_ports = Arc::new(allowed_ports);
     loop {
         let (client, addr) = listener.accept().await?;
-        log::info!("Accepted client from {addr}");
+        let Some(permit) = load_shedder.try_acquire() else {
+            if CONNECTION_LOG_SAMPLER.sample() {
+                log::warn!(
+                    "Refusing client {addr}: {} active connections",
+                    load_shedder.active_connections()
+                );
+            }
+            continue;
+        };
+
+        log::debug!("Accepted client from {addr}");
         let clients_ref = Arc::downgrade(&clients);
         let password = password.clone();
+        let allowed_ports = allowed_ports.clone();
         clients.lock().insert(
             addr,
             spawn(async move {
-                if let Err(e) = serve(client, &password).await {
-                    log::error!("Error serving {addr}: {e:?}");
+                let _permit = permit;
+                let started = Instant::now();
+                match serve(client, &password, &allowed_ports).await {
+                    Err(e) => log::error!("Error serving {addr}: {e:?}"),
+                    Ok(_) if CONNECTION_LOG_SAMPLER.sample() => {
+                        log::info!("Client {addr} disconnected after {:?}", started.elapsed())
+                    }
+                    Ok(_) => {}
                 }
                 if let Some(clients) = clients_ref.upgrade() {
                     clients.lock().remove(&addr);
@@ -43,6 +70,7 @@ pub async fn run_server(listener: TcpListener, password: PasswordedKey) -> anyho
 pub async fn serve(
     stream: impl AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
     password: &PasswordedKey,
+    allowed_ports: &AllowedPorts,
 ) -> anyhow::Result<()> {
     let (r, mut w) = stream.split();
 
@@ -58,6 +86,8 @@ pub async fn serve(
         .await
         .context("Reading request")?;
 
+    allowed_ports.check(addr.get_port())?;
+
     r.set_establish_cipher(
         est_cipher.map(|CipherOption { key, nonce }| ChaCha20::new(&key.into(), &nonce.into())),
     )
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Context};
+use md5::{Digest, Md5};
+use rand::Rng;
+
+use crate::protocol::tcpman::Credentials;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Challenge {
+    pub scheme: String,
+    pub params: HashMap<String, String>,
+}
+
+// Parses the `key=value, key="quoted value"` list that follows an auth scheme.
+// Keys are lowercased.
+pub fn parse_params(mut s: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    loop {
+        s = s.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+        let Some((key, rest)) = s.split_once('=') else {
+            break;
+        };
+
+        let rest = rest.trim_start();
+        let (value, rest) = match rest.strip_prefix('"') {
+            Some(quoted) => {
+                let mut value = String::new();
+                let mut end = quoted.len();
+                let mut chars = quoted.char_indices();
+                while let Some((i, c)) = chars.next() {
+                    match c {
+                        '\\' => value.extend(chars.next().map(|(_, c)| c)),
+                        '"' => {
+                            end = i + 1;
+                            break;
+                        }
+                        c => value.push(c),
+                    }
+                }
+                (value, &quoted[end..])
+            }
+            None => {
+                let (value, rest) = rest.split_once(',').unwrap_or((rest, ""));
+                (value.trim().to_string(), rest)
+            }
+        };
+
+        params.insert(key.trim().to_ascii_lowercase(), value);
+        s = rest;
+    }
+    params
+}
+
+// Parses one `Proxy-Authenticate` header value, e.g. `Digest realm="x", nonce="y"`
+pub fn parse_challenge(value: &str) -> Option<Challenge> {
+    let value = value.trim();
+    let (scheme, params) = value.split_once(' ').unwrap_or((value, ""));
+    if scheme.is_empty() {
+        return None;
+    }
+
+    Some(Challenge {
+        scheme: scheme.to_ascii_lowercase(),
+        params: parse_params(params),
+    })
+}
+
+fn md5_hex(input: impl AsRef<[u8]>) -> String {
+    format!("{:x}", Md5::digest(input))
+}
+
+fn digest_authorization(
+    params: &HashMap<String, String>,
+    credentials: &Credentials,
+    method: &str,
+    uri: &str,
+) -> anyhow::Result<String> {
+    let realm = params.get("realm").map(String::as_str).unwrap_or_default();
+    let nonce = params
+        .get("nonce")
+        .context("Digest challenge without nonce")?;
+    let cnonce = format!("{:016x}", rand::thread_rng().gen::<u64>());
+    let nc = "00000001";
+
+    let mut ha1 = md5_hex(format!(
+        "{}:{realm}:{}",
+        credentials.username, credentials.password
+    ));
+    match params.get("algorithm").map(|a| a.to_ascii_lowercase()) {
+        None => {}
+        Some(a) if a == "md5" => {}
+        Some(a) if a == "md5-sess" => ha1 = md5_hex(format!("{ha1}:{nonce}:{cnonce}")),
+        Some(a) => bail!("Unsupported digest algorithm {a}"),
+    }
+    let ha2 = md5_hex(format!("{method}:{uri}"));
+
+    let qop = match params.get("qop") {
+        None => None,
+        Some(qop) if qop.split(',').any(|q| q.trim() == "auth") => Some("auth"),
+        Some(qop) => bail!("Unsupported digest qop {qop}"),
+    };
+
+    let mut header = format!(
+        "Digest username=\"{}\", realm=\"{realm}\", nonce=\"{nonce}\", uri=\"{uri}\"",
+        credentials.username
+    );
+    let response = match qop {
+        Some(qop) => {
+            header += &format!(", qop={qop}, nc={nc}, cnonce=\"{cnonce}\"");
+            md5_hex(format!("{ha1}:{nonce}:{nc}:{cnonce}:{qop}:{ha2}"))
+        }
+        None => md5_hex(format!("{ha1}:{nonce}:{ha2}")),
+    };
+    header += &format!(", response=\"{response}\"");
+
+    if let Some(algorithm) = params.get("algorithm") {
+        header += &format!(", algorithm={algorithm}");
+    }
+    if let Some(opaque) = params.get("opaque") {
+        header += &format!(", opaque=\"{opaque}\"");
+    }
+
+    Ok(header)
+}
+
+// Answers the first supported challenge with a `Proxy-Authorization` value.
+// Digest is preferred over Basic as it doesn't send the password.
+pub fn authorization<'a>(
+    challenges: impl IntoIterator<Item = &'a str>,
+    credentials: &Credentials,
+    method: &str,
+    uri: &str,
+) -> anyhow::Result<String> {
+    let challenges: Vec<_> = challenges.into_iter().filter_map(parse_challenge).collect();
+
+    if let Some(c) = challenges.iter().find(|c| c.scheme == "digest") {
+        return digest_authorization(&c.params, credentials, method, uri);
+    }
+
+    if challenges.iter().any(|c| c.scheme == "basic") {
+        return Ok(credentials.to_header_value().to_string());
+    }
+
+    bail!(
+        "No supported proxy authentication scheme in {:?}",
+        challenges.iter().map(|c| &c.scheme).collect::<Vec<_>>()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn challenges_parse() {
+        let challenge = parse_challenge(
+            r#"Digest realm="proxy, inc", nonce="abc", qop="auth,auth-int", stale=FALSE"#,
+        )
+        .unwrap();
+        assert_eq!(challenge.scheme, "digest");
+        assert_eq!(challenge.params["realm"], "proxy, inc");
+        assert_eq!(challenge.params["nonce"], "abc");
+        assert_eq!(challenge.params["qop"], "auth,auth-int");
+        assert_eq!(challenge.params["stale"], "FALSE");
+
+        let challenge = parse_challenge(r#"Basic realm="a \"quoted\" realm""#).unwrap();
+        assert_eq!(challenge.scheme, "basic");
+        assert_eq!(challenge.params["realm"], r#"a "quoted" realm"#);
+    }
+
+    #[test]
+    fn unsupported_schemes_fail() {
+        let credentials = Credentials {
+            username: "user".to_string(),
+            password: "pass".to_string(),
+            password_source: None,
+        };
+        let err = authorization(["Negotiate", "NTLM"], &credentials, "CONNECT", "a:1")
+            .expect_err("No supported scheme");
+        assert!(err.to_string().contains("negotiate"), "{err:?}");
+    }
+}
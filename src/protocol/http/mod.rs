@@ -1,51 +1,107 @@
+mod auth;
 pub mod server;
 
-use anyhow::{bail, Context};
+use anyhow::{anyhow, Context};
 use async_trait::async_trait;
-use futures::AsyncWriteExt;
+use futures::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 
 use crate::{
     buf::RWBuffer,
-    fetch::connect_http_stream,
-    http::{parse_response, HttpRequestBuilder},
-    io::{connect_tcp_marked, AsyncStreamCounter},
-    socks5::Address,
+    fetch::{connect_http_stream, connect_with_tls_fallback, TlsModes},
+    http::{parse_response, AsyncHttpStream, HttpRequestBuilder, HttpResponse},
+    io::{
+        connect_tcp_with, read_ahead, write_initial_data, AsyncStreamCounter, TcpOptions, TcpPeer,
+    },
+    socks5::{Address, ConnStatusCode},
+    tls::{ClientIdentity, TlsOptions},
 };
 
-use super::{AsyncStream, Protocol, Stats, TrafficType};
+use super::{tcpman::Credentials, AsyncStream, Protocol, Stats, TrafficType};
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct HttpProxy {
     pub address: Address<'static>,
     pub ssl: bool,
     pub auth_header: Option<String>,
+    #[serde(default)]
+    pub client_identity: Option<ClientIdentity>,
+    #[serde(default)]
+    pub sni: Option<String>,
+    #[serde(default)]
+    pub alpn: Option<Vec<String>>,
+    // Used to answer a 407 challenge from the proxy
+    #[serde(default)]
+    pub credentials: Option<Credentials>,
+    // Retry with the opposite of `ssl` if connecting fails, remembering what worked
+    #[serde(default)]
+    pub ssl_fallback: bool,
+    // Bytes read ahead from the proxy at a time. Reads aren't buffered if it isn't set.
+    #[serde(default)]
+    pub read_buffer_size: Option<usize>,
 }
 
-#[async_trait]
-impl Protocol for HttpProxy {
-    fn supports(&self, t: TrafficType) -> bool {
-        t == TrafficType::Stream
+lazy_static! {
+    static ref TLS_MODES: TlsModes<HttpProxy> = Default::default();
+}
+
+impl HttpProxy {
+    fn tls_options(&self) -> TlsOptions<'_> {
+        TlsOptions {
+            identity: self.client_identity.as_ref(),
+            sni: self.sni.as_deref(),
+            alpn: self.alpn.as_deref(),
+        }
     }
 
-    async fn new_stream(
+    async fn send_connect(
         &self,
         dst: &Address<'_>,
         initial_data: Option<&[u8]>,
         stats: &Stats,
-        fwmark: Option<u32>,
-    ) -> anyhow::Result<Box<dyn AsyncStream>> {
-        let upstream = connect_tcp_marked(&self.address, fwmark)
+        options: &TcpOptions,
+        authorization: Option<&str>,
+    ) -> anyhow::Result<
+        AsyncHttpStream<HttpResponse<'static>, impl AsyncRead + AsyncWrite + Unpin + Send + Sync>,
+    > {
+        // Credentials never go out in plain text, unless that's how it's configured
+        let allow_plain = self.auth_header.is_none() && self.credentials.is_none();
+        connect_with_tls_fallback(
+            (&TLS_MODES, self),
+            &self.address,
+            self.ssl,
+            self.ssl_fallback,
+            allow_plain,
+            |tls| self.send_connect_with_tls(tls, dst, initial_data, stats, options, authorization),
+        )
+        .await
+    }
+
+    async fn send_connect_with_tls(
+        &self,
+        tls: bool,
+        dst: &Address<'_>,
+        initial_data: Option<&[u8]>,
+        stats: &Stats,
+        options: &TcpOptions,
+        authorization: Option<&str>,
+    ) -> anyhow::Result<
+        AsyncHttpStream<HttpResponse<'static>, impl AsyncRead + AsyncWrite + Unpin + Send + Sync>,
+    > {
+        let upstream = connect_tcp_with(&self.address, TcpPeer::Upstream, options)
             .await
             .context("Connecting to HTTP Proxy")?;
+        let upstream = read_ahead(upstream, self.read_buffer_size);
 
-        let upstream = connect_http_stream(self.ssl, &self.address, upstream).await?;
+        let upstream =
+            connect_http_stream(tls, &self.address, upstream, self.tls_options()).await?;
 
         let mut upstream = AsyncStreamCounter::new(upstream, stats.rx.clone(), stats.tx.clone());
 
         let mut request = HttpRequestBuilder::new("CONNECT", dst)?;
-        if let Some(auth_header) = &self.auth_header {
-            request.put_header_text("Proxy-Authorization", auth_header)?;
+        if let Some(authorization) = authorization {
+            request.put_header_text("Proxy-Authorization", authorization)?;
         }
 
         upstream
@@ -53,23 +109,67 @@ impl Protocol for HttpProxy {
             .await
             .context("Writing CONNECT request")?;
 
-        match initial_data {
-            Some(d) if d.len() > 0 => upstream
-                .write_all(d)
-                .await
-                .context("Writing initial data")?,
-            _ => {}
-        };
+        write_initial_data(&mut upstream, initial_data, dst).await?;
 
-        let upstream = parse_response(upstream, RWBuffer::new_vec_uninitialised(128))
+        parse_response(upstream, RWBuffer::new_vec_uninitialised(128))
             .await
-            .context("Parsing response")?;
+            .context("Parsing response")
+    }
+}
+
+#[async_trait]
+impl Protocol for HttpProxy {
+    fn supports(&self, t: TrafficType) -> bool {
+        t == TrafficType::Stream
+    }
+
+    async fn new_stream(
+        &self,
+        dst: &Address<'_>,
+        initial_data: Option<&[u8]>,
+        stats: &Stats,
+        options: &TcpOptions,
+    ) -> anyhow::Result<Box<dyn AsyncStream>> {
+        let upstream = self
+            .send_connect(
+                dst,
+                initial_data,
+                stats,
+                options,
+                self.auth_header.as_deref(),
+            )
+            .await?;
+
+        let upstream = match (upstream.status_code, &self.credentials) {
+            (407, Some(credentials)) => {
+                let challenges = upstream
+                    .headers
+                    .iter()
+                    .filter(|(n, _)| n.eq_ignore_ascii_case("Proxy-Authenticate"))
+                    .filter_map(|(_, v)| std::str::from_utf8(v).ok());
+                let authorization =
+                    auth::authorization(challenges, credentials, "CONNECT", &dst.to_string())
+                        .context("Answering HTTP Proxy authentication challenge")?;
+
+                // The initial data was sent after the rejected CONNECT, so retry on a new connection
+                drop(upstream);
+                self.send_connect(dst, initial_data, stats, options, Some(&authorization))
+                    .await?
+            }
+            _ => upstream,
+        };
 
         if upstream.status_code != 200 {
-            bail!(
+            let err = anyhow!(
                 "Invalid status code from HTTP Proxy: {}",
                 upstream.status_code
             );
+            // The proxy refusing or failing to reach the destination still answered for itself
+            return Err(match upstream.status_code {
+                403 => anyhow::Error::new(ConnStatusCode::NOT_ALLOWED).context(err),
+                502..=504 => anyhow::Error::new(ConnStatusCode::HOST_UNREACHABLE).context(err),
+                _ => err,
+            });
         }
 
         Ok(Box::new(upstream))
@@ -78,34 +178,294 @@ impl Protocol for HttpProxy {
 
 #[cfg(test)]
 mod tests {
-    use smol::spawn;
+    use futures::AsyncReadExt;
+    use md5::{Digest, Md5};
+    use smol::{spawn, Task};
+    use smol_timeout::TimeoutExt;
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
 
     use super::*;
     use crate::{
+        http::{parse_request, WithHeaders},
+        io::connect_tcp,
         protocol::{
             direct::Direct,
             test::{test_protocol_http, test_protocol_tcp},
         },
-        test::create_http_server,
+        test::{create_http_server, create_tcp_server, echo_tcp_server},
         url::HttpUrl,
+        utils::copy_duplex,
     };
 
+    // A proxy that answers 407 with `challenge` unless `accepts` the Proxy-Authorization
+    async fn run_auth_proxy(
+        challenge: &'static str,
+        accepts: fn(&str) -> bool,
+    ) -> (Task<()>, SocketAddr) {
+        let (listener, addr) = create_tcp_server().await;
+        let task = spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                spawn(async move {
+                    let mut req = parse_request(stream, RWBuffer::new_vec_uninitialised(4096))
+                        .await
+                        .map_err(|(e, _)| e)?;
+                    // Without a challenge, everyone is let through
+                    let authorization = req.get_header_text("Proxy-Authorization");
+                    if !challenge.is_empty() && !authorization.map(accepts).unwrap_or(false) {
+                        let response = format!(
+                            "HTTP/1.1 407 Proxy Authentication Required\r\n\
+                            Proxy-Authenticate: {challenge}\r\n\
+                            Content-Length: 0\r\n\r\n"
+                        );
+                        return req.write_all(response.as_bytes()).await.map_err(Into::into);
+                    }
+
+                    let dst = Address::try_from(req.path.as_ref())?.into_owned();
+                    let upstream = connect_tcp(&dst).await?;
+                    req.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await?;
+                    copy_duplex(req, upstream, None, None).await
+                })
+                .detach();
+            }
+        });
+        (task, addr)
+    }
+
+    async fn assert_proxy_auth_works(proxy_addr: SocketAddr) {
+        let (_echo_task, echo_addr) = echo_tcp_server().await;
+        let protocol = HttpProxy {
+            address: proxy_addr.into(),
+            ssl: false,
+            auth_header: None,
+            client_identity: None,
+            sni: None,
+            alpn: None,
+            credentials: Some(Credentials {
+                username: "user".to_string(),
+                password: "pass".to_string(),
+                password_source: None,
+            }),
+            ssl_fallback: false,
+            read_buffer_size: None,
+        };
+
+        let mut stream = protocol
+            .new_stream(
+                &echo_addr.into(),
+                None,
+                &Default::default(),
+                &Default::default(),
+            )
+            .await
+            .expect("To authenticate with proxy");
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        let err = HttpProxy {
+            credentials: None,
+            ..protocol
+        }
+        .new_stream(
+            &echo_addr.into(),
+            None,
+            &Default::default(),
+            &Default::default(),
+        )
+        .await
+        .err()
+        .expect("To fail without credentials");
+        assert!(err.to_string().contains("407"), "{err:?}");
+    }
+
+    #[test]
+    fn http_proxy_retries_with_basic_auth() {
+        smol::block_on(async move {
+            let (_task, proxy_addr) = run_auth_proxy(r#"Basic realm="proxy""#, |auth| {
+                auth == "Basic dXNlcjpwYXNz"
+            })
+            .await;
+            assert_proxy_auth_works(proxy_addr).await;
+        });
+    }
+
+    #[test]
+    fn http_proxy_retries_with_digest_auth() {
+        fn md5_hex(s: String) -> String {
+            format!("{:x}", Md5::digest(s))
+        }
+
+        smol::block_on(async move {
+            let (_task, proxy_addr) = run_auth_proxy(
+                r#"Digest realm="proxy", nonce="n0nce", qop="auth", opaque="0paque""#,
+                |auth| {
+                    let Some(params) = auth.strip_prefix("Digest ") else {
+                        return false;
+                    };
+                    let p = auth::parse_params(params);
+                    let ha1 = md5_hex("user:proxy:pass".to_string());
+                    let ha2 = md5_hex(format!("CONNECT:{}", p["uri"]));
+                    let expected = md5_hex(format!(
+                        "{ha1}:n0nce:{}:{}:auth:{ha2}",
+                        p["nc"], p["cnonce"]
+                    ));
+                    p["username"] == "user" && p["opaque"] == "0paque" && p["response"] == expected
+                },
+            )
+            .await;
+            assert_proxy_auth_works(proxy_addr).await;
+        });
+    }
+
+    #[test]
+    fn ssl_fallback_sends_initial_data_once() {
+        smol::block_on(async move {
+            let (_proxy_task, proxy_addr) = run_auth_proxy("", |_| true).await;
+
+            // Echoes back, counting the connections made to it
+            let (target, target_addr) = create_tcp_server().await;
+            let accepted = Arc::new(AtomicUsize::new(0));
+            let _target_task = spawn({
+                let accepted = accepted.clone();
+                async move {
+                    loop {
+                        let (stream, _) = target.accept().await.unwrap();
+                        accepted.fetch_add(1, Ordering::SeqCst);
+                        let (r, mut w) = stream.split();
+                        spawn(async move { futures::io::copy(r, &mut w).await }).detach();
+                    }
+                }
+            });
+
+            // Credentials would go out in plain text, so there's no falling back with them
+            let protocol = HttpProxy {
+                address: proxy_addr.into(),
+                ssl: true,
+                auth_header: Some("Basic dXNlcjpwYXNz".to_string()),
+                client_identity: None,
+                sni: None,
+                alpn: None,
+                credentials: None,
+                ssl_fallback: true,
+                read_buffer_size: Some(4096),
+            };
+            let err = protocol
+                .new_stream(
+                    &target_addr.into(),
+                    Some(b"hello"),
+                    &Default::default(),
+                    &Default::default(),
+                )
+                .await
+                .err()
+                .expect("Not to fall back to plain");
+            assert!(crate::tls::is_tls_handshake_error(&err), "{err:?}");
+            assert_eq!(accepted.load(Ordering::SeqCst), 0);
+
+            let protocol = HttpProxy {
+                auth_header: None,
+                ..protocol
+            };
+            let mut stream = protocol
+                .new_stream(
+                    &target_addr.into(),
+                    Some(b"hello"),
+                    &Default::default(),
+                    &Default::default(),
+                )
+                .await
+                .expect("To fall back to plain");
+            stream.write_all(b" world").await.unwrap();
+
+            let mut buf = [0u8; 11];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello world");
+            assert!(stream
+                .read(&mut buf)
+                .timeout(Duration::from_millis(200))
+                .await
+                .is_none());
+            assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        });
+    }
+
     #[test]
     fn http_proxy_works() {
         let _ = env_logger::try_init();
         smol::block_on(async move {
             let (server, server_url) = create_http_server().await;
             let url: HttpUrl = server_url.as_str().try_into().unwrap();
-            spawn(super::server::serve(server, Direct {})).detach();
+            spawn(super::server::serve(server, Direct {}, Default::default())).detach();
 
             let protocol = HttpProxy {
                 address: url.address.clone().into_owned(),
                 ssl: url.is_https,
                 auth_header: None,
+                client_identity: None,
+                sni: None,
+                alpn: None,
+                credentials: None,
+                ssl_fallback: false,
+                read_buffer_size: None,
             };
 
             test_protocol_http(&protocol).await;
             test_protocol_tcp(&protocol).await;
         });
     }
+
+    #[test]
+    fn http_proxy_rejects_disallowed_ports() {
+        let _ = env_logger::try_init();
+        smol::block_on(async move {
+            let (_echo_task, echo_addr) = echo_tcp_server().await;
+            let (server, server_url) = create_http_server().await;
+            let url: HttpUrl = server_url.as_str().try_into().unwrap();
+            let allowed_ports = format!("443,{}", echo_addr.port()).parse().unwrap();
+            spawn(super::server::serve(server, Direct {}, allowed_ports)).detach();
+
+            let protocol = HttpProxy {
+                address: url.address.clone().into_owned(),
+                ssl: url.is_https,
+                auth_header: None,
+                client_identity: None,
+                sni: None,
+                alpn: None,
+                credentials: None,
+                ssl_fallback: false,
+                read_buffer_size: None,
+            };
+
+            protocol
+                .new_stream(
+                    &echo_addr.into(),
+                    None,
+                    &Default::default(),
+                    &Default::default(),
+                )
+                .await
+                .expect("Allowed port to connect");
+
+            let err = protocol
+                .new_stream(
+                    &"127.0.0.1:25".parse().unwrap(),
+                    None,
+                    &Default::default(),
+                    &Default::default(),
+                )
+                .await
+                .err()
+                .expect("Port 25 to be rejected");
+            assert!(err.to_string().contains("403"), "{err:?}");
+        });
+    }
 }
@@ -3,7 +3,7 @@ use std::sync::Arc;
 use crate::{
     buf::RWBuffer,
     http::{parse_request, HttpRequestBuilder},
-    protocol::Protocol,
+    protocol::{allowed_ports::AllowedPorts, Protocol},
     socks5::Address,
     url::HttpUrl,
     utils::copy_duplex,
@@ -16,12 +16,15 @@ use smol::spawn;
 pub async fn serve(
     stream: TcpListener,
     upstream: impl Protocol + Send + Sync + 'static,
+    allowed_ports: AllowedPorts,
 ) -> anyhow::Result<()> {
     let upstream = Arc::new(upstream);
+    let allowed_ports = Arc::new(allowed_ports);
     loop {
         let (stream, from) = stream.accept().await?;
         log::debug!("Client {from} connected");
         let upstream = upstream.clone();
+        let allowed_ports = allowed_ports.clone();
         spawn(async move {
             let mut stream =
                 match parse_request(stream, RWBuffer::new_vec_uninitialised(4096)).await {
@@ -47,6 +50,12 @@ pub async fn serve(
                     (false, url.address, Some(builder.finalise()))
                 }
             };
+            if let Err(e) = allowed_ports.check(dst.get_port()) {
+                let e = e.context(format!("Rejecting {dst} from {from}"));
+                stream.write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n").await?;
+                return Err(e);
+            }
+
             log::debug!("Sending HTTPPROXY://{dst} from {from} to upstream");
 
             let upstream = match upstream
@@ -54,7 +63,7 @@ pub async fn serve(
                     &dst,
                     initial_data.as_ref().map(Vec::as_slice),
                     &Default::default(),
-                    None,
+                    &Default::default(),
                 )
                 .await
             {
@@ -0,0 +1,46 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+// Refuses new connections once the number of active ones reaches the limit, so an
+// overloaded server turns clients away quickly instead of degrading everyone.
+// A limit of 0 disables shedding. Clones share the same count.
+#[derive(Debug, Clone, Default)]
+pub struct LoadShedder {
+    max_connections: usize,
+    active: Arc<AtomicUsize>,
+}
+
+impl LoadShedder {
+    pub fn new(max_connections: usize) -> Self {
+        Self {
+            max_connections,
+            active: Default::default(),
+        }
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    // Returns None if the connection should be shed. The connection counts as active
+    // until the permit is dropped.
+    pub fn try_acquire(&self) -> Option<ConnectionPermit> {
+        self.active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                (self.max_connections == 0 || active < self.max_connections).then_some(active + 1)
+            })
+            .ok()
+            .map(|_| ConnectionPermit(self.active.clone()))
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnectionPermit(Arc<AtomicUsize>);
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
@@ -0,0 +1,49 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Samples 1 in `rate` events to log, so busy servers don't log every connection
+#[derive(Debug)]
+pub struct LogSampler {
+    rate: AtomicUsize,
+    count: AtomicUsize,
+}
+
+impl LogSampler {
+    pub const fn new(rate: usize) -> Self {
+        Self {
+            rate: AtomicUsize::new(rate),
+            count: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn set_rate(&self, rate: usize) {
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+
+    pub fn sample(&self) -> bool {
+        let rate = self.rate.load(Ordering::Relaxed).max(1);
+        self.count
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(rate)
+    }
+}
+
+// Controls the connection summaries the servers log. Errors are always logged.
+pub static CONNECTION_LOG_SAMPLER: LogSampler = LogSampler::new(1);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_sampler_works() {
+        let sampler = LogSampler::new(10);
+        let logged = (0..1000).filter(|_| sampler.sample()).count();
+        assert_eq!(logged, 100);
+
+        sampler.set_rate(1);
+        assert!((0..10).all(|_| sampler.sample()));
+
+        sampler.set_rate(0);
+        assert!(sampler.sample());
+    }
+}
@@ -1,4 +1,4 @@
-use std::{pin::Pin, sync::Arc};
+use std::{any::Any, pin::Pin, sync::Arc};
 
 use anyhow::bail;
 use async_trait::async_trait;
@@ -6,11 +6,16 @@ use bytes::Bytes;
 use futures::{AsyncRead, AsyncWrite, Sink, Stream};
 
 use crate::counter::Counter;
+use crate::io::TcpOptions;
 use crate::socks5::Address;
+use crate::ws::CompressionStats;
 
+pub mod allowed_ports;
 pub mod direct;
 pub mod firetcp;
 pub mod http;
+pub mod load_shed;
+pub mod log_sampler;
 pub mod socks5;
 pub mod tcpman;
 pub mod udpman;
@@ -18,9 +23,16 @@ pub mod udpman;
 #[cfg(test)]
 mod test;
 
-pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static {
+    // Lets relaying find out what's underneath, e.g. a socket it can splice
+    fn as_any(&self) -> &dyn Any;
+}
 
-impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> AsyncStream for T {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static> AsyncStream for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
 
 pub type BoxedSink = Pin<Box<dyn Sink<(Bytes, Address<'static>), Error = anyhow::Error> + Send>>;
 pub type BoxedStream =
@@ -30,6 +42,8 @@ pub type BoxedStream =
 pub struct Stats {
     pub tx: Arc<Counter>,
     pub rx: Arc<Counter>,
+    // Only counted by upstreams that compress their traffic
+    pub compression: Arc<CompressionStats>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -47,7 +61,7 @@ pub trait Protocol {
         _dst: &Address<'_>,
         _initial_data: Option<&[u8]>,
         _stats: &Stats,
-        _fwmark: Option<u32>,
+        _options: &TcpOptions,
     ) -> anyhow::Result<Box<dyn AsyncStream>> {
         bail!("Stream unsupported")
     }
@@ -57,7 +71,7 @@ pub trait Protocol {
         _dst: &Address<'_>,
         _initial_data: Bytes,
         _stats: &Stats,
-        _fwmark: Option<u32>,
+        _options: &TcpOptions,
     ) -> anyhow::Result<(BoxedSink, BoxedStream)> {
         bail!("Datagram unsupported")
     }
@@ -1,25 +1,77 @@
-use std::{future::ready, net::SocketAddr};
+use std::{
+    future::ready,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context as TaskContext, Poll},
+};
 
 use anyhow::{bail, Context};
 use async_trait::async_trait;
 use bytes::Bytes;
-use futures::{AsyncRead, AsyncWrite, AsyncWriteExt, SinkExt, StreamExt, TryStreamExt};
+use futures::{stream, AsyncRead, AsyncWrite, Sink, SinkExt, Stream, StreamExt, TryStreamExt};
+use pin_project_lite::pin_project;
 use serde::{Deserialize, Serialize};
+use smol::net::TcpStream;
 
 use crate::{
-    io::{bind_udp, connect_tcp, connect_tcp_marked, AsRawFdExt, AsyncStreamCounter, UdpSocketExt},
+    io::{
+        bind_udp, connect_tcp_with, mark_dscp, write_initial_data, AsRawFdExt, AsyncStreamCounter,
+        TcpOptions, TcpPeer, UdpSocketExt,
+    },
     socks5::{
-        Address, ClientConnRequest, ClientGreeting, Command, ConnStatusCode, UdpPacket, UdpRepr,
-        AUTH_NO_PASSWORD,
+        fragment, Address, ClientConnRequest, ClientGreeting, Command, ConnStatusCode, Reassembler,
+        UdpPacket, AUTH_NO_PASSWORD,
     },
 };
 
 use super::{AsyncStream, BoxedSink, BoxedStream, Protocol, Stats, TrafficType};
 
+pin_project! {
+    // The sink or stream of a UDP association. Servers close the relay along with the
+    // control connection, so each holds it open for as long as it's in use.
+    struct UdpAssociation<T> {
+        #[pin]
+        inner: T,
+        _control: TcpStream,
+    }
+}
+
+impl<T: Stream> Stream for UdpAssociation<T> {
+    type Item = T::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        self.project().inner.poll_next(cx)
+    }
+}
+
+impl<I, T: Sink<I>> Sink<I> for UdpAssociation<T> {
+    type Error = T::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Result<(), T::Error>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: I) -> Result<(), T::Error> {
+        self.project().inner.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Result<(), T::Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Result<(), T::Error>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Socks5 {
     pub address: Address<'static>,
     pub supports_udp: bool,
+    // Datagrams larger than this are sent to the relay in fragments, which not every
+    // SOCKS5 server supports
+    #[serde(default)]
+    pub udp_mtu: Option<usize>,
 }
 
 async fn request_socks5(
@@ -53,12 +105,22 @@ async fn request_socks5(
         .context("Receiving conn response")?;
 
     if code != ConnStatusCode::GRANTED {
-        bail!("Invalid socks5 status code: {code:?}");
+        return Err(anyhow::Error::new(code).context("Invalid socks5 status code"));
     }
 
     Ok(addr)
 }
 
+// Where to send datagrams for a relay bound at `bound`. An unspecified address means the
+// relay listens on the same host as the SOCKS server.
+fn relay_address(bound: SocketAddr, control_peer: SocketAddr) -> SocketAddr {
+    if bound.ip().is_unspecified() {
+        SocketAddr::new(control_peer.ip(), bound.port())
+    } else {
+        bound
+    }
+}
+
 #[async_trait]
 impl Protocol for Socks5 {
     fn supports(&self, t: TrafficType) -> bool {
@@ -73,9 +135,9 @@ impl Protocol for Socks5 {
         dst: &Address<'_>,
         initial_data: Option<&[u8]>,
         stats: &Stats,
-        fwmark: Option<u32>,
+        options: &TcpOptions,
     ) -> anyhow::Result<Box<dyn AsyncStream>> {
-        let mut upstream = connect_tcp_marked(&self.address, fwmark)
+        let mut upstream = connect_tcp_with(&self.address, TcpPeer::Upstream, options)
             .await
             .context("Connecting to SOCKS sever")?;
         let _ = request_socks5(
@@ -89,13 +151,7 @@ impl Protocol for Socks5 {
         .context("Requesting SOCKS5 proxy")?;
 
         let mut upstream = AsyncStreamCounter::new(upstream, stats.rx.clone(), stats.tx.clone());
-        match initial_data {
-            Some(b) if b.len() > 0 => upstream
-                .write_all(b)
-                .await
-                .context("Writing initial data")?,
-            _ => {}
-        }
+        write_initial_data(&mut upstream, initial_data, dst).await?;
 
         Ok(Box::new(upstream))
     }
@@ -105,16 +161,12 @@ impl Protocol for Socks5 {
         dst: &Address<'_>,
         initial_data: Bytes,
         stats: &Stats,
-        fwmark: Option<u32>,
+        options: &TcpOptions,
     ) -> anyhow::Result<(BoxedSink, BoxedStream)> {
-        let mut socks_stream = connect_tcp(&self.address)
+        let mut socks_stream = connect_tcp_with(&self.address, TcpPeer::Upstream, options)
             .await
             .with_context(|| format!("Connecting to Socks5://{}", self.address))?;
 
-        if let Some(m) = fwmark {
-            socks_stream.set_sock_mark(m)?;
-        }
-
         let bounded = request_socks5(
             &mut socks_stream,
             &ClientConnRequest {
@@ -125,57 +177,238 @@ impl Protocol for Socks5 {
         .await
         .with_context(|| format!("Requesting SOCKS5 at: {}", self.address))?;
 
-        let client = bind_udp(matches!(bounded, Address::IP(SocketAddr::V4(_)))).await?;
+        let relay_addr = relay_address(
+            bounded
+                .resolve_first(options.address_family)
+                .await
+                .with_context(|| format!("Resolving UDP relay {bounded}"))?,
+            socks_stream.peer_addr()?,
+        );
+        let client = bind_udp(relay_addr.is_ipv4()).await?;
 
-        if let Some(m) = fwmark {
+        if let Some(m) = options.fwmark {
             client.set_sock_mark(m)?;
         }
+        mark_dscp(&client, client.local_addr(), options.dscp)?;
 
         let tx = stats.tx.clone();
         let rx = stats.rx.clone();
 
-        let relay_addr = bounded.resolve_first().await?;
         log::debug!("Sending to initial data to relay UDP server at {relay_addr}");
-        let initial_data = UdpRepr {
-            addr: dst,
-            payload: initial_data.as_ref(),
-            frag_no: 0,
+        for pkt in fragment(dst, initial_data, self.udp_mtu)? {
+            tx.inc(pkt.inner().len());
+            client
+                .send_to(pkt.inner().as_ref(), relay_addr)
+                .await
+                .context("Sending initial data")?;
         }
-        .to_packet()?
-        .into_inner();
-
-        tx.inc(initial_data.len());
-        client
-            .send_to(initial_data.as_ref(), relay_addr)
-            .await
-            .context("Sending initial data")?;
 
         let (sink, stream) = client.to_sink_stream().to_connected(relay_addr);
+        let mtu = self.udp_mtu;
+        let mut reassembler = Reassembler::default();
+
+        let sink = sink.sink_map_err(anyhow::Error::from).with_flat_map(
+            move |(data, dst): (Bytes, Address<'static>)| {
+                let tx = tx.clone();
+                let packets = match fragment(&dst, data, mtu) {
+                    Ok(v) => v,
+                    Err(e) => return stream::once(ready(Err(e))).left_stream(),
+                };
+                stream::iter(packets.into_iter().map(move |p| {
+                    tx.inc(p.inner().len());
+                    Ok(p.into_inner())
+                }))
+                .right_stream()
+            },
+        );
+        let stream = stream
+            .inspect_ok(move |pkt| rx.inc(pkt.len()))
+            .filter_map(move |pkt| {
+                ready(match pkt.and_then(UdpPacket::new_checked) {
+                    Ok(p) => reassembler.push(p).map(|(addr, data)| Ok((data, addr))),
+                    Err(e) => Some(Err(e)),
+                })
+            });
 
         Ok((
-            Box::pin(sink.with(move |(data, dst): (Bytes, Address<'static>)| {
-                let buf = UdpRepr {
-                    addr: &dst,
-                    frag_no: 0,
-                    payload: data,
-                }
-                .to_packet()
-                .map(UdpPacket::into_inner);
-                if let Ok(b) = &buf {
-                    tx.inc(b.len());
-                }
-                ready(buf)
-            })),
-            Box::pin(
-                stream
-                    .inspect_ok(move |pkt| rx.inc(pkt.len()))
-                    .filter_map(move |pkt| {
-                        ready(Some(
-                            pkt.and_then(UdpPacket::new_checked)
-                                .map(|p| (p.payload_bytes(), p.addr().into_owned())),
-                        ))
-                    }),
-            ),
+            Box::pin(UdpAssociation {
+                inner: sink,
+                _control: socks_stream.clone(),
+            }),
+            Box::pin(UdpAssociation {
+                inner: stream,
+                _control: socks_stream,
+            }),
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use futures::AsyncReadExt;
+    use smol::{net::UdpSocket, spawn, Task, Timer};
+    use smol_timeout::TimeoutExt;
+
+    use super::*;
+    use crate::test::create_tcp_server;
+
+    // A SOCKS5 server that grants UDP associations with `bound` as the relay address, and
+    // counts the control connections closed
+    async fn socks5_udp_server(
+        bound: Address<'static>,
+    ) -> (Task<()>, SocketAddr, Arc<AtomicUsize>) {
+        let (server, addr) = create_tcp_server().await;
+        let closed = Arc::new(AtomicUsize::new(0));
+        let task = spawn({
+            let closed = closed.clone();
+            async move {
+                while let Ok((mut stream, _)) = server.accept().await {
+                    let bound = bound.clone();
+                    let closed = closed.clone();
+                    spawn(async move {
+                        let mut greeting = [0u8; 3];
+                        stream.read_exact(&mut greeting).await.unwrap();
+                        ClientGreeting::respond(AUTH_NO_PASSWORD, &mut stream)
+                            .await
+                            .unwrap();
+
+                        let mut header = [0u8; 3];
+                        stream.read_exact(&mut header).await.unwrap();
+                        let _ = Address::parse_async(&mut stream).await.unwrap();
+                        ClientConnRequest::respond(&mut stream, ConnStatusCode::GRANTED, &bound)
+                            .await
+                            .unwrap();
+
+                        // The association lasts as long as the control connection
+                        let _ = stream.read(&mut [0u8; 1]).await;
+                        closed.fetch_add(1, Ordering::SeqCst);
+                    })
+                    .detach();
+                }
+            }
+        });
+        (task, addr, closed)
+    }
+
+    async fn assert_relay_receives(bound: impl FnOnce(u16) -> Address<'static>, relay_ip: &str) {
+        let relay = UdpSocket::bind((relay_ip, 0)).await.unwrap();
+        let (_server, server_addr, _) =
+            socks5_udp_server(bound(relay.local_addr().unwrap().port())).await;
+
+        let p = Socks5 {
+            address: server_addr.into(),
+            supports_udp: true,
+            udp_mtu: None,
+        };
+        let dst: Address = "1.2.3.4:53".parse().unwrap();
+        let _conn = p
+            .new_datagram(
+                &dst,
+                Bytes::from_static(b"hello"),
+                &Default::default(),
+                &Default::default(),
+            )
+            .await
+            .expect("To associate");
+
+        let mut buf = [0u8; 128];
+        let (len, _) = relay
+            .recv_from(&mut buf)
+            .timeout(Duration::from_secs(1))
+            .await
+            .expect("No timeout")
+            .unwrap();
+        let pkt = UdpPacket::new_checked(&buf[..len]).unwrap();
+        assert_eq!(pkt.addr(), dst);
+        assert_eq!(pkt.payload(), b"hello");
+    }
+
+    #[test]
+    fn udp_relay_on_v4() {
+        smol::block_on(assert_relay_receives(
+            |port| SocketAddr::from(([127, 0, 0, 1], port)).into(),
+            "127.0.0.1",
+        ));
+    }
+
+    #[test]
+    fn udp_relay_on_v6() {
+        smol::block_on(assert_relay_receives(
+            |port| format!("[::1]:{port}").parse().unwrap(),
+            "::1",
+        ));
+    }
+
+    #[test]
+    fn udp_relay_named_v6() {
+        // A name resolving to IPv6 without depending on DNS
+        smol::block_on(assert_relay_receives(
+            |port| Address::Name {
+                host: "::1".into(),
+                port,
+            },
+            "::1",
+        ));
+    }
+
+    #[test]
+    fn unspecified_relay_uses_socks_server() {
+        let control_peer: SocketAddr = "[::1]:1080".parse().unwrap();
+        assert_eq!(
+            relay_address("0.0.0.0:5000".parse().unwrap(), control_peer),
+            "[::1]:5000".parse().unwrap()
+        );
+        assert_eq!(
+            relay_address("[::]:5000".parse().unwrap(), control_peer),
+            "[::1]:5000".parse().unwrap()
+        );
+        assert_eq!(
+            relay_address("10.0.0.1:5000".parse().unwrap(), control_peer),
+            "10.0.0.1:5000".parse().unwrap()
+        );
+
+        // The v4 server advertises a wildcard relay
+        smol::block_on(assert_relay_receives(
+            |port| SocketAddr::from(([0, 0, 0, 0], port)).into(),
+            "127.0.0.1",
+        ));
+    }
+
+    #[test]
+    fn control_connection_outlives_either_half() {
+        smol::block_on(async move {
+            let (_server, server_addr, closed) =
+                socks5_udp_server(SocketAddr::from(([127, 0, 0, 1], 9)).into()).await;
+            let p = Socks5 {
+                address: server_addr.into(),
+                supports_udp: true,
+                udp_mtu: None,
+            };
+            let (sink, stream) = p
+                .new_datagram(
+                    &"1.2.3.4:53".parse().unwrap(),
+                    Bytes::new(),
+                    &Default::default(),
+                    &Default::default(),
+                )
+                .await
+                .expect("To associate");
+
+            drop(stream);
+            Timer::after(Duration::from_millis(100)).await;
+            assert_eq!(closed.load(Ordering::SeqCst), 0);
+
+            drop(sink);
+            Timer::after(Duration::from_millis(100)).await;
+            assert_eq!(closed.load(Ordering::SeqCst), 1);
+        });
+    }
+}
@@ -0,0 +1,350 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use anyhow::anyhow;
+use futures::{ready, AsyncRead, AsyncWrite};
+use pin_project_lite::pin_project;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+
+// Frame structure:
+// |plain_len(u16, big endian)|ciphertext(plain_len)|tag|
+const HEADER_LEN: usize = 2;
+const TAG_LEN: usize = 16;
+const MAX_FRAME_LEN: usize = 16384;
+
+// Who sealed a frame. Both sides share the key and iv, so the direction goes into the nonce
+// to keep them from ever using the same one.
+#[derive(Debug, Clone, Copy)]
+pub enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+// Seals or opens the frames going one way. Every frame gets the next nonce, so frames that
+// are dropped, reordered or replayed fail to open.
+pub struct FrameCipher {
+    key: LessSafeKey,
+    iv: [u8; NONCE_LEN],
+    counter: u64,
+}
+
+impl FrameCipher {
+    pub fn new(key: &[u8], iv: &[u8], direction: Direction) -> anyhow::Result<Self> {
+        let key = UnboundKey::new(&AES_256_GCM, key)
+            .map_err(|_| anyhow!("Invalid key length for AES256-GCM cipher"))?;
+        let mut iv: [u8; NONCE_LEN] = iv
+            .try_into()
+            .map_err(|_| anyhow!("Invalid iv length for AES256-GCM cipher"))?;
+        if let Direction::ServerToClient = direction {
+            iv[0] ^= 0x80;
+        }
+
+        Ok(Self {
+            key: LessSafeKey::new(key),
+            iv,
+            counter: 0,
+        })
+    }
+
+    fn next_nonce(&mut self) -> Nonce {
+        let mut nonce = self.iv;
+        for (n, c) in nonce[4..].iter_mut().zip(self.counter.to_be_bytes()) {
+            *n ^= c;
+        }
+        self.counter += 1;
+        Nonce::assume_unique_for_key(nonce)
+    }
+
+    // Seals `plain` into a message of its own, without a frame header
+    pub fn seal(&mut self, plain: &[u8]) -> Vec<u8> {
+        let mut sealed = Vec::with_capacity(plain.len() + TAG_LEN);
+        sealed.extend_from_slice(plain);
+        self.seal_in_place(&[], &mut sealed);
+        sealed
+    }
+
+    // Opens a message sealed with `seal`
+    pub fn open(&mut self, sealed: &mut Vec<u8>) -> io::Result<()> {
+        let len = self.open_in_place(&[], sealed)?;
+        sealed.truncate(len);
+        Ok(())
+    }
+
+    fn seal_in_place(&mut self, aad: &[u8], in_out: &mut Vec<u8>) {
+        let nonce = self.next_nonce();
+        self.key
+            .seal_in_place_append_tag(nonce, Aad::from(aad), in_out)
+            .expect("Sealing to succeed");
+    }
+
+    fn open_in_place(&mut self, aad: &[u8], in_out: &mut [u8]) -> io::Result<usize> {
+        let nonce = self.next_nonce();
+        self.key
+            .open_in_place(nonce, Aad::from(aad), in_out)
+            .map(|plain| plain.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Frame failed to authenticate"))
+    }
+}
+
+pin_project! {
+    // Splits what's written into frames sealed with AES-256-GCM, so a tampered, truncated
+    // or mismatched stream fails to read instead of decrypting to garbage.
+    pub struct AeadStream<R, W> {
+        #[pin]
+        r: R,
+        #[pin]
+        w: W,
+        opener: FrameCipher,
+        sealer: FrameCipher,
+        // Sealed frames waiting to be written to the inner stream
+        write_buf: Vec<u8>,
+        write_offset: usize,
+        // Frames read from the inner stream, the start of the first one at `read_offset`,
+        // and the plain data opened from it that hasn't been read yet
+        read_buf: Vec<u8>,
+        read_offset: usize,
+        plain: Vec<u8>,
+        plain_offset: usize,
+    }
+}
+
+impl<R, W> AeadStream<R, W> {
+    pub fn new(r: R, w: W, opener: FrameCipher, sealer: FrameCipher) -> Self {
+        Self {
+            r,
+            w,
+            opener,
+            sealer,
+            write_buf: Vec::new(),
+            write_offset: 0,
+            read_buf: Vec::new(),
+            read_offset: 0,
+            plain: Vec::new(),
+            plain_offset: 0,
+        }
+    }
+}
+
+// Writes out the sealed frames the inner stream hasn't taken yet
+fn poll_drain<W: AsyncWrite>(
+    mut w: Pin<&mut W>,
+    cx: &mut Context<'_>,
+    write_buf: &mut Vec<u8>,
+    write_offset: &mut usize,
+) -> Poll<io::Result<()>> {
+    while *write_offset < write_buf.len() {
+        match ready!(w.as_mut().poll_write(cx, &write_buf[*write_offset..]))? {
+            0 => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+            n => *write_offset += n,
+        }
+    }
+    write_buf.clear();
+    *write_offset = 0;
+    Poll::Ready(Ok(()))
+}
+
+impl<R: AsyncRead, W> AsyncRead for AeadStream<R, W> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut this = self.project();
+        loop {
+            if *this.plain_offset < this.plain.len() {
+                let available = &this.plain[*this.plain_offset..];
+                let n = available.len().min(buf.len());
+                buf[..n].copy_from_slice(&available[..n]);
+                *this.plain_offset += n;
+                return Poll::Ready(Ok(n));
+            }
+
+            let frame = &this.read_buf[*this.read_offset..];
+            if frame.len() >= HEADER_LEN {
+                let plain_len = u16::from_be_bytes([frame[0], frame[1]]) as usize;
+                let frame_len = HEADER_LEN + plain_len + TAG_LEN;
+                if frame.len() >= frame_len {
+                    let header = [frame[0], frame[1]];
+                    this.plain.clear();
+                    this.plain.extend_from_slice(&frame[HEADER_LEN..frame_len]);
+                    *this.plain_offset = 0;
+                    *this.read_offset += frame_len;
+                    let len = this.opener.open_in_place(&header, this.plain)?;
+                    this.plain.truncate(len);
+                    continue;
+                }
+            }
+
+            // Need more input
+            if *this.read_offset > 0 {
+                this.read_buf.drain(..*this.read_offset);
+                *this.read_offset = 0;
+            }
+
+            let len = this.read_buf.len();
+            this.read_buf
+                .resize(len + MAX_FRAME_LEN + HEADER_LEN + TAG_LEN, 0);
+            let rc = this.r.as_mut().poll_read(cx, &mut this.read_buf[len..]);
+            let n = match rc {
+                Poll::Ready(Ok(n)) => n,
+                v => {
+                    this.read_buf.truncate(len);
+                    return v.map_ok(|_| 0);
+                }
+            };
+            this.read_buf.truncate(len + n);
+
+            if n == 0 {
+                if !this.read_buf.is_empty() {
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+                return Poll::Ready(Ok(0));
+            }
+        }
+    }
+}
+
+impl<R, W: AsyncWrite> AsyncWrite for AeadStream<R, W> {
+    // A write is accepted once it's sealed. What the inner stream doesn't take right away
+    // is written out by the next write, flush or close.
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut this = self.project();
+        ready!(poll_drain(
+            this.w.as_mut(),
+            cx,
+            this.write_buf,
+            this.write_offset
+        ))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let len = buf.len().min(MAX_FRAME_LEN);
+        let header = (len as u16).to_be_bytes();
+        let mut sealed = Vec::with_capacity(len + TAG_LEN);
+        sealed.extend_from_slice(&buf[..len]);
+        this.sealer.seal_in_place(&header, &mut sealed);
+        this.write_buf.extend_from_slice(&header);
+        this.write_buf.extend_from_slice(&sealed);
+
+        if let Poll::Ready(Err(e)) = poll_drain(this.w, cx, this.write_buf, this.write_offset) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        ready!(poll_drain(
+            this.w.as_mut(),
+            cx,
+            this.write_buf,
+            this.write_offset
+        ))?;
+        this.w.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        ready!(poll_drain(
+            this.w.as_mut(),
+            cx,
+            this.write_buf,
+            this.write_offset
+        ))?;
+        this.w.poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{io::Cursor, AsyncReadExt, AsyncWriteExt};
+    use rand::Rng;
+
+    fn pair() -> (Vec<u8>, Vec<u8>) {
+        let mut key = vec![0u8; 32];
+        let mut iv = vec![0u8; NONCE_LEN];
+        rand::thread_rng().fill(key.as_mut_slice());
+        rand::thread_rng().fill(iv.as_mut_slice());
+        (key, iv)
+    }
+
+    fn seal_stream(key: &[u8], iv: &[u8], data: &[u8]) -> Vec<u8> {
+        smol::block_on(async move {
+            let mut stream = AeadStream::new(
+                futures::io::empty(),
+                Cursor::new(Vec::new()),
+                FrameCipher::new(key, iv, Direction::ServerToClient).unwrap(),
+                FrameCipher::new(key, iv, Direction::ClientToServer).unwrap(),
+            );
+            stream.write_all(data).await.unwrap();
+            stream.close().await.unwrap();
+            stream.w.into_inner()
+        })
+    }
+
+    fn open_stream(key: &[u8], iv: &[u8], sealed: Vec<u8>) -> io::Result<Vec<u8>> {
+        smol::block_on(async move {
+            let mut stream = AeadStream::new(
+                Cursor::new(sealed),
+                futures::io::sink(),
+                FrameCipher::new(key, iv, Direction::ClientToServer).unwrap(),
+                FrameCipher::new(key, iv, Direction::ServerToClient).unwrap(),
+            );
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await?;
+            Ok(received)
+        })
+    }
+
+    #[test]
+    fn round_trip_works() {
+        let (key, iv) = pair();
+        let data = b"hello, world".repeat(5000);
+        let sealed = seal_stream(&key, &iv, &data);
+        assert!(sealed.len() > data.len());
+        assert_eq!(open_stream(&key, &iv, sealed).unwrap(), data);
+    }
+
+    #[test]
+    fn tampered_stream_fails() {
+        let (key, iv) = pair();
+        let data = b"hello, world".repeat(5000);
+        let sealed = seal_stream(&key, &iv, &data);
+
+        let mut flipped = sealed.clone();
+        flipped[HEADER_LEN + 100] ^= 1;
+        let err = open_stream(&key, &iv, flipped).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Frames can't be dropped or reordered either
+        let first_frame = HEADER_LEN + MAX_FRAME_LEN + TAG_LEN;
+        let dropped = sealed[first_frame..].to_vec();
+        assert!(open_stream(&key, &iv, dropped).is_err());
+
+        let truncated = sealed[..sealed.len() - 1].to_vec();
+        assert!(open_stream(&key, &iv, truncated).is_err());
+    }
+
+    #[test]
+    fn mismatched_keys_fail() {
+        let (key, iv) = pair();
+        let sealed = seal_stream(&key, &iv, b"hello, world");
+
+        let (other_key, _) = pair();
+        assert!(open_stream(&other_key, &iv, sealed.clone()).is_err());
+
+        // What one side seals can't be opened as if the other side had sealed it
+        let mut frame = FrameCipher::new(&key, &iv, Direction::ClientToServer)
+            .unwrap()
+            .seal(b"hello");
+        let mut opener = FrameCipher::new(&key, &iv, Direction::ServerToClient).unwrap();
+        assert!(opener.open(&mut frame).is_err());
+    }
+}
@@ -1,8 +1,20 @@
-use std::{borrow::Cow, fmt::Display, str::FromStr};
+use std::{
+    borrow::Cow,
+    fmt::Display,
+    str::FromStr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
+use super::aead::{AeadStream, Direction, FrameCipher};
 use super::strategy::EncryptionStrategy;
 use super::stream::CipherStream;
-use crate::{http::HttpRequestBuilder, url::HttpUrl, ws::negotiate_websocket};
+use super::suite::{create_cipher, CipherKind, AES256_GCM_TYPE};
+use crate::{
+    http::HttpRequestBuilder,
+    url::HttpUrl,
+    ws::{negotiate_websocket, CompressionStats, DeflateStream, KeepAlive},
+};
 use anyhow::{anyhow, Context};
 use base64::{
     alphabet, decode_engine,
@@ -10,7 +22,8 @@ use base64::{
     engine::fast_portable::{self, FastPortable},
 };
 use cipher::StreamCipher;
-use futures::{AsyncRead, AsyncReadExt, AsyncWrite};
+use futures::{future::Either, AsyncRead, AsyncReadExt, AsyncWrite};
+use serde::{Deserialize, Serialize};
 
 pub const BASE64_ENGINE: &FastPortable =
     &FastPortable::from(&alphabet::URL_SAFE, fast_portable::NO_PAD);
@@ -21,17 +34,28 @@ pub struct CipherParams<'a> {
     pub send_strategy: EncryptionStrategy,
     pub recv_strategy: EncryptionStrategy,
     pub cipher_type: u8,
+    // Seconds since the UNIX epoch when the request was made, so the server can refuse
+    // replayed requests once they're too old to remember
+    pub timestamp: u64,
+}
+
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
 }
 
 impl<'a> Display for CipherParams<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_fmt(format_args!(
-            "/{}/{}/{}/{}/{}",
+            "/{}/{}/{}/{}/{}/{}",
             base64::display::Base64Display::from(self.key.as_ref(), BASE64_ENGINE),
             base64::display::Base64Display::from(self.iv.as_ref(), BASE64_ENGINE),
             self.send_strategy,
             self.recv_strategy,
-            self.cipher_type
+            self.cipher_type,
+            self.timestamp
         ))
     }
 }
@@ -42,13 +66,21 @@ impl FromStr for CipherParams<'static> {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let mut ss = s.split('/');
         let _ = ss.next();
-        match (ss.next(), ss.next(), ss.next(), ss.next(), ss.next()) {
-            (Some(k), Some(iv), Some(ss), Some(rs), Some(t)) => Ok(Self {
+        match (
+            ss.next(),
+            ss.next(),
+            ss.next(),
+            ss.next(),
+            ss.next(),
+            ss.next(),
+        ) {
+            (Some(k), Some(iv), Some(ss), Some(rs), Some(t), Some(ts)) => Ok(Self {
                 key: Cow::Owned(decode_engine(k, BASE64_ENGINE).context("Decoding key")?),
                 iv: Cow::Owned(decode_engine(iv, BASE64_ENGINE).context("Decoding iv")?),
                 send_strategy: ss.parse().context("parse send_strategy")?,
                 recv_strategy: rs.parse().context("parse recv_strategy")?,
                 cipher_type: t.parse().context("parse cipher_type")?,
+                timestamp: ts.parse().context("parse timestamp")?,
             }),
             _ => return Err(anyhow!("Invalid URL {s}")),
         }
@@ -57,16 +89,34 @@ impl FromStr for CipherParams<'static> {
 
 pub const INITIAL_DATA_HEADER: &'static str = "X-Cache-Key";
 
+// What a browser opening a websocket sends along with the `Origin`, for servers only
+// answering those. Nothing is made up when it isn't configured, as a browser that doesn't
+// match the rest of the handshake stands out more than no browser at all.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BrowserHeaders {
+    pub user_agent: String,
+    pub accept_language: String,
+}
+
 pub async fn connect(
     url: &HttpUrl<'_>,
     stream: impl AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
     send_strategy: EncryptionStrategy,
     recv_strategy: EncryptionStrategy,
+    cipher: CipherKind,
     auth: Option<impl Display>,
     mut initial_data: impl AsMut<[u8]> + Send,
+    // Offers to compress the stream, counting what goes through in the given stats
+    compression: Option<Arc<CompressionStats>>,
+    keepalive: Option<KeepAlive>,
+    browser_headers: Option<&BrowserHeaders>,
 ) -> anyhow::Result<impl AsyncRead + AsyncWrite + Unpin + Send + Sync> {
-    let (cipher_type, wr_cipher, key, iv) = super::suite::pick_cipher();
-    let mut wr_cipher = send_strategy.wrap_cipher(wr_cipher);
+    let (cipher_type, key, iv) = super::suite::pick_keys(cipher);
+    let mut wr_cipher = if cipher_type == AES256_GCM_TYPE {
+        Either::Right(FrameCipher::new(&key, &iv, Direction::ClientToServer)?)
+    } else {
+        Either::Left(send_strategy.wrap_cipher(create_cipher(cipher_type, &key, &iv)?))
+    };
 
     let params = CipherParams {
         key: Cow::Borrowed(key.as_slice()),
@@ -74,36 +124,77 @@ pub async fn connect(
         send_strategy,
         recv_strategy,
         cipher_type,
+        timestamp: unix_timestamp(),
     };
 
-    if initial_data.as_mut().len() > 0 {
-        wr_cipher.apply_keystream(initial_data.as_mut());
-    }
+    // Sealed as a message of its own when the stream is authenticated
+    let sealed;
+    let initial_data: &[u8] = match &mut wr_cipher {
+        Either::Left(c) => {
+            c.apply_keystream(initial_data.as_mut());
+            initial_data.as_mut()
+        }
+        Either::Right(sealer) => {
+            sealed = sealer.seal(initial_data.as_mut());
+            &sealed
+        }
+    };
 
-    let mut builder = HttpRequestBuilder::new("GET", params)?;
+    // The params follow the URL's path, which is where the server is reached
+    let mut builder = HttpRequestBuilder::new(
+        "GET",
+        format_args!("{}{params}", url.path.trim_end_matches('/')),
+    )?;
+    builder.put_header_text("Host", url.address.get_host())?;
+    if let Some(headers) = browser_headers {
+        builder
+            .put_header_text("User-Agent", &headers.user_agent)?
+            .put_header_text(
+                "Origin",
+                format_args!(
+                    "{}://{}",
+                    if url.is_https { "https" } else { "http" },
+                    url.address.get_host()
+                ),
+            )?
+            .put_header_text("Accept-Language", &headers.accept_language)?;
+    }
     builder
-        .put_header_text("Host", url.address.get_host())?
+        .put_header_text("Cache-Control", "no-cache")?
+        .put_header_text("Pragma", "no-cache")?
         .put_header_text(
             INITIAL_DATA_HEADER,
-            Base64Display::from(initial_data.as_mut(), BASE64_ENGINE),
+            Base64Display::from(initial_data, BASE64_ENGINE),
         )?;
 
     if let Some(auth) = auth {
         builder.put_header_text("Authorization", auth)?;
     }
 
-    let (r, w) = negotiate_websocket(builder, stream).await?.split();
+    let offer = compression.is_some().then(Default::default);
+    let (stream, deflate) = negotiate_websocket(builder, stream, offer, keepalive).await?;
+    let (r, w) = stream.split();
 
-    let rd_cipher = recv_strategy.wrap_cipher(
-        super::suite::create_cipher(cipher_type, key.as_slice(), iv.as_slice())
-            .expect("To have created a same cipher as wr_cipher"),
-    );
+    let stream = match wr_cipher {
+        Either::Left(wr_cipher) => {
+            let rd_cipher = recv_strategy.wrap_cipher(
+                create_cipher(cipher_type, key.as_slice(), iv.as_slice())
+                    .expect("To have created a same cipher as wr_cipher"),
+            );
+            Either::Left(CipherStream::new(
+                "client".to_string(),
+                r,
+                w,
+                rd_cipher,
+                wr_cipher,
+            ))
+        }
+        Either::Right(sealer) => {
+            let opener = FrameCipher::new(&key, &iv, Direction::ServerToClient)?;
+            Either::Right(AeadStream::new(r, w, opener, sealer))
+        }
+    };
 
-    Ok(CipherStream::new(
-        "client".to_string(),
-        r,
-        w,
-        rd_cipher,
-        wr_cipher,
-    ))
+    // Compressed before it's encrypted, as ciphertext doesn't compress
+    Ok(DeflateStream::for_client(stream, deflate).with_stats(compression))
 }
@@ -1,6 +1,9 @@
+mod aead;
 pub mod client;
 mod partial;
 pub mod server;
 pub mod strategy;
 mod stream;
-mod suite;
+pub(crate) mod suite;
+
+pub use suite::CipherKind;
@@ -1,24 +1,78 @@
+use std::collections::{HashSet, VecDeque};
 use std::fmt::Debug;
 
-use super::client::{CipherParams, BASE64_ENGINE};
+use super::client::{unix_timestamp, CipherParams, BASE64_ENGINE};
 use anyhow::{bail, Context};
 use base64::decode_engine;
 use cipher::StreamCipher;
-use futures::{AsyncRead, AsyncReadExt, AsyncWrite};
+use futures::{future::Either, AsyncRead, AsyncReadExt, AsyncWrite};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
 
-use crate::http::WithHeaders;
-use crate::ws::{serve_websocket, WebSocketServeResult};
+use crate::http::{HttpRequest, WithHeaders};
+use crate::ws::{serve_websocket, DeflateStream, WebSocketServeResult};
 
+use super::aead::{AeadStream, Direction, FrameCipher};
 use super::stream::CipherStream;
-use super::suite::{create_cipher, StreamCipherExt};
+use super::suite::{create_cipher, StreamCipherExt, AES256_GCM_TYPE};
+
+// How far, in seconds, a request's timestamp may be from the server's clock. Requests
+// outside the window are refused, so only the ones inside it have to be remembered.
+const FRESHNESS_WINDOW_SECS: u64 = 120;
+
+fn is_fresh(timestamp: u64, now: u64) -> bool {
+    timestamp.abs_diff(now) <= FRESHNESS_WINDOW_SECS
+}
+
+// Remembers the key and iv of fresh requests. Clients pick them randomly for every request,
+// so seeing them again means the request, and the initial data encrypted with them, is
+// being replayed, e.g. by someone who captured a 0-RTT packet. Once a request is too old
+// to be accepted again, it's forgotten.
+#[derive(Default)]
+struct ReplayFilter {
+    seen: HashSet<Vec<u8>>,
+    // The params in the order they were seen, with when they stop being fresh
+    order: VecDeque<(u64, Vec<u8>)>,
+}
+
+impl ReplayFilter {
+    // Whether the params haven't been seen before
+    fn check(&mut self, key: &[u8], iv: &[u8], timestamp: u64, now: u64) -> bool {
+        while let Some((stale_at, _)) = self.order.front() {
+            if *stale_at >= now {
+                break;
+            }
+            if let Some((_, params)) = self.order.pop_front() {
+                self.seen.remove(&params);
+            }
+        }
+
+        let params = [key, iv].concat();
+        if !self.seen.insert(params.clone()) {
+            return false;
+        }
+
+        self.order
+            .push_back((timestamp + FRESHNESS_WINDOW_SECS, params));
+        true
+    }
+}
+
+lazy_static! {
+    static ref REPLAY_FILTER: Mutex<ReplayFilter> = Default::default();
+}
+
+// The stream ciphers reading and writing the stream, or the frame ciphers opening and
+// sealing it when it's authenticated
+type Ciphers<RC, WC> = Either<(RC, WC), (FrameCipher, FrameCipher)>;
 
 fn check_request(
-    path: &str,
+    params: CipherParams<'static>,
 ) -> Result<
-    (
+    Ciphers<
         impl StreamCipherExt + Send + Sync + 'static,
         impl StreamCipherExt + Send + Sync + 'static,
-    ),
+    >,
     (&'static str, &'static str),
 > {
     let CipherParams {
@@ -27,13 +81,30 @@ fn check_request(
         send_strategy: client_send_strategy,
         recv_strategy: client_receive_strategy,
         cipher_type,
-    } = match path.parse() {
-        Ok(v) => v,
-        Err(e) => {
-            log::error!("Error parsing params: {e}");
-            return Err(("HTTP/1.1 404 Not found\r\n\r\n", "Invalid PATH"));
-        }
-    };
+        timestamp,
+    } = params;
+
+    let now = unix_timestamp();
+    if !is_fresh(timestamp, now) {
+        return Err(("HTTP/1.1 401 Unauthorized\r\n\r\n", "Stale request"));
+    }
+
+    if !REPLAY_FILTER
+        .lock()
+        .check(key.as_ref(), iv.as_ref(), timestamp, now)
+    {
+        return Err(("HTTP/1.1 401 Unauthorized\r\n\r\n", "Replayed request"));
+    }
+
+    if cipher_type == AES256_GCM_TYPE {
+        let invalid = |_| ("HTTP/1.1 401 Invalid type\r\n\r\n", "Invalid cipher keys");
+        return Ok(Either::Right((
+            FrameCipher::new(key.as_ref(), iv.as_ref(), Direction::ClientToServer)
+                .map_err(invalid)?,
+            FrameCipher::new(key.as_ref(), iv.as_ref(), Direction::ServerToClient)
+                .map_err(invalid)?,
+        )));
+    }
 
     let rd_cipher = client_send_strategy.wrap_cipher(
         create_cipher(cipher_type, key.as_ref(), iv.as_ref())
@@ -43,13 +114,12 @@ fn check_request(
     let wr_cipher = client_receive_strategy
         .wrap_cipher(create_cipher(cipher_type, key.as_ref(), iv.as_ref()).unwrap());
 
-    Ok((rd_cipher, wr_cipher))
+    Ok(Either::Left((rd_cipher, wr_cipher)))
 }
 
 pub struct Handshaker<T, RC, WC> {
     r: WebSocketServeResult<T>,
-    rc: RC,
-    wc: WC,
+    ciphers: Ciphers<RC, WC>,
 }
 
 impl<T, RC, WC> Handshaker<T, RC, WC>
@@ -78,25 +148,102 @@ where
     pub async fn respond_success(
         self,
     ) -> anyhow::Result<impl AsyncRead + AsyncWrite + Unpin + Send + Sync> {
-        let Handshaker { r, rc, wc } = self;
+        let Handshaker { r, ciphers } = self;
 
-        let (r, w) = r
+        let (stream, deflate) = r
             .respond_success()
             .await
-            .context("Responding success to cipher client")?
-            .split();
-        Ok(CipherStream::new("server".to_string(), r, w, rc, wc))
+            .context("Responding success to cipher client")?;
+        let (r, w) = stream.split();
+        let stream = match ciphers {
+            Either::Left((rc, wc)) => {
+                Either::Left(CipherStream::new("server".to_string(), r, w, rc, wc))
+            }
+            Either::Right((opener, sealer)) => Either::Right(AeadStream::new(r, w, opener, sealer)),
+        };
+        // Compressed before it's encrypted, as ciphertext doesn't compress
+        Ok(DeflateStream::for_server(stream, deflate))
+    }
+}
+
+// The params part of `path`, if it's under `prefix`
+fn strip_path_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    path.strip_prefix(prefix.trim_end_matches('/'))
+        .filter(|params| params.starts_with('/'))
+}
+
+// Sent by browsers opening a websocket, and by clients set up to send them
+const BROWSER_HEADERS: &[&str] = &["Host", "User-Agent", "Origin", "Accept-Language"];
+
+// Whether the request is at least `min_bytes` long, counted as sent, and looks like it
+// came from a browser
+fn meets_budget(req: &HttpRequest, min_bytes: usize) -> bool {
+    if min_bytes == 0 {
+        return true;
     }
+
+    let request_line = req.method.len() + " ".len() + req.path.len() + " HTTP/1.1\r\n".len();
+    let headers = req
+        .headers
+        .iter()
+        .map(|(name, value)| name.len() + ": ".len() + value.len() + "\r\n".len())
+        .sum::<usize>();
+    let len = request_line + headers + "\r\n".len();
+    len >= min_bytes
+        && BROWSER_HEADERS
+            .iter()
+            .all(|name| req.get_header(name).is_some())
 }
 
+// Only paths under `path_prefix` are taken as params, e.g. when the server is reached
+// through a reverse proxy at a subpath. Those, requests short of `min_request_bytes` and
+// paths that aren't params are answered as missing pages, whatever their credentials.
+// Compression the client offers is only taken up if `allow_compression` is set.
 pub async fn accept_client<T: AsyncRead + AsyncWrite + Send + Sync + Unpin>(
     stream: T,
+    path_prefix: &str,
+    min_request_bytes: usize,
+    allow_compression: bool,
+    accepts_auth: impl FnOnce(Option<&str>) -> bool,
 ) -> anyhow::Result<(
     Option<Vec<u8>>,
     Handshaker<T, impl StreamCipherExt + Send + Sync, impl StreamCipherExt + Send + Sync>,
 )> {
-    let req = serve_websocket(stream).await?;
-    let (mut rd_cipher, wr_cipher) = match check_request(req.request().path.as_ref()) {
+    let mut req = serve_websocket(stream).await?;
+    if !allow_compression {
+        req.decline_compression();
+    }
+    let Some(params) =
+        strip_path_prefix(req.request().path.as_ref(), path_prefix).map(str::to_string)
+    else {
+        req.respond_fail_with_raw_response(b"HTTP/1.1 404 Not found\r\n\r\n")
+            .await?;
+        bail!("Path outside of {path_prefix}");
+    };
+
+    if !meets_budget(req.request(), min_request_bytes) {
+        req.respond_fail_with_raw_response(b"HTTP/1.1 404 Not found\r\n\r\n")
+            .await?;
+        bail!("Request short of {min_request_bytes} bytes");
+    }
+
+    let params: CipherParams = match params.parse() {
+        Ok(v) => v,
+        Err(e) => {
+            log::error!("Error parsing params: {e}");
+            req.respond_fail_with_raw_response(b"HTTP/1.1 404 Not found\r\n\r\n")
+                .await?;
+            bail!("Invalid PATH");
+        }
+    };
+
+    if !accepts_auth(req.request().get_header_text("Authorization")) {
+        req.respond_fail_with_raw_response(b"HTTP/1.1 401 Unauthorized\r\n\r\n")
+            .await?;
+        bail!("Invalid credentials");
+    }
+
+    let mut ciphers = match check_request(params) {
         Ok(v) => v,
         Err((res, err)) => {
             req.respond_fail_with_raw_response(res.as_bytes()).await?;
@@ -104,36 +251,96 @@ pub async fn accept_client<T: AsyncRead + AsyncWrite + Send + Sync + Unpin>(
         }
     };
 
-    let initial_data = match req.request().get_header(super::client::INITIAL_DATA_HEADER) {
-        Some(value) => {
-            let mut data = decode_engine(value, BASE64_ENGINE)?;
+    let initial_data = req
+        .request()
+        .get_header(super::client::INITIAL_DATA_HEADER)
+        .map(|value| decode_engine(value, BASE64_ENGINE))
+        .transpose()?;
+    let initial_data = match &mut ciphers {
+        Either::Left((rd_cipher, _)) => initial_data.map(|mut data| {
             rd_cipher.apply_keystream(&mut data);
+            data
+        }),
+        // Always sent, sealed as the first message from the client
+        Either::Right((opener, _)) => {
+            let mut data = initial_data.unwrap_or_default();
+            if opener.open(&mut data).is_err() {
+                req.respond_fail_with_raw_response(b"HTTP/1.1 401 Unauthorized\r\n\r\n")
+                    .await?;
+                bail!("Initial data failed to authenticate");
+            }
             Some(data)
         }
-        None => None,
     };
 
-    Ok((
-        initial_data,
-        Handshaker {
-            r: req,
-            rc: rd_cipher,
-            wc: wr_cipher,
-        },
-    ))
+    Ok((initial_data, Handshaker { r: req, ciphers }))
 }
 
 #[cfg(test)]
 mod test {
     use super::super::client::connect;
     use super::super::strategy::EncryptionStrategy;
+    use super::super::suite::{pick_keys, CipherKind};
     use super::*;
     use crate::{
         fetch::connect_http_stream, io::connect_tcp, test::create_http_server, url::HttpUrl,
+        ws::KeepAlive,
     };
     use futures::{io::copy, AsyncReadExt, AsyncWriteExt};
     use rand::RngCore;
     use smol::spawn;
+    use std::time::Duration;
+
+    #[test]
+    fn replayed_params_are_refused() {
+        let mut filter = ReplayFilter::default();
+        assert!(filter.check(b"key1", b"iv1", 1000, 1000));
+        assert!(!filter.check(b"key1", b"iv1", 1000, 1000));
+        assert!(filter.check(b"key1", b"iv2", 1000, 1000));
+
+        // However many newer requests come in, a request is remembered while it's fresh
+        for i in 0..100_000u32 {
+            assert!(filter.check(&i.to_be_bytes(), b"iv", 1010, 1010));
+        }
+        let last_fresh = 1000 + FRESHNESS_WINDOW_SECS;
+        assert!(is_fresh(1000, last_fresh));
+        assert!(!filter.check(b"key1", b"iv1", 1000, last_fresh));
+
+        // After which it's refused for being stale, and forgotten
+        assert!(!is_fresh(1000, last_fresh + 1));
+        assert!(filter.check(b"key3", b"iv3", 1200, last_fresh + 1));
+        assert!(!filter.seen.contains(&[&b"key1"[..], b"iv1"].concat()));
+        assert!(filter.seen.contains(&[&b"key3"[..], b"iv3"].concat()));
+    }
+
+    #[test]
+    fn stale_requests_are_refused() {
+        let (cipher_type, key, iv) = pick_keys(CipherKind::ChaCha20);
+        let params = |timestamp| CipherParams {
+            key: key.clone().into(),
+            iv: iv.clone().into(),
+            send_strategy: EncryptionStrategy::Always,
+            recv_strategy: EncryptionStrategy::Always,
+            cipher_type,
+            timestamp,
+        };
+
+        let now = unix_timestamp();
+        for timestamp in [
+            now - FRESHNESS_WINDOW_SECS - 10,
+            now + FRESHNESS_WINDOW_SECS + 10,
+        ] {
+            assert_eq!(
+                check_request(params(timestamp)).err().unwrap().1,
+                "Stale request"
+            );
+        }
+        assert!(check_request(params(now)).is_ok());
+
+        // What's sent over the wire keeps the timestamp
+        let parsed: CipherParams = params(now).to_string().parse().unwrap();
+        assert_eq!(parsed.timestamp, now);
+    }
 
     #[test]
     fn test_cipher_server() {
@@ -142,7 +349,8 @@ mod test {
             let server_task = spawn(async move {
                 loop {
                     let (stream, _) = http_server.accept().await.unwrap();
-                    let (initial_data, hs) = accept_client(stream).await.unwrap();
+                    let (initial_data, hs) =
+                        accept_client(stream, "", 0, false, |_| true).await.unwrap();
                     let (r, mut w) = hs.respond_success().await.unwrap().split();
                     w.write_all(&initial_data.unwrap_or_default())
                         .await
@@ -151,50 +359,62 @@ mod test {
                 }
             });
 
-            let data = b"hello, world";
             let url = HttpUrl::try_from(url.as_str()).unwrap();
-            let stream = connect_http_stream(
-                url.is_https,
-                &url.address,
-                connect_tcp(&url.address).await.unwrap(),
-            )
-            .await
-            .unwrap();
-
-            let mut client = connect(
-                &url,
-                stream,
-                EncryptionStrategy::FirstN(5.try_into().unwrap()),
-                EncryptionStrategy::Always,
-                Option::<&str>::None,
-                data.to_vec(),
-            )
-            .await
-            .expect("To connect to server");
-
-            let mut buf = Vec::new();
-
-            buf.resize(data.len(), 0);
-            client
-                .read_exact(buf.as_mut_slice())
+            for cipher in [
+                CipherKind::ChaCha20,
+                CipherKind::Aes256Ctr,
+                CipherKind::Aes256Gcm,
+            ] {
+                let data = b"hello, world";
+                let stream = connect_http_stream(
+                    url.is_https,
+                    &url.address,
+                    connect_tcp(&url.address).await.unwrap(),
+                    Default::default(),
+                )
                 .await
-                .expect("To read server response");
-            assert_eq!(buf, data);
+                .unwrap();
 
-            let mut data = vec![0u8; 65536];
-            rand::thread_rng().fill_bytes(data.as_mut_slice());
-            client
-                .write_all(data.as_slice())
-                .await
-                .expect("To write to server");
-            buf.resize(data.len(), 0);
-            client
-                .read_exact(buf.as_mut_slice())
+                let mut client = connect(
+                    &url,
+                    stream,
+                    EncryptionStrategy::FirstN(5.try_into().unwrap()),
+                    EncryptionStrategy::Always,
+                    cipher,
+                    Option::<&str>::None,
+                    data.to_vec(),
+                    None,
+                    Some(KeepAlive::new(Duration::from_secs(30))),
+                    None,
+                )
                 .await
-                .expect("To read second server response");
-            assert_eq!(buf, data);
+                .expect("To connect to server");
+
+                let mut buf = Vec::new();
+
+                buf.resize(data.len(), 0);
+                client
+                    .read_exact(buf.as_mut_slice())
+                    .await
+                    .expect("To read server response");
+                assert_eq!(buf, data);
+
+                let mut data = vec![0u8; 65536];
+                rand::thread_rng().fill_bytes(data.as_mut_slice());
+                client
+                    .write_all(data.as_slice())
+                    .await
+                    .expect("To write to server");
+                buf.resize(data.len(), 0);
+                client
+                    .read_exact(buf.as_mut_slice())
+                    .await
+                    .expect("To read second server response");
+                assert_eq!(buf, data);
+
+                drop(client);
+            }
 
-            drop(client);
             let _ = server_task.cancel();
         });
     }
@@ -145,6 +145,10 @@ impl<R, T: AsyncWrite, RC, WC: StreamCipherExt + Send + Sync> AsyncWrite
                     match self.as_mut().poll_write(cx, buf) {
                         Poll::Ready(Ok(len)) => {
                             total_len += len;
+                            // The rest of the buffer must go before any of the next
+                            if len < buf.len() {
+                                return Poll::Ready(Ok(total_len));
+                            }
                         }
                         Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
                         Poll::Pending if total_len > 0 => return Poll::Ready(Ok(total_len)),
@@ -1,6 +1,7 @@
 use anyhow::anyhow;
 use cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
 use rand::Rng;
+use serde::{Deserialize, Serialize};
 
 pub trait StreamCipherExt: StreamCipher {
     fn will_modify_data(&self) -> bool;
@@ -18,6 +19,85 @@ impl StreamCipherExt for chacha20::ChaCha20 {
     }
 }
 
+// AES-256 in counter mode. Like ChaCha20 here it's unauthenticated: the streams rewind the
+// keystream after partial writes, which an AEAD doesn't allow.
+pub type Aes256Ctr = ctr::Ctr128BE<aes::Aes256>;
+
+impl StreamCipherExt for Aes256Ctr {
+    fn will_modify_data(&self) -> bool {
+        true
+    }
+
+    fn rewind(&mut self, cnt: usize) {
+        self.seek(self.current_pos::<usize>() - cnt)
+    }
+}
+
+// The stream ciphers only encrypt, and only as much as the encryption strategies ask for.
+// They don't detect tampering, so a mismatch or a modified stream decrypts to garbage
+// rather than failing. AES-256-GCM seals the whole stream in authenticated frames instead,
+// whatever the strategies.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CipherKind {
+    #[default]
+    #[serde(rename = "chacha20")]
+    ChaCha20,
+    #[serde(rename = "aes256-ctr")]
+    Aes256Ctr,
+    #[serde(rename = "aes256-gcm")]
+    Aes256Gcm,
+}
+
+pub const AES256_GCM_TYPE: CipherType = 3;
+
+impl CipherKind {
+    fn cipher_type(self) -> CipherType {
+        match self {
+            CipherKind::ChaCha20 => 1,
+            CipherKind::Aes256Ctr => 2,
+            CipherKind::Aes256Gcm => AES256_GCM_TYPE,
+        }
+    }
+
+    fn iv_len(self) -> usize {
+        match self {
+            CipherKind::ChaCha20 => 12,
+            CipherKind::Aes256Ctr => 16,
+            CipherKind::Aes256Gcm => 12,
+        }
+    }
+}
+
+pub enum SuiteCipher {
+    ChaCha20(chacha20::ChaCha20),
+    Aes256Ctr(Box<Aes256Ctr>),
+}
+
+impl StreamCipher for SuiteCipher {
+    fn try_apply_keystream_inout(
+        &mut self,
+        buf: cipher::inout::InOutBuf<'_, '_, u8>,
+    ) -> Result<(), cipher::StreamCipherError> {
+        match self {
+            SuiteCipher::ChaCha20(c) => c.try_apply_keystream_inout(buf),
+            SuiteCipher::Aes256Ctr(c) => c.try_apply_keystream_inout(buf),
+        }
+    }
+}
+
+impl StreamCipherExt for SuiteCipher {
+    fn will_modify_data(&self) -> bool {
+        true
+    }
+
+    fn rewind(&mut self, cnt: usize) {
+        match self {
+            SuiteCipher::ChaCha20(c) => c.rewind(cnt),
+            SuiteCipher::Aes256Ctr(c) => c.rewind(cnt),
+        }
+    }
+}
+
 pub type CipherType = u8;
 pub type CipherKey = Vec<u8>;
 pub type CipherIv = Vec<u8>;
@@ -28,30 +108,64 @@ pub fn create_cipher(
     iv: &[u8],
 ) -> anyhow::Result<impl StreamCipherExt + Send + Sync + 'static> {
     match cipher_type {
-        1 => Ok(chacha20::ChaCha20::new_from_slices(key, iv)
-            .map_err(|_| anyhow!("Invalid key/iv lengths for Chacha20 cipher"))?),
+        1 => Ok(SuiteCipher::ChaCha20(
+            chacha20::ChaCha20::new_from_slices(key, iv)
+                .map_err(|_| anyhow!("Invalid key/iv lengths for Chacha20 cipher"))?,
+        )),
+        2 => Ok(SuiteCipher::Aes256Ctr(Box::new(
+            Aes256Ctr::new_from_slices(key, iv)
+                .map_err(|_| anyhow!("Invalid key/iv lengths for AES256-CTR cipher"))?,
+        ))),
         _ => Err(anyhow!("Unknown cipher_type {cipher_type}")),
     }
 }
 
-pub fn pick_cipher() -> (
-    CipherType,
-    impl StreamCipherExt + Send + Sync + 'static,
-    CipherKey,
-    CipherIv,
-) {
-    let mut key;
-    let mut iv;
-    let cipher_type;
-
-    key = vec![0u8; 32];
-    iv = vec![0u8; 12];
-    cipher_type = 1;
+// A random key and iv for the kind of cipher
+pub fn pick_keys(kind: CipherKind) -> (CipherType, CipherKey, CipherIv) {
+    let mut key = vec![0u8; 32];
+    let mut iv = vec![0u8; kind.iv_len()];
     rand::thread_rng().fill(key.as_mut_slice());
     rand::thread_rng().fill(iv.as_mut_slice());
+    (kind.cipher_type(), key, iv)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aes256_ctr_round_trips() {
+        let (cipher_type, key, iv) = pick_keys(CipherKind::Aes256Ctr);
+        let mut enc = create_cipher(cipher_type, &key, &iv).unwrap();
+        let mut dec = create_cipher(cipher_type, &key, &iv).unwrap();
+
+        let data = b"hello, world".repeat(100);
+        let mut buf = data.clone();
+        enc.apply_keystream(&mut buf);
+        assert_ne!(buf, data);
 
-    let cipher = chacha20::ChaCha20::new_from_slices(key.as_slice(), iv.as_slice())
-        .expect("to create chacha20 cipher");
+        // Rewinding re-encrypts the same bytes identically
+        enc.rewind(buf.len());
+        let mut again = data.clone();
+        enc.apply_keystream(&mut again);
+        assert_eq!(again, buf);
 
-    (cipher_type, cipher, key, iv)
+        dec.apply_keystream(&mut buf);
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn aes256_is_named_for_its_mode() {
+        let kind: CipherKind = serde_json::from_str("\"aes256-ctr\"").unwrap();
+        assert_eq!(kind, CipherKind::Aes256Ctr);
+        assert!(serde_json::from_str::<CipherKind>("\"aes256\"").is_err());
+    }
+
+    #[test]
+    fn aes256_gcm_isnt_a_stream_cipher() {
+        let (cipher_type, key, iv) = pick_keys(CipherKind::Aes256Gcm);
+        assert!(create_cipher(cipher_type, &key, &iv).is_err());
+        let kind: CipherKind = serde_json::from_str("\"aes256-gcm\"").unwrap();
+        assert_eq!(kind, CipherKind::Aes256Gcm);
+    }
 }
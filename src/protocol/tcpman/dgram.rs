@@ -30,11 +30,20 @@ pub fn create_udp_stream(
 pub fn create_udp_sink(
     mut w: impl AsyncWrite + Unpin + Send + 'static,
 ) -> impl Sink<(Bytes, Address<'static>), Error = anyhow::Error> {
-    let (tx, mut rx) = channel::<(Bytes, Address<'static>)>(10);
+    let (tx, rx) = channel::<(Bytes, Address<'static>)>(10);
     spawn(async move {
         let mut writer = PacketWriter::new();
-        while let Some((data, addr)) = rx.next().await {
-            if let Err(e) = writer.write(&mut w, &addr, &data).await {
+        // Packets queued up while writing go out together
+        let mut rx = rx.ready_chunks(16);
+        while let Some(packets) = rx.next().await {
+            let result = match packets.as_slice() {
+                [(data, addr)] => writer.write(&mut w, addr, data).await,
+                packets => {
+                    let packets = packets.iter().map(|(data, addr)| (addr, data.as_ref()));
+                    writer.write_batch(&mut w, packets).await
+                }
+            };
+            if let Err(e) = result {
                 log::error!("Error writing packet: {e:?}");
                 break;
             }
@@ -1,33 +1,130 @@
 mod cipher;
 mod dgram;
+mod mux;
 mod proto;
+pub mod quic;
 pub mod server;
 mod udp_stream;
 
 use std::borrow::Cow;
 use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::Context;
+use anyhow::{anyhow, bail, Context};
 use async_trait::async_trait;
 use bytes::Bytes;
-use futures::{AsyncRead, AsyncReadExt, AsyncWrite};
+use futures::{future::Either, AsyncRead, AsyncReadExt, AsyncWrite, Future};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
+use smol::net::TcpStream;
 
-use crate::fetch::connect_http_stream;
-use crate::io::{connect_tcp_marked, AsyncStreamCounter};
-use crate::{socks5::Address, url::HttpUrl};
+use crate::fetch::{connect_http_stream, connect_with_tls_fallback, HttpStream, TlsModes};
+use crate::io::{connect_tcp_with, read_ahead, AsyncStreamCounter, ReadAhead, TcpOptions, TcpPeer};
+use crate::{
+    socks5::Address,
+    tls::{ClientIdentity, TlsOptions},
+    url::HttpUrl,
+    ws::KeepAlive,
+};
+
+pub use self::cipher::client::BrowserHeaders;
+pub use self::cipher::CipherKind;
+pub use self::quic::QuicConfig;
 
 use self::{
     cipher::strategy::EncryptionStrategy,
     dgram::{create_udp_sink, create_udp_stream},
+    mux::MuxSession,
 };
 
 use super::{AsyncStream, BoxedSink, BoxedStream, Protocol, Stats, TrafficType};
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(try_from = "CredentialsConfig", into = "CredentialsConfig")]
 pub struct Credentials {
+    pub username: String,
+    pub password: String,
+    // Where the password was read from, if it isn't kept in the config
+    pub password_source: Option<PasswordSource>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PasswordSource {
+    File(PathBuf),
+    Env(String),
+}
+
+impl PasswordSource {
+    // The cause is kept in the message, as serde only shows the outermost error
+    fn read(&self) -> anyhow::Result<String> {
+        match self {
+            PasswordSource::File(path) => Ok(std::fs::read_to_string(path)
+                .map_err(|e| anyhow!("Reading password file {}: {e}", path.display()))?
+                .trim_end_matches(['\r', '\n'])
+                .to_string()),
+            PasswordSource::Env(name) => std::env::var(name)
+                .map_err(|e| anyhow!("Reading password from environment variable {name}: {e}")),
+        }
+    }
+}
+
+// How credentials are written in the config: the password is given inline, or read
+// from a file or an environment variable when the config is loaded
+#[derive(Serialize, Deserialize)]
+struct CredentialsConfig {
     username: String,
-    password: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password_file: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password_env: Option<String>,
+}
+
+impl TryFrom<CredentialsConfig> for Credentials {
+    type Error = anyhow::Error;
+
+    fn try_from(c: CredentialsConfig) -> Result<Self, Self::Error> {
+        let source = match (c.password_file, c.password_env) {
+            (Some(path), None) => Some(PasswordSource::File(path)),
+            (None, Some(name)) => Some(PasswordSource::Env(name)),
+            (None, None) => None,
+            (Some(_), Some(_)) => bail!("Only one of password_file and password_env can be set"),
+        };
+
+        let password = match (c.password, &source) {
+            (Some(password), None) => password,
+            (None, Some(source)) => source.read()?,
+            (None, None) => bail!("Expecting password, password_file or password_env"),
+            (Some(_), Some(_)) => bail!("password can't be set along with where to read it from"),
+        };
+
+        Ok(Self {
+            username: c.username,
+            password,
+            password_source: source,
+        })
+    }
+}
+
+impl From<Credentials> for CredentialsConfig {
+    fn from(c: Credentials) -> Self {
+        let (password, password_file, password_env) = match c.password_source {
+            None => (Some(c.password), None, None),
+            Some(PasswordSource::File(path)) => (None, Some(path), None),
+            Some(PasswordSource::Env(name)) => (None, None, Some(name)),
+        };
+        Self {
+            username: c.username,
+            password,
+            password_file,
+            password_env,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
@@ -36,40 +133,320 @@ pub struct TcpMan {
     pub ssl: bool,
     pub allows_udp: bool,
     pub credentials: Option<Credentials>,
+    #[serde(default)]
+    pub keepalive_secs: Option<u64>,
+    #[serde(default)]
+    pub client_identity: Option<ClientIdentity>,
+    #[serde(default)]
+    pub sni: Option<String>,
+    #[serde(default)]
+    pub alpn: Option<Vec<String>>,
+    #[serde(default)]
+    pub cipher: CipherKind,
+    // Retry with the opposite of `ssl` if connecting fails, remembering what worked
+    #[serde(default)]
+    pub ssl_fallback: bool,
+    // Bytes read ahead from the server at a time. Reads aren't buffered if it isn't set.
+    #[serde(default)]
+    pub read_buffer_size: Option<usize>,
+    // Carries TCP streams over a few long-lived connections instead of a connection each.
+    // The server has to support multiplexing.
+    #[serde(default)]
+    pub pool: Option<PoolConfig>,
+    // Connects over QUIC instead of TCP, sending each request over a stream of a
+    // connection shared with the others. The server has to listen for QUIC, and `ssl`,
+    // `ssl_fallback`, `alpn` and `read_buffer_size` don't apply.
+    #[serde(default)]
+    pub quic: Option<QuicConfig>,
+    // The path the server is served under, e.g. `/cdn` behind a reverse proxy
+    #[serde(default)]
+    pub path_prefix: Option<String>,
+    // Offers to compress the stream before it's encrypted. Off by default, as the length
+    // of compressed traffic gives away what's in it. The server has to allow it too.
+    #[serde(default)]
+    pub compression: bool,
+    // Sent as a browser would, which servers with a minimum request size ask for
+    #[serde(default)]
+    pub browser_headers: Option<BrowserHeaders>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct PoolConfig {
+    // Connections kept open to the server at most
+    #[serde(default = "default_pool_size")]
+    pub size: usize,
+    // Seconds a connection can go without streams before it's closed
+    #[serde(default = "default_pool_idle_secs")]
+    pub idle_secs: u64,
+    // Streams a connection carries at once before another one is opened, or any number
+    // when 0. It should be no more than what the server allows.
+    #[serde(default)]
+    pub max_streams: usize,
+}
+
+fn default_pool_size() -> usize {
+    4
+}
+
+fn default_pool_idle_secs() -> u64 {
+    60
+}
+
+// The multiplexed connections to one of a pooled upstream's resolved addresses, made with
+// some TCP options
+struct Pool {
+    upstream: TcpMan,
+    options: TcpOptions,
+    target: SocketAddr,
+    sessions: Vec<Arc<MuxSession>>,
+}
+
+lazy_static! {
+    static ref POOLS: Mutex<Vec<Pool>> = Default::default();
+    static ref TLS_MODES: TlsModes<TcpMan> = Default::default();
 }
 
 impl TcpMan {
+    fn tls_options(&self) -> TlsOptions<'_> {
+        TlsOptions {
+            identity: self.client_identity.as_ref(),
+            sni: self.sni.as_deref(),
+            alpn: self.alpn.as_deref(),
+        }
+    }
+
     async fn send_request<'a>(
         &self,
-        dst: &Address<'_>,
         req: proto::Request<'a>,
         stats: &Stats,
-        fwmark: Option<u32>,
-    ) -> anyhow::Result<impl AsyncRead + AsyncWrite + Unpin + Send + Sync> {
-        let stream = connect_tcp_marked(&self.address, fwmark)
+        options: &TcpOptions,
+    ) -> anyhow::Result<impl AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static> {
+        self.send_request_to(&self.address, req, stats, options)
+            .await
+    }
+
+    // Connects to `target` in place of the configured address, which is still what the
+    // server is told it's reached as
+    async fn send_request_to<'a>(
+        &self,
+        target: &Address<'_>,
+        req: proto::Request<'a>,
+        stats: &Stats,
+        options: &TcpOptions,
+    ) -> anyhow::Result<impl AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static> {
+        if let Some(quic) = &self.quic {
+            let (stream, early_data) = quic::open_stream(self, quic, target, options)
+                .await
+                .context("Opening QUIC stream")?;
+            // QUIC is always encrypted
+            let result = self
+                .handshake(true, Either::Right(stream), req.clone(), stats)
+                .await;
+
+            // The request is sent again once the handshake is done if the server didn't
+            // take it with 0-RTT
+            return match (result, early_data) {
+                (Err(e), Some(accepted)) if !accepted.clone().await => {
+                    log::debug!("0-RTT request to {} rejected: {e:?}", self.address);
+                    let (stream, _) = quic::open_stream(self, quic, target, options)
+                        .await
+                        .context("Opening QUIC stream")?;
+                    self.handshake(true, Either::Right(stream), req, stats)
+                        .await
+                }
+                (result, _) => result,
+            };
+        }
+
+        // Credentials never go out in plain text, unless that's how it's configured
+        connect_with_tls_fallback(
+            (&TLS_MODES, self),
+            &self.address,
+            self.ssl,
+            self.ssl_fallback,
+            self.credentials.is_none(),
+            |tls| {
+                let req = req.clone();
+                async move {
+                    let stream = self.connect_stream(tls, target, options).await?;
+                    self.handshake(tls, Either::Left(stream), req, stats).await
+                }
+            },
+        )
+        .await
+    }
+
+    async fn connect_stream(
+        &self,
+        tls: bool,
+        target: &Address<'_>,
+        options: &TcpOptions,
+    ) -> anyhow::Result<HttpStream<ReadAhead<TcpStream>>> {
+        let stream = connect_tcp_with(target, TcpPeer::Upstream, options)
             .await
             .context("Connect to TCPMan server")?;
+        let stream = read_ahead(stream, self.read_buffer_size);
 
-        let stream = connect_http_stream(self.ssl, &self.address, stream)
+        connect_http_stream(tls, &self.address, stream, self.tls_options())
             .await
-            .context("Connect to TLS stream")?;
+            .context("Connect to TLS stream")
+    }
 
+    async fn handshake<'a>(
+        &self,
+        tls: bool,
+        stream: Either<HttpStream<ReadAhead<TcpStream>>, quic::QuicStream>,
+        req: proto::Request<'a>,
+        stats: &Stats,
+    ) -> anyhow::Result<impl AsyncRead + AsyncWrite + Unpin + Send + Sync> {
+        // Multiplexed connections carry any port, so they're treated as port 0 here
+        let dst_port = req.dst().map(Address::get_port).unwrap_or_default();
         let initial_data = req.to_vec();
 
         cipher::client::connect(
             &HttpUrl {
-                is_https: self.ssl,
+                is_https: tls,
                 address: self.address.clone(),
-                path: Cow::Borrowed("/"),
+                path: Cow::Borrowed(self.path_prefix.as_deref().unwrap_or("/")),
             },
             AsyncStreamCounter::new(stream, stats.rx.clone(), stats.tx.clone()),
-            EncryptionStrategy::new_send(true, dst.get_port(), self.ssl),
-            EncryptionStrategy::new_receive(true, dst.get_port()),
+            EncryptionStrategy::new_send(true, dst_port, tls),
+            EncryptionStrategy::new_receive(true, dst_port),
+            self.cipher,
             self.credentials.as_ref().map(|c| c.to_header_value()),
             initial_data,
+            self.compression.then(|| stats.compression.clone()),
+            self.keepalive_secs
+                .map(|secs| KeepAlive::new(Duration::from_secs(secs))),
+            self.browser_headers.as_ref(),
         )
         .await
     }
+
+    // A pooled connection to `target` with no streams, or the least busy one once the pool
+    // is full. Connections carrying as many streams as they may aren't used.
+    fn pooled_session(
+        &self,
+        pool: &PoolConfig,
+        options: &TcpOptions,
+        target: SocketAddr,
+    ) -> Option<Arc<MuxSession>> {
+        let mut pools = POOLS.lock();
+        // Pools for addresses the upstream no longer resolves to empty out as their
+        // connections close
+        for p in pools.iter_mut() {
+            p.sessions.retain(|s| !s.is_closed());
+        }
+        pools.retain(|p| !p.sessions.is_empty());
+
+        let sessions = &pools
+            .iter()
+            .find(|p| p.upstream == *self && p.options == *options && p.target == target)?
+            .sessions;
+        let session = sessions
+            .iter()
+            .filter(|s| pool.max_streams == 0 || s.active_streams() < pool.max_streams)
+            .min_by_key(|s| s.active_streams())?;
+        if session.active_streams() == 0 || sessions.len() >= pool.size {
+            return Some(session.clone());
+        }
+        None
+    }
+
+    fn add_pooled_session(
+        &self,
+        pool: &PoolConfig,
+        options: &TcpOptions,
+        target: SocketAddr,
+        session: Arc<MuxSession>,
+    ) {
+        let mut pools = POOLS.lock();
+        let index = match pools
+            .iter()
+            .position(|p| p.upstream == *self && p.options == *options && p.target == target)
+        {
+            Some(index) => index,
+            None => {
+                pools.push(Pool {
+                    upstream: self.clone(),
+                    options: options.clone(),
+                    target,
+                    sessions: Vec::new(),
+                });
+                pools.len() - 1
+            }
+        };
+
+        // Past the limit, the connection only lasts as long as its first stream
+        let sessions = &mut pools[index].sessions;
+        if sessions.len() < pool.size {
+            sessions.push(session);
+        }
+    }
+
+    async fn new_pooled_stream(
+        &self,
+        pool: &PoolConfig,
+        dst: &Address<'_>,
+        initial_data: Option<&[u8]>,
+        stats: &Stats,
+        options: &TcpOptions,
+    ) -> anyhow::Result<Box<dyn AsyncStream>> {
+        self.new_pooled_stream_with(
+            pool,
+            |address| async move { address.resolve_first(options.address_family).await },
+            dst,
+            initial_data,
+            stats,
+            options,
+        )
+        .await
+    }
+
+    // The upstream is resolved by `lookup` for every stream, so that once its address
+    // changes, new streams go to the new address while the ones already open carry on
+    async fn new_pooled_stream_with<F, Fut>(
+        &self,
+        pool: &PoolConfig,
+        lookup: F,
+        dst: &Address<'_>,
+        initial_data: Option<&[u8]>,
+        stats: &Stats,
+        options: &TcpOptions,
+    ) -> anyhow::Result<Box<dyn AsyncStream>>
+    where
+        F: FnOnce(Address<'static>) -> Fut,
+        Fut: Future<Output = anyhow::Result<SocketAddr>>,
+    {
+        let target = lookup(self.address.clone())
+            .await
+            .context("Resolving TCPMan server")?;
+        let session = match self.pooled_session(pool, options, target) {
+            Some(v) => v,
+            None => {
+                // Traffic is counted per stream instead
+                let stream = self
+                    .send_request_to(
+                        &Address::IP(target),
+                        proto::Request::Mux,
+                        &Default::default(),
+                        options,
+                    )
+                    .await
+                    .context("Opening multiplexed connection")?;
+                let session = MuxSession::new(stream, Some(Duration::from_secs(pool.idle_secs)));
+                self.add_pooled_session(pool, options, target, session.clone());
+                session
+            }
+        };
+
+        let stream = session.open(dst, initial_data).await?;
+        Ok(Box::new(AsyncStreamCounter::new(
+            stream,
+            stats.rx.clone(),
+            stats.tx.clone(),
+        )))
+    }
 }
 
 #[async_trait]
@@ -86,17 +463,22 @@ impl Protocol for TcpMan {
         dst: &Address<'_>,
         initial_data: Option<&[u8]>,
         stats: &Stats,
-        fwmark: Option<u32>,
+        options: &TcpOptions,
     ) -> anyhow::Result<Box<dyn AsyncStream>> {
+        if let Some(pool) = &self.pool {
+            return self
+                .new_pooled_stream(pool, dst, initial_data, stats, options)
+                .await;
+        }
+
         Ok(Box::new(
             self.send_request(
-                dst,
                 proto::Request::TCP {
                     dst: dst.clone(),
                     initial_data: initial_data.unwrap_or_default(),
                 },
                 stats,
-                fwmark,
+                options,
             )
             .await?,
         ))
@@ -107,17 +489,16 @@ impl Protocol for TcpMan {
         dst: &Address<'_>,
         initial_data: Bytes,
         stats: &Stats,
-        fwmark: Option<u32>,
+        options: &TcpOptions,
     ) -> anyhow::Result<(BoxedSink, BoxedStream)> {
         let (r, w) = self
             .send_request(
-                dst,
                 proto::Request::UDP {
                     dst: dst.clone(),
                     initial_data: initial_data.as_ref(),
                 },
                 stats,
-                fwmark,
+                options,
             )
             .await?
             .split();
@@ -139,10 +520,44 @@ impl Credentials {
 
 #[cfg(test)]
 mod tests {
-    use smol::spawn;
+    use futures::{AsyncReadExt, AsyncWriteExt, SinkExt, StreamExt};
+    use smol::{spawn, Task, Timer};
+    use smol_timeout::TimeoutExt;
+    use std::sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    };
+    use std::time::Duration;
 
     use super::*;
-    use crate::{protocol::test::*, test::create_tcp_server};
+    use crate::{
+        io::connect_tcp,
+        protocol::{load_shed::LoadShedder, test::*},
+        test::{create_tcp_server, echo_tcp_server, echo_udp_server},
+        utils::copy_duplex,
+    };
+
+    // Each test sets just the fields it's about
+    fn test_tcpman(address: Address<'static>) -> TcpMan {
+        TcpMan {
+            address,
+            ssl: false,
+            allows_udp: false,
+            credentials: None,
+            keepalive_secs: None,
+            client_identity: None,
+            sni: None,
+            alpn: None,
+            cipher: Default::default(),
+            ssl_fallback: false,
+            read_buffer_size: None,
+            pool: None,
+            path_prefix: None,
+            quic: None,
+            compression: false,
+            browser_headers: None,
+        }
+    }
 
     #[test]
     fn tcpman_works() {
@@ -150,13 +565,12 @@ mod tests {
         let _ = env_logger::try_init();
         smol::block_on(async move {
             let (server, addr) = create_tcp_server().await;
-            let _task = spawn(super::server::run_server(server));
+            let _task = spawn(super::server::run_server(server, Default::default()));
 
             let p = TcpMan {
-                address: addr.into(),
-                ssl: false,
                 allows_udp: true,
-                credentials: None,
+                keepalive_secs: Some(30),
+                ..test_tcpman(addr.into())
             };
 
             test_protocol_http(&p).await;
@@ -164,4 +578,864 @@ mod tests {
             test_protocol_udp(&p).await;
         });
     }
+
+    #[test]
+    fn load_shedding_works() {
+        smol::block_on(async move {
+            let (server, addr) = create_tcp_server().await;
+            let shedder = LoadShedder::new(1);
+            let _task = spawn(super::server::run_server(
+                server,
+                super::server::ServerOptions {
+                    load_shedder: shedder.clone(),
+                    ..Default::default()
+                },
+            ));
+            let (_echo_task, echo_addr) = echo_tcp_server().await;
+
+            let p = test_tcpman(addr.into());
+            let connect = || async {
+                p.new_stream(
+                    &echo_addr.into(),
+                    None,
+                    &Default::default(),
+                    &Default::default(),
+                )
+                .timeout(Duration::from_secs(1))
+                .await
+                .expect("No timeout")
+            };
+            let echo = |mut stream: Box<dyn AsyncStream>| async move {
+                stream.write_all(b"hello").await.unwrap();
+                let mut buf = [0u8; 5];
+                stream.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hello");
+                stream
+            };
+
+            let existing = echo(connect().await.unwrap()).await;
+            assert_eq!(shedder.active_connections(), 1);
+
+            // New connections are refused while the existing one keeps working
+            let refused = connect().await.err().expect("To be refused");
+            assert!(format!("{refused:?}").contains("got 503"), "{refused:?}");
+            let existing = echo(existing).await;
+
+            drop(existing);
+            for _ in 0..50 {
+                if shedder.active_connections() == 0 {
+                    break;
+                }
+                Timer::after(Duration::from_millis(20)).await;
+            }
+            echo(connect().await.unwrap()).await;
+        });
+    }
+
+    #[test]
+    fn udp_associate_preserves_datagrams() {
+        smol::block_on(async move {
+            let (server, addr) = create_tcp_server().await;
+            let _task = spawn(super::server::run_server(server, Default::default()));
+            let (_echo_task, echo_addr) = echo_udp_server().await;
+
+            let p = TcpMan {
+                allows_udp: true,
+                ..test_tcpman(addr.into())
+            };
+
+            let (mut sink, mut stream) = p
+                .new_datagram(
+                    &echo_addr.into(),
+                    Bytes::from_static(b"init"),
+                    &Default::default(),
+                    &Default::default(),
+                )
+                .timeout(Duration::from_secs(1))
+                .await
+                .expect("No timeout")
+                .expect("To create new dgram conn");
+
+            let (initial, _) = stream.next().await.unwrap().unwrap();
+            assert_eq!(initial.as_ref(), b"init");
+
+            // Sizes larger than a TLS record (16KiB) arrive split over several reads
+            let sizes = [1usize, 512, 1400, 16384, 16385, 40000, 60000];
+            let datagrams: Vec<Bytes> = sizes
+                .iter()
+                .enumerate()
+                .map(|(i, len)| (0..*len).map(|b| (b + i) as u8).collect::<Vec<_>>().into())
+                .collect();
+
+            for data in &datagrams {
+                sink.send((data.clone(), echo_addr.into())).await.unwrap();
+            }
+
+            for data in &datagrams {
+                let (received, from) = stream
+                    .next()
+                    .timeout(Duration::from_secs(1))
+                    .await
+                    .expect("No timeout")
+                    .expect("To receive something")
+                    .expect("To receive datagram");
+                assert_eq!(&received, data);
+                assert_eq!(from.get_port(), echo_addr.port());
+            }
+        });
+    }
+
+    #[test]
+    fn ssl_fallback_remembers_plain() {
+        smol::block_on(async move {
+            let (server, server_addr) = create_tcp_server().await;
+            let _task = spawn(super::server::run_server(server, Default::default()));
+            let (_echo_task, echo_addr) = echo_tcp_server().await;
+
+            // Counts the connections made to the plain-only server
+            let (front, front_addr) = create_tcp_server().await;
+            let accepted = Arc::new(AtomicUsize::new(0));
+            let _front_task = spawn({
+                let accepted = accepted.clone();
+                async move {
+                    loop {
+                        let (stream, _) = front.accept().await.unwrap();
+                        accepted.fetch_add(1, Ordering::SeqCst);
+                        let upstream = connect_tcp(&server_addr.into()).await.unwrap();
+                        spawn(copy_duplex(stream, upstream, None, None)).detach();
+                    }
+                }
+            });
+
+            let p = TcpMan {
+                ssl: true,
+                ssl_fallback: true,
+                ..test_tcpman(front_addr.into())
+            };
+            let echo = || async {
+                let mut stream = p
+                    .new_stream(
+                        &echo_addr.into(),
+                        None,
+                        &Default::default(),
+                        &Default::default(),
+                    )
+                    .timeout(Duration::from_secs(5))
+                    .await
+                    .expect("No timeout")
+                    .expect("To fall back to plain");
+                stream.write_all(b"hello").await.unwrap();
+                let mut buf = [0u8; 5];
+                stream.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hello");
+            };
+
+            echo().await;
+            assert_eq!(accepted.load(Ordering::SeqCst), 2);
+            assert_eq!(TLS_MODES.get(&p), Some(false));
+
+            // Plain is tried first from now on
+            echo().await;
+            assert_eq!(accepted.load(Ordering::SeqCst), 3);
+
+            // But never with credentials, which would go out in plain text
+            let with_credentials = TcpMan {
+                credentials: Some(Credentials {
+                    username: "user".to_string(),
+                    password: "password".to_string(),
+                    password_source: None,
+                }),
+                ..p.clone()
+            };
+            assert_eq!(TLS_MODES.get(&with_credentials), None);
+            assert!(with_credentials
+                .new_stream(
+                    &echo_addr.into(),
+                    None,
+                    &Default::default(),
+                    &Default::default()
+                )
+                .timeout(Duration::from_secs(5))
+                .await
+                .expect("No timeout")
+                .is_err());
+            assert_eq!(accepted.load(Ordering::SeqCst), 4);
+        });
+    }
+
+    // Forwards to the server, counting the connections made through it
+    async fn counting_front(server_addr: SocketAddr) -> (Task<()>, SocketAddr, Arc<AtomicUsize>) {
+        let (front, front_addr) = create_tcp_server().await;
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let task = spawn({
+            let accepted = accepted.clone();
+            async move {
+                loop {
+                    let (stream, _) = front.accept().await.unwrap();
+                    accepted.fetch_add(1, Ordering::SeqCst);
+                    let upstream = connect_tcp(&server_addr.into()).await.unwrap();
+                    spawn(copy_duplex(stream, upstream, None, None)).detach();
+                }
+            }
+        });
+        (task, front_addr, accepted)
+    }
+
+    #[test]
+    fn pooled_streams_reuse_connections() {
+        smol::block_on(async move {
+            let (server, server_addr) = create_tcp_server().await;
+            let _task = spawn(super::server::run_server(server, Default::default()));
+            let (_echo_task, echo_addr) = echo_tcp_server().await;
+
+            let (_front_task, front_addr, accepted) = counting_front(server_addr).await;
+
+            let p = TcpMan {
+                pool: Some(PoolConfig {
+                    size: 2,
+                    idle_secs: 60,
+                    max_streams: 0,
+                }),
+                ..test_tcpman(front_addr.into())
+            };
+
+            let requests = 5;
+            for i in 0..requests {
+                let msg = format!("hello {i}");
+                let mut stream = p
+                    .new_stream(
+                        &echo_addr.into(),
+                        Some(msg.as_bytes()),
+                        &Default::default(),
+                        &Default::default(),
+                    )
+                    .timeout(Duration::from_secs(5))
+                    .await
+                    .expect("No timeout")
+                    .expect("To open pooled stream");
+                let mut buf = vec![0u8; msg.len()];
+                stream.read_exact(&mut buf).await.unwrap();
+                assert_eq!(buf, msg.as_bytes());
+            }
+
+            assert!(accepted.load(Ordering::SeqCst) < requests);
+        });
+    }
+
+    #[test]
+    fn pooled_streams_are_capped_per_connection() {
+        smol::block_on(async move {
+            let (server, server_addr) = create_tcp_server().await;
+            let _task = spawn(super::server::run_server(server, Default::default()));
+            let (_echo_task, echo_addr) = echo_tcp_server().await;
+
+            let (_front_task, front_addr, accepted) = counting_front(server_addr).await;
+
+            let p = TcpMan {
+                pool: Some(PoolConfig {
+                    size: 1,
+                    idle_secs: 60,
+                    max_streams: 2,
+                }),
+                ..test_tcpman(front_addr.into())
+            };
+
+            // Kept open so they all count against the cap
+            let mut streams = Vec::new();
+            for i in 0..3 {
+                let msg = format!("hello {i}");
+                let mut stream = p
+                    .new_stream(
+                        &echo_addr.into(),
+                        Some(msg.as_bytes()),
+                        &Default::default(),
+                        &Default::default(),
+                    )
+                    .timeout(Duration::from_secs(5))
+                    .await
+                    .expect("No timeout")
+                    .expect("To open pooled stream");
+                let mut buf = vec![0u8; msg.len()];
+                stream.read_exact(&mut buf).await.unwrap();
+                assert_eq!(buf, msg.as_bytes());
+                streams.push(stream);
+                // The third stream needs a connection of its own
+                assert_eq!(accepted.load(Ordering::SeqCst), if i < 2 { 1 } else { 2 });
+            }
+        });
+    }
+
+    #[test]
+    fn pooled_streams_follow_address_changes() {
+        smol::block_on(async move {
+            let (server, server_addr) = create_tcp_server().await;
+            let _task = spawn(super::server::run_server(server, Default::default()));
+            let (_echo_task, echo_addr) = echo_tcp_server().await;
+
+            // Where the upstream resolves to before and after its DNS record changes
+            let (_old_task, old_addr, old_accepted) = counting_front(server_addr).await;
+            let (_new_task, new_addr, new_accepted) = counting_front(server_addr).await;
+
+            let p = TcpMan {
+                pool: Some(PoolConfig {
+                    size: 4,
+                    idle_secs: 60,
+                    max_streams: 0,
+                }),
+                ..test_tcpman(Address::Name {
+                    host: "tcpman.example.com".into(),
+                    port: old_addr.port(),
+                })
+            };
+            let pool = p.pool.clone().unwrap();
+
+            let echo = |mut stream: Box<dyn AsyncStream>, msg: &'static str| async move {
+                stream.write_all(msg.as_bytes()).await.unwrap();
+                let mut buf = vec![0u8; msg.len()];
+                stream
+                    .read_exact(&mut buf)
+                    .timeout(Duration::from_secs(5))
+                    .await
+                    .expect("No timeout")
+                    .unwrap();
+                assert_eq!(buf, msg.as_bytes());
+                stream
+            };
+
+            // Stands in for DNS, answering with the new address once the record changes
+            let changed = Arc::new(AtomicBool::new(false));
+            let lookups = Arc::new(AtomicUsize::new(0));
+            let open = || {
+                let p = p.clone();
+                let pool = pool.clone();
+                let changed = changed.clone();
+                let lookups = lookups.clone();
+                let expected = p.address.clone();
+                async move {
+                    p.new_pooled_stream_with(
+                        &pool,
+                        |address| async move {
+                            assert_eq!(address, expected);
+                            lookups.fetch_add(1, Ordering::SeqCst);
+                            Ok(match changed.load(Ordering::SeqCst) {
+                                false => old_addr,
+                                true => new_addr,
+                            })
+                        },
+                        &echo_addr.into(),
+                        None,
+                        &Default::default(),
+                        &Default::default(),
+                    )
+                    .timeout(Duration::from_secs(5))
+                    .await
+                    .expect("No timeout")
+                    .expect("To open pooled stream")
+                }
+            };
+
+            let old_stream = echo(open().await, "before").await;
+            assert_eq!(old_accepted.load(Ordering::SeqCst), 1);
+
+            // New streams go to the new address, over a connection of their own
+            changed.store(true, Ordering::SeqCst);
+            for _ in 0..3 {
+                drop(echo(open().await, "after").await);
+            }
+            assert_eq!(old_accepted.load(Ordering::SeqCst), 1);
+            assert_eq!(new_accepted.load(Ordering::SeqCst), 1);
+            assert_eq!(lookups.load(Ordering::SeqCst), 4);
+
+            // Streams opened before the change carry on
+            echo(old_stream, "still there").await;
+        });
+    }
+
+    // Echoes a repetitive payload, returning the compression stats of the stream
+    async fn echo_compressible(allow_compression: bool, compression: bool) -> Stats {
+        let (server, addr) = create_tcp_server().await;
+        let _task = spawn(super::server::run_server(
+            server,
+            super::server::ServerOptions {
+                handshake_rules: super::server::HandshakeRules {
+                    allow_compression,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        ));
+        let (_echo_task, echo_addr) = echo_tcp_server().await;
+
+        let p = TcpMan {
+            compression,
+            ..test_tcpman(addr.into())
+        };
+        let stats = Stats::default();
+        assert_eq!(stats.compression.ratio(), None);
+
+        let stream = p
+            .new_stream(&echo_addr.into(), None, &stats, &Default::default())
+            .timeout(Duration::from_secs(5))
+            .await
+            .expect("No timeout")
+            .expect("To connect");
+        let (mut r, mut w) = stream.split();
+        let payload = b"hello, world! ".repeat(5000);
+        let mut received = vec![0u8; payload.len()];
+        let (write, read) = futures::join!(w.write_all(&payload), r.read_exact(&mut received));
+        write.unwrap();
+        read.unwrap();
+        assert_eq!(received, payload);
+        stats
+    }
+
+    #[test]
+    fn compression_ratio_is_reported() {
+        smol::block_on(async move {
+            let stats = echo_compressible(true, true).await;
+
+            // Both what was sent and what came back are counted, and as it's compressed
+            // before it's encrypted, the repetitive payload compresses well
+            assert!(stats.compression.plain.get() >= b"hello, world! ".len() * 5000 * 2);
+            let ratio = stats.compression.ratio().unwrap();
+            assert!(ratio < 0.1, "{ratio}");
+        });
+    }
+
+    #[test]
+    fn compression_needs_both_sides() {
+        smol::block_on(async move {
+            for (allow_compression, compression) in [(false, true), (true, false)] {
+                let stats = echo_compressible(allow_compression, compression).await;
+                assert_eq!(stats.compression.ratio(), None);
+            }
+        });
+    }
+
+    #[test]
+    fn server_accepts_rotated_credentials() {
+        smol::block_on(async move {
+            let (server, addr) = create_tcp_server().await;
+            let _task = spawn(super::server::run_server(
+                server,
+                super::server::ServerOptions {
+                    credentials: "user:new-password user:old-password".parse().unwrap(),
+                    ..Default::default()
+                },
+            ));
+            let (_echo_task, echo_addr) = echo_tcp_server().await;
+
+            let connect = |password: Option<&str>| {
+                let p = TcpMan {
+                    credentials: password.map(|password| Credentials {
+                        username: "user".to_string(),
+                        password: password.to_string(),
+                        password_source: None,
+                    }),
+                    ..test_tcpman(addr.into())
+                };
+                async move {
+                    p.new_stream(
+                        &echo_addr.into(),
+                        None,
+                        &Default::default(),
+                        &Default::default(),
+                    )
+                    .timeout(Duration::from_secs(5))
+                    .await
+                    .expect("No timeout")
+                }
+            };
+
+            for password in ["new-password", "old-password"] {
+                let mut stream = connect(Some(password))
+                    .await
+                    .expect("Rotated credentials to be accepted");
+                stream.write_all(b"hello").await.unwrap();
+                let mut buf = [0u8; 5];
+                stream.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hello");
+            }
+
+            for password in [Some("wrong-password"), None] {
+                let err = connect(password)
+                    .await
+                    .err()
+                    .expect("Other credentials to be refused");
+                assert!(format!("{err:?}").contains("401"), "{err:?}");
+            }
+
+            // Anything but a tcpman request gets the missing page, credentials or not
+            let mut probe = connect_tcp(&addr.into()).await.unwrap();
+            probe
+                .write_all(
+                    format!(
+                        "GET /index.html HTTP/1.1\r\n\
+                        Host: {addr}\r\n\
+                        Connection: Upgrade\r\n\
+                        Upgrade: websocket\r\n\
+                        Sec-WebSocket-Version: 13\r\n\
+                        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"
+                    )
+                    .as_bytes(),
+                )
+                .await
+                .unwrap();
+            let mut response = Vec::new();
+            probe.read_to_end(&mut response).await.unwrap();
+            assert!(
+                response.starts_with(b"HTTP/1.1 404"),
+                "{}",
+                String::from_utf8_lossy(&response)
+            );
+        });
+    }
+
+    #[test]
+    fn server_only_serves_under_path_prefix() {
+        smol::block_on(async move {
+            let (server, addr) = create_tcp_server().await;
+            let _task = spawn(super::server::run_server(
+                server,
+                super::server::ServerOptions {
+                    handshake_rules: super::server::HandshakeRules {
+                        path_prefix: "/cdn/".parse().unwrap(),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            ));
+            let (_echo_task, echo_addr) = echo_tcp_server().await;
+
+            let connect = |path_prefix: Option<&str>| {
+                let p = TcpMan {
+                    path_prefix: path_prefix.map(str::to_string),
+                    ..test_tcpman(addr.into())
+                };
+                async move {
+                    p.new_stream(
+                        &echo_addr.into(),
+                        None,
+                        &Default::default(),
+                        &Default::default(),
+                    )
+                    .await
+                }
+            };
+
+            for prefix in ["/cdn", "/cdn/"] {
+                let mut stream = connect(Some(prefix))
+                    .await
+                    .expect("Prefixed path to be served");
+                stream.write_all(b"hello").await.unwrap();
+                let mut buf = [0u8; 5];
+                stream.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hello");
+            }
+
+            // Other paths are answered as if they were invalid
+            for prefix in [None, Some("/cdnx"), Some("/other/cdn")] {
+                let err = connect(prefix)
+                    .await
+                    .err()
+                    .expect("Path outside the prefix to be refused");
+                assert!(format!("{err:?}").contains("404"), "{err:?}");
+            }
+        });
+    }
+
+    #[test]
+    fn short_requests_get_not_found() {
+        smol::block_on(async move {
+            let (server, addr) = create_tcp_server().await;
+            let _task = spawn(super::server::run_server(
+                server,
+                super::server::ServerOptions {
+                    handshake_rules: super::server::HandshakeRules {
+                        min_request_bytes: 400,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            ));
+            let (_echo_task, echo_addr) = echo_tcp_server().await;
+
+            // A bare websocket upgrade to a valid path, as a prober would send
+            let (cipher_type, key, iv) = cipher::suite::pick_keys(CipherKind::ChaCha20);
+            let params = cipher::client::CipherParams {
+                key: Cow::Borrowed(key.as_slice()),
+                iv: Cow::Borrowed(iv.as_slice()),
+                send_strategy: EncryptionStrategy::Always,
+                recv_strategy: EncryptionStrategy::Always,
+                cipher_type,
+                timestamp: cipher::client::unix_timestamp(),
+            };
+            let mut probe = connect_tcp(&addr.into()).await.unwrap();
+            probe
+                .write_all(
+                    format!(
+                        "GET {params} HTTP/1.1\r\n\
+                        Host: {addr}\r\n\
+                        Connection: Upgrade\r\n\
+                        Upgrade: websocket\r\n\
+                        Sec-WebSocket-Version: 13\r\n\
+                        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"
+                    )
+                    .as_bytes(),
+                )
+                .await
+                .unwrap();
+            let mut response = Vec::new();
+            probe.read_to_end(&mut response).await.unwrap();
+            assert!(
+                response.starts_with(b"HTTP/1.1 404"),
+                "{}",
+                String::from_utf8_lossy(&response)
+            );
+
+            // Clients get through once they send what a browser would
+            let p = test_tcpman(addr.into());
+            assert!(p
+                .new_stream(
+                    &echo_addr.into(),
+                    None,
+                    &Default::default(),
+                    &Default::default(),
+                )
+                .await
+                .is_err());
+
+            let p = TcpMan {
+                browser_headers: Some(BrowserHeaders {
+                    user_agent: "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 \
+                        (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36"
+                        .to_string(),
+                    accept_language: "en-US,en;q=0.9".to_string(),
+                }),
+                ..p
+            };
+            let mut stream = p
+                .new_stream(
+                    &echo_addr.into(),
+                    Some(b"hello"),
+                    &Default::default(),
+                    &Default::default(),
+                )
+                .await
+                .expect("Client's request to be served");
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+        });
+    }
+
+    #[test]
+    fn quic_transport_works() {
+        smol::block_on(async move {
+            let mut config = quic::server_config(
+                include_bytes!("../../test/certs/server.pem"),
+                include_bytes!("../../test/certs/server.key"),
+                true,
+            )
+            .unwrap();
+            let mut transport = quinn::TransportConfig::default();
+            transport.max_concurrent_bidi_streams(2u32.into());
+            config.transport_config(Arc::new(transport));
+            let endpoint = quic::bind_server("127.0.0.1:0".parse().unwrap(), config).unwrap();
+            let server_addr = endpoint.local_addr().unwrap();
+            let _task = spawn(super::server::run_quic_server(
+                endpoint,
+                super::server::ServerOptions {
+                    credentials: "user:password".parse().unwrap(),
+                    ..Default::default()
+                },
+            ));
+            let (_echo_task, echo_addr) = echo_tcp_server().await;
+
+            let p = TcpMan {
+                credentials: Some(Credentials {
+                    username: "user".to_string(),
+                    password: "password".to_string(),
+                    password_source: None,
+                }),
+                cipher: CipherKind::ChaCha20,
+                quic: Some(QuicConfig {
+                    ca: Some(include_str!("../../test/certs/ca.pem").to_string()),
+                }),
+                ..test_tcpman(Address::Name {
+                    host: "localhost".into(),
+                    port: server_addr.port(),
+                })
+            };
+
+            // Later streams are opened over the connection the first one made, until it
+            // has as many as the server allows
+            let mut streams = Vec::new();
+            for i in 0..5 {
+                let msg = format!("hello {i}");
+                let mut stream = p
+                    .new_stream(
+                        &echo_addr.into(),
+                        Some(msg.as_bytes()),
+                        &Default::default(),
+                        &Default::default(),
+                    )
+                    .timeout(Duration::from_secs(5))
+                    .await
+                    .expect("No timeout")
+                    .expect("To open stream over QUIC");
+                let mut buf = vec![0u8; msg.len()];
+                stream.read_exact(&mut buf).await.unwrap();
+                assert_eq!(buf, msg.as_bytes());
+
+                stream.write_all(b"world").await.unwrap();
+                let mut buf = [0u8; 5];
+                stream.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"world");
+                streams.push(stream);
+            }
+            drop(streams);
+
+            // The server isn't trusted without the CA
+            let untrusted = TcpMan {
+                quic: Some(Default::default()),
+                ..p.clone()
+            };
+            assert!(untrusted
+                .new_stream(
+                    &echo_addr.into(),
+                    None,
+                    &Default::default(),
+                    &Default::default()
+                )
+                .timeout(Duration::from_secs(5))
+                .await
+                .expect("No timeout")
+                .is_err());
+        });
+    }
+
+    #[test]
+    fn initial_data_round_trips_over_quic_early_data() {
+        smol::block_on(async move {
+            let (_echo_task, echo_addr) = echo_tcp_server().await;
+
+            for early_data in [true, false] {
+                let config = quic::server_config(
+                    include_bytes!("../../test/certs/server.pem"),
+                    include_bytes!("../../test/certs/server.key"),
+                    early_data,
+                )
+                .unwrap();
+                let endpoint = quic::bind_server("127.0.0.1:0".parse().unwrap(), config).unwrap();
+                let server_addr = endpoint.local_addr().unwrap();
+                let _task = spawn(super::server::run_quic_server(
+                    endpoint,
+                    super::server::ServerOptions {
+                        credentials: "user:password".parse().unwrap(),
+                        ..Default::default()
+                    },
+                ));
+
+                let quic_config = QuicConfig {
+                    ca: Some(include_str!("../../test/certs/ca.pem").to_string()),
+                };
+                let p = TcpMan {
+                    credentials: Some(Credentials {
+                        username: "user".to_string(),
+                        password: "password".to_string(),
+                        password_source: None,
+                    }),
+                    cipher: CipherKind::ChaCha20,
+                    quic: Some(quic_config.clone()),
+                    ..test_tcpman(Address::Name {
+                        host: "localhost".into(),
+                        port: server_addr.port(),
+                    })
+                };
+
+                // The first connection gets the session the next one resumes
+                let mut stream = p
+                    .new_stream(
+                        &echo_addr.into(),
+                        Some(b"hello"),
+                        &Default::default(),
+                        &Default::default(),
+                    )
+                    .timeout(Duration::from_secs(5))
+                    .await
+                    .expect("No timeout")
+                    .expect("To open stream over QUIC");
+                let mut buf = [0u8; 5];
+                stream.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hello");
+                quic::close_connections(&p);
+
+                let (stream, accepted) =
+                    quic::open_stream(&p, &quic_config, &p.address, &Default::default())
+                        .await
+                        .expect("To open stream over QUIC");
+                assert_eq!(accepted.is_some(), early_data);
+
+                let mut stream = p
+                    .handshake(
+                        true,
+                        Either::Right(stream),
+                        proto::Request::TCP {
+                            dst: echo_addr.into(),
+                            initial_data: b"early",
+                        },
+                        &Default::default(),
+                    )
+                    .timeout(Duration::from_secs(5))
+                    .await
+                    .expect("No timeout")
+                    .expect("To send request");
+                let mut buf = [0u8; 5];
+                stream.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"early");
+
+                if let Some(accepted) = accepted {
+                    assert!(accepted.await, "0-RTT to be accepted");
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn password_can_be_read_from_file_or_env() {
+        let inline: Credentials = serde_yaml::from_str("username: user\npassword: secret").unwrap();
+
+        let path = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::write(&path, "secret\n").unwrap();
+        let from_file: Credentials = serde_yaml::from_value(serde_yaml::Value::Mapping(
+            [
+                ("username".into(), "user".into()),
+                ("password_file".into(), path.to_str().unwrap().into()),
+            ]
+            .into_iter()
+            .collect(),
+        ))
+        .unwrap();
+
+        let env = format!("CPXY_TEST_PASSWORD_{}", uuid::Uuid::new_v4().simple());
+        std::env::set_var(&env, "secret");
+        let from_env: Credentials =
+            serde_yaml::from_str(&format!("username: user\npassword_env: {env}")).unwrap();
+
+        for c in [&from_file, &from_env] {
+            assert_eq!(
+                c.to_header_value().to_string(),
+                inline.to_header_value().to_string()
+            );
+
+            // The password itself isn't written back out
+            let written = serde_yaml::to_string(c).unwrap();
+            assert!(!written.contains("secret"), "{written}");
+            assert_eq!(&serde_yaml::from_str::<Credentials>(&written).unwrap(), c);
+        }
+
+        let _ = std::fs::remove_file(&path);
+        assert!(serde_yaml::from_str::<Credentials>("username: user").is_err());
+    }
 }
@@ -0,0 +1,780 @@
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{ready, Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context as _};
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, Bytes};
+use enum_primitive_derive::Primitive;
+use futures::{
+    channel::{mpsc, oneshot},
+    future::pending,
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, StreamExt,
+};
+use num_traits::FromPrimitive;
+use parking_lot::Mutex;
+use smol::{spawn, Task, Timer};
+
+use super::proto;
+use crate::{socks5::Address, utils::race};
+
+// Many streams share one tcpman connection, each frame being `id: u32`, `kind: u8` and
+// `len: u16` followed by `len` bytes of payload.
+const FRAME_HEADER_LEN: usize = 7;
+const MAX_FRAME_PAYLOAD: usize = u16::MAX as usize;
+
+// Bytes a stream may send before its peer has read them. Readers grant more as they go,
+// so a stream that isn't read stalls on its own rather than for the whole connection.
+const STREAM_WINDOW: usize = 256 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Primitive)]
+#[repr(u8)]
+enum FrameKind {
+    // From the client, a `proto::Request` opening a stream. From the server, the stream is open.
+    Open = 0,
+    Data = 1,
+    // The sender won't write to the stream anymore, though it may still read from it
+    Close = 2,
+    // The stream is aborted both ways. From the server in answer to an `Open`, the stream
+    // couldn't be opened, with the reason as payload.
+    Reset = 3,
+    // The receiver has read some data, and grants the sender `u32` more bytes of window
+    Window = 4,
+}
+
+#[derive(Debug)]
+struct Frame {
+    id: u32,
+    kind: FrameKind,
+    payload: Bytes,
+}
+
+impl Frame {
+    fn new(id: u32, kind: FrameKind) -> Self {
+        Self {
+            id,
+            kind,
+            payload: Bytes::new(),
+        }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.put_u32(self.id);
+        buf.put_u8(self.kind as u8);
+        buf.put_u16(self.payload.len() as u16);
+        buf.extend_from_slice(&self.payload);
+    }
+
+    async fn read(r: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<Option<Self>> {
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        match r.read_exact(&mut header).await {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            r => r.context("Reading frame header")?,
+        }
+
+        let kind = FrameKind::from_u8(header[4]).context("Reading frame kind")?;
+        let mut payload = vec![0u8; BigEndian::read_u16(&header[5..]) as usize];
+        r.read_exact(&mut payload)
+            .await
+            .context("Reading frame payload")?;
+
+        Ok(Some(Self {
+            id: BigEndian::read_u32(&header),
+            kind,
+            payload: payload.into(),
+        }))
+    }
+}
+
+// What a stream and the connection running it both see
+struct StreamState {
+    // What's left of the window the peer granted, and the writer waiting for more
+    send_window: Mutex<(usize, Option<Waker>)>,
+    // What's left of the window granted to the peer
+    recv_window: AtomicUsize,
+    reset: AtomicBool,
+}
+
+impl StreamState {
+    fn new() -> Self {
+        Self {
+            send_window: Mutex::new((STREAM_WINDOW, None)),
+            recv_window: AtomicUsize::new(STREAM_WINDOW),
+            reset: AtomicBool::new(false),
+        }
+    }
+
+    fn grant(&self, len: usize) {
+        let mut window = self.send_window.lock();
+        window.0 = window.0.saturating_add(len);
+        if let Some(waker) = window.1.take() {
+            waker.wake();
+        }
+    }
+
+    fn wake_writer(&self) {
+        if let Some(waker) = self.send_window.lock().1.take() {
+            waker.wake();
+        }
+    }
+
+    fn reset(&self) {
+        self.reset.store(true, Ordering::Relaxed);
+        self.wake_writer();
+    }
+}
+
+struct StreamEntry {
+    // Gone once the peer has closed its side
+    data: Option<mpsc::UnboundedSender<Bytes>>,
+    state: Arc<StreamState>,
+    // Whether our side is closed. The entry goes once both sides are.
+    closed: bool,
+    // Only there on the client until the server answers the `Open`
+    opened: Option<oneshot::Sender<Result<(), String>>>,
+}
+
+struct Shared {
+    streams: Mutex<HashMap<u32, StreamEntry>>,
+    // Unbounded as what's queued is bounded by the stream windows, and so that dropping a
+    // stream can always tell the peer
+    outgoing: mpsc::UnboundedSender<Frame>,
+    // `MuxStream`s not dropped yet, which may be fewer than `streams` while peers finish
+    // closing theirs
+    handles: AtomicUsize,
+    last_active: Mutex<Instant>,
+    closed: AtomicBool,
+}
+
+impl Shared {
+    fn touch(&self) {
+        *self.last_active.lock() = Instant::now();
+    }
+
+    fn send(&self, frame: Frame) -> std::io::Result<()> {
+        self.outgoing
+            .unbounded_send(frame)
+            .map_err(|_| ErrorKind::BrokenPipe.into())
+    }
+
+    fn remove(&self, id: u32) -> Option<StreamEntry> {
+        self.touch();
+        self.streams.lock().remove(&id)
+    }
+
+    // Applies `f` to the stream, removing it if that leaves both sides closed
+    fn update(&self, id: u32, f: impl FnOnce(&mut StreamEntry)) {
+        let mut streams = self.streams.lock();
+        let Some(entry) = streams.get_mut(&id) else {
+            return;
+        };
+        f(entry);
+        if entry.closed && entry.data.is_none() {
+            streams.remove(&id);
+            drop(streams);
+            self.touch();
+        }
+    }
+
+    // Aborts the stream both here and on the peer
+    fn reset(&self, id: u32) {
+        if let Some(entry) = self.remove(id) {
+            entry.state.reset();
+        }
+        let _ = self.send(Frame::new(id, FrameKind::Reset));
+    }
+
+    fn idle_for(&self) -> Duration {
+        if !self.streams.lock().is_empty() {
+            return Duration::ZERO;
+        }
+        self.last_active.lock().elapsed()
+    }
+
+    fn new_stream(
+        self: &Arc<Self>,
+        id: u32,
+        opened: Option<oneshot::Sender<Result<(), String>>>,
+    ) -> MuxStream {
+        let (tx, rx) = mpsc::unbounded();
+        let state = Arc::new(StreamState::new());
+        self.streams.lock().insert(
+            id,
+            StreamEntry {
+                data: Some(tx),
+                state: state.clone(),
+                closed: false,
+                opened,
+            },
+        );
+        self.handles.fetch_add(1, Ordering::Relaxed);
+        self.touch();
+        MuxStream {
+            id,
+            shared: self.clone(),
+            incoming: rx,
+            pending: Bytes::new(),
+            state,
+            unacknowledged: 0,
+            closed: false,
+            _session: None,
+        }
+    }
+}
+
+async fn write_frames(
+    mut w: impl AsyncWrite + Unpin,
+    mut frames: mpsc::UnboundedReceiver<Frame>,
+) -> anyhow::Result<()> {
+    let mut buf = Vec::new();
+    while let Some(frame) = frames.next().await {
+        buf.clear();
+        frame.encode(&mut buf);
+        // Write out whatever else is queued up along with it
+        while buf.len() < MAX_FRAME_PAYLOAD {
+            match frames.try_next() {
+                Ok(Some(frame)) => frame.encode(&mut buf),
+                _ => break,
+            }
+        }
+        w.write_all(&buf).await.context("Writing frames")?;
+        w.flush().await.context("Flushing frames")?;
+    }
+    Ok(())
+}
+
+async fn read_frames(
+    mut r: impl AsyncRead + Unpin,
+    shared: &Arc<Shared>,
+    mut on_open: impl FnMut(MuxStream, Bytes),
+) -> anyhow::Result<()> {
+    while let Some(frame) = Frame::read(&mut r).await? {
+        shared.touch();
+        match frame.kind {
+            FrameKind::Open => {
+                let entry = shared
+                    .streams
+                    .lock()
+                    .get_mut(&frame.id)
+                    .map(|e| e.opened.take());
+                match entry {
+                    Some(Some(opened)) => {
+                        let _ = opened.send(Ok(()));
+                    }
+                    Some(None) => log::warn!("Stream {} is already open", frame.id),
+                    None => on_open(shared.new_stream(frame.id, None), frame.payload),
+                }
+            }
+            FrameKind::Data => {
+                let len = frame.payload.len();
+                let accepted = match shared.streams.lock().get(&frame.id) {
+                    Some(entry) => {
+                        // Data beyond the window, after the peer's `Close` or for a
+                        // stream that is no longer read can't be taken
+                        entry
+                            .state
+                            .recv_window
+                            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |w| {
+                                w.checked_sub(len)
+                            })
+                            .is_ok()
+                            && entry
+                                .data
+                                .as_ref()
+                                .is_some_and(|d| d.unbounded_send(frame.payload).is_ok())
+                    }
+                    None => continue,
+                };
+                if !accepted {
+                    log::debug!("Resetting stream {} that can't take more data", frame.id);
+                    shared.reset(frame.id);
+                }
+            }
+            FrameKind::Close => shared.update(frame.id, |e| e.data = None),
+            FrameKind::Reset => {
+                if let Some(entry) = shared.remove(frame.id) {
+                    entry.state.reset();
+                    if let Some(opened) = entry.opened {
+                        let _ =
+                            opened.send(Err(String::from_utf8_lossy(&frame.payload).into_owned()));
+                    }
+                }
+            }
+            FrameKind::Window => {
+                if frame.payload.len() != 4 {
+                    bail!("Invalid window frame for stream {}", frame.id);
+                }
+                let len = BigEndian::read_u32(&frame.payload) as usize;
+                if let Some(entry) = shared.streams.lock().get(&frame.id) {
+                    entry.state.grant(len);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Runs the connection until it fails, or until it's been without streams for `idle`.
+// Streams still open see the end of their data when it stops.
+fn run_session(
+    stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    idle: Option<Duration>,
+    on_open: impl FnMut(MuxStream, Bytes) + Send + 'static,
+) -> (Arc<Shared>, Task<()>) {
+    let (tx, rx) = mpsc::unbounded();
+    let shared = Arc::new(Shared {
+        streams: Default::default(),
+        outgoing: tx,
+        handles: AtomicUsize::new(0),
+        last_active: Mutex::new(Instant::now()),
+        closed: AtomicBool::new(false),
+    });
+
+    let task = spawn({
+        let shared = shared.clone();
+        async move {
+            let (r, w) = stream.split();
+            let idle_check = async {
+                let Some(idle) = idle else {
+                    return pending().await;
+                };
+                loop {
+                    Timer::after(idle / 2).await;
+                    if shared.idle_for() >= idle {
+                        return Ok(());
+                    }
+                }
+            };
+
+            let result = race(
+                read_frames(r, &shared, on_open),
+                race(write_frames(w, rx), idle_check),
+            )
+            .await;
+            if let Err(e) = result {
+                log::info!("Multiplexed connection closed: {e:?}");
+            }
+
+            shared.closed.store(true, Ordering::Relaxed);
+            // Writers waiting for window find the connection gone
+            for (_, entry) in shared.streams.lock().drain() {
+                entry.state.wake_writer();
+            }
+        }
+    });
+
+    (shared, task)
+}
+
+// The client end of a multiplexed connection
+pub struct MuxSession {
+    shared: Arc<Shared>,
+    next_id: AtomicU32,
+    _task: Task<()>,
+}
+
+impl MuxSession {
+    pub fn new(
+        stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        idle: Option<Duration>,
+    ) -> Arc<Self> {
+        let (shared, task) = run_session(stream, idle, |stream, _| {
+            log::warn!("Unexpected stream {} opened by the server", stream.id)
+        });
+        Arc::new(Self {
+            shared,
+            next_id: AtomicU32::new(0),
+            _task: task,
+        })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Relaxed)
+    }
+
+    pub fn active_streams(&self) -> usize {
+        self.shared.handles.load(Ordering::Relaxed)
+    }
+
+    pub async fn open(
+        self: &Arc<Self>,
+        dst: &Address<'_>,
+        initial_data: Option<&[u8]>,
+    ) -> anyhow::Result<MuxStream> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (opened_tx, opened_rx) = oneshot::channel();
+        let mut stream = self.shared.new_stream(id, Some(opened_tx));
+        stream._session = Some(self.clone());
+
+        // The initial data may not fit in the `Open`, so it follows as data instead
+        let mut open = Frame::new(id, FrameKind::Open);
+        open.payload = proto::Request::TCP {
+            dst: dst.clone(),
+            initial_data: Default::default(),
+        }
+        .to_vec()
+        .into();
+        self.shared.send(open)?;
+        if let Some(data) = initial_data {
+            stream.write_all(data).await?;
+        }
+
+        match opened_rx.await {
+            Ok(Ok(())) => Ok(stream),
+            Ok(Err(e)) => bail!("Server failed to open stream to {dst}: {e}"),
+            Err(_) => bail!("Multiplexed connection closed opening stream to {dst}"),
+        }
+    }
+}
+
+// Serves the streams a client opens over `stream`, until the client goes away. Streams
+// opened while `max_streams` are already are refused, unless it's 0.
+pub async fn serve_mux_session(
+    stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    max_streams: usize,
+    mut on_open: impl FnMut(MuxStream, Bytes) + Send + 'static,
+) {
+    let (_, task) = run_session(stream, None, move |stream, request| {
+        // The new stream is already counted
+        if max_streams > 0 && stream.shared.handles.load(Ordering::Relaxed) > max_streams {
+            log::debug!("Refusing stream {}: too many streams", stream.id);
+            spawn(async move {
+                let _ = stream.reject(&anyhow::anyhow!("Too many streams")).await;
+            })
+            .detach();
+            return;
+        }
+        on_open(stream, request)
+    });
+    task.await
+}
+
+// A stream carried by a multiplexed connection. Closing it only closes the writing side,
+// while dropping it closes both.
+pub struct MuxStream {
+    id: u32,
+    shared: Arc<Shared>,
+    incoming: mpsc::UnboundedReceiver<Bytes>,
+    pending: Bytes,
+    state: Arc<StreamState>,
+    // Bytes read since the peer was last granted more window
+    unacknowledged: usize,
+    closed: bool,
+    // Keeps the client's connection running while the stream is in use
+    _session: Option<Arc<MuxSession>>,
+}
+
+impl MuxStream {
+    // Tells the client the stream is open
+    pub async fn accept(&mut self) -> std::io::Result<()> {
+        self.shared.send(Frame::new(self.id, FrameKind::Open))
+    }
+
+    // Tells the client why the stream couldn't be opened
+    pub async fn reject(mut self, e: &anyhow::Error) -> std::io::Result<()> {
+        let mut reset = Frame::new(self.id, FrameKind::Reset);
+        let reason = format!("{e:#}");
+        reset.payload =
+            Bytes::copy_from_slice(&reason.as_bytes()[..reason.len().min(MAX_FRAME_PAYLOAD)]);
+        self.closed = true;
+        self.shared.remove(self.id);
+        self.shared.send(reset)
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        while self.pending.is_empty() {
+            match ready!(self.incoming.poll_next_unpin(cx)) {
+                Some(data) => self.pending = data,
+                None if self.state.reset.load(Ordering::Relaxed) => {
+                    return Poll::Ready(Err(ErrorKind::ConnectionReset.into()))
+                }
+                None => return Poll::Ready(Ok(0)),
+            }
+        }
+
+        let len = buf.len().min(self.pending.len());
+        buf[..len].copy_from_slice(&self.pending.split_to(len));
+
+        self.unacknowledged += len;
+        if self.unacknowledged >= STREAM_WINDOW / 2 {
+            let granted = std::mem::take(&mut self.unacknowledged);
+            self.state.recv_window.fetch_add(granted, Ordering::Relaxed);
+            let mut window = Frame::new(self.id, FrameKind::Window);
+            window.payload = Bytes::copy_from_slice(&(granted as u32).to_be_bytes());
+            let _ = self.shared.send(window);
+        }
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        if self.closed || self.shared.outgoing.is_closed() {
+            return Poll::Ready(Err(ErrorKind::BrokenPipe.into()));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let len = {
+            let mut window = self.state.send_window.lock();
+            // Checked with the lock held, as resets and closing the connection take it to wake us
+            if self.state.reset.load(Ordering::Relaxed) {
+                return Poll::Ready(Err(ErrorKind::ConnectionReset.into()));
+            }
+            if self.shared.outgoing.is_closed() {
+                return Poll::Ready(Err(ErrorKind::BrokenPipe.into()));
+            }
+            if window.0 == 0 {
+                window.1 = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+
+            let len = buf.len().min(MAX_FRAME_PAYLOAD).min(window.0);
+            window.0 -= len;
+            len
+        };
+
+        let mut frame = Frame::new(self.id, FrameKind::Data);
+        frame.payload = Bytes::copy_from_slice(&buf[..len]);
+        self.shared.send(frame)?;
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        // The frames are flushed by the connection as soon as they're written
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        if self.closed {
+            return Poll::Ready(Ok(()));
+        }
+
+        self.closed = true;
+        self.shared.send(Frame::new(self.id, FrameKind::Close))?;
+        self.shared.update(self.id, |e| e.closed = true);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        if !self.closed {
+            let _ = self.shared.send(Frame::new(self.id, FrameKind::Close));
+        }
+        // Until the peer closes its side too, whatever it still sends resets the stream
+        // as there's no one to read it
+        self.shared.update(self.id, |e| e.closed = true);
+        self.shared.handles.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::duplex;
+    use smol_timeout::TimeoutExt;
+
+    #[test]
+    fn streams_share_connection() {
+        smol::block_on(async move {
+            let (client, server) = duplex(0).await;
+            let _server = spawn(serve_mux_session(server, 0, |mut stream, request| {
+                spawn(async move {
+                    match proto::Request::parse(&request).unwrap() {
+                        proto::Request::TCP { dst, .. } if dst.get_port() == 1 => {
+                            let _ = stream.reject(&anyhow::anyhow!("Port 1 refused")).await;
+                        }
+                        _ => {
+                            stream.accept().await.unwrap();
+                            let (r, mut w) = stream.split();
+                            futures::io::copy(r, &mut w).await.unwrap();
+                        }
+                    }
+                })
+                .detach();
+            }));
+
+            let session = MuxSession::new(client, None);
+            let dst: Address = "example.com:80".parse().unwrap();
+            let mut streams = Vec::new();
+            for i in 0..3 {
+                let stream = session
+                    .open(&dst, Some(format!("hello {i}").as_bytes()))
+                    .await
+                    .unwrap();
+                streams.push(stream);
+            }
+            assert_eq!(session.active_streams(), 3);
+
+            // Each stream only sees its own data, including data larger than a frame
+            let large: Vec<u8> = (0..200_000).map(|b| b as u8).collect();
+            for (i, stream) in streams.iter_mut().enumerate().rev() {
+                let mut buf = vec![0u8; 7];
+                stream.read_exact(&mut buf).await.unwrap();
+                assert_eq!(buf, format!("hello {i}").as_bytes());
+
+                stream.write_all(&large).await.unwrap();
+                let mut buf = vec![0u8; large.len()];
+                stream.read_exact(&mut buf).await.unwrap();
+                assert_eq!(buf, large);
+            }
+
+            let err = session
+                .open(&"example.com:1".parse().unwrap(), None)
+                .await
+                .err()
+                .expect("To be refused");
+            assert!(format!("{err}").contains("Port 1 refused"), "{err}");
+
+            drop(streams);
+            assert_eq!(session.active_streams(), 0);
+            // Both sides close once the server sees the end of the data
+            while !session.shared.streams.lock().is_empty() {
+                Timer::after(Duration::from_millis(10)).await;
+            }
+            assert!(!session.is_closed());
+        });
+    }
+
+    #[test]
+    fn streams_half_close_and_stall_alone() {
+        smol::block_on(async move {
+            let (client, server) = duplex(0).await;
+            let _server = spawn(serve_mux_session(server, 0, |mut stream, request| {
+                spawn(async move {
+                    let port = match proto::Request::parse(&request).unwrap() {
+                        proto::Request::TCP { dst, .. } => dst.get_port(),
+                        _ => unreachable!(),
+                    };
+                    stream.accept().await.unwrap();
+                    match port {
+                        // Answers once the client is done writing
+                        1 => {
+                            let mut request = Vec::new();
+                            stream.read_to_end(&mut request).await.unwrap();
+                            stream
+                                .write_all(format!("got {}", request.len()).as_bytes())
+                                .await
+                                .unwrap();
+                            stream.close().await.unwrap();
+                        }
+                        // Writes more than the client reads
+                        2 => {
+                            let _ = stream.write_all(&vec![0u8; STREAM_WINDOW * 4]).await;
+                        }
+                        _ => {
+                            let (r, mut w) = stream.split();
+                            futures::io::copy(r, &mut w).await.unwrap();
+                        }
+                    }
+                })
+                .detach();
+            }));
+
+            let session = MuxSession::new(client, None);
+
+            let mut stream = session
+                .open(&"example.com:1".parse().unwrap(), None)
+                .await
+                .unwrap();
+            stream.write_all(&[1u8; 100_000]).await.unwrap();
+            stream.close().await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            assert_eq!(response, "got 100000");
+            drop(stream);
+
+            // The stream nobody reads doesn't hold up the others
+            let stalled = session
+                .open(&"example.com:2".parse().unwrap(), None)
+                .await
+                .unwrap();
+            let stream = session
+                .open(&"example.com:3".parse().unwrap(), None)
+                .await
+                .unwrap();
+            Timer::after(Duration::from_millis(100)).await;
+            let large = vec![2u8; STREAM_WINDOW * 2];
+            let (r, mut w) = stream.split();
+            let (_, echoed) = futures::join!(
+                async {
+                    w.write_all(&large).await.unwrap();
+                },
+                async {
+                    let mut buf = vec![0u8; large.len()];
+                    let mut r = r;
+                    r.read_exact(&mut buf)
+                        .timeout(Duration::from_secs(5))
+                        .await
+                        .expect("Not to stall")
+                        .unwrap();
+                    buf
+                }
+            );
+            assert_eq!(echoed, large);
+
+            // The stalled stream was sent its window's worth and no more
+            assert_eq!(stalled.state.recv_window.load(Ordering::Relaxed), 0);
+            drop(stalled);
+        });
+    }
+
+    #[test]
+    fn streams_over_the_limit_are_refused() {
+        smol::block_on(async move {
+            let (client, server) = duplex(0).await;
+            let _server = spawn(serve_mux_session(server, 2, |mut stream, _| {
+                spawn(async move {
+                    stream.accept().await.unwrap();
+                    let (r, mut w) = stream.split();
+                    let _ = futures::io::copy(r, &mut w).await;
+                })
+                .detach();
+            }));
+
+            let session = MuxSession::new(client, None);
+            let dst: Address = "example.com:80".parse().unwrap();
+            let first = session.open(&dst, None).await.unwrap();
+            let _second = session.open(&dst, None).await.unwrap();
+
+            let err = session.open(&dst, None).await.err().expect("To be refused");
+            assert!(format!("{err}").contains("Too many streams"), "{err}");
+
+            // There's room again once a stream is gone on both sides
+            drop(first);
+            let mut reopened = None;
+            for _ in 0..50 {
+                if let Ok(stream) = session.open(&dst, None).await {
+                    reopened = Some(stream);
+                    break;
+                }
+                Timer::after(Duration::from_millis(20)).await;
+            }
+            let mut stream = reopened.expect("To open once there's room");
+            stream.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+        });
+    }
+}
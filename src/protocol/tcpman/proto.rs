@@ -15,6 +15,8 @@ pub enum Request<'a> {
         dst: Address<'a>,
         initial_data: &'a [u8],
     },
+    // Carries many TCP streams, see `mux`
+    Mux,
 }
 
 #[derive(Debug, Primitive)]
@@ -22,6 +24,7 @@ pub enum Request<'a> {
 enum RequestType {
     TCP = 0,
     UDP = 1,
+    Mux = 2,
 }
 
 impl<'a> Request<'a> {
@@ -30,6 +33,9 @@ impl<'a> Request<'a> {
             bail!("Invalid buf size");
         }
         let request_type = RequestType::from_u8(buf.get_u8()).context("Reading request_type")?;
+        if let RequestType::Mux = request_type {
+            return Ok(Request::Mux);
+        }
 
         let (offset, dst) = Address::parse(&buf)
             .context("Parsing address")?
@@ -45,13 +51,22 @@ impl<'a> Request<'a> {
                 dst,
                 initial_data: buf,
             },
+            RequestType::Mux => unreachable!("Returned above"),
         })
     }
 
+    pub fn dst(&self) -> Option<&Address<'a>> {
+        match self {
+            Request::TCP { dst, .. } | Request::UDP { dst, .. } => Some(dst),
+            Request::Mux => None,
+        }
+    }
+
     pub fn to_vec(self) -> Vec<u8> {
         let (t, dst, initial_data) = match self {
             Request::TCP { dst, initial_data } => (RequestType::TCP, dst, initial_data),
             Request::UDP { dst, initial_data } => (RequestType::UDP, dst, initial_data),
+            Request::Mux => return vec![RequestType::Mux as u8],
         };
 
         let mut buf = Vec::<u8>::with_capacity(1 + dst.write_len() + initial_data.len());
@@ -112,5 +127,30 @@ mod tests {
             dst: "google.com:50".parse().unwrap(),
             initial_data: b"",
         });
+
+        test_request(&Request::Mux);
+    }
+
+    #[test]
+    fn initial_data_slices_without_copy() {
+        let buf = bytes::Bytes::from(
+            Request::UDP {
+                dst: "google.com:53".parse().unwrap(),
+                initial_data: b"hello,world",
+            }
+            .to_vec(),
+        );
+
+        let initial_data = match Request::parse(&buf).expect("To parse request") {
+            Request::UDP { initial_data, .. } => buf.slice_ref(initial_data),
+            r => panic!("Unexpected request {r:?}"),
+        };
+
+        assert_eq!(initial_data.as_ref(), b"hello,world");
+        // Shares the request buffer instead of allocating a new one
+        assert_eq!(
+            initial_data.as_ptr(),
+            buf[buf.len() - initial_data.len()..].as_ptr()
+        );
     }
 }
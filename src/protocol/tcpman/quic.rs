@@ -0,0 +1,269 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use futures::future::{FutureExt, Shared};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::{
+    Connection, Endpoint, EndpointConfig, RecvStream, SendStream, SmolRuntime, ZeroRttAccepted,
+};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::RootCertStore;
+use serde::{Deserialize, Serialize};
+use smol::spawn;
+
+use super::TcpMan;
+use crate::io::{union, StreamUnion, TcpOptions};
+use crate::socks5::Address;
+
+// Offered by both ends. What's carried isn't HTTP/3, so it doesn't claim to be.
+const ALPN: &[u8] = b"tcpman";
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+pub struct QuicConfig {
+    // PEM certificates trusted on top of the built-in roots, e.g. to connect to a server
+    // with a self-signed certificate
+    #[serde(default)]
+    pub ca: Option<String>,
+}
+
+pub type QuicStream = StreamUnion<RecvStream, SendStream>;
+
+// Resolves once the handshake of a connection resumed with 0-RTT completes, to whether the
+// server took what was sent before then. Streams opened before it resolved to false have to
+// be opened again.
+pub type EarlyDataAccepted = Shared<ZeroRttAccepted>;
+
+// The connection to one of an upstream's resolved addresses, made with some TCP options. Requests
+// to the same server share it, each over a stream of its own.
+struct CachedConnection {
+    upstream: TcpMan,
+    options: TcpOptions,
+    target: SocketAddr,
+    connection: Connection,
+}
+
+lazy_static! {
+    static ref CONNECTIONS: Mutex<Vec<CachedConnection>> = Default::default();
+    // Kept for each upstream, as they hold the sessions later connections resume
+    static ref CLIENT_CONFIGS: Mutex<Vec<(TcpMan, quinn::ClientConfig)>> = Default::default();
+}
+
+fn crypto_provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn client_config(upstream: &TcpMan, quic: &QuicConfig) -> anyhow::Result<quinn::ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    if let Some(ca) = &quic.ca {
+        for cert in CertificateDer::pem_slice_iter(ca.as_bytes()) {
+            roots
+                .add(cert.context("Reading QUIC CA certificate")?)
+                .context("Adding QUIC CA certificate")?;
+        }
+    }
+
+    let builder = rustls::ClientConfig::builder_with_provider(crypto_provider())
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_root_certificates(roots);
+    let mut crypto = match &upstream.client_identity {
+        Some(identity) => builder
+            .with_client_auth_cert(
+                CertificateDer::pem_slice_iter(identity.cert.as_bytes())
+                    .collect::<Result<_, _>>()
+                    .context("Reading client certificate")?,
+                PrivateKeyDer::from_pem_slice(identity.key.as_bytes())
+                    .context("Reading client key")?,
+            )
+            .context("Loading client certificate")?,
+        None => builder.with_no_client_auth(),
+    };
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    crypto.enable_early_data = true;
+
+    let mut config = quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(crypto)?));
+    if let Some(secs) = upstream.keepalive_secs {
+        let mut transport = quinn::TransportConfig::default();
+        transport.keep_alive_interval(Some(Duration::from_secs(secs)));
+        config.transport_config(Arc::new(transport));
+    }
+    Ok(config)
+}
+
+fn cached_client_config(
+    upstream: &TcpMan,
+    quic: &QuicConfig,
+) -> anyhow::Result<quinn::ClientConfig> {
+    let mut configs = CLIENT_CONFIGS.lock();
+    if let Some((_, config)) = configs.iter().find(|(u, _)| u == upstream) {
+        return Ok(config.clone());
+    }
+
+    let config = client_config(upstream, quic)?;
+    configs.push((upstream.clone(), config.clone()));
+    Ok(config)
+}
+
+// Certificates and key are PEM encoded. With `early_data`, clients resuming a session can
+// send their first requests with 0-RTT.
+pub fn server_config(
+    cert: &[u8],
+    key: &[u8],
+    early_data: bool,
+) -> anyhow::Result<quinn::ServerConfig> {
+    let mut crypto = rustls::ServerConfig::builder_with_provider(crypto_provider())
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_no_client_auth()
+        .with_single_cert(
+            CertificateDer::pem_slice_iter(cert)
+                .collect::<Result<_, _>>()
+                .context("Reading server certificate")?,
+            PrivateKeyDer::from_pem_slice(key).context("Reading server key")?,
+        )
+        .context("Loading server certificate")?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    if early_data {
+        crypto.max_early_data_size = u32::MAX;
+    }
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(
+        QuicServerConfig::try_from(crypto)?,
+    )))
+}
+
+fn new_endpoint(
+    socket: UdpSocket,
+    server_config: Option<quinn::ServerConfig>,
+) -> std::io::Result<Endpoint> {
+    Endpoint::new(
+        EndpointConfig::default(),
+        server_config,
+        socket,
+        Arc::new(SmolRuntime),
+    )
+}
+
+pub fn bind_server(addr: SocketAddr, config: quinn::ServerConfig) -> std::io::Result<Endpoint> {
+    new_endpoint(UdpSocket::bind(addr)?, Some(config))
+}
+
+// Resumes the last session with the server when there's one, so streams can be opened
+// before the handshake completes
+async fn connect(
+    upstream: &TcpMan,
+    quic: &QuicConfig,
+    target: SocketAddr,
+    options: &TcpOptions,
+) -> anyhow::Result<(Connection, Option<ZeroRttAccepted>)> {
+    let socket = if target.is_ipv4() {
+        UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?
+    } else {
+        UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))?
+    };
+    #[cfg(unix)]
+    {
+        use crate::io::AsRawFdExt;
+        if let Some(mark) = options.fwmark {
+            socket.set_sock_mark(mark)?;
+        }
+        crate::io::mark_dscp(&socket, socket.local_addr(), options.dscp)?;
+    }
+
+    let endpoint = new_endpoint(socket, None)?;
+    let tls_options = upstream.tls_options();
+    let server_name = tls_options.server_name(&upstream.address);
+    let connecting =
+        endpoint.connect_with(cached_client_config(upstream, quic)?, target, &server_name)?;
+    match connecting.into_0rtt() {
+        Ok((connection, accepted)) => Ok((connection, Some(accepted))),
+        Err(connecting) => Ok((connecting.await?, None)),
+    }
+}
+
+fn cache_connection(
+    upstream: &TcpMan,
+    options: &TcpOptions,
+    target: SocketAddr,
+    connection: Connection,
+) {
+    CONNECTIONS.lock().push(CachedConnection {
+        upstream: upstream.clone(),
+        options: options.clone(),
+        target,
+        connection,
+    });
+}
+
+// Opens a stream to the server at `target`, over the connection already made to it if
+// it's still open. A stream of a new connection sent with 0-RTT comes with what tells if
+// the server took it.
+pub async fn open_stream(
+    upstream: &TcpMan,
+    quic: &QuicConfig,
+    target: &Address<'_>,
+    options: &TcpOptions,
+) -> anyhow::Result<(QuicStream, Option<EarlyDataAccepted>)> {
+    let target = target
+        .resolve_first(options.address_family)
+        .await
+        .context("Resolving TCPMan server")?;
+
+    let cached: Vec<_> = {
+        let mut connections = CONNECTIONS.lock();
+        connections.retain(|c| c.connection.close_reason().is_none());
+        connections
+            .iter()
+            .rev()
+            .filter(|c| c.upstream == *upstream && c.options == *options && c.target == target)
+            .map(|c| c.connection.clone())
+            .collect()
+    };
+    // A connection with as many streams open as the server allows would wait for one of
+    // them to finish, so the stream goes over a new connection instead
+    for connection in cached {
+        if let Some(Ok((w, r))) = connection.open_bi().now_or_never() {
+            return Ok((union(r, w), None));
+        }
+    }
+
+    let (connection, accepted) = connect(upstream, quic, target, options)
+        .await
+        .context("Connecting to QUIC server")?;
+    let (w, r) = connection.open_bi().await?;
+
+    let Some(accepted) = accepted else {
+        cache_connection(upstream, options, target, connection);
+        return Ok((union(r, w), None));
+    };
+
+    // Other requests only share the connection once the handshake is done, as they
+    // couldn't tell if what they sent before was taken
+    let accepted = accepted.shared();
+    spawn({
+        let upstream = upstream.clone();
+        let accepted = accepted.clone();
+        let options = options.clone();
+        async move {
+            accepted.await;
+            cache_connection(&upstream, &options, target, connection);
+        }
+    })
+    .detach();
+    Ok((union(r, w), Some(accepted)))
+}
+
+// Closes the connections made for `upstream`, so the next stream has to make a new one
+#[cfg(test)]
+pub(crate) fn close_connections(upstream: &TcpMan) {
+    CONNECTIONS.lock().retain(|c| {
+        if c.upstream != *upstream {
+            return true;
+        }
+        c.connection.close(0u32.into(), b"");
+        false
+    });
+}
@@ -1,26 +1,175 @@
+use crate::io::union;
+use crate::protocol::allowed_ports::AllowedPorts;
 use crate::protocol::direct::Direct;
+use crate::protocol::load_shed::LoadShedder;
+use crate::protocol::log_sampler::CONNECTION_LOG_SAMPLER;
 use crate::protocol::tcpman::dgram::{create_udp_sink, create_udp_stream};
+use crate::protocol::tcpman::mux::{serve_mux_session, MuxStream};
+use crate::protocol::tcpman::Credentials;
 use crate::protocol::Protocol;
-use anyhow::Context;
+use anyhow::{bail, Context};
 use async_net::TcpListener;
 use bytes::Bytes;
-use futures::{AsyncRead, AsyncReadExt, AsyncWrite, StreamExt};
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, StreamExt};
+use quinn::Endpoint;
 use smol::spawn;
+use smol_timeout::TimeoutExt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use super::{super::cipher, super::proto};
 use crate::utils::{copy_duplex, race};
 
-pub async fn serve_client<P: Protocol + Send + Sync>(
+// The credentials clients may connect with. More than one can be valid at a time, so
+// a password can be rotated without cutting off the clients still using the old one.
+// Anyone is accepted when there are none.
+#[derive(Debug, Default, Clone)]
+pub struct AcceptedCredentials(Arc<Vec<String>>);
+
+impl AcceptedCredentials {
+    pub fn new(credentials: impl IntoIterator<Item = Credentials>) -> Self {
+        Self(Arc::new(
+            credentials
+                .into_iter()
+                .map(|c| c.to_header_value().to_string())
+                .collect(),
+        ))
+    }
+
+    // Every credential is compared in full, so timing doesn't give away how close a guess was
+    pub fn accepts(&self, authorization: Option<&str>) -> bool {
+        if self.0.is_empty() {
+            return true;
+        }
+
+        let Some(a) = authorization else {
+            return false;
+        };
+        self.0
+            .iter()
+            .fold(false, |accepted, c| accepted | constant_time_eq(c, a))
+    }
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// Whitespace separated `username:password` pairs
+impl FromStr for AcceptedCredentials {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let credentials = s
+            .split_whitespace()
+            .map(|c| {
+                let (username, password) = c
+                    .split_once(':')
+                    .context("Expecting credentials as username:password")?;
+                Ok(Credentials {
+                    username: username.to_string(),
+                    password: password.to_string(),
+                    password_source: None,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self::new(credentials))
+    }
+}
+
+// Where under the server's root clients are served, e.g. `/cdn` when a reverse proxy
+// forwards that subpath to the server. Empty to serve from the root.
+#[derive(Debug, Default, Clone)]
+pub struct PathPrefix(Arc<str>);
+
+impl PathPrefix {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for PathPrefix {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !s.starts_with('/') {
+            bail!("Path prefix {s} doesn't start with /");
+        }
+        Ok(Self(s.trim_end_matches('/').into()))
+    }
+}
+
+// What a request has to look like before the server shows it's anything but a web server.
+// Other requests are answered as requests for a missing page are.
+#[derive(Debug, Default, Clone)]
+pub struct HandshakeRules {
+    pub path_prefix: PathPrefix,
+    // How long a request has to be, also having the headers browsers send, so probing the
+    // server takes as much as a real client's request. Any request goes when it's 0.
+    pub min_request_bytes: usize,
+    // Whether clients may compress the stream. Off by default, as compressing before
+    // encrypting lets the length of the traffic give away what's in it.
+    pub allow_compression: bool,
+}
+
+async fn serve_mux_stream<P: Protocol + Send + Sync>(
+    mut stream: MuxStream,
+    request: Bytes,
+    upstream_factory: impl Fn(&proto::Request) -> anyhow::Result<P>,
+) {
+    let upstream = async {
+        let req = proto::Request::parse(&request).context("Parsing TCPMan request")?;
+        let proto::Request::TCP { dst, .. } = &req else {
+            bail!("Only TCP streams can be multiplexed");
+        };
+        upstream_factory(&req)?
+            .new_stream(dst, None, &Default::default(), &Default::default())
+            .await
+    };
+
+    let upstream = match upstream.await {
+        Ok(v) => v,
+        Err(e) => {
+            log::debug!("Error opening multiplexed stream: {e:?}");
+            let _ = stream.reject(&e).await;
+            return;
+        }
+    };
+
+    if stream.accept().await.is_ok() {
+        if let Err(e) = copy_duplex(stream, upstream, None, None).await {
+            log::debug!("Error serving multiplexed stream: {e:?}");
+        }
+    }
+}
+
+pub async fn serve_client<P: Protocol + Send + Sync + 'static>(
     stream: impl AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
-    upstream_factory: impl FnOnce(&proto::Request) -> anyhow::Result<P>,
+    credentials: AcceptedCredentials,
+    handshake_rules: &HandshakeRules,
+    max_mux_streams: usize,
+    upstream_factory: impl Fn(&proto::Request) -> anyhow::Result<P> + Clone + Send + Sync + 'static,
 ) -> anyhow::Result<()> {
-    let (initial_data, hs) = cipher::server::accept_client(stream)
-        .await
-        .context("Awaiting handshake")?;
+    let (initial_data, hs) = cipher::server::accept_client(
+        stream,
+        handshake_rules.path_prefix.as_str(),
+        handshake_rules.min_request_bytes,
+        handshake_rules.allow_compression,
+        move |auth| credentials.accepts(auth),
+    )
+    .await
+    .context("Awaiting handshake")?;
 
-    let initial_data = initial_data.unwrap_or_default();
+    // Kept as Bytes so the UDP initial data can be sliced out without copying
+    let request_buf = Bytes::from(initial_data.unwrap_or_default());
 
-    let req = match proto::Request::parse(&initial_data).context("Parsing TCPMan request") {
+    let req = match proto::Request::parse(&request_buf).context("Parsing TCPMan request") {
         Ok(v) => v,
         Err(e) => {
             hs.respond_error(&e).await?;
@@ -28,6 +177,15 @@ pub async fn serve_client<P: Protocol + Send + Sync>(
         }
     };
 
+    if let proto::Request::Mux = req {
+        let stream = hs.respond_success().await?;
+        serve_mux_session(stream, max_mux_streams, move |stream, request| {
+            spawn(serve_mux_stream(stream, request, upstream_factory.clone())).detach();
+        })
+        .await;
+        return Ok(());
+    }
+
     let upstream_protocol = match upstream_factory(&req) {
         Ok(v) => v,
         Err(e) => {
@@ -39,7 +197,12 @@ pub async fn serve_client<P: Protocol + Send + Sync>(
     match req {
         proto::Request::TCP { dst, initial_data } => {
             let upstream = match upstream_protocol
-                .new_stream(&dst, Some(initial_data), &Default::default(), None)
+                .new_stream(
+                    &dst,
+                    Some(initial_data),
+                    &Default::default(),
+                    &Default::default(),
+                )
                 .await
             {
                 Ok(v) => v,
@@ -55,9 +218,9 @@ pub async fn serve_client<P: Protocol + Send + Sync>(
             let (upstream_sink, upstream_stream) = match upstream_protocol
                 .new_datagram(
                     &dst,
-                    Bytes::copy_from_slice(initial_data),
+                    request_buf.slice_ref(initial_data),
+                    &Default::default(),
                     &Default::default(),
-                    None,
                 )
                 .await
             {
@@ -75,19 +238,138 @@ pub async fn serve_client<P: Protocol + Send + Sync>(
 
             race(task1, task2).await
         }
+        proto::Request::Mux => unreachable!("Served above"),
+    }
+}
+
+// Serves a client the load shedder let in, only letting it reach the allowed ports
+// How the TCP and QUIC servers treat the clients they accept
+#[derive(Debug, Default, Clone)]
+pub struct ServerOptions {
+    pub allowed_ports: AllowedPorts,
+    pub credentials: AcceptedCredentials,
+    pub handshake_rules: HandshakeRules,
+    pub load_shedder: LoadShedder,
+    // Streams a multiplexed connection may carry at once, or any number when 0
+    pub max_mux_streams: usize,
+}
+
+async fn serve_accepted_client(
+    stream: impl AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
+    addr: SocketAddr,
+    options: Arc<ServerOptions>,
+) {
+    let started = Instant::now();
+    let upstream_factory = {
+        let options = options.clone();
+        move |req: &proto::Request| {
+            if let Some(dst) = req.dst() {
+                options.allowed_ports.check(dst.get_port())?;
+            }
+            Ok(Direct {})
+        }
+    };
+    let credentials = options.credentials.clone();
+    match serve_client(
+        stream,
+        credentials,
+        &options.handshake_rules,
+        options.max_mux_streams,
+        upstream_factory,
+    )
+    .await
+    {
+        Err(e) => log::error!("Error serving client {addr}: {e:?}"),
+        Ok(_) if CONNECTION_LOG_SAMPLER.sample() => {
+            log::info!("Client {addr} disconnected after {:?}", started.elapsed())
+        }
+        Ok(_) => {}
     }
 }
 
-pub async fn run_server(listener: TcpListener) -> anyhow::Result<()> {
+// Tells a client turned away by the load shedder that the server is busy, as a web server
+// would, instead of just dropping the connection
+async fn refuse_overloaded(mut stream: impl AsyncWrite + Unpin) {
+    const RESPONSE: &[u8] =
+        b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+    let _ = async {
+        stream.write_all(RESPONSE).await?;
+        stream.close().await
+    }
+    .timeout(Duration::from_secs(1))
+    .await;
+}
+
+pub async fn run_server(listener: TcpListener, options: ServerOptions) -> anyhow::Result<()> {
+    let options = Arc::new(options);
     loop {
         let (stream, addr) = listener.accept().await?;
-        log::info!("Accepted client {addr}");
+        let Some(permit) = options.load_shedder.try_acquire() else {
+            if CONNECTION_LOG_SAMPLER.sample() {
+                log::warn!(
+                    "Refusing client {addr}: {} active connections",
+                    options.load_shedder.active_connections()
+                );
+            }
+            spawn(refuse_overloaded(stream)).detach();
+            continue;
+        };
+
+        log::debug!("Accepted client {addr}");
+        let options = options.clone();
+        spawn(async move {
+            let _permit = permit;
+            serve_accepted_client(stream, addr, options).await;
+        })
+        .detach();
+    }
+}
+
+// Serves clients connecting over QUIC, where every stream they open is served like a TCP
+// connection would be
+pub async fn run_quic_server(endpoint: Endpoint, options: ServerOptions) -> anyhow::Result<()> {
+    let options = Arc::new(options);
+    while let Some(incoming) = endpoint.accept().await {
+        let options = options.clone();
         spawn(async move {
-            if let Err(e) = serve_client(stream, |_| Ok(Direct {})).await {
-                log::error!("Error serving client {addr}: {e:?}");
+            let addr = incoming.remote_address();
+            // Streams sent with 0-RTT are served before the handshake completes. They can
+            // be replayed, which the cipher refuses by not accepting the same params twice.
+            let connection = match incoming.accept().map(|c| c.into_0rtt()) {
+                Ok(Ok((connection, _))) => Ok(connection),
+                Ok(Err(connecting)) => connecting.await,
+                Err(e) => Err(e),
+            };
+            let connection = match connection {
+                Ok(v) => v,
+                Err(e) => {
+                    log::error!("Error accepting QUIC client {addr}: {e:?}");
+                    return;
+                }
+            };
+
+            log::debug!("Accepted QUIC client {addr}");
+            while let Ok((w, r)) = connection.accept_bi().await {
+                let Some(permit) = options.load_shedder.try_acquire() else {
+                    if CONNECTION_LOG_SAMPLER.sample() {
+                        log::warn!(
+                            "Refusing stream from {addr}: {} active connections",
+                            options.load_shedder.active_connections()
+                        );
+                    }
+                    spawn(refuse_overloaded(w)).detach();
+                    continue;
+                };
+
+                let options = options.clone();
+                spawn(async move {
+                    let _permit = permit;
+                    serve_accepted_client(union(r, w), addr, options).await;
+                })
+                .detach();
             }
-            log::info!("Client {addr} disconnected");
         })
         .detach();
     }
+    Ok(())
 }
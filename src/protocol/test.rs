@@ -21,7 +21,12 @@ pub async fn test_protocol_tcp(p: &(impl Protocol + Send + Sync)) {
     assert!(p.supports(TrafficType::Stream));
 
     let mut stream = p
-        .new_stream(&echo_addr.into(), None, &Default::default(), None)
+        .new_stream(
+            &echo_addr.into(),
+            None,
+            &Default::default(),
+            &Default::default(),
+        )
         .timeout(TIMEOUT)
         .await
         .expect("No timeout")
@@ -63,7 +68,7 @@ pub async fn test_protocol_http(p: &(impl Protocol + Send + Sync)) {
             &"www.google.com:80".parse().unwrap(),
             Some(&initial_data),
             &Default::default(),
-            None,
+            &Default::default(),
         )
         .timeout(TIMEOUT)
         .await
@@ -89,7 +94,7 @@ pub async fn test_protocol_udp(p: &(impl Protocol + Send + Sync)) {
             &echo_addr.into(),
             Bytes::from_static(initial_data),
             &Default::default(),
-            None,
+            &Default::default(),
         )
         .timeout(TIMEOUT)
         .await
@@ -1,6 +1,6 @@
 use super::super::{Protocol, Stats};
 use super::proto::{self, Message};
-use crate::io::{bind_udp, AsRawFdExt, UdpSocketExt};
+use crate::io::{bind_udp, mark_dscp, AsRawFdExt, TcpOptions, UdpSocketExt};
 use crate::protocol::{BoxedSink, BoxedStream, TrafficType};
 use crate::socks5::Address;
 use crate::utils::race;
@@ -34,19 +34,20 @@ impl Protocol for UdpMan {
         dst: &Address<'_>,
         initial_data: Bytes,
         stats: &Stats,
-        fwmark: Option<u32>,
+        options: &TcpOptions,
     ) -> anyhow::Result<(BoxedSink, BoxedStream)> {
         let (tx, rx) = (stats.tx.clone(), stats.rx.clone());
         let upstream = bind_udp(matches!(self.addr, Address::IP(SocketAddr::V4(_)))).await?;
 
-        if let Some(m) = fwmark {
+        if let Some(m) = options.fwmark {
             upstream.set_sock_mark(m)?;
         }
-        let upstream_addr = self.addr.resolve_first().await?;
+        mark_dscp(&upstream, upstream.local_addr(), options.dscp)?;
+        let upstream_addr = self.addr.resolve_first(options.address_family).await?;
 
         // Send connect message
         let uuid = Uuid::new_v4();
-        let initial_dst = dst.resolve_first().await?;
+        let initial_dst = dst.resolve_first(options.address_family).await?;
         let connect_msg = proto::Message::Connect {
             uuid: uuid.as_ref().into(),
             initial_data: initial_data.into(),
@@ -180,7 +181,10 @@ impl Protocol for UdpMan {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{protocol::test::test_protocol_udp, test::create_udp_socket};
+    use crate::{
+        protocol::test::test_protocol_udp,
+        test::{create_udp_socket, echo_udp_server},
+    };
     use smol::{block_on, spawn};
 
     #[test]
@@ -189,7 +193,11 @@ mod tests {
         let _ = env_logger::try_init();
         block_on(async move {
             let (server_socket, server_addr) = create_udp_socket().await;
-            let _task = spawn(super::super::server::serve_socket(server_socket));
+            let _task = spawn(super::super::server::serve_socket(
+                server_socket,
+                Default::default(),
+                Default::default(),
+            ));
 
             let protocol = UdpMan {
                 addr: server_addr.into(),
@@ -198,4 +206,45 @@ mod tests {
             test_protocol_udp(&protocol).await;
         });
     }
+
+    #[test]
+    fn udpman_rejects_disallowed_ports() {
+        let _ = env_logger::try_init();
+        block_on(async move {
+            let (_echo_task, echo_addr) = echo_udp_server().await;
+            let (server_socket, server_addr) = create_udp_socket().await;
+            let _task = spawn(super::super::server::serve_socket(
+                server_socket,
+                Default::default(),
+                format!("443,{}", echo_addr.port()).parse().unwrap(),
+            ));
+
+            let protocol = UdpMan {
+                addr: server_addr.into(),
+            };
+            let first_reply = |dst: SocketAddr| {
+                let protocol = protocol.clone();
+                async move {
+                    let (_sink, mut stream) = protocol
+                        .new_datagram(
+                            &dst.into(),
+                            Bytes::from_static(b"hello"),
+                            &Default::default(),
+                            &Default::default(),
+                        )
+                        .await
+                        .unwrap();
+                    stream.next().await.unwrap()
+                }
+            };
+
+            let (reply, _) = first_reply(echo_addr).await.expect("Allowed port to reply");
+            assert_eq!(reply.as_ref(), b"hello");
+
+            first_reply("127.0.0.1:25".parse().unwrap())
+                .await
+                .err()
+                .expect("Port 25 to be rejected");
+        });
+    }
 }
@@ -1,4 +1,5 @@
 mod client;
+pub mod nat;
 mod proto;
 pub mod server;
 mod stream;
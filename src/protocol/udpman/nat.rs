@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use async_net::UdpSocket;
+use bytes::Bytes;
+use futures::channel::mpsc::{channel, Receiver, Sender};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use smol::{spawn, Task};
+
+use crate::utils::new_vec_for_udp;
+
+// Who can send to a client through its mapping. Either way a client keeps the same
+// outbound port whatever it sends to, which apps punching holes through NATs, e.g.
+// WebRTC, rely on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NatFiltering {
+    // Anyone who learns the mapped port
+    #[default]
+    FullCone,
+    // Only the addresses the client has sent to, from any of their ports
+    RestrictedCone,
+}
+
+impl FromStr for NatFiltering {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full-cone" => Ok(Self::FullCone),
+            "restricted-cone" => Ok(Self::RestrictedCone),
+            _ => anyhow::bail!("Unknown NAT filtering {s}, expecting full-cone or restricted-cone"),
+        }
+    }
+}
+
+// Where replies from a destination go
+struct Route {
+    dst: SocketAddr,
+    tx: Sender<(Bytes, SocketAddr)>,
+}
+
+// The socket a client's datagrams to one address family go out of
+pub struct NatMapping {
+    client: SocketAddr,
+    socket: UdpSocket,
+    filtering: NatFiltering,
+    last_active: Mutex<Instant>,
+    routes: Mutex<Vec<Route>>,
+    _task: Task<()>,
+}
+
+impl NatMapping {
+    fn new(client: SocketAddr, socket: UdpSocket, filtering: NatFiltering) -> Arc<Self> {
+        Arc::new_cyclic(|mapping: &Weak<Self>| {
+            let mapping = mapping.clone();
+            let receiving = socket.clone();
+            let _task = spawn(async move {
+                let mut buf = new_vec_for_udp();
+                while let Ok((len, from)) = receiving.recv_from(&mut buf).await {
+                    let Some(mapping) = mapping.upgrade() else {
+                        break;
+                    };
+                    mapping.deliver(Bytes::copy_from_slice(&buf[..len]), from);
+                }
+            });
+
+            Self {
+                client,
+                socket,
+                filtering,
+                last_active: Mutex::new(Instant::now()),
+                routes: Default::default(),
+                _task,
+            }
+        })
+    }
+
+    pub fn client(&self) -> SocketAddr {
+        self.client
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    // Replies from `dst` are received here, along with the ones from elsewhere the
+    // filtering lets in, until it's dropped
+    pub fn subscribe(&self, dst: SocketAddr) -> Receiver<(Bytes, SocketAddr)> {
+        let (tx, rx) = channel(10);
+        self.routes.lock().push(Route { dst, tx });
+        rx
+    }
+
+    pub async fn send_to(&self, buf: &[u8], dst: SocketAddr) -> std::io::Result<usize> {
+        *self.last_active.lock() = Instant::now();
+        self.socket.send_to(buf, dst).await
+    }
+
+    fn deliver(&self, data: Bytes, from: SocketAddr) {
+        let mut routes = self.routes.lock();
+        routes.retain(|r| !r.tx.is_closed());
+
+        // The connection that sent to it, or else the latest one the filtering allows
+        let route = routes.iter().find(|r| r.dst == from).or_else(|| {
+            routes.iter().rev().find(|r| match self.filtering {
+                NatFiltering::FullCone => true,
+                NatFiltering::RestrictedCone => r.dst.ip() == from.ip(),
+            })
+        });
+
+        match route {
+            Some(route) => {
+                *self.last_active.lock() = Instant::now();
+                if let Err(e) = route.tx.clone().try_send((data, from)) {
+                    log::debug!("Dropping datagram from {from}: {e:?}");
+                }
+            }
+            None => log::debug!("Filtered datagram from {from}"),
+        }
+    }
+
+    fn is_idle(&self, timeout: Duration) -> bool {
+        self.last_active.lock().elapsed() >= timeout
+    }
+}
+
+// Maps each client to an outbound socket per address family, reused for whatever the
+// client sends to until it's been idle for `idle_timeout`
+pub struct NatTable {
+    filtering: NatFiltering,
+    idle_timeout: Duration,
+    mappings: Mutex<HashMap<(SocketAddr, bool), Arc<NatMapping>>>,
+}
+
+impl Default for NatTable {
+    fn default() -> Self {
+        Self::new(Default::default(), Duration::from_secs(60))
+    }
+}
+
+impl NatTable {
+    pub fn new(filtering: NatFiltering, idle_timeout: Duration) -> Self {
+        Self {
+            filtering,
+            idle_timeout,
+            mappings: Default::default(),
+        }
+    }
+
+    pub fn mapping(&self, client: SocketAddr, v4: bool) -> std::io::Result<Arc<NatMapping>> {
+        let mut mappings = self.mappings.lock();
+        mappings.retain(|_, m| !m.is_idle(self.idle_timeout));
+        if let Some(mapping) = mappings.get(&(client, v4)) {
+            return Ok(mapping.clone());
+        }
+
+        let socket = if v4 {
+            std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?
+        } else {
+            std::net::UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))?
+        };
+        let mapping = NatMapping::new(client, UdpSocket::try_from(socket)?, self.filtering);
+        mappings.insert((client, v4), mapping.clone());
+        log::debug!(
+            "Mapped {client} to {:?}, {} active mappings",
+            mapping.local_addr(),
+            mappings.len()
+        );
+        Ok(mapping)
+    }
+
+    pub fn active_mappings(&self) -> usize {
+        let mut mappings = self.mappings.lock();
+        mappings.retain(|_, m| !m.is_idle(self.idle_timeout));
+        mappings.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use smol_timeout::TimeoutExt;
+
+    use super::*;
+    use crate::test::create_udp_socket;
+
+    #[test]
+    fn mapping_is_reused_until_idle() {
+        smol::block_on(async move {
+            let table = NatTable::new(NatFiltering::FullCone, Duration::from_millis(200));
+            let client: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+            let (dst1, dst1_addr) = create_udp_socket().await;
+            let (dst2, dst2_addr) = create_udp_socket().await;
+
+            // The same source port whoever it's sent to
+            let mapping = table.mapping(client, true).unwrap();
+            let mut replies = mapping.subscribe(dst1_addr);
+            mapping.send_to(b"hello", dst1_addr).await.unwrap();
+            table
+                .mapping(client, true)
+                .unwrap()
+                .send_to(b"hello", dst2_addr)
+                .await
+                .unwrap();
+            let mut buf = [0u8; 5];
+            let (_, from1) = dst1.recv_from(&mut buf).await.unwrap();
+            let (_, from2) = dst2.recv_from(&mut buf).await.unwrap();
+            assert_eq!(from1.port(), from2.port());
+            assert_eq!(from1.port(), mapping.local_addr().unwrap().port());
+            assert_eq!(table.active_mappings(), 1);
+
+            // Full cone lets in a reply from anyone
+            let (stranger, _) = create_udp_socket().await;
+            stranger.send_to(b"world", from1).await.unwrap();
+            let (data, _) = replies
+                .next()
+                .timeout(Duration::from_secs(1))
+                .await
+                .expect("No timeout")
+                .unwrap();
+            assert_eq!(data.as_ref(), b"world");
+
+            // Another client gets its own mapping
+            let other = table
+                .mapping("10.0.0.2:5000".parse().unwrap(), true)
+                .unwrap();
+            assert_ne!(
+                other.local_addr().unwrap().port(),
+                mapping.local_addr().unwrap().port()
+            );
+            drop(other);
+
+            smol::Timer::after(Duration::from_millis(300)).await;
+            assert_eq!(table.active_mappings(), 0);
+            assert_ne!(
+                table
+                    .mapping(client, true)
+                    .unwrap()
+                    .local_addr()
+                    .unwrap()
+                    .port(),
+                mapping.local_addr().unwrap().port()
+            );
+        });
+    }
+
+    #[test]
+    fn restricted_cone_filters_strangers() {
+        smol::block_on(async move {
+            let table = NatTable::new(NatFiltering::RestrictedCone, Duration::from_secs(60));
+            let mapping = table
+                .mapping("10.0.0.1:5000".parse().unwrap(), true)
+                .unwrap();
+            let (dst, dst_addr) = create_udp_socket().await;
+            let mut replies = mapping.subscribe(dst_addr);
+            mapping.send_to(b"hello", dst_addr).await.unwrap();
+            let mut buf = [0u8; 5];
+            let (_, mapped) = dst.recv_from(&mut buf).await.unwrap();
+
+            // A stranger on another address isn't let in, unlike the destination
+            let stranger = std::net::UdpSocket::bind("127.0.0.2:0").unwrap();
+            stranger.send_to(b"nope!", mapped).unwrap();
+            dst.send_to(b"world", mapped).await.unwrap();
+            let (data, from) = replies
+                .next()
+                .timeout(Duration::from_secs(1))
+                .await
+                .expect("No timeout")
+                .unwrap();
+            assert_eq!(data.as_ref(), b"world");
+            assert_eq!(from, dst_addr);
+        });
+    }
+}
@@ -6,10 +6,12 @@ use std::{
     time::Duration,
 };
 
+use super::nat::{NatMapping, NatTable};
 use super::proto::Message;
 use crate::{
-    io::{bind_udp, get_one_off_udp_query_timeout, Timer, UdpSocketExt},
-    utils::{new_vec_for_udp, race, VecExt},
+    io::{get_one_off_udp_query_timeout, Timer, UdpSocketExt},
+    protocol::allowed_ports::AllowedPorts,
+    utils::race,
 };
 use anyhow::{bail, Context};
 use async_net::UdpSocket;
@@ -23,9 +25,15 @@ use scopeguard::defer;
 use smol::{spawn, Task};
 use smol_timeout::TimeoutExt;
 
-pub async fn serve_socket(socket: UdpSocket) -> anyhow::Result<()> {
+pub async fn serve_socket(
+    socket: UdpSocket,
+    nat: NatTable,
+    allowed_ports: AllowedPorts,
+) -> anyhow::Result<()> {
     let (sink, stream) = socket.to_sink_stream().split();
     serve(
+        nat,
+        allowed_ports,
         Box::pin(
             sink.sink_map_err(anyhow::Error::from).with(
                 |(data, addr): (Message<'static>, SocketAddr)| async move {
@@ -54,6 +62,8 @@ pub async fn serve_socket(socket: UdpSocket) -> anyhow::Result<()> {
 }
 
 pub async fn serve(
+    nat: NatTable,
+    allowed_ports: AllowedPorts,
     mut sink: impl Sink<(Message<'static>, SocketAddr), Error = anyhow::Error> + Unpin + Send + 'static,
     mut stream: impl Stream<Item = anyhow::Result<(Message<'static>, SocketAddr)>>
         + Unpin
@@ -61,6 +71,7 @@ pub async fn serve(
         + 'static,
 ) -> anyhow::Result<()> {
     let connections: Arc<RwLock<ConnMap>> = Default::default();
+    let nat = Arc::new(nat);
     let (sink_tx, mut sink_rx) = channel::<(Message<'static>, SocketAddr)>(20);
 
     let task1: Task<anyhow::Result<()>> = {
@@ -83,6 +94,12 @@ pub async fn serve(
                         dst,
                         initial_data_nonce: None,
                     } => {
+                        // Not establishing is all the client is told
+                        if let Err(e) = allowed_ports.check(dst.port()) {
+                            log::warn!("Rejecting client {from}: {e:?}");
+                            continue;
+                        }
+
                         let mut map = connections.write();
                         let conn_id = match map.find_available_id() {
                             Some(v) => v,
@@ -91,14 +108,23 @@ pub async fn serve(
                                 continue;
                             }
                         };
+                        // Shared with the client's other connections, so they go out of the
+                        // same port
+                        let upstream = match nat.mapping(from, dst.is_ipv4()) {
+                            Ok(v) => v,
+                            Err(e) => {
+                                log::error!("Unable to map client {from}: {e:?}");
+                                continue;
+                            }
+                        };
                         let conn = match Conn::new(
                             uuid.into(),
                             initial_data.into(),
-                            from,
                             dst,
                             conn_id,
                             sink_tx.clone(),
                             Arc::downgrade(&connections),
+                            upstream,
                         ) {
                             Ok(v) => v,
                             Err(e) => {
@@ -213,14 +239,15 @@ impl Conn {
     pub fn new(
         uuid: Bytes,
         initial_data: Bytes,
-        src: SocketAddr,
         dst: SocketAddr,
         conn_id: u16,
         mut outgoing_tx: Sender<(Message<'static>, SocketAddr)>,
         connections: Weak<RwLock<ConnMap>>,
+        upstream: Arc<NatMapping>,
     ) -> anyhow::Result<Self> {
+        let src = upstream.client();
         log::debug!("Created UDP Connection(id = {conn_id}), src = {src}, dst = {dst}");
-        let (incoming_tx, mut incoming_rx) = channel(10);
+        let (incoming_tx, mut incoming_rx) = channel::<Bytes>(10);
         let _task = spawn(async move {
             defer! {
                 if let Some(conns) = connections.upgrade() {
@@ -229,25 +256,21 @@ impl Conn {
                 }
             }
 
-            let upstream = bind_udp(matches!(dst, SocketAddr::V4(_))).await?;
+            let mut upstream_stream = upstream.subscribe(dst);
             upstream
                 .send_to(initial_data.as_ref(), dst)
                 .await
                 .with_context(|| format!("Sending initial data {dst}"))?;
 
             // Try to receive initial message within 500ms
-            let mut buf = new_vec_for_udp();
-            let initial_reply = match upstream
-                .recv_from(&mut buf)
+            let initial_reply = match upstream_stream
+                .next()
                 .timeout(Duration::from_millis(500))
                 .await
             {
                 None => None,
-                Some(Ok((len, addr))) => {
-                    buf.set_len_uninit(len);
-                    Some((Bytes::from(buf), addr))
-                }
-                Some(Err(e)) => bail!("Error receiving initial data: {e:?}"),
+                Some(Some(v)) => Some(v),
+                Some(None) => bail!("NAT mapping closed"),
             };
 
             // Send Establish message
@@ -267,14 +290,13 @@ impl Conn {
                 return Ok(());
             }
 
-            let (mut upstream_sink, mut upstream_stream) = upstream.to_sink_stream().split();
             let timer = Timer::new(Duration::from_secs(60));
 
             let upload_task = {
                 let timer = timer.clone();
                 spawn(async move {
                     while let Some(data) = incoming_rx.next().await {
-                        upstream_sink.send((data, dst)).await?;
+                        upstream.send_to(&data, dst).await?;
                         timer.reset();
                     }
                     anyhow::Result::<()>::Ok(())
@@ -284,8 +306,7 @@ impl Conn {
             let download_task = {
                 let timer = timer.clone();
                 spawn(async move {
-                    while let Some(p) = upstream_stream.next().await {
-                        let (data, addr) = p?;
+                    while let Some((data, addr)) = upstream_stream.next().await {
                         outgoing_tx
                             .send((
                                 Message::Data {
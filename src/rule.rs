@@ -1,8 +1,10 @@
 use std::{
     borrow::Cow,
+    cell::OnceCell,
     collections::HashMap,
     fmt::Debug,
     net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
     str::FromStr,
     sync::Arc,
 };
@@ -10,14 +12,16 @@ use std::{
 use anyhow::{bail, Context};
 use clap::{Parser, ValueEnum};
 use ipnetwork::IpNetwork;
+use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
 
 use crate::sni::{extract_http_host_header, extract_ssl_sni_host};
 use crate::{
     abp::{adblock_list_engine, gfw_list_engine, ABPEngine},
-    dns::dns_get_host_names,
-    geoip::CountryCode,
+    dns::{dns_get_host_names, DnsCache},
+    geoip::{find_asn, find_geoip, CountryCode},
     pattern::Pattern,
+    schedule::Schedule,
     socks5::Address,
 };
 
@@ -30,10 +34,15 @@ pub enum HostMatch {
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum RuleDestination {
     GeoIP(CountryCode),
+    Asn(u32),
     Network(IpNetwork),
-    Port(u16),
+    Port(RangeInclusive<u16>),
+    SourceNetwork(IpNetwork),
+    Time(Schedule),
     Domain(HostMatch),
     DnsHost(HostMatch),
+    // Whether the destination was given as an IP literal rather than a domain
+    IsIp(bool),
 }
 
 #[derive(Debug, ValueEnum, Clone, Copy, PartialEq, PartialOrd, Eq, Ord)]
@@ -43,29 +52,62 @@ pub enum RuleProtocol {
 }
 
 #[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord)]
-enum RuleAction {
+pub(crate) enum RuleAction {
     Proxy(Arc<str>),
-    ProxyGroup(Arc<str>),
+    ProxyGroup(Arc<str>, GroupSelection),
+    Direct,
     Reject,
     Jump(Arc<str>),
     Return,
+    // Left to the external decider registered under the name
+    External(Arc<str>),
+}
+
+// How a member of a proxy group is picked, given as `proxygroup:NAME:MODE`
+#[derive(Debug, Clone, Copy, Default, PartialEq, PartialOrd, Eq, Ord)]
+pub enum GroupSelection {
+    // Prefer the member used most recently
+    #[default]
+    Score,
+    // Pin each destination to a member with rendezvous hashing, so a change in
+    // membership only moves the destinations of the member added or removed
+    Hash,
+}
+
+impl FromStr for GroupSelection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("score") {
+            Ok(Self::Score)
+        } else if s.eq_ignore_ascii_case("hash") {
+            Ok(Self::Hash)
+        } else {
+            bail!("Unknown proxy group selection mode {s}")
+        }
+    }
 }
 
 enum TableExecuteResult<'a> {
     Proxy(&'a str),
-    ProxyGroup(&'a str),
+    ProxyGroup(&'a str, GroupSelection),
+    Direct,
     Reject,
     Return,
+    External(&'a str),
 }
 
 #[derive(Debug, Parser, PartialEq, Eq, Clone)]
 pub struct Rule {
     #[clap(short)]
-    dest: Vec<RuleDestination>,
+    pub(crate) dest: Vec<RuleDestination>,
     #[clap(short)]
-    proto: Option<RuleProtocol>,
+    pub(crate) proto: Option<RuleProtocol>,
     #[clap(short)]
-    action: RuleAction,
+    pub(crate) action: RuleAction,
+    // Where the rule is in the source, for error messages
+    #[clap(skip)]
+    line: usize,
 }
 
 impl FromStr for RuleDestination {
@@ -79,16 +121,33 @@ impl FromStr for RuleDestination {
                     format!("Parsing args into country code: {args}")
                 })?))
             }
+            "asn" => {
+                let asn = match args.get(..2) {
+                    Some(prefix) if prefix.eq_ignore_ascii_case("as") => &args[2..],
+                    _ => args,
+                };
+                Ok(Self::Asn(asn.parse().with_context(|| {
+                    format!("Parsing args into ASN: {args}")
+                })?))
+            }
             "network" => {
                 Ok(Self::Network(args.parse().with_context(|| {
                     format!("Parsing args into network: {args}")
                 })?))
             }
             "port" => {
-                Ok(Self::Port(args.parse().with_context(|| {
+                Ok(Self::Port(parse_port_range(args).with_context(|| {
                     format!("Parsing args into port: {args}")
                 })?))
             }
+            "src_ip" => Ok(Self::SourceNetwork(args.parse().with_context(|| {
+                format!("Parsing args into source network: {args}")
+            })?)),
+            "time" => {
+                Ok(Self::Time(args.parse().with_context(|| {
+                    format!("Parsing args into schedule: {args}")
+                })?))
+            }
             "domain" => {
                 Ok(Self::Domain(args.parse().with_context(|| {
                     format!("Parsing args into domain: {args}")
@@ -99,11 +158,31 @@ impl FromStr for RuleDestination {
                     format!("Parsing args into dnshost: {args}")
                 })?))
             }
+            "is_ip" if args.is_empty() => Ok(Self::IsIp(true)),
+            "is_ip" => {
+                Ok(Self::IsIp(args.parse().with_context(|| {
+                    format!("Parsing args into bool: {args}")
+                })?))
+            }
             _ => bail!("Unknown rule: {s}"),
         }
     }
 }
 
+// Accepts a single port, or an inclusive range as `5000-6000` or `5000..6000`
+fn parse_port_range(s: &str) -> anyhow::Result<RangeInclusive<u16>> {
+    let (start, end) = s
+        .split_once("..")
+        .or_else(|| s.split_once('-'))
+        .unwrap_or((s, s));
+    let start: u16 = start.parse()?;
+    let end: u16 = end.parse()?;
+    if start > end {
+        bail!("Port range {start}..{end} is empty");
+    }
+    Ok(start..=end)
+}
+
 impl FromStr for RuleAction {
     type Err = anyhow::Error;
 
@@ -111,14 +190,22 @@ impl FromStr for RuleAction {
         let mut splits = s.split(':');
         match (splits.next(), splits.next()) {
             (Some(n), Some(v)) if n.eq_ignore_ascii_case("proxy") => Ok(Self::Proxy(v.into())),
-            (Some(n), Some(v)) if n.eq_ignore_ascii_case("proxygroup") => {
-                Ok(Self::ProxyGroup(v.into()))
-            }
+            (Some(n), Some(v)) if n.eq_ignore_ascii_case("proxygroup") => Ok(Self::ProxyGroup(
+                v.into(),
+                match splits.next() {
+                    Some(mode) => mode.parse()?,
+                    None => Default::default(),
+                },
+            )),
+            (Some(n), None) if n.eq_ignore_ascii_case("direct") => Ok(Self::Direct),
             (Some(n), None) if n.eq_ignore_ascii_case("reject") => Ok(Self::Reject),
             (Some(n), Some(table_name)) if n.eq_ignore_ascii_case("jump") => {
                 Ok(Self::Jump(table_name.into()))
             }
             (Some(n), None) if n.eq_ignore_ascii_case("return") => Ok(Self::Return),
+            (Some(n), Some(name)) if n.eq_ignore_ascii_case("external") => {
+                Ok(Self::External(name.into()))
+            }
             _ => {
                 bail!("Unknown rule action {s}")
             }
@@ -126,12 +213,74 @@ impl FromStr for RuleAction {
     }
 }
 
+// Replaces `${NAME}` in the line with the variable's value, looking it up in `variables`
+// first and then with `env`. `$$` is a literal `$`, and any other `$` is left as it is,
+// so regex anchors in `matches:` patterns keep working.
+fn substitute_variables<'a>(
+    line: &'a str,
+    variables: &HashMap<String, String>,
+    env: &impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<Cow<'a, str>> {
+    if !line.contains("${") && !line.contains("$$") {
+        return Ok(Cow::Borrowed(line));
+    }
+
+    let mut result = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+
+        if let Some(r) = rest.strip_prefix('$') {
+            result.push('$');
+            rest = r;
+            continue;
+        }
+
+        let Some(r) = rest.strip_prefix('{') else {
+            result.push('$');
+            continue;
+        };
+        let (name, r) = r
+            .split_once('}')
+            .with_context(|| format!("Expecting '}}' after '${{' in {line}"))?;
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            bail!("Invalid variable name '{name}'");
+        }
+
+        match variables.get(name) {
+            Some(value) => result.push_str(value),
+            None => result
+                .push_str(&env(name).with_context(|| format!("Undefined variable ${{{name}}}"))?),
+        }
+        rest = r;
+    }
+    result.push_str(rest);
+    Ok(Cow::Owned(result))
+}
+
 impl Rule {
     pub fn parse_rules(s: &str) -> anyhow::Result<HashMap<String, Vec<Rule>>> {
+        Self::parse_rules_with_variables(s, &Default::default())
+    }
+
+    // Variables not in `variables` are taken from the environment
+    pub fn parse_rules_with_variables(
+        s: &str,
+        variables: &HashMap<String, String>,
+    ) -> anyhow::Result<HashMap<String, Vec<Rule>>> {
+        Self::parse_rules_with_env(s, variables, |name| std::env::var(name).ok())
+    }
+
+    fn parse_rules_with_env(
+        s: &str,
+        variables: &HashMap<String, String>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> anyhow::Result<HashMap<String, Vec<Rule>>> {
         let mut rulemap = HashMap::<String, Vec<Rule>>::new();
         let mut last_name = None;
 
-        for line in s.split('\n') {
+        for (line_no, line) in s.split('\n').enumerate() {
             let line = match line.trim() {
                 v if v.is_empty() => continue,
                 v => v,
@@ -155,8 +304,10 @@ impl Rule {
                 .as_ref()
                 .context("Expecting a table name before rules")?;
 
-            let rule = Rule::try_parse_from(line.split_ascii_whitespace())
-                .with_context(|| format!("Parsing rule \"{line}\""))?;
+            let mut rule = substitute_variables(line, variables, &env)
+                .and_then(|l| Ok(Rule::try_parse_from(l.split_ascii_whitespace())?))
+                .with_context(|| format!("Parsing rule \"{line}\" on line {}", line_no + 1))?;
+            rule.line = line_no + 1;
             match rulemap.get_mut(*name) {
                 Some(rules) => rules.push(rule),
                 None => {
@@ -172,14 +323,82 @@ impl Rule {
 #[derive(Eq, Default, Clone)]
 pub struct RuleString {
     s: String,
+    variables: HashMap<String, String>,
     rules: HashMap<String, Vec<Rule>>,
 }
 
+// Rules can be given as a plain string, or along with the variables they reference
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum RuleStringRepr<'a> {
+    Plain(Cow<'a, str>),
+    WithVariables {
+        rules: Cow<'a, str>,
+        #[serde(default)]
+        variables: Cow<'a, HashMap<String, String>>,
+    },
+}
+
+// The rules the client routes with. They can be swapped while the client is running,
+// and a lookup keeps using the snapshot it started with.
+#[derive(Default)]
+pub struct LiveRules(RwLock<Arc<RuleString>>);
+
+impl LiveRules {
+    pub fn load(&self) -> Arc<RuleString> {
+        self.0.read().clone()
+    }
+
+    pub fn store(&self, rules: RuleString) {
+        *self.0.write() = Arc::new(rules);
+    }
+}
+
+impl Clone for LiveRules {
+    fn clone(&self) -> Self {
+        Self(RwLock::new(self.load()))
+    }
+}
+
+impl Debug for LiveRules {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.load().fmt(f)
+    }
+}
+
+impl From<RuleString> for LiveRules {
+    fn from(rules: RuleString) -> Self {
+        Self(RwLock::new(Arc::new(rules)))
+    }
+}
+
+impl Serialize for LiveRules {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.load().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for LiveRules {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        RuleString::deserialize(deserializer).map(Self::from)
+    }
+}
+
 #[derive(PartialEq, Eq, Debug)]
 pub enum RuleExecutionResult<'a> {
     Proxy(&'a str),
-    ProxyGroup(&'a str),
+    ProxyGroup(&'a str, GroupSelection),
+    // Connect without going through any upstream
+    Direct,
     Reject,
+    // The external decider of the name is to be asked
+    External(&'a str),
 }
 
 #[derive(Debug)]
@@ -192,16 +411,66 @@ pub enum PacketDestination<'a> {
     Domain {
         hostname: &'a str,
         port: u16,
-        resolved_ips: Vec<(Option<CountryCode>, IpAddr)>,
+        // Looked up in the DNS cache the first time an IP based condition needs it
+        resolved_ips: OnceCell<Vec<(Option<CountryCode>, IpAddr)>>,
     },
 }
 
 impl RuleString {
+    pub(crate) fn table(&self, name: &str) -> Option<&[Rule]> {
+        self.rules.get(name).map(Vec::as_slice)
+    }
+
+    // Checks that every jump lands on a table, every proxy/group named by the rules is
+    // known, and that there's ASN data for the `asn:` rules to match against. All the
+    // problems are reported at once, with their line numbers.
+    pub fn validate(
+        &self,
+        has_proxy: impl Fn(&str) -> bool,
+        has_group: impl Fn(&str) -> bool,
+        has_asn_data: bool,
+    ) -> anyhow::Result<()> {
+        let mut rules: Vec<_> = self.rules.values().flatten().collect();
+        rules.sort_by_key(|r| r.line);
+
+        let errors: Vec<_> = rules
+            .into_iter()
+            .flat_map(|rule| {
+                let action = match &rule.action {
+                    RuleAction::Jump(t) if !self.rules.contains_key(t.as_ref()) => {
+                        Some(format!("line {}: jump to undefined table {t}", rule.line))
+                    }
+                    RuleAction::Proxy(p) if !has_proxy(p) => {
+                        Some(format!("line {}: undefined proxy {p}", rule.line))
+                    }
+                    RuleAction::ProxyGroup(g, _) if !has_group(g) => {
+                        Some(format!("line {}: undefined proxy group {g}", rule.line))
+                    }
+                    _ => None,
+                };
+                let asn = rule.dest.iter().find_map(|d| match d {
+                    RuleDestination::Asn(asn) if !has_asn_data => Some(format!(
+                        "line {}: asn:{asn} can't match without ASN data",
+                        rule.line
+                    )),
+                    _ => None,
+                });
+                action.into_iter().chain(asn)
+            })
+            .collect();
+
+        if !errors.is_empty() {
+            bail!("Invalid rules: {}", errors.join("; "));
+        }
+        Ok(())
+    }
+
     fn execute_table<'a>(
         &'a self,
         level: usize,
         table_name: &str,
         target: &PacketDestination<'_>,
+        src: Option<IpAddr>,
         proto: RuleProtocol,
         initial_data: Option<&[u8]>,
     ) -> Option<TableExecuteResult<'a>> {
@@ -222,7 +491,7 @@ impl RuleString {
             let mut matches_dest = true;
             for dest in &rule.dest {
                 // Match dest
-                matches_dest &= dest.matches(target, initial_data);
+                matches_dest &= dest.matches(target, src, initial_data);
 
                 if !matches_dest {
                     break;
@@ -245,6 +514,7 @@ impl RuleString {
                         level + 1,
                         table_name.as_ref(),
                         target,
+                        src,
                         proto,
                         initial_data.clone(),
                     ) {
@@ -256,15 +526,25 @@ impl RuleString {
                     log::debug!("Using proxy {name} for target={target:?}, proto={proto:?}");
                     return Some(TableExecuteResult::Proxy(name.as_ref()));
                 }
-                RuleAction::ProxyGroup(name) => {
-                    log::debug!("Using proxy group {name} for target={target:?}, proto={proto:?}");
-                    return Some(TableExecuteResult::ProxyGroup(name.as_ref()));
+                RuleAction::ProxyGroup(name, selection) => {
+                    log::debug!("Using proxy group {name} ({selection:?}) for target={target:?}, proto={proto:?}");
+                    return Some(TableExecuteResult::ProxyGroup(name.as_ref(), *selection));
+                }
+                RuleAction::Direct => {
+                    log::debug!("Connecting directly for target={target:?}, proto={proto:?}");
+                    return Some(TableExecuteResult::Direct);
                 }
                 RuleAction::Reject => {
                     log::debug!("Reject target={target:?}, proto={proto:?}");
                     return Some(TableExecuteResult::Reject);
                 }
                 RuleAction::Return => return Some(TableExecuteResult::Return),
+                RuleAction::External(name) => {
+                    log::debug!(
+                        "Asking external decider {name} for target={target:?}, proto={proto:?}"
+                    );
+                    return Some(TableExecuteResult::External(name.as_ref()));
+                }
             }
         }
         None
@@ -273,23 +553,33 @@ impl RuleString {
     pub fn execute_rules<'a>(
         &'a self,
         target: &PacketDestination<'_>,
+        src: Option<IpAddr>,
         proto: RuleProtocol,
         initial_data: Option<&[u8]>,
     ) -> anyhow::Result<Option<RuleExecutionResult<'a>>> {
         // Start from main table
-        match self.execute_table(0, "main", target, proto, initial_data) {
+        match self.execute_table(0, "main", target, src, proto, initial_data) {
             Some(TableExecuteResult::Proxy(name)) => Ok(Some(RuleExecutionResult::Proxy(name))),
-            Some(TableExecuteResult::ProxyGroup(name)) => {
-                Ok(Some(RuleExecutionResult::ProxyGroup(name)))
+            Some(TableExecuteResult::ProxyGroup(name, selection)) => {
+                Ok(Some(RuleExecutionResult::ProxyGroup(name, selection)))
             }
+            Some(TableExecuteResult::Direct) => Ok(Some(RuleExecutionResult::Direct)),
             Some(TableExecuteResult::Reject) => Ok(Some(RuleExecutionResult::Reject)),
+            Some(TableExecuteResult::External(name)) => {
+                Ok(Some(RuleExecutionResult::External(name)))
+            }
             None | Some(TableExecuteResult::Return) => Ok(None),
         }
     }
 }
 
 impl RuleDestination {
-    fn matches(&self, target: &PacketDestination<'_>, initial_data: Option<&[u8]>) -> bool {
+    fn matches(
+        &self,
+        target: &PacketDestination<'_>,
+        src: Option<IpAddr>,
+        initial_data: Option<&[u8]>,
+    ) -> bool {
         match (self, target) {
             (
                 RuleDestination::GeoIP(c),
@@ -304,8 +594,9 @@ impl RuleDestination {
                     false
                 }
             }
-            (RuleDestination::GeoIP(c), PacketDestination::Domain { resolved_ips, .. }) => {
-                if let Some((_, addr)) = resolved_ips
+            (RuleDestination::GeoIP(c), pd @ PacketDestination::Domain { .. }) => {
+                if let Some((_, addr)) = pd
+                    .resolved_ips()
                     .iter()
                     .find(|(code, _)| code.as_ref() == Some(c))
                 {
@@ -315,16 +606,40 @@ impl RuleDestination {
                     false
                 }
             }
+            (RuleDestination::Asn(asn), PacketDestination::IP { addr, .. }) => {
+                if find_asn(&addr.ip()) == Some(*asn) {
+                    log::debug!("IP {addr} matches asn:{asn}");
+                    true
+                } else {
+                    false
+                }
+            }
+            (RuleDestination::Asn(asn), pd @ PacketDestination::Domain { .. }) => {
+                if let Some((_, addr)) = pd
+                    .resolved_ips()
+                    .iter()
+                    .find(|(_, addr)| find_asn(addr) == Some(*asn))
+                {
+                    log::debug!("Resolved IP {addr} matches asn:{asn}");
+                    true
+                } else {
+                    false
+                }
+            }
             (RuleDestination::Network(n), PacketDestination::IP { addr, .. }) => {
-                if n.contains(addr.ip()) {
+                if n.contains(addr.ip().to_canonical()) {
                     log::debug!("IP {addr} matches network:{n}");
                     true
                 } else {
                     false
                 }
             }
-            (RuleDestination::Network(n), PacketDestination::Domain { resolved_ips, .. }) => {
-                if let Some((_, addr)) = resolved_ips.iter().find(|(_, addr)| n.contains(*addr)) {
+            (RuleDestination::Network(n), pd @ PacketDestination::Domain { .. }) => {
+                if let Some((_, addr)) = pd
+                    .resolved_ips()
+                    .iter()
+                    .find(|(_, addr)| n.contains(addr.to_canonical()))
+                {
                     log::debug!("Resolved IP {addr} matches network:{n}");
                     true
                 } else {
@@ -333,8 +648,23 @@ impl RuleDestination {
             }
             (RuleDestination::Domain(p), dst) => Self::domain_matches(p, dst, initial_data),
             (RuleDestination::Port(p), pd) => {
-                if *p == pd.port() {
-                    log::debug!("Dst port matches port:{p}");
+                if p.contains(&pd.port()) {
+                    log::debug!("Dst port matches port:{p:?}");
+                    true
+                } else {
+                    false
+                }
+            }
+            (RuleDestination::SourceNetwork(n), _) => match src {
+                Some(src) if n.contains(src.to_canonical()) => {
+                    log::debug!("Source {src} matches src_ip:{n}");
+                    true
+                }
+                _ => false,
+            },
+            (RuleDestination::Time(s), _) => {
+                if s.contains_now() {
+                    log::debug!("Local time matches time:{s}");
                     true
                 } else {
                     false
@@ -353,6 +683,14 @@ impl RuleDestination {
                     false
                 }
             }
+            (RuleDestination::IsIp(is_ip), pd) => {
+                if pd.is_ip() == *is_ip {
+                    log::debug!("Dst matches is_ip:{is_ip}");
+                    true
+                } else {
+                    false
+                }
+            }
         }
     }
 
@@ -417,6 +755,27 @@ impl<'a> PacketDestination<'a> {
             Self::IP { addr, .. } => addr.port(),
         }
     }
+
+    pub fn is_ip(&self) -> bool {
+        matches!(self, Self::IP { .. })
+    }
+
+    fn resolved_ips(&self) -> &[(Option<CountryCode>, IpAddr)] {
+        match self {
+            Self::Domain {
+                hostname,
+                resolved_ips,
+                ..
+            } => resolved_ips.get_or_init(|| {
+                DnsCache::global()
+                    .lookup(hostname)
+                    .into_iter()
+                    .map(|ip| (find_geoip(&ip), ip))
+                    .collect()
+            }),
+            Self::IP { .. } => &[],
+        }
+    }
 }
 
 impl HostMatch {
@@ -455,7 +814,7 @@ impl Debug for RuleString {
 
 impl PartialEq for RuleString {
     fn eq(&self, other: &Self) -> bool {
-        self.s.eq(&other.s)
+        self.s.eq(&other.s) && self.variables.eq(&other.variables)
     }
 }
 
@@ -464,7 +823,27 @@ impl Serialize for RuleString {
     where
         S: serde::Serializer,
     {
-        self.s.serialize(serializer)
+        if self.variables.is_empty() {
+            RuleStringRepr::Plain(Cow::Borrowed(&self.s))
+        } else {
+            RuleStringRepr::WithVariables {
+                rules: Cow::Borrowed(&self.s),
+                variables: Cow::Borrowed(&self.variables),
+            }
+        }
+        .serialize(serializer)
+    }
+}
+
+impl FromStr for RuleString {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self {
+            rules: Rule::parse_rules(s)?,
+            s: s.to_string(),
+            variables: Default::default(),
+        })
     }
 }
 
@@ -473,9 +852,19 @@ impl<'de> Deserialize<'de> for RuleString {
     where
         D: serde::Deserializer<'de>,
     {
-        let s = <String as Deserialize<'_>>::deserialize(deserializer)?;
-        let rules = Rule::parse_rules(&s).map_err(|e| serde::de::Error::custom(e))?;
-        Ok(Self { s, rules })
+        let (s, variables) = match RuleStringRepr::deserialize(deserializer)? {
+            RuleStringRepr::Plain(s) => (s.into_owned(), Default::default()),
+            RuleStringRepr::WithVariables { rules, variables } => {
+                (rules.into_owned(), variables.into_owned())
+            }
+        };
+        let rules =
+            Rule::parse_rules_with_variables(&s, &variables).map_err(serde::de::Error::custom)?;
+        Ok(Self {
+            s,
+            variables,
+            rules,
+        })
     }
 }
 
@@ -505,11 +894,13 @@ mod tests {
                     dest: vec![RuleDestination::Domain(HostMatch::HostList(gfw_list_engine()))],
                     proto: Some(RuleProtocol::Tcp),
                     action: RuleAction::Proxy("proxy1".into()),
+                    line: 2,
                 },
                 Rule {
                     dest: vec![RuleDestination::Domain(HostMatch::HostList(adblock_list_engine()))],
                     proto: Some(RuleProtocol::Tcp),
                     action: RuleAction::Proxy("proxy1".into()),
+                    line: 3,
                 },
                 Rule {
                     dest: vec![
@@ -518,16 +909,19 @@ mod tests {
                     ],
                     proto: Some(RuleProtocol::Udp),
                     action: RuleAction::Reject,
+                    line: 4,
                 },
                 Rule {
                     dest: vec![RuleDestination::GeoIP("nz".parse().unwrap())],
                     proto: None,
-                    action: RuleAction::Jump("nz".into())
+                    action: RuleAction::Jump("nz".into()),
+                    line: 5,
                 },
                 Rule {
                     dest: vec![],
                     proto: None,
-                    action: RuleAction::Reject
+                    action: RuleAction::Reject,
+                    line: 6,
                 }
             ],
             "nz".to_string() => vec![
@@ -535,11 +929,13 @@ mod tests {
                     dest: vec![],
                     proto: Some(RuleProtocol::Udp),
                     action: RuleAction::Return,
+                    line: 8,
                 },
                 Rule {
                     dest: vec![],
                     proto: None,
-                    action: RuleAction::ProxyGroup("group".into()),
+                    action: RuleAction::ProxyGroup("group".into(), GroupSelection::Score),
+                    line: 9,
                 },
             ]
         };
@@ -550,6 +946,7 @@ mod tests {
         let rules = RuleString {
             rules: rulemap,
             s: rules.to_string(),
+            variables: Default::default(),
         };
 
         let action = rules
@@ -559,6 +956,7 @@ mod tests {
                     country_code: Some("nz".parse().unwrap()),
                     resolved_host: Default::default(),
                 },
+                None,
                 RuleProtocol::Tcp,
                 None,
             )
@@ -566,7 +964,502 @@ mod tests {
 
         assert_eq!(
             action,
-            Some(RuleExecutionResult::ProxyGroup("group".into()))
+            Some(RuleExecutionResult::ProxyGroup(
+                "group",
+                GroupSelection::Score
+            ))
+        );
+    }
+
+    #[test]
+    fn asn_rule_works() {
+        crate::geoip::set_asn_database(crate::geoip::test_asn_database());
+
+        let rules = r#"
+        main:
+            test -d asn:AS13335 -a proxy:cloudflare
+            test -d asn:15169 -a proxy:google
+        "#;
+
+        let rules = RuleString {
+            rules: Rule::parse_rules(rules).expect("To parse rules"),
+            s: rules.to_string(),
+            variables: Default::default(),
+        };
+
+        let execute = |addr: &str| {
+            rules
+                .execute_rules(
+                    &PacketDestination::IP {
+                        addr: addr.parse().unwrap(),
+                        country_code: None,
+                        resolved_host: Default::default(),
+                    },
+                    None,
+                    RuleProtocol::Tcp,
+                    None,
+                )
+                .unwrap()
+        };
+
+        assert_eq!(
+            execute("1.1.1.1:443"),
+            Some(RuleExecutionResult::Proxy("cloudflare"))
+        );
+        assert_eq!(
+            execute("8.8.8.8:53"),
+            Some(RuleExecutionResult::Proxy("google"))
+        );
+        assert_eq!(execute("9.9.9.9:53"), None);
+        assert_eq!(
+            execute("[::ffff:1.1.1.1]:443"),
+            Some(RuleExecutionResult::Proxy("cloudflare"))
+        );
+    }
+
+    #[test]
+    fn rule_variables_work() {
+        let rules: RuleString = serde_json::from_value(serde_json::json!({
+            "rules": "main:\n\
+                test -d network:${HOME_NETWORK} -a proxy:home\n\
+                test -d network:${OFFICE_NETWORK} -a proxy:office\n",
+            "variables": {
+                "HOME_NETWORK": "192.168.1.0/24",
+                "OFFICE_NETWORK": "10.1.0.0/16",
+            },
+        }))
+        .expect("To parse rules");
+
+        let execute = |addr: &str| {
+            rules
+                .execute_rules(
+                    &PacketDestination::IP {
+                        addr: addr.parse().unwrap(),
+                        country_code: None,
+                        resolved_host: Default::default(),
+                    },
+                    None,
+                    RuleProtocol::Tcp,
+                    None,
+                )
+                .unwrap()
+        };
+
+        assert_eq!(
+            execute("192.168.1.5:443"),
+            Some(RuleExecutionResult::Proxy("home"))
+        );
+        assert_eq!(
+            execute("10.1.2.3:443"),
+            Some(RuleExecutionResult::Proxy("office"))
+        );
+        assert_eq!(execute("192.168.2.5:443"), None);
+        assert_eq!(
+            execute("[::ffff:192.168.1.5]:443"),
+            Some(RuleExecutionResult::Proxy("home"))
+        );
+
+        // The variables survive a round trip
+        let value = serde_json::to_value(&rules).unwrap();
+        assert_eq!(serde_json::from_value::<RuleString>(value).unwrap(), rules);
+    }
+
+    #[test]
+    fn rule_variables_fall_back_to_env() {
+        let env = |name: &str| {
+            matches!(name, "HOME_NETWORK" | "OFFICE_NETWORK").then(|| "10.1.0.0/16".to_string())
+        };
+        let variables = [("HOME_NETWORK".to_string(), "192.168.1.0/24".to_string())].into();
+
+        let rules = Rule::parse_rules_with_env(
+            "main:\n\
+            test -d network:${HOME_NETWORK} -a proxy:home\n\
+            test -d network:${OFFICE_NETWORK} -a proxy:office",
+            &variables,
+            env,
+        )
+        .unwrap();
+        // What's in `variables` comes first
+        assert_eq!(
+            rules["main"][0].dest,
+            vec![RuleDestination::Network("192.168.1.0/24".parse().unwrap())]
+        );
+        assert_eq!(
+            rules["main"][1].dest,
+            vec![RuleDestination::Network("10.1.0.0/16".parse().unwrap())]
+        );
+
+        let err = Rule::parse_rules_with_env(
+            "main:\ntest -d network:${UNDEFINED} -a reject",
+            &variables,
+            env,
+        )
+        .expect_err("Undefined variable to fail");
+        assert!(
+            format!("{err:#}").contains("Undefined variable ${UNDEFINED}"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn anchored_patterns_are_not_variables() {
+        let rules = Rule::parse_rules(
+            "main:\n\
+            test -d domain:matches:^(.+\\.)?example\\.com$ -a proxy:anchored\n\
+            test -d domain:matches:^$HOME\\.test$ -a proxy:literal\n\
+            test -d domain:matches:^cost\\$$\\.test$ -a proxy:escaped\n",
+        )
+        .expect("To parse rules");
+        let rules = &rules["main"];
+        assert_eq!(rules.len(), 3);
+
+        let first_match = |hostname: &str| {
+            rules.iter().position(|rule| {
+                rule.dest.iter().any(|d| match d {
+                    RuleDestination::Domain(m) => m.matches(hostname),
+                    _ => false,
+                })
+            })
+        };
+        assert_eq!(first_match("www.example.com"), Some(0));
+        assert_eq!(first_match("example.com"), Some(0));
+        assert_eq!(first_match("example.com.cn"), None);
+        assert_eq!(first_match("cost$.test"), Some(2));
+        assert_eq!(first_match("cost.test"), None);
+
+        // A `$` not followed by `{` is kept, even if it looks like a variable
+        assert!(matches!(
+            &rules[1].dest[..],
+            [RuleDestination::Domain(HostMatch::Pattern(p))] if p.to_string() == r"^$HOME\.test$"
+        ));
+
+        assert!(Rule::parse_rules("main:\ntest -d network:${UNCLOSED -a reject").is_err());
+    }
+
+    #[test]
+    fn geoip_rule_resolves_domains() {
+        let rules = r#"
+        main:
+            test -d geoip:cn -a proxy:cn
+        "#;
+
+        let rules = RuleString {
+            rules: Rule::parse_rules(rules).expect("To parse rules"),
+            s: rules.to_string(),
+            variables: Default::default(),
+        };
+
+        // Seed the DNS cache with an A record, as if the answer had passed through the proxy
+        let mut response =
+            crate::dns::build_query(1, "cn.geoip-rule.test", crate::dns::DnsQueryType::A).unwrap();
+        response[2] = 0x81;
+        response[3] = 0x80;
+        response[7] = 1;
+        response.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1, 0, 0, 1, 0, 0, 4, 114, 114, 114, 114]);
+        DnsCache::global().cache(&response).unwrap();
+
+        let execute = |target: PacketDestination| {
+            rules
+                .execute_rules(&target, None, RuleProtocol::Tcp, None)
+                .unwrap()
+        };
+        let ip = |addr: &str| {
+            let addr: SocketAddr = addr.parse().unwrap();
+            PacketDestination::IP {
+                addr,
+                country_code: find_geoip(&addr.ip()),
+                resolved_host: Default::default(),
+            }
+        };
+        let domain = |hostname| PacketDestination::Domain {
+            hostname,
+            port: 443,
+            resolved_ips: Default::default(),
+        };
+
+        assert_eq!(
+            execute(ip("114.114.114.114:53")),
+            Some(RuleExecutionResult::Proxy("cn"))
         );
+        assert_eq!(execute(ip("8.8.8.8:53")), None);
+        assert_eq!(
+            execute(domain("cn.geoip-rule.test")),
+            Some(RuleExecutionResult::Proxy("cn"))
+        );
+        assert_eq!(execute(domain("unknown.geoip-rule.test")), None);
+
+        // The resolution is kept on the destination for later conditions
+        let target = domain("cn.geoip-rule.test");
+        rules
+            .execute_rules(&target, None, RuleProtocol::Tcp, None)
+            .unwrap();
+        assert!(matches!(
+            &target,
+            PacketDestination::Domain { resolved_ips, .. } if resolved_ips.get().map(Vec::len) == Some(1)
+        ));
+    }
+
+    #[test]
+    fn is_ip_rule_works() {
+        let rules = r#"
+        main:
+            raw -d is_ip -a proxy:raw
+            named -d is_ip:false -d port:443 -a proxy:named
+        "#;
+
+        let rules = RuleString {
+            rules: Rule::parse_rules(rules).expect("To parse rules"),
+            s: rules.to_string(),
+            variables: Default::default(),
+        };
+
+        let ip = PacketDestination::IP {
+            addr: "1.2.3.4:443".parse().unwrap(),
+            country_code: None,
+            resolved_host: Default::default(),
+        };
+        let domain = PacketDestination::Domain {
+            hostname: "example.com",
+            port: 443,
+            resolved_ips: Default::default(),
+        };
+        assert!(ip.is_ip());
+        assert!(!domain.is_ip());
+
+        let execute = |target: &PacketDestination| {
+            rules
+                .execute_rules(target, None, RuleProtocol::Tcp, None)
+                .unwrap()
+        };
+        assert_eq!(execute(&ip), Some(RuleExecutionResult::Proxy("raw")));
+        assert_eq!(execute(&domain), Some(RuleExecutionResult::Proxy("named")));
+
+        assert!(Rule::parse_rules("main:\n  test -d is_ip:maybe -a reject").is_err());
+    }
+
+    #[test]
+    fn port_range_rule_works() {
+        let rules = r#"
+        main:
+            test -d port:5000..6000 -a proxy:range
+            test -d port:443 -a proxy:https
+        "#;
+
+        let rules = RuleString {
+            rules: Rule::parse_rules(rules).expect("To parse rules"),
+            s: rules.to_string(),
+            variables: Default::default(),
+        };
+
+        let execute = |port: u16| {
+            rules
+                .execute_rules(
+                    &PacketDestination::IP {
+                        addr: SocketAddr::new([1, 2, 3, 4].into(), port),
+                        country_code: None,
+                        resolved_host: Default::default(),
+                    },
+                    None,
+                    RuleProtocol::Tcp,
+                    None,
+                )
+                .unwrap()
+        };
+
+        assert_eq!(execute(5500), Some(RuleExecutionResult::Proxy("range")));
+        assert_eq!(execute(4999), None);
+        assert_eq!(execute(6001), None);
+        assert_eq!(execute(5000), Some(RuleExecutionResult::Proxy("range")));
+        assert_eq!(execute(6000), Some(RuleExecutionResult::Proxy("range")));
+        assert_eq!(execute(443), Some(RuleExecutionResult::Proxy("https")));
+
+        assert_eq!(
+            "port:80-90".parse::<RuleDestination>().unwrap(),
+            RuleDestination::Port(80..=90)
+        );
+        for malformed in ["6000..5000", "5000..", "a-b", "1..70000"] {
+            let rules = format!("main:\n  test -a reject\n  test -d port:{malformed} -a reject\n");
+            let err = Rule::parse_rules(&rules).expect_err("Malformed range to fail");
+            assert!(err.to_string().contains("on line 3"), "{err:?}");
+        }
+    }
+
+    #[test]
+    fn src_ip_rule_works() {
+        let rules = r#"
+        main:
+            test -d src_ip:192.168.1.0/24 -a proxy:lan
+            test -d src_ip:fd00::/64 -a proxy:lan6
+        "#;
+
+        let rules = RuleString {
+            rules: Rule::parse_rules(rules).expect("To parse rules"),
+            s: rules.to_string(),
+            variables: Default::default(),
+        };
+
+        let execute = |src: Option<&str>| {
+            rules
+                .execute_rules(
+                    &PacketDestination::IP {
+                        addr: "1.2.3.4:443".parse().unwrap(),
+                        country_code: None,
+                        resolved_host: Default::default(),
+                    },
+                    src.map(|s| s.parse().unwrap()),
+                    RuleProtocol::Tcp,
+                    None,
+                )
+                .unwrap()
+        };
+
+        assert_eq!(
+            execute(Some("192.168.1.20")),
+            Some(RuleExecutionResult::Proxy("lan"))
+        );
+        assert_eq!(
+            execute(Some("::ffff:192.168.1.20")),
+            Some(RuleExecutionResult::Proxy("lan"))
+        );
+        assert_eq!(execute(Some("192.168.2.20")), None);
+        assert_eq!(
+            execute(Some("fd00::1234")),
+            Some(RuleExecutionResult::Proxy("lan6"))
+        );
+        assert_eq!(execute(Some("fd00:0:0:1::1")), None);
+        assert_eq!(execute(None), None);
+
+        let err = Rule::parse_rules("main:\n  test -d src_ip:192.168.1.0/33 -a reject")
+            .expect_err("Invalid CIDR to fail");
+        assert!(err.to_string().contains("on line 2"), "{err:?}");
+    }
+
+    #[test]
+    fn time_rule_parses() {
+        let rules = Rule::parse_rules("main:\n  test -d time:mon-fri,09:00-17:00 -a reject")
+            .expect("To parse rules");
+        assert_eq!(
+            rules["main"][0].dest,
+            vec![RuleDestination::Time(
+                "mon-fri,09:00-17:00".parse().unwrap()
+            )]
+        );
+
+        // A schedule covering the whole week always matches
+        let rules = RuleString {
+            rules: Rule::parse_rules("main:\n  test -d time:mon-sun -a reject").unwrap(),
+            s: Default::default(),
+            variables: Default::default(),
+        };
+        assert_eq!(
+            rules
+                .execute_rules(
+                    &PacketDestination::IP {
+                        addr: "1.2.3.4:443".parse().unwrap(),
+                        country_code: None,
+                        resolved_host: Default::default(),
+                    },
+                    None,
+                    RuleProtocol::Tcp,
+                    None,
+                )
+                .unwrap(),
+            Some(RuleExecutionResult::Reject)
+        );
+
+        let err =
+            Rule::parse_rules("main:\n  test -a reject\n  test -d time:mon-fri,9-5 -a reject")
+                .expect_err("Malformed schedule to fail");
+        assert!(err.to_string().contains("on line 3"), "{err:?}");
+    }
+
+    #[test]
+    fn validation_reports_asn_rules_without_data() {
+        let rules: RuleString = serde_json::from_value(serde_json::json!(
+            "main:\n  test -d port:80 -a direct\n  test -d asn:13335 -a direct"
+        ))
+        .unwrap();
+
+        let err = rules
+            .validate(|_| true, |_| true, false)
+            .expect_err("ASN rules without data to fail");
+        assert_eq!(
+            err.to_string(),
+            "Invalid rules: line 3: asn:13335 can't match without ASN data"
+        );
+        assert!(rules.validate(|_| true, |_| true, true).is_ok());
+    }
+
+    #[test]
+    fn validation_reports_undefined_targets() {
+        let rules: RuleString = serde_json::from_value(serde_json::json!(
+            "main:\n  test -d port:80 -a proxy:known\n  test -a jump:missing\n  test -a proxy:unknown\n  test -a proxygroup:group"
+        ))
+        .unwrap();
+
+        let err = rules
+            .validate(|p| p == "known", |g| g == "group", true)
+            .expect_err("Undefined targets to fail");
+        assert_eq!(
+            err.to_string(),
+            "Invalid rules: line 3: jump to undefined table missing; line 4: undefined proxy unknown"
+        );
+
+        assert!(rules
+            .validate(|_| true, |_| true, true)
+            .expect_err("Undefined table to fail")
+            .to_string()
+            .contains("line 3"));
+    }
+
+    #[test]
+    fn live_rules_reload_atomically() {
+        let program = |proxy: &str| -> RuleString {
+            // A lookup seeing half of this would find the jump but not the table
+            serde_json::from_value(serde_json::json!(format!(
+                "main:\n  test -a jump:{proxy}\n{proxy}:\n  test -a proxy:{proxy}"
+            )))
+            .unwrap()
+        };
+        let evaluate = |rules: &LiveRules| {
+            let target = PacketDestination::IP {
+                addr: "1.2.3.4:443".parse().unwrap(),
+                country_code: None,
+                resolved_host: Default::default(),
+            };
+            rules
+                .load()
+                .execute_rules(&target, None, RuleProtocol::Tcp, None)
+                .unwrap()
+                .map(|r| format!("{r:?}"))
+        };
+
+        let live = LiveRules::from(program("a"));
+        assert_eq!(evaluate(&live).as_deref(), Some("Proxy(\"a\")"));
+
+        let done = std::sync::atomic::AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            let evaluations = scope.spawn(|| {
+                let mut count = 0;
+                while !done.load(std::sync::atomic::Ordering::SeqCst) {
+                    let result = evaluate(&live);
+                    assert!(
+                        matches!(result.as_deref(), Some("Proxy(\"a\")" | "Proxy(\"b\")")),
+                        "{result:?}"
+                    );
+                    count += 1;
+                }
+                count
+            });
+
+            for i in 0..1000 {
+                live.store(program(if i % 2 == 0 { "b" } else { "a" }));
+            }
+            live.store(program("b"));
+            done.store(true, std::sync::atomic::Ordering::SeqCst);
+            assert!(evaluations.join().unwrap() > 0);
+        });
+
+        assert_eq!(evaluate(&live).as_deref(), Some("Proxy(\"b\")"));
     }
 }
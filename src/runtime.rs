@@ -0,0 +1,59 @@
+use std::num::NonZeroUsize;
+
+// Read by smol's executor when the first task is spawned
+const THREADS_VAR: &str = "SMOL_THREADS";
+// smol names the threads running spawned tasks smol-1, smol-2...
+const WORKER_THREAD_PREFIX: &str = "smol-";
+
+// Sets how many threads run spawned tasks. It only takes effect if called before anything
+// is spawned. A single thread, the default, suits low-resource devices like routers best.
+pub fn set_worker_threads(threads: NonZeroUsize) {
+    std::env::set_var(THREADS_VAR, threads.to_string());
+}
+
+// How many threads are running spawned tasks, none if nothing has been spawned yet
+#[cfg(target_os = "linux")]
+pub fn count_worker_threads() -> std::io::Result<usize> {
+    let mut count = 0;
+    for task in std::fs::read_dir("/proc/self/task")? {
+        let name = std::fs::read_to_string(task?.path().join("comm"))?;
+        if name.starts_with(WORKER_THREAD_PREFIX) {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    // Other tests have usually started the executor by the time this runs, so the check
+    // is done in a process of its own
+    const PROBE_VAR: &str = "CPXY_WORKER_THREADS_PROBE";
+
+    #[test]
+    fn worker_threads_are_configured() {
+        if let Some(threads) = std::env::var_os(PROBE_VAR) {
+            let threads: NonZeroUsize = threads.to_str().unwrap().parse().unwrap();
+            set_worker_threads(threads);
+            smol::block_on(smol::spawn(async {}));
+            assert_eq!(count_worker_threads().unwrap(), threads.get());
+            return;
+        }
+
+        for threads in ["1", "3"] {
+            let output = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["runtime::tests::worker_threads_are_configured", "--exact"])
+                .env(PROBE_VAR, threads)
+                .env_remove(THREADS_VAR)
+                .output()
+                .unwrap();
+            assert!(
+                output.status.success(),
+                "Running with {threads} worker threads: {}",
+                String::from_utf8_lossy(&output.stdout)
+            );
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use std::{fmt::Display, str::FromStr};
+
+use anyhow::{bail, Context};
+use chrono::{Datelike, Local, NaiveTime, Timelike, Weekday};
+
+const ALL_DAYS: u8 = 0x7F;
+
+// A weekly window such as `mon-fri,09:00-17:00`. Days and time ranges are both
+// optional, and a time range that ends before it starts runs past midnight.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    s: String,
+    // Bit N is set for the Nth day from Monday
+    days: u8,
+    // Minutes since midnight, end exclusive
+    times: Vec<(u32, u32)>,
+}
+
+fn parse_day(s: &str) -> anyhow::Result<u32> {
+    Ok(s.parse::<Weekday>()
+        .map_err(|_| anyhow::anyhow!("Invalid weekday {s}"))?
+        .num_days_from_monday())
+}
+
+fn parse_time(s: &str) -> anyhow::Result<u32> {
+    let time =
+        NaiveTime::parse_from_str(s, "%H:%M").with_context(|| format!("Invalid time {s}"))?;
+    Ok(time.hour() * 60 + time.minute())
+}
+
+impl FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut days = 0;
+        let mut times = Vec::new();
+
+        for item in s.split(',').map(str::trim) {
+            if item.contains(':') {
+                let (start, end) = item
+                    .split_once('-')
+                    .with_context(|| format!("Expecting a time range instead of {item}"))?;
+                times.push((parse_time(start)?, parse_time(end)?));
+                continue;
+            }
+
+            let (start, end) = item.split_once('-').unwrap_or((item, item));
+            let (start, end) = (parse_day(start)?, parse_day(end)?);
+            // Day ranges wrap around the week too, e.g. fri-mon
+            let mut day = start;
+            loop {
+                days |= 1 << day;
+                if day == end {
+                    break;
+                }
+                day = (day + 1) % 7;
+            }
+        }
+
+        if days == 0 && times.is_empty() {
+            bail!("Expecting weekdays or time ranges in schedule \"{s}\"");
+        }
+
+        Ok(Self {
+            s: s.to_string(),
+            days: if days == 0 { ALL_DAYS } else { days },
+            times,
+        })
+    }
+}
+
+impl Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.s)
+    }
+}
+
+impl Schedule {
+    fn has_day(&self, day: Weekday) -> bool {
+        self.days & (1 << day.num_days_from_monday()) != 0
+    }
+
+    pub fn contains(&self, day: Weekday, time: NaiveTime) -> bool {
+        if self.times.is_empty() {
+            return self.has_day(day);
+        }
+
+        let minute = time.hour() * 60 + time.minute();
+        self.times.iter().any(|&(start, end)| {
+            if start < end {
+                self.has_day(day) && (start..end).contains(&minute)
+            } else {
+                // The part after midnight belongs to the day the range started on
+                (minute >= start && self.has_day(day)) || (minute < end && self.has_day(day.pred()))
+            }
+        })
+    }
+
+    pub fn contains_now(&self) -> bool {
+        let now = Local::now();
+        self.contains(now.weekday(), now.time())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(time, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn working_hours_work() {
+        let schedule: Schedule = "mon-fri,09:00-17:00".parse().unwrap();
+        assert!(schedule.contains(Weekday::Mon, at("09:00")));
+        assert!(schedule.contains(Weekday::Fri, at("16:59")));
+        assert!(!schedule.contains(Weekday::Fri, at("17:00")));
+        assert!(!schedule.contains(Weekday::Wed, at("08:59")));
+        assert!(!schedule.contains(Weekday::Sat, at("12:00")));
+
+        let schedule: Schedule = "sat,sun".parse().unwrap();
+        assert!(schedule.contains(Weekday::Sun, at("03:00")));
+        assert!(!schedule.contains(Weekday::Mon, at("03:00")));
+    }
+
+    #[test]
+    fn overnight_ranges_wrap() {
+        let schedule: Schedule = "22:00-06:00".parse().unwrap();
+        assert!(schedule.contains(Weekday::Tue, at("23:30")));
+        assert!(schedule.contains(Weekday::Wed, at("05:59")));
+        assert!(!schedule.contains(Weekday::Wed, at("06:00")));
+        assert!(!schedule.contains(Weekday::Wed, at("21:59")));
+
+        // Friday night carries on into Saturday morning, but Sunday night doesn't start
+        let schedule: Schedule = "fri-sat,22:00-06:00".parse().unwrap();
+        assert!(schedule.contains(Weekday::Sat, at("01:00")));
+        assert!(schedule.contains(Weekday::Sun, at("01:00")));
+        assert!(!schedule.contains(Weekday::Sun, at("23:00")));
+        assert!(!schedule.contains(Weekday::Mon, at("01:00")));
+    }
+
+    #[test]
+    fn malformed_schedules_fail() {
+        for s in ["", "mon-funday", "25:00-26:00", "09:00", "09:00-"] {
+            assert!(s.parse::<Schedule>().is_err(), "{s} should fail");
+        }
+    }
+}
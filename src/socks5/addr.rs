@@ -1,5 +1,4 @@
 use anyhow::{bail, Context};
-use async_net::resolve;
 use byteorder::{BigEndian, WriteBytesExt};
 use serde::{Deserialize, Serialize};
 use smallvec::{smallvec, SmallVec};
@@ -12,6 +11,8 @@ use std::str::FromStr;
 use bytes::Buf;
 use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+use crate::dns::STALE_CACHE;
+use crate::io::{order_by_family, AddressFamilyPreference, Connectivity};
 use crate::parse::ParseError;
 
 #[derive(Eq, PartialEq, Clone, Hash)]
@@ -113,15 +114,50 @@ impl<'a> Address<'a> {
     pub async fn resolve(&self) -> std::io::Result<impl Iterator<Item = SocketAddr>> {
         match self {
             Address::IP(addr) => Ok(vec![*addr].into_iter()),
-            Address::Name { host, port } => Ok(resolve((host.as_ref(), *port)).await?.into_iter()),
+            Address::Name { host, port } => Ok(STALE_CACHE
+                .resolve(host, *port)
+                .await
+                .map_err(|e| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::HostUnreachable,
+                        format!("Resolving {host}: {e}"),
+                    )
+                })?
+                .into_iter()),
         }
     }
 
-    pub async fn resolve_first(&self) -> anyhow::Result<SocketAddr> {
-        let mut addresses = self.resolve().await?;
-        addresses
+    // The resolved addresses in the given family preference, without those of a family
+    // the host can't reach the internet over
+    pub async fn resolve_reachable(
+        &self,
+        preference: AddressFamilyPreference,
+    ) -> std::io::Result<Vec<SocketAddr>> {
+        Ok(order_by_family(
+            self.resolve().await?,
+            preference,
+            &Connectivity::current(),
+        ))
+    }
+
+    pub async fn resolve_first(
+        &self,
+        preference: AddressFamilyPreference,
+    ) -> anyhow::Result<SocketAddr> {
+        let addresses = self.resolve_reachable(preference).await?;
+        Ok(addresses
+            .into_iter()
             .next()
-            .ok_or_else(|| anyhow::anyhow!("Unable to resolve {self}"))
+            .ok_or_else(|| self.no_usable_addresses())?)
+    }
+
+    // For a name that resolves fine but leaves nothing to connect to, either because
+    // the lookup came back empty or because every address got filtered out.
+    pub fn no_usable_addresses(&self) -> std::io::Error {
+        std::io::Error::new(
+            std::io::ErrorKind::HostUnreachable,
+            format!("No usable addresses for {self}"),
+        )
     }
 
     pub async fn parse_async(r: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<Address<'static>> {
@@ -211,9 +247,16 @@ impl<'a> Address<'a> {
                 }
 
                 position += name_len + 2;
-                let host = String::from_utf8_lossy(&buf[..name_len]);
+                let host = std::str::from_utf8(&buf[..name_len])
+                    .map_err(|e| ParseError::unexpected("domain name", e, "UTF-8"))?;
                 buf.advance(name_len);
-                Ok(Some((position, (host, buf.get_u16()).into())))
+                Ok(Some((
+                    position,
+                    Address::Name {
+                        host: Cow::Borrowed(host),
+                        port: buf.get_u16(),
+                    },
+                )))
             }
 
             0x4 => {
@@ -422,4 +465,12 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn non_utf8_domain_is_rejected() {
+        let buf = [0x3, 4, b'a', 0xff, 0xfe, b'b', 0, 80];
+
+        assert!(Address::parse(&buf).is_err());
+        assert!(block_on(Address::parse_async(&mut buf.as_slice())).is_err());
+    }
 }
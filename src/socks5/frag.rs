@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+use bytes::{Bytes, BytesMut};
+
+use super::{Address, UdpPacket, UdpRepr};
+
+// Set on the FRAG field of the last fragment of a datagram (RFC 1928)
+const END_OF_FRAGMENTS: u8 = 0x80;
+// Positions go from 1 to 127
+const MAX_FRAGMENTS: usize = 0x7f;
+
+// How long the fragments of a datagram are kept waiting for the rest
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+// How much incomplete datagrams can hold across a relay, so a peer sending fragments that
+// never complete can't grow it without bound
+pub const MAX_PENDING_BYTES: usize = 256 * 1024;
+// How many incomplete datagrams a relay holds at once
+pub const MAX_PENDING_DATAGRAMS: usize = 64;
+// What each incomplete datagram is charged on top of its fragments, so that empty
+// fragments still add up
+const PENDING_DATAGRAM_COST: usize = 512;
+
+// Splits `payload` into packets of at most `mtu` bytes. A payload that fits is sent whole,
+// with a FRAG of 0, which is all that peers not supporting fragmentation accept.
+pub fn fragment(
+    addr: &Address<'_>,
+    payload: Bytes,
+    mtu: Option<usize>,
+) -> anyhow::Result<Vec<UdpPacket<Bytes>>> {
+    let whole = UdpRepr {
+        addr,
+        payload: payload.clone(),
+        frag_no: 0,
+    };
+    let mtu = match mtu {
+        Some(mtu) if whole.write_len() > mtu => mtu,
+        _ => return Ok(vec![whole.to_packet()?]),
+    };
+
+    let header_len = whole.header_write_len();
+    if mtu <= header_len {
+        bail!("MTU {mtu} can't fit the header for {addr}");
+    }
+    let chunk_len = mtu - header_len;
+    let count = payload.len().div_ceil(chunk_len);
+    if count > MAX_FRAGMENTS {
+        bail!(
+            "Datagram of {} bytes needs {count} fragments with MTU {mtu}",
+            payload.len()
+        );
+    }
+
+    (0..count)
+        .map(|i| {
+            let end = payload.len().min((i + 1) * chunk_len);
+            let mut frag_no = i as u8 + 1;
+            if i + 1 == count {
+                frag_no |= END_OF_FRAGMENTS;
+            }
+            UdpRepr {
+                addr,
+                payload: payload.slice(i * chunk_len..end),
+                frag_no,
+            }
+            .to_packet()
+        })
+        .collect()
+}
+
+struct Pending {
+    started: Instant,
+    // Grown as fragments arrive, up to the furthest position seen
+    fragments: Vec<Option<Bytes>>,
+    // Position of the last fragment, once it's been seen
+    last: Option<usize>,
+    len: usize,
+}
+
+impl Pending {
+    fn is_complete(&self) -> bool {
+        match self.last {
+            Some(last) => self.fragments[..last].iter().all(Option::is_some),
+            None => false,
+        }
+    }
+
+    // What it counts towards `max_pending_bytes`
+    fn cost(&self) -> usize {
+        PENDING_DATAGRAM_COST + self.len
+    }
+}
+
+// Puts fragmented datagrams back together, one at a time for each address
+pub struct Reassembler {
+    timeout: Duration,
+    max_pending_bytes: usize,
+    pending: HashMap<Address<'static>, Pending>,
+    pending_bytes: usize,
+    last_swept: Instant,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self::new(REASSEMBLY_TIMEOUT, MAX_PENDING_BYTES)
+    }
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration, max_pending_bytes: usize) -> Self {
+        Self {
+            timeout,
+            max_pending_bytes,
+            pending: Default::default(),
+            pending_bytes: 0,
+            last_swept: Instant::now(),
+        }
+    }
+
+    // Returns the datagram `pkt` completes, or `pkt` itself if it isn't a fragment
+    pub fn push(&mut self, pkt: UdpPacket<Bytes>) -> Option<(Address<'static>, Bytes)> {
+        let addr = pkt.addr().into_owned();
+        if pkt.frag_no() == 0 {
+            return Some((addr, pkt.payload_bytes()));
+        }
+
+        let position = (pkt.frag_no() & !END_OF_FRAGMENTS) as usize;
+        let is_last = pkt.frag_no() & END_OF_FRAGMENTS != 0;
+        let payload = pkt.payload_bytes();
+        if position == 0 {
+            log::debug!("Dropping fragment of {addr} with no position");
+            return None;
+        }
+
+        // Expired datagrams are looked for every so often, or when they'd make room
+        let cost = match self.pending.get(&addr) {
+            Some(p) if p.started.elapsed() >= self.timeout => {
+                log::debug!("Discarding incomplete datagram of {addr}");
+                self.remove(&addr);
+                PENDING_DATAGRAM_COST + payload.len()
+            }
+            Some(_) => payload.len(),
+            None => PENDING_DATAGRAM_COST + payload.len(),
+        };
+        let is_new = !self.pending.contains_key(&addr);
+        let over_limit = |r: &Self| {
+            (is_new && r.pending.len() >= MAX_PENDING_DATAGRAMS)
+                || r.pending_bytes + cost > r.max_pending_bytes
+        };
+        if self.last_swept.elapsed() >= self.timeout / 2 || over_limit(self) {
+            self.discard_expired();
+        }
+        if over_limit(self) {
+            log::warn!("Too many incomplete datagrams, dropping fragment of {addr}");
+            return None;
+        }
+
+        self.pending_bytes += cost;
+        let pending = self.pending.entry(addr.clone()).or_insert_with(|| Pending {
+            started: Instant::now(),
+            fragments: Vec::new(),
+            last: None,
+            len: 0,
+        });
+
+        // A fragment past the end, or a second end, means the datagram can't be made
+        // whole any more
+        let past_end = pending.last.is_some_and(|last| position > last);
+        let other_end = is_last
+            && (pending.last.is_some_and(|last| position != last)
+                || pending.fragments.len() > position);
+        let seen = pending
+            .fragments
+            .get(position - 1)
+            .is_some_and(Option::is_some);
+        pending.len += payload.len();
+        if past_end || other_end || seen {
+            log::debug!("Dropping inconsistent fragments of {addr}");
+            self.remove(&addr);
+            return None;
+        }
+
+        if pending.fragments.len() < position {
+            pending.fragments.resize(position, None);
+        }
+        pending.fragments[position - 1] = Some(payload);
+        if is_last {
+            pending.last = Some(position);
+        }
+        if !pending.is_complete() {
+            return None;
+        }
+
+        let pending = self.remove(&addr)?;
+        let mut datagram = BytesMut::with_capacity(pending.len);
+        for fragment in pending.fragments.into_iter().flatten() {
+            datagram.extend_from_slice(&fragment);
+        }
+        Some((addr, datagram.freeze()))
+    }
+
+    fn remove(&mut self, addr: &Address<'static>) -> Option<Pending> {
+        let pending = self.pending.remove(addr)?;
+        self.pending_bytes -= pending.cost();
+        Some(pending)
+    }
+
+    fn discard_expired(&mut self) {
+        let timeout = self.timeout;
+        let mut discarded = 0;
+        self.pending.retain(|addr, p| {
+            let expired = p.started.elapsed() >= timeout;
+            if expired {
+                log::debug!("Discarding incomplete datagram of {addr}");
+                discarded += p.cost();
+            }
+            !expired
+        });
+        self.pending_bytes -= discarded;
+        self.last_swept = Instant::now();
+    }
+
+    // What incomplete datagrams hold, each charged `PENDING_DATAGRAM_COST` on top of
+    // its fragments
+    pub fn pending_bytes(&self) -> usize {
+        self.pending_bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+
+    fn datagram(len: usize) -> Bytes {
+        (0..len).map(|i| i as u8).collect::<Vec<_>>().into()
+    }
+
+    #[test]
+    fn fragments_and_reassembles() {
+        let addr: Address = "1.2.3.4:53".parse().unwrap();
+        let payload = datagram(10 * 1024);
+
+        let packets = fragment(&addr, payload.clone(), Some(1200)).unwrap();
+        assert_eq!(packets.len(), 9);
+        assert!(packets.iter().all(|p| p.inner().len() <= 1200));
+        assert_eq!(packets[0].frag_no(), 1);
+        assert_eq!(packets[8].frag_no(), 9 | END_OF_FRAGMENTS);
+
+        // In whatever order they arrive
+        let mut reassembler = Reassembler::default();
+        let mut packets = packets.into_iter().rev();
+        let last = packets.next().unwrap();
+        assert!(reassembler.push(last).is_none());
+        let mut reassembled = None;
+        for pkt in packets {
+            assert!(reassembled.is_none());
+            reassembled = reassembler.push(pkt);
+        }
+        assert_eq!(reassembled, Some((addr.clone(), payload)));
+        assert_eq!(reassembler.pending_bytes(), 0);
+
+        // Small enough datagrams aren't fragmented
+        let packets = fragment(&addr, datagram(100), Some(1200)).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].frag_no(), 0);
+        assert!(fragment(&addr, datagram(100), Some(4)).is_err());
+        assert!(fragment(&addr, datagram(100 * 1024), Some(200)).is_err());
+    }
+
+    #[test]
+    fn incomplete_datagrams_are_dropped() {
+        let addr: Address = "1.2.3.4:53".parse().unwrap();
+        let mut reassembler = Reassembler::new(Duration::from_millis(100), 4096);
+
+        let mut packets = fragment(&addr, datagram(3000), Some(1000)).unwrap();
+        let last = packets.pop().unwrap();
+        packets.remove(0);
+        for pkt in packets {
+            assert!(reassembler.push(pkt).is_none());
+        }
+        assert!(reassembler.pending_bytes() > 0);
+
+        // The missing fragment is too late to complete it
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(reassembler.push(last).is_none());
+        assert!(reassembler.pending_bytes() < 1000);
+
+        // Nor can fragments that never complete take more than the cap
+        let other: Address = "5.6.7.8:53".parse().unwrap();
+        for pkt in fragment(&other, datagram(8000), Some(1000)).unwrap() {
+            assert!(reassembler.push(pkt).is_none());
+        }
+        assert!(reassembler.pending_bytes() <= 4096);
+    }
+
+    #[test]
+    fn empty_fragments_are_bounded() {
+        let mut reassembler = Reassembler::default();
+        for port in 1..=10_000u16 {
+            let addr = Address::IP(SocketAddr::from(([1, 2, 3, 4], port)));
+            let pkt = UdpRepr {
+                addr: &addr,
+                payload: Bytes::new(),
+                frag_no: 1,
+            }
+            .to_packet()
+            .unwrap();
+            assert!(reassembler.push(pkt).is_none());
+        }
+
+        assert_eq!(reassembler.pending.len(), MAX_PENDING_DATAGRAMS);
+        assert_eq!(
+            reassembler.pending_bytes(),
+            MAX_PENDING_DATAGRAMS * PENDING_DATAGRAM_COST
+        );
+        assert!(reassembler.pending.values().all(|p| p.fragments.len() == 1));
+    }
+}
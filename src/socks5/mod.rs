@@ -1,10 +1,12 @@
 mod addr;
+mod frag;
 mod greeting;
 mod req;
 mod udp;
 mod udp_relay;
 
 pub use addr::*;
+pub use frag::*;
 pub use greeting::*;
 pub use req::*;
 pub use udp::*;
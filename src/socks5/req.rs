@@ -2,6 +2,7 @@ use crate::parse::ParseError;
 use anyhow::bail;
 use bytes::Buf;
 use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use std::io::ErrorKind;
 
 use super::Address;
 
@@ -11,7 +12,31 @@ pub struct ConnStatusCode(u8);
 impl ConnStatusCode {
     pub const GRANTED: Self = ConnStatusCode(0);
     pub const FAILED: Self = ConnStatusCode(0x1);
+    pub const NOT_ALLOWED: Self = ConnStatusCode(0x2);
+    pub const NETWORK_UNREACHABLE: Self = ConnStatusCode(0x3);
+    pub const HOST_UNREACHABLE: Self = ConnStatusCode(0x4);
+    pub const CONNECTION_REFUSED: Self = ConnStatusCode(0x5);
+    pub const TTL_EXPIRED: Self = ConnStatusCode(0x6);
     pub const UNSUPPORTED_COMMAND: Self = ConnStatusCode(0x7);
+
+    // Picks the reply closest to the first recognisable cause in the error chain
+    pub fn from_error(e: &anyhow::Error) -> Self {
+        e.chain()
+            .find_map(|e| {
+                if let Some(code) = e.downcast_ref::<ConnStatusCode>() {
+                    return Some(*code);
+                }
+
+                match e.downcast_ref::<std::io::Error>()?.kind() {
+                    ErrorKind::ConnectionRefused => Some(Self::CONNECTION_REFUSED),
+                    ErrorKind::HostUnreachable => Some(Self::HOST_UNREACHABLE),
+                    ErrorKind::NetworkUnreachable => Some(Self::NETWORK_UNREACHABLE),
+                    ErrorKind::TimedOut => Some(Self::TTL_EXPIRED),
+                    _ => None,
+                }
+            })
+            .unwrap_or(Self::FAILED)
+    }
 }
 
 impl std::fmt::Display for ConnStatusCode {
@@ -27,6 +52,7 @@ pub struct Command(u8);
 
 impl Command {
     pub const CONNECT_TCP: Self = Self(1);
+    pub const BIND_TCP: Self = Self(2);
     pub const BIND_UDP: Self = Self(3);
 }
 
@@ -1,17 +1,38 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
 
 use anyhow::{anyhow, Context};
 use bytes::Bytes;
-use futures::{future::ready, Sink, SinkExt, Stream, StreamExt};
+use futures::{future::ready, stream, Sink, SinkExt, Stream, StreamExt};
 use parking_lot::Mutex;
 
 use crate::{
     io::{bind_udp, UdpSocketExt},
-    socks5::UdpPacket,
+    socks5::{fragment, Reassembler, UdpPacket, UdpRepr},
 };
 
+// Where the relay takes datagrams from: the client's IP, and its port too if known
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RelaySource {
+    pub ip: IpAddr,
+    pub port: Option<u16>,
+}
+
+impl RelaySource {
+    fn accepts(&self, addr: &SocketAddr) -> bool {
+        addr.ip().to_canonical() == self.ip.to_canonical()
+            && self.port.is_none_or(|port| port == addr.port())
+    }
+}
+
+// Packets sent to the client are fragmented to fit `mtu`, if given. Fragments from the
+// client are always put back together. Datagrams from anywhere but `source` are dropped.
 pub async fn new_udp_relay(
     v4: bool,
+    mtu: Option<usize>,
+    source: Option<RelaySource>,
 ) -> anyhow::Result<(
     SocketAddr,
     impl Sink<UdpPacket<Bytes>, Error = anyhow::Error> + Unpin,
@@ -25,22 +46,39 @@ pub async fn new_udp_relay(
     let last_addr = Arc::new(Mutex::new(None));
     let sink = {
         let last_addr = last_addr.clone();
-        sink.with(move |pkt: UdpPacket<Bytes>| {
-            ready(
-                last_addr
+        sink.sink_map_err(anyhow::Error::from)
+            .with_flat_map(move |pkt: UdpPacket<Bytes>| {
+                let packets = last_addr
                     .lock()
                     .ok_or_else(|| anyhow!("No last address"))
-                    .map(|addr| (pkt.into_inner(), addr)),
-            )
-        })
+                    .and_then(|addr| {
+                        let packets = if pkt.frag_no() == 0 {
+                            fragment(&pkt.addr(), pkt.payload_bytes(), mtu)?
+                        } else {
+                            vec![pkt]
+                        };
+                        Ok(packets.into_iter().map(move |p| Ok((p.into_inner(), addr))))
+                    });
+                match packets {
+                    Ok(packets) => stream::iter(packets).left_stream(),
+                    Err(e) => stream::once(ready(Err(e))).right_stream(),
+                }
+            })
     };
 
+    let mut reassembler = Reassembler::default();
+
     let stream = stream.filter_map(move |item| {
         let (data, addr) = match item {
             Ok(v) => v,
             Err(e) => return ready(Some(Err(e))),
         };
 
+        if let Some(source) = source.filter(|s| !s.accepts(&addr)) {
+            log::warn!("Dropping datagram from {addr}, the relay is for {source:?}");
+            return ready(None);
+        }
+
         last_addr.lock().replace(addr);
         let pkt = match UdpPacket::new_checked(data) {
             Ok(v) => v,
@@ -49,7 +87,17 @@ pub async fn new_udp_relay(
                 return ready(None);
             }
         };
-        ready(Some(Ok(pkt)))
+        if pkt.frag_no() == 0 {
+            return ready(Some(Ok(pkt)));
+        }
+        ready(reassembler.push(pkt).map(|(addr, payload)| {
+            UdpRepr {
+                addr: &addr,
+                payload,
+                frag_no: 0,
+            }
+            .to_packet()
+        }))
     });
 
     Ok((bound_addr, Box::pin(sink), Box::pin(stream)))
@@ -73,7 +121,7 @@ mod tests {
     #[test]
     fn udp_relay_works() -> anyhow::Result<()> {
         block_on(async move {
-            let (mut relay_addr, mut tx, mut rx) = new_udp_relay(true).await?;
+            let (mut relay_addr, mut tx, mut rx) = new_udp_relay(true, None, None).await?;
             set_ip_local(&mut relay_addr);
 
             let client = bind_udp(true).await?;
@@ -133,4 +181,109 @@ mod tests {
             Ok(())
         })
     }
+
+    #[test]
+    fn udp_relay_fragments_large_datagrams() -> anyhow::Result<()> {
+        block_on(async move {
+            let (mut relay_addr, mut tx, mut rx) = new_udp_relay(true, Some(1200), None).await?;
+            set_ip_local(&mut relay_addr);
+
+            let client = bind_udp(true).await?;
+            let target_addr: Address = "1.2.3.4:53".parse()?;
+            let payload: Bytes = (0..10 * 1024).map(|i| i as u8).collect::<Vec<_>>().into();
+
+            // The client's fragments come out whole
+            for pkt in fragment(&target_addr, payload.clone(), Some(1200))? {
+                client.send_to(pkt.inner(), relay_addr).await?;
+            }
+            let pkt = rx
+                .next()
+                .timeout(Duration::from_secs(1))
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            assert_eq!(pkt.frag_no(), 0);
+            assert_eq!(pkt.addr(), target_addr);
+            assert_eq!(pkt.payload(), payload.as_ref());
+
+            // Replies go out in fragments that fit the MTU
+            tx.send(
+                UdpRepr {
+                    addr: &target_addr,
+                    payload: payload.clone(),
+                    frag_no: 0,
+                }
+                .to_packet()?,
+            )
+            .await?;
+
+            let mut reassembler = Reassembler::default();
+            let reassembled = loop {
+                let mut buf = new_vec_for_udp();
+                let (len, _) = client
+                    .recv_from(&mut buf)
+                    .timeout(Duration::from_secs(2))
+                    .await
+                    .unwrap()?;
+                assert!(len <= 1200);
+                buf.set_len_uninit(len);
+                if let Some(v) = reassembler.push(UdpPacket::new_checked(Bytes::from(buf))?) {
+                    break v;
+                }
+            };
+            assert_eq!(reassembled, (target_addr, payload));
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn udp_relay_drops_datagrams_from_others() -> anyhow::Result<()> {
+        block_on(async move {
+            let client = smol::net::UdpSocket::bind("127.0.0.1:0").await?;
+            let same_ip = smol::net::UdpSocket::bind("127.0.0.1:0").await?;
+            let other_ip = smol::net::UdpSocket::bind("127.0.0.2:0").await?;
+            let target_addr: Address = "1.2.3.4:53".parse()?;
+            let packet = |payload: &'static [u8]| {
+                UdpRepr {
+                    addr: &target_addr,
+                    payload,
+                    frag_no: 0,
+                }
+                .to_packet()
+                .unwrap()
+                .into_inner()
+            };
+
+            // Only the client's IP is checked by default, and strict mode checks its port
+            for (port, relayed_from_same_ip) in
+                [(None, true), (Some(client.local_addr()?.port()), false)]
+            {
+                let source = RelaySource {
+                    ip: "127.0.0.1".parse()?,
+                    port,
+                };
+                let (mut relay_addr, _tx, mut rx) = new_udp_relay(true, None, Some(source)).await?;
+                set_ip_local(&mut relay_addr);
+
+                other_ip.send_to(&packet(b"spoofed"), relay_addr).await?;
+                same_ip.send_to(&packet(b"same ip"), relay_addr).await?;
+                client.send_to(&packet(b"client"), relay_addr).await?;
+
+                let mut received = Vec::new();
+                while let Some(Some(pkt)) = rx.next().timeout(Duration::from_millis(200)).await {
+                    received.push(pkt?.payload().to_vec());
+                }
+                let expected: Vec<&[u8]> = if relayed_from_same_ip {
+                    vec![b"same ip", b"client"]
+                } else {
+                    vec![b"client"]
+                };
+                assert_eq!(received, expected);
+            }
+
+            Ok(())
+        })
+    }
 }
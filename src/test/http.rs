@@ -79,3 +79,214 @@ fn test_http_tunnel() {
         );
     });
 }
+
+#[test]
+fn test_http_rejected_gets_forbidden() {
+    let _ = env_logger::try_init();
+    block_on(async move {
+        let listener = bind_tcp(&Default::default()).await.unwrap();
+        let mut client_addr = listener.local_addr().unwrap();
+        set_ip_local(&mut client_addr);
+
+        let config = ClientConfig {
+            upstreams: hashmap! {
+                String::from("direct") => UpstreamConfig {
+                    protocol: UpstreamProtocol::Direct(crate::protocol::direct::Direct),
+                    enabled: true,
+                    idle_timeout_secs: None,
+                    groups: Default::default(),
+                }
+            },
+            traffic_rules: serde_json::from_value(serde_json::json!(
+                "main:\n  blocked -d port:9 -a reject"
+            ))
+            .unwrap(),
+            ..Default::default()
+        };
+        let stats = ClientStatistics::new(&config);
+        let _client = spawn(run_proxy_with(
+            listener,
+            Arc::new(config),
+            Arc::new(stats),
+            Default::default(),
+        ));
+
+        let status = |request: &'static str| async move {
+            let mut proxy_client = TcpStream::connect(client_addr).await.unwrap();
+            proxy_client.write_all(request.as_bytes()).await.unwrap();
+            parse_response(proxy_client, RWBuffer::new_vec_uninitialised(4096))
+                .timeout(TIMEOUT)
+                .await
+                .expect("No timeout")
+                .expect("A response")
+                .status_code
+        };
+
+        // Both tunnels and forwarded requests are refused, other failures aren't the client's
+        assert_eq!(status("CONNECT 127.0.0.1:9 HTTP/1.1\r\n\r\n").await, 403);
+        assert_eq!(
+            status("GET http://127.0.0.1:9/ HTTP/1.1\r\nHost: 127.0.0.1:9\r\n\r\n").await,
+            403
+        );
+        assert_eq!(
+            status("CONNECT cpxy-test.invalid:80 HTTP/1.1\r\n\r\n").await,
+            500
+        );
+    });
+}
+
+#[test]
+fn test_http_forwarding() {
+    let _ = env_logger::try_init();
+    block_on(async move {
+        let (_server, server_addr) = run_test_server().await;
+        let (_client, client_addr) = run_test_client(server_addr).await;
+        let (http_server, http_server_addr) = create_tcp_server().await;
+
+        let http_server_task = spawn(async move {
+            let (mut stream, _) = http_server.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                assert!(n > 0);
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello")
+                .await
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let mut proxy_client = TcpStream::connect(client_addr).await.unwrap();
+        proxy_client
+            .write_all(
+                format!(
+                    "GET http://{http_server_addr}/greeting?lang=en HTTP/1.1\r\n\
+                     Host: {http_server_addr}\r\n\
+                     X-Custom: kept\r\n\
+                     Proxy-Connection: keep-alive\r\n\
+                     Proxy-Authorization: Basic dXNlcjpwYXNz\r\n\
+                     Connection: keep-alive, X-Private\r\n\
+                     X-Private: dropped\r\n\r\n"
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut res = parse_response(proxy_client, RWBuffer::new_vec_uninitialised(4096))
+            .timeout(TIMEOUT)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(res.status_code, 200);
+        assert_eq!(res.body().await.unwrap(), b"hello");
+
+        let request = http_server_task.timeout(TIMEOUT).await.unwrap();
+        let mut lines = request.lines();
+        assert_eq!(lines.next(), Some("GET /greeting?lang=en HTTP/1.1"));
+        let headers: Vec<_> = lines
+            .filter(|l| !l.is_empty())
+            .map(|l| l.split_once(':').unwrap().0.to_ascii_lowercase())
+            .collect();
+        assert_eq!(headers, vec!["host", "x-custom"]);
+        assert!(request.contains("X-Custom: kept"));
+    });
+}
+
+#[test]
+fn test_fetch_decodes_content() {
+    use flate2::{
+        write::{DeflateEncoder, GzEncoder, ZlibEncoder},
+        Compression,
+    };
+    use std::io::Write;
+
+    fn encode(encoding: &str, body: &[u8]) -> Vec<u8> {
+        match encoding {
+            "gzip" => {
+                let mut e = GzEncoder::new(Vec::new(), Compression::default());
+                e.write_all(body).unwrap();
+                e.finish().unwrap()
+            }
+            "deflate" => {
+                let mut e = ZlibEncoder::new(Vec::new(), Compression::default());
+                e.write_all(body).unwrap();
+                e.finish().unwrap()
+            }
+            "raw-deflate" => {
+                let mut e = DeflateEncoder::new(Vec::new(), Compression::default());
+                e.write_all(body).unwrap();
+                e.finish().unwrap()
+            }
+            "br" => {
+                let mut e = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+                e.write_all(body).unwrap();
+                e.into_inner()
+            }
+            _ => body.to_vec(),
+        }
+    }
+
+    let _ = env_logger::try_init();
+    block_on(async move {
+        let list = b"||ads.example.com^\n||tracker.example.com^\n".repeat(20);
+        let encodings = ["identity", "gzip", "deflate", "raw-deflate", "br"];
+
+        // Serves the list with the encoding named by the path
+        let (server, server_addr) = create_tcp_server().await;
+        let _server_task = spawn({
+            let list = list.clone();
+            async move {
+                loop {
+                    let (mut stream, _) = server.accept().await.unwrap();
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        let n = stream.read(&mut buf).await.unwrap();
+                        assert!(n > 0);
+                        request.extend_from_slice(&buf[..n]);
+                    }
+                    let request = String::from_utf8(request).unwrap();
+                    assert!(request.contains("Accept-Encoding: gzip, deflate, br\r\n"));
+
+                    let path = request.split(' ').nth(1).unwrap();
+                    let encoding = path.rsplit('/').next().unwrap();
+                    let body = encode(encoding, &list);
+                    let encoding = encoding.trim_start_matches("raw-");
+                    stream
+                        .write_all(
+                            format!(
+                                "HTTP/1.1 200 OK\r\nContent-Encoding: {encoding}\r\n\
+                                 Content-Length: {}\r\n\r\n",
+                                body.len()
+                            )
+                            .as_bytes(),
+                        )
+                        .await
+                        .unwrap();
+                    stream.write_all(&body).await.unwrap();
+                }
+            }
+        });
+
+        for encoding in encodings {
+            let mut res = fetch_http_with_proxy(
+                &format!("http://lists.example.com/{encoding}"),
+                "GET",
+                std::iter::empty(),
+                &Address::IP(server_addr),
+                None,
+            )
+            .timeout(TIMEOUT)
+            .await
+            .unwrap()
+            .unwrap();
+
+            assert_eq!(res.status_code, 200);
+            assert_eq!(res.body().await.unwrap(), list, "{encoding}");
+        }
+    });
+}
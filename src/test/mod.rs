@@ -1,6 +1,8 @@
 use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr},
+    pin::Pin,
     sync::Arc,
+    task::{Context, Poll},
     time::Duration,
     vec,
 };
@@ -31,6 +33,39 @@ use crate::{
     socks5::Address,
 };
 
+// Takes a few bytes every other write, and is pending in between
+#[derive(Default)]
+pub struct ChoppyWriter {
+    pub written: Vec<u8>,
+    ready: bool,
+}
+
+impl AsyncWrite for ChoppyWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.ready = !self.ready;
+        if !self.ready {
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+
+        let n = buf.len().min(3);
+        self.written.extend_from_slice(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
 #[allow(dead_code)]
 pub async fn duplex(
     _: usize,
@@ -145,22 +180,74 @@ pub async fn run_test_client(upstream_address: SocketAddr) -> (Task<()>, SocketA
                                 ssl: false,
                                 allows_udp: true,
                                 credentials: None,
+                                keepalive_secs: None,
+                                client_identity: None,
+                                sni: None,
+                                alpn: None,
+                                cipher: Default::default(),
+                                ssl_fallback: false,
+                                read_buffer_size: None,
+                                pool: None,
+                                path_prefix: None,
+                                quic: None,
+                                compression: false,
+                                browser_headers: None,
                             }),
                             enabled: true,
+                            idle_timeout_secs: None,
                             groups: Default::default(),
                         }
                     },
                     socks5_udp_host: "0.0.0.0".parse().unwrap(),
                     fwmark: None,
+                    dscp: None,
                     udp_tproxy_address: None,
+                    udp_associate_strict_port: false,
+                    sni_proxy_address: None,
+                    block_page_address: None,
                     traffic_rules: Default::default(),
                     set_router_rules: false,
+                    connect_timeout_secs: None,
+                    first_byte_timeout_secs: None,
+                    socks5_bind_timeout_secs: None,
+                    abp_whitelist: Default::default(),
+                    abp_fetch_attempts: Default::default(),
+                    abp_max_redirects: Default::default(),
+                    abp_cache_dir: Default::default(),
+                    gfw_list_sources: None,
+                    adblock_list_sources: None,
+                    disable_gfw_list: false,
+                    geoip_overlap_policy: Default::default(),
+                    max_concurrent_dns_queries: None,
+                    dns_serve_stale_secs: None,
+                    dns_servers: Default::default(),
+                    dns_client_subnet: Default::default(),
+                    max_upstream_attempts: None,
+                    upstream_circuit_breaker: None,
+                    health_check: None,
+                    access_log: None,
+                    drain_grace_secs: None,
+                    bypass_networks: Default::default(),
+                    rule_load_policy: Default::default(),
+                    external_decision_timeout_ms: None,
+                    external_decision_fallback: None,
+                    upstream_keepalive_secs: None,
+                    direct_keepalive_secs: None,
+                    tcp_keepalive_interval_secs: None,
+                    tcp_keepalive_count: None,
+                    tcp_nodelay: true,
+                    address_family_preference: Default::default(),
                 };
                 let stats = ClientStatistics::new(&config);
 
-                run_proxy_with(listener, Arc::new(config), Arc::new(stats))
-                    .await
-                    .unwrap();
+                run_proxy_with(
+                    listener,
+                    Arc::new(config),
+                    Arc::new(stats),
+                    Default::default(),
+                )
+                .await
+                .unwrap();
             })
         },
         addr,
@@ -172,7 +259,7 @@ pub async fn run_test_server() -> (Task<()>, SocketAddr) {
     let mut addr = listener.local_addr().unwrap();
     set_ip_local(&mut addr);
     (
-        spawn(async move { run_server(listener).await.unwrap() }),
+        spawn(async move { run_server(listener, Default::default()).await.unwrap() }),
         addr,
     )
 }
@@ -204,7 +291,7 @@ async fn parse_address(
     }
 }
 
-async fn send_socks5_request(
+pub async fn send_socks5_request(
     socks: &mut (impl AsyncRead + AsyncWrite + Unpin + Send + Sync),
     target: &Address<'_>,
     is_udp: bool,
@@ -3,6 +3,8 @@ use smol::block_on;
 use smol_timeout::TimeoutExt;
 
 use super::*;
+use crate::protocol::direct::Direct;
+use crate::socks5::{ClientConnRequest, Command, ConnStatusCode};
 
 #[test]
 fn test_tcp_socks5_proxy() {
@@ -34,3 +36,181 @@ fn test_tcp_socks5_proxy() {
         );
     });
 }
+
+#[test]
+fn test_tcp_socks5_failure_reply_codes() {
+    let _ = env_logger::try_init();
+    block_on(async move {
+        let listener = bind_tcp(&Default::default()).await.unwrap();
+        let mut client_addr = listener.local_addr().unwrap();
+        set_ip_local(&mut client_addr);
+
+        let config = ClientConfig {
+            upstreams: hashmap! {
+                String::from("direct") => UpstreamConfig {
+                    protocol: UpstreamProtocol::Direct(Direct),
+                    enabled: true,
+                    idle_timeout_secs: None,
+                    groups: Default::default(),
+                }
+            },
+            traffic_rules: serde_json::from_value(serde_json::json!(
+                "main:\n  blocked -d port:9 -a reject"
+            ))
+            .unwrap(),
+            ..Default::default()
+        };
+        let stats = ClientStatistics::new(&config);
+        let _client = spawn(run_proxy_with(
+            listener,
+            Arc::new(config),
+            Arc::new(stats),
+            Default::default(),
+        ));
+
+        let reply_code = |target: Address<'static>| async move {
+            let mut socks5_client = TcpStream::connect(client_addr).await.unwrap();
+            send_socks5_request(&mut socks5_client, &target, false)
+                .timeout(TIMEOUT)
+                .await
+                .expect("No timeout")
+                .expect_err("Connection to fail")
+                .to_string()
+        };
+
+        assert_eq!(
+            reply_code("cpxy-test.invalid:80".parse().unwrap()).await,
+            "Connection refused with code = 4"
+        );
+        assert_eq!(
+            reply_code("127.0.0.1:9".parse().unwrap()).await,
+            "Connection refused with code = 2"
+        );
+    });
+}
+
+#[test]
+fn test_tcp_socks5_bind() {
+    let _ = env_logger::try_init();
+    block_on(async move {
+        let listener = bind_tcp(&Default::default()).await.unwrap();
+        let mut client_addr = listener.local_addr().unwrap();
+        set_ip_local(&mut client_addr);
+
+        let config = ClientConfig {
+            socks5_bind_timeout_secs: Some(1),
+            traffic_rules: serde_json::from_value(serde_json::json!(
+                "main:\n  blocked -d port:9 -a reject\n  blocked -d network:127.0.0.2/32 -a reject"
+            ))
+            .unwrap(),
+            ..Default::default()
+        };
+        let stats = ClientStatistics::new(&config);
+        let _client = spawn(run_proxy_with(
+            listener,
+            Arc::new(config),
+            Arc::new(stats),
+            Default::default(),
+        ));
+
+        let bind = |address: &'static str| async move {
+            let mut socks5_client = TcpStream::connect(client_addr).await.unwrap();
+            socks5_client.write_all(&[0x5, 0x1, 0x0]).await.unwrap();
+            assert_eq!(read_exact(&mut socks5_client, 2).await.unwrap(), [0x5, 0x0]);
+            ClientConnRequest {
+                cmd: Command::BIND_TCP,
+                address: address.parse().unwrap(),
+            }
+            .to_async_writer(&mut socks5_client)
+            .await
+            .unwrap();
+
+            let (code, listening) = ClientConnRequest::parse_response(&mut socks5_client)
+                .timeout(TIMEOUT)
+                .await
+                .unwrap()
+                .unwrap();
+            (socks5_client, code, listening)
+        };
+        let peer_response = |mut socks5_client: TcpStream| async move {
+            ClientConnRequest::parse_response(&mut socks5_client)
+                .timeout(TIMEOUT)
+                .await
+                .unwrap()
+                .unwrap()
+        };
+
+        // The peer connects to where the first reply says, and the second reply says who
+        let (mut socks5_client, code, listening) = bind("127.0.0.1:0").await;
+        assert_eq!(code, ConnStatusCode::GRANTED);
+        let mut peer = TcpStream::connect(listening.to_string()).await.unwrap();
+        let (code, peer_addr) = ClientConnRequest::parse_response(&mut socks5_client)
+            .timeout(TIMEOUT)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(code, ConnStatusCode::GRANTED);
+        assert_eq!(peer_addr, Address::IP(peer.local_addr().unwrap()));
+
+        socks5_client.write_all(b"to peer").await.unwrap();
+        assert_eq!(read_exact(&mut peer, 7).await.unwrap(), b"to peer");
+        peer.write_all(b"to client").await.unwrap();
+        assert_eq!(
+            read_exact(&mut socks5_client, 9).await.unwrap(),
+            b"to client"
+        );
+
+        // A named peer is resolved before it's compared
+        let (socks5_client, code, listening) = bind("localhost:0").await;
+        assert_eq!(code, ConnStatusCode::GRANTED);
+        let peer = TcpStream::connect(listening.to_string()).await.unwrap();
+        let (code, peer_addr) = peer_response(socks5_client).await;
+        assert_eq!(code, ConnStatusCode::GRANTED);
+        assert_eq!(peer_addr, Address::IP(peer.local_addr().unwrap()));
+
+        // Peers the rules reject are dropped
+        let (socks5_client, code, listening) = bind("0.0.0.0:0").await;
+        assert_eq!(code, ConnStatusCode::GRANTED);
+        let listening = match listening {
+            Address::IP(addr) => addr,
+            Address::Name { .. } => panic!("Expected an IP to connect to"),
+        };
+        let mut rejected = connect_from("127.0.0.2".parse().unwrap(), listening);
+        let peer = TcpStream::connect(listening).await.unwrap();
+        let (code, peer_addr) = peer_response(socks5_client).await;
+        assert_eq!(code, ConnStatusCode::GRANTED);
+        assert_eq!(peer_addr, Address::IP(peer.local_addr().unwrap()));
+        let mut buf = [0u8; 1];
+        assert_eq!(std::io::Read::read(&mut rejected, &mut buf).unwrap(), 0);
+
+        // So are destinations
+        let (_socks5_client, code, _) = bind("127.0.0.1:9").await;
+        assert_eq!(code, ConnStatusCode::NOT_ALLOWED);
+
+        // Nobody connects before the timeout
+        let (socks5_client, code, _) = bind("127.0.0.1:0").await;
+        assert_eq!(code, ConnStatusCode::GRANTED);
+        let (code, _) = peer_response(socks5_client).await;
+        assert_eq!(code, ConnStatusCode::TTL_EXPIRED);
+    });
+}
+
+fn connect_from(src: std::net::Ipv4Addr, dst: SocketAddr) -> std::net::TcpStream {
+    use nix::sys::socket::*;
+    use std::os::unix::io::FromRawFd;
+
+    let fd = socket(
+        AddressFamily::Inet,
+        SockType::Stream,
+        SockFlag::empty(),
+        None,
+    )
+    .unwrap();
+    bind(fd, &SockaddrIn::from(std::net::SocketAddrV4::new(src, 0))).unwrap();
+    let dst = match dst {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => panic!("Expected an IPv4 address"),
+    };
+    connect(fd, &SockaddrIn::from(dst)).unwrap();
+    unsafe { std::net::TcpStream::from_raw_fd(fd) }
+}
@@ -1,4 +1,5 @@
 use crate::io::send_to_addr;
+use crate::protocol::{direct::Direct, socks5::Socks5};
 use async_net::TcpStream;
 use smol::block_on;
 use smol_timeout::TimeoutExt;
@@ -56,3 +57,206 @@ fn test_udp() {
         assert_eq!(pkt.payload(), payload.as_ref());
     });
 }
+
+#[test]
+fn test_udp_payload_excludes_socks5_headers() {
+    block_on(async move {
+        let (_echo_server, echo_server_addr) = echo_udp_server().await;
+
+        // A SOCKS5 server with UDP relays, for the client to use as its upstream
+        let (upstream_listener, upstream_addr) = create_tcp_server().await;
+        let upstream_config = ClientConfig {
+            upstreams: hashmap! {
+                String::from("direct") => UpstreamConfig {
+                    protocol: UpstreamProtocol::Direct(Direct),
+                    enabled: true,
+                    idle_timeout_secs: None,
+                    groups: Default::default(),
+                }
+            },
+            ..Default::default()
+        };
+        let _upstream = spawn(run_proxy_with(
+            upstream_listener,
+            Arc::new(upstream_config.clone()),
+            Arc::new(ClientStatistics::new(&upstream_config)),
+            Default::default(),
+        ));
+
+        let (listener, client_addr) = create_tcp_server().await;
+        let config = ClientConfig {
+            upstreams: hashmap! {
+                String::from("socks5") => UpstreamConfig {
+                    protocol: UpstreamProtocol::Socks5(Socks5 {
+                        address: upstream_addr.into(),
+                        supports_udp: true,
+                        udp_mtu: None,
+                    }),
+                    enabled: true,
+                    idle_timeout_secs: None,
+                    groups: Default::default(),
+                }
+            },
+            ..Default::default()
+        };
+        let stats = Arc::new(ClientStatistics::new(&config));
+        let _client = spawn(run_proxy_with(
+            listener,
+            Arc::new(config),
+            stats.clone(),
+            Default::default(),
+        ));
+
+        let mut socks5_client = TcpStream::connect(client_addr).await.unwrap();
+        let mut relay_addr =
+            send_socks5_request(&mut socks5_client, &echo_server_addr.into(), true)
+                .timeout(TIMEOUT)
+                .await
+                .unwrap()
+                .unwrap();
+        set_ip_local_address(&mut relay_addr);
+
+        let socket = bind_udp(true).await.unwrap();
+        let target = echo_server_addr.into();
+        let payload = [7u8; 100];
+        // Replies are passed on with the header the upstream sent them with
+        let mut reply_headers_len = 0;
+        for _ in 0..3 {
+            let pkt = Socks5UdpRepr {
+                addr: &target,
+                payload,
+                frag_no: 0,
+            }
+            .to_packet()
+            .unwrap();
+            send_to_addr(&socket, pkt.inner().as_ref(), &relay_addr)
+                .await
+                .unwrap();
+
+            let mut buf = new_vec_for_udp();
+            let (received, _) = socket
+                .recv_from(&mut buf)
+                .timeout(TIMEOUT)
+                .await
+                .unwrap()
+                .unwrap();
+            buf.set_len_uninit(received);
+            let pkt = Socks5UdpPacket::new_checked(buf).unwrap();
+            assert_eq!(pkt.payload(), payload.as_ref());
+            reply_headers_len += received - payload.len();
+        }
+
+        // Each datagram to and from the upstream carries a SOCKS5 header on the wire
+        let header_len = Socks5UdpRepr {
+            addr: &target,
+            payload,
+            frag_no: 0,
+        }
+        .header_write_len();
+        let s = &stats.upstreams["socks5"];
+        assert_eq!(s.payload_tx.get(), 300);
+        assert_eq!(s.payload_rx.get(), 300);
+        assert_eq!(s.tx.get(), 300 + 3 * header_len);
+        assert_eq!(s.rx.get(), 300 + reply_headers_len);
+    });
+}
+
+#[test]
+fn socks5_udp_applies_client_subnet_policy_to_every_query() {
+    block_on(async move {
+        // A SOCKS5 upstream whose relay only records what it's sent
+        let (upstream_listener, upstream_addr) = create_tcp_server().await;
+        let relay = bind_udp(true).await.unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+        let _upstream = spawn(async move {
+            let (mut socks, _) = upstream_listener.accept().await.unwrap();
+            let mut buf = RWBuffer::new_vec_uninitialised(512);
+            let (hs, _) = crate::handshake::Handshaker::start(&mut socks, &mut buf)
+                .await
+                .unwrap();
+            hs.respond_ok(&mut socks, Some(relay_addr)).await.unwrap();
+            let mut buf = [0u8; 1];
+            let _ = socks.read(&mut buf).await;
+        });
+
+        let (listener, client_addr) = create_tcp_server().await;
+        let config = ClientConfig {
+            upstreams: hashmap! {
+                String::from("socks5") => UpstreamConfig {
+                    protocol: UpstreamProtocol::Socks5(Socks5 {
+                        address: upstream_addr.into(),
+                        supports_udp: true,
+                        udp_mtu: None,
+                    }),
+                    enabled: true,
+                    idle_timeout_secs: None,
+                    groups: Default::default(),
+                }
+            },
+            dns_client_subnet: crate::dns::ClientSubnetPolicy::Strip,
+            ..Default::default()
+        };
+        let _client = spawn(run_proxy_with(
+            listener,
+            Arc::new(config.clone()),
+            Arc::new(ClientStatistics::new(&config)),
+            Default::default(),
+        ));
+
+        let mut socks5_client = TcpStream::connect(client_addr).await.unwrap();
+        let mut relay_addr = send_socks5_request(
+            &mut socks5_client,
+            &"127.0.0.1:9".parse::<SocketAddr>().unwrap().into(),
+            true,
+        )
+        .timeout(TIMEOUT)
+        .await
+        .unwrap()
+        .unwrap();
+        set_ip_local_address(&mut relay_addr);
+
+        // A query for example.com with the client's subnet, 192.168.1.0/24
+        let mut query =
+            crate::dns::build_query(1, "example.com", crate::dns::DnsQueryType::A).unwrap();
+        query[11] = 1;
+        query.extend_from_slice(&[0, 0, 41, 16, 0, 0, 0, 0, 0, 0, 11]);
+        query.extend_from_slice(&[0, 8, 0, 7, 0, 1, 24, 0, 192, 168, 1]);
+        let stripped = crate::dns::rewrite_client_subnet(&query, None).unwrap();
+        assert_ne!(stripped, query);
+
+        // The first datagram isn't a query, so the relay carries on for the ones after it
+        let socket = bind_udp(true).await.unwrap();
+        let datagrams = [
+            ("127.0.0.1:9".parse::<SocketAddr>().unwrap(), query.clone()),
+            ("127.0.0.1:53".parse().unwrap(), query.clone()),
+            ("127.0.0.1:53".parse().unwrap(), query.clone()),
+        ];
+        for (dst, payload) in &datagrams {
+            let pkt = Socks5UdpRepr {
+                addr: &(*dst).into(),
+                payload,
+                frag_no: 0,
+            }
+            .to_packet()
+            .unwrap();
+            send_to_addr(&socket, pkt.inner().as_ref(), &relay_addr)
+                .await
+                .unwrap();
+        }
+
+        for (dst, _) in &datagrams {
+            let mut buf = new_vec_for_udp();
+            let (received, _) = relay
+                .recv_from(&mut buf)
+                .timeout(TIMEOUT)
+                .await
+                .unwrap()
+                .unwrap();
+            buf.set_len_uninit(received);
+            let pkt = Socks5UdpPacket::new_checked(buf).unwrap();
+            assert_eq!(pkt.addr(), Address::from(*dst));
+            let expected = if dst.port() == 53 { &stripped } else { &query };
+            assert_eq!(pkt.payload(), expected.as_slice(), "to {dst}");
+        }
+    });
+}
@@ -0,0 +1,499 @@
+use std::{
+    borrow::Cow,
+    fmt::Debug,
+    future::poll_fn,
+    io::{self, Read, Write},
+    pin::Pin,
+    sync::{Arc, OnceLock},
+    task::{Context, Poll, Wake, Waker},
+};
+
+use anyhow::{anyhow, Context as _};
+use futures::{AsyncRead, AsyncWrite};
+use native_tls::{
+    HandshakeError, Identity, MidHandshakeTlsStream, TlsConnector, TlsConnectorBuilder,
+};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::socks5::Address;
+
+// A PEM encoded certificate and PKCS#8 private key to present to servers that require
+// client authentication.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ClientIdentity {
+    pub cert: String,
+    pub key: String,
+
+    // Parsed on first use and shared by the clones
+    #[serde(skip)]
+    identity: Arc<OnceLock<Result<Identity, String>>>,
+}
+
+impl ClientIdentity {
+    pub fn new(cert: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            cert: cert.into(),
+            key: key.into(),
+            identity: Default::default(),
+        }
+    }
+
+    fn identity(&self) -> anyhow::Result<Identity> {
+        self.identity
+            .get_or_init(|| {
+                Identity::from_pkcs8(self.cert.as_bytes(), self.key.as_bytes())
+                    .map_err(|e| e.to_string())
+            })
+            .clone()
+            .map_err(|e| anyhow!("Loading client certificate: {e}"))
+    }
+}
+
+impl PartialEq for ClientIdentity {
+    fn eq(&self, other: &Self) -> bool {
+        self.cert == other.cert && self.key == other.key
+    }
+}
+
+impl Eq for ClientIdentity {}
+
+impl Debug for ClientIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientIdentity")
+            .field("cert", &self.cert)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TlsOptions<'a> {
+    pub identity: Option<&'a ClientIdentity>,
+    // The name to send as SNI and verify the certificate against, instead of the host
+    // being connected to.
+    pub sni: Option<&'a str>,
+    // The protocols to offer via ALPN, in order of preference
+    pub alpn: Option<&'a [String]>,
+}
+
+impl<'a> TlsOptions<'a> {
+    pub fn builder(&self) -> anyhow::Result<TlsConnectorBuilder> {
+        let mut builder = TlsConnector::builder();
+        if let Some(identity) = self.identity {
+            builder.identity(identity.identity()?);
+        }
+        if let Some(alpn) = self.alpn.filter(|p| !p.is_empty()) {
+            builder.request_alpns(&alpn.iter().map(String::as_str).collect::<Vec<_>>());
+        }
+        Ok(builder)
+    }
+
+    pub fn connector(&self) -> anyhow::Result<TlsConnector> {
+        self.builder()?.build().context("Creating TLS connector")
+    }
+
+    pub fn server_name<'b>(&'b self, address: &'b Address<'_>) -> Cow<'b, str> {
+        match self.sni {
+            Some(sni) => Cow::Borrowed(sni),
+            None => address.get_host(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Side {
+    Read,
+    Write,
+}
+
+// The tasks waiting to read from and write to the TLS stream. Either side can need the
+// inner stream to be read or written, so both are woken whenever it's ready.
+#[derive(Debug, Default)]
+struct SideWakers {
+    read: Mutex<Option<Waker>>,
+    write: Mutex<Option<Waker>>,
+}
+
+impl SideWakers {
+    fn set(&self, side: Side, cx: &Context<'_>) {
+        let mut waker = match side {
+            Side::Read => self.read.lock(),
+            Side::Write => self.write.lock(),
+        };
+        match &*waker {
+            Some(waker) if waker.will_wake(cx.waker()) => {}
+            _ => *waker = Some(cx.waker().clone()),
+        }
+    }
+}
+
+impl Wake for SideWakers {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        [self.read.lock().take(), self.write.lock().take()]
+            .into_iter()
+            .flatten()
+            .for_each(Waker::wake);
+    }
+}
+
+// Adapts an async stream to the blocking IO traits native-tls expects, waking the tasks
+// polling either side of the TLS stream.
+#[derive(Debug)]
+struct StdAdapter<S> {
+    inner: S,
+    wakers: Arc<SideWakers>,
+    waker: Waker,
+}
+
+impl<S: Unpin> StdAdapter<S> {
+    fn new(inner: S) -> Self {
+        let wakers: Arc<SideWakers> = Default::default();
+        Self {
+            inner,
+            waker: wakers.clone().into(),
+            wakers,
+        }
+    }
+
+    fn set_waker(&self, side: Side, cx: &Context<'_>) {
+        self.wakers.set(side, cx);
+    }
+
+    fn poll<R>(
+        &mut self,
+        f: impl FnOnce(Pin<&mut S>, &mut Context<'_>) -> Poll<io::Result<R>>,
+    ) -> io::Result<R> {
+        match f(
+            Pin::new(&mut self.inner),
+            &mut Context::from_waker(&self.waker),
+        ) {
+            Poll::Ready(r) => r,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> Read for StdAdapter<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.poll(|s, cx| s.poll_read(cx, buf))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Write for StdAdapter<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.poll(|s, cx| s.poll_write(cx, buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.poll(|s, cx| s.poll_flush(cx))
+    }
+}
+
+#[derive(Debug)]
+pub struct TlsStream<S>(native_tls::TlsStream<StdAdapter<S>>);
+
+impl<S: AsyncRead + AsyncWrite + Unpin> TlsStream<S> {
+    // The protocol agreed on via ALPN, if the server selected one
+    pub fn negotiated_alpn(&self) -> anyhow::Result<Option<Vec<u8>>> {
+        self.0
+            .negotiated_alpn()
+            .context("Reading negotiated ALPN protocol")
+    }
+
+    fn poll_io<R>(
+        &mut self,
+        side: Side,
+        cx: &mut Context<'_>,
+        f: impl FnOnce(&mut native_tls::TlsStream<StdAdapter<S>>) -> io::Result<R>,
+    ) -> Poll<io::Result<R>> {
+        self.0.get_ref().set_waker(side, cx);
+        match f(&mut self.0) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Poll::Pending,
+            r => Poll::Ready(r),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for TlsStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_io(Side::Read, cx, |s| s.read(buf))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for TlsStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_io(Side::Write, cx, |s| s.write(buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_io(Side::Write, cx, |s| s.flush())
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_io(Side::Write, cx, |s| s.shutdown())
+    }
+}
+
+// Whether the error is from failing to make a TLS connection, as opposed to using one
+pub fn is_tls_handshake_error(e: &anyhow::Error) -> bool {
+    e.chain().any(|e| e.is::<native_tls::Error>())
+}
+
+pub async fn connect_tls<T: AsyncRead + AsyncWrite + Unpin>(
+    connector: TlsConnector,
+    host: &str,
+    stream: T,
+) -> anyhow::Result<TlsStream<T>> {
+    let mut stream = Some(StdAdapter::new(stream));
+    let mut mid_handshake: Option<MidHandshakeTlsStream<StdAdapter<T>>> = None;
+
+    poll_fn(|cx| {
+        let result = match mid_handshake.take() {
+            // Only this task is polling during the handshake
+            Some(s) => {
+                s.get_ref().set_waker(Side::Read, cx);
+                s.handshake()
+            }
+            None => {
+                let s = stream.take().expect("Polled after completion");
+                s.set_waker(Side::Read, cx);
+                connector.connect(host, s)
+            }
+        };
+
+        match result {
+            Ok(s) => Poll::Ready(Ok(TlsStream(s))),
+            Err(HandshakeError::WouldBlock(s)) => {
+                mid_handshake = Some(s);
+                Poll::Pending
+            }
+            Err(HandshakeError::Failure(e)) => Poll::Ready(Err(e)),
+        }
+    })
+    .await
+    .context("TLS handshake")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_net::TcpStream;
+    use futures::{AsyncReadExt, AsyncWriteExt};
+    use native_tls::Certificate;
+    use openssl::{
+        pkey::PKey,
+        ssl::{
+            select_next_proto, AlpnError, SslAcceptor, SslAcceptorBuilder, SslMethod, SslVerifyMode,
+        },
+        x509::{store::X509StoreBuilder, X509},
+    };
+    use std::io::{Read, Write};
+
+    const CA: &str = include_str!("test/certs/ca.pem");
+    const SERVER_CERT: &str = include_str!("test/certs/server.pem");
+    const SERVER_KEY: &str = include_str!("test/certs/server.key");
+    const FRONTED_CERT: &str = include_str!("test/certs/fronted.pem");
+    const FRONTED_KEY: &str = include_str!("test/certs/fronted.key");
+    const CLIENT_CERT: &str = include_str!("test/certs/client.pem");
+    const CLIENT_KEY: &str = include_str!("test/certs/client.key");
+
+    fn acceptor_builder(cert: &str, key: &str, require_client_cert: bool) -> SslAcceptorBuilder {
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor
+            .set_certificate(&X509::from_pem(cert.as_bytes()).unwrap())
+            .unwrap();
+        acceptor
+            .set_private_key(&PKey::private_key_from_pem(key.as_bytes()).unwrap())
+            .unwrap();
+
+        if require_client_cert {
+            let mut store = X509StoreBuilder::new().unwrap();
+            store
+                .add_cert(X509::from_pem(CA.as_bytes()).unwrap())
+                .unwrap();
+            acceptor.set_verify_cert_store(store.build()).unwrap();
+            acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        }
+        acceptor
+    }
+
+    fn acceptor(cert: &str, key: &str, require_client_cert: bool) -> SslAcceptor {
+        acceptor_builder(cert, key, require_client_cert).build()
+    }
+
+    // Returns the echoed message and the negotiated ALPN protocol
+    async fn connect(
+        acceptor: SslAcceptor,
+        options: TlsOptions<'_>,
+    ) -> anyhow::Result<(Vec<u8>, Option<Vec<u8>>)> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // The server echoes a single message back
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            if let Ok(mut stream) = acceptor.accept(stream) {
+                let mut buf = [0u8; 5];
+                if stream.read_exact(&mut buf).is_ok() {
+                    let _ = stream.write_all(&buf);
+                }
+            }
+        });
+
+        let result = async {
+            let connector = options
+                .builder()?
+                .add_root_certificate(Certificate::from_pem(CA.as_bytes()).unwrap())
+                .build()?;
+            let mut stream = connect_tls(
+                connector,
+                &options.server_name(&Address::IP(addr)),
+                TcpStream::connect(addr).await?,
+            )
+            .await?;
+            stream.write_all(b"hello").await?;
+            let mut buf = vec![0u8; 5];
+            stream.read_exact(&mut buf).await?;
+            Ok((buf, stream.negotiated_alpn()?))
+        }
+        .await;
+
+        server.join().unwrap();
+        result
+    }
+
+    #[test]
+    fn client_certificate_works() {
+        smol::block_on(async move {
+            let mtls = || acceptor(SERVER_CERT, SERVER_KEY, true);
+            let identity = ClientIdentity::new(CLIENT_CERT, CLIENT_KEY);
+            let options = TlsOptions {
+                identity: Some(&identity),
+                ..Default::default()
+            };
+            assert_eq!(connect(mtls(), options).await.unwrap().0, b"hello");
+
+            // The identity is loaded once and reused
+            let identity2 = identity.clone();
+            let options = TlsOptions {
+                identity: Some(&identity2),
+                ..Default::default()
+            };
+            assert_eq!(connect(mtls(), options).await.unwrap().0, b"hello");
+            assert!(identity.identity.get().is_some());
+
+            assert!(connect(mtls(), Default::default()).await.is_err());
+            assert!(ClientIdentity::new(CLIENT_CERT, "invalid")
+                .identity()
+                .is_err());
+        });
+    }
+
+    #[test]
+    fn sni_override_works() {
+        smol::block_on(async move {
+            // The certificate is only valid for the SNI name, not the IP dialed
+            let fronted = || acceptor(FRONTED_CERT, FRONTED_KEY, false);
+            let options = TlsOptions {
+                sni: Some("fronted.example.com"),
+                ..Default::default()
+            };
+            assert_eq!(connect(fronted(), options).await.unwrap().0, b"hello");
+
+            assert!(connect(fronted(), Default::default()).await.is_err());
+
+            let options = TlsOptions {
+                sni: Some("other.example.com"),
+                ..Default::default()
+            };
+            assert!(connect(fronted(), options).await.is_err());
+        });
+    }
+
+    #[test]
+    fn halves_work_from_separate_tasks() {
+        smol::block_on(async move {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let acceptor = acceptor(SERVER_CERT, SERVER_KEY, false);
+
+            // Echoes until the client goes away
+            let server = std::thread::spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                let mut stream = acceptor.accept(stream).unwrap();
+                let mut buf = [0u8; 5];
+                while stream.read_exact(&mut buf).is_ok() {
+                    stream.write_all(&buf).unwrap();
+                }
+            });
+
+            let connector = TlsOptions::default()
+                .builder()
+                .unwrap()
+                .add_root_certificate(Certificate::from_pem(CA.as_bytes()).unwrap())
+                .build()
+                .unwrap();
+            let stream = connect_tls(
+                connector,
+                &TlsOptions::default().server_name(&Address::IP(addr)),
+                TcpStream::connect(addr).await.unwrap(),
+            )
+            .await
+            .unwrap();
+            let (mut r, mut w) = stream.split();
+
+            // The reader waits on the stream while the writer uses it
+            let reader = smol::spawn(async move {
+                let mut buf = vec![0u8; 15];
+                r.read_exact(&mut buf).await.unwrap();
+                buf
+            });
+            for msg in [b"hello", b"world", b"again"] {
+                smol::Timer::after(std::time::Duration::from_millis(20)).await;
+                w.write_all(msg).await.unwrap();
+            }
+
+            assert_eq!(reader.await, b"helloworldagain");
+            drop(w);
+            server.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn alpn_works() {
+        smol::block_on(async move {
+            let alpn_acceptor = || {
+                let mut acceptor = acceptor_builder(SERVER_CERT, SERVER_KEY, false);
+                acceptor.set_alpn_select_callback(|_, client| {
+                    select_next_proto(b"\x02h2\x08http/1.1", client).ok_or(AlpnError::NOACK)
+                });
+                acceptor.build()
+            };
+
+            let protocols = vec!["http/1.1".to_string()];
+            let options = TlsOptions {
+                alpn: Some(&protocols),
+                ..Default::default()
+            };
+            let (echo, alpn) = connect(alpn_acceptor(), options).await.unwrap();
+            assert_eq!(echo, b"hello");
+            assert_eq!(alpn.as_deref(), Some(b"http/1.1".as_slice()));
+
+            // Nothing is negotiated unless the client asks for it
+            let (_, alpn) = connect(alpn_acceptor(), Default::default()).await.unwrap();
+            assert_eq!(alpn, None);
+        });
+    }
+}
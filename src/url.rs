@@ -26,6 +26,38 @@ impl<'a> HttpUrl<'a> {
             path: Cow::Owned(self.path.to_string()),
         }
     }
+
+    // Resolves a link, such as a Location header, found at this URL
+    pub fn join(&self, link: &str) -> anyhow::Result<HttpUrl<'static>> {
+        if link.contains("://") {
+            return Ok(HttpUrl::try_from(link)?.to_owned());
+        }
+
+        let scheme = if self.is_https { "https" } else { "http" };
+        if let Some(rest) = link.strip_prefix("//") {
+            return Ok(HttpUrl::try_from(format!("{scheme}://{rest}").as_str())?.to_owned());
+        }
+
+        let path = if link.starts_with('/') {
+            link.to_string()
+        } else {
+            let dir = self.path.split(['?', '#']).next().unwrap_or_default();
+            let dir = &dir[..dir.rfind('/').map_or(0, |i| i + 1)];
+            format!("{dir}{link}")
+        };
+        Ok(HttpUrl {
+            is_https: self.is_https,
+            address: self.address.clone().into_owned(),
+            path: Cow::Owned(path),
+        })
+    }
+}
+
+impl std::fmt::Display for HttpUrl<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let scheme = if self.is_https { "https" } else { "http" };
+        write!(f, "{scheme}://{}{}", self.address, self.path)
+    }
 }
 
 impl<'a> TryFrom<&'a str> for HttpUrl<'a> {
@@ -202,4 +234,20 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn test_join() {
+        let base: super::HttpUrl = "https://example.com/lists/list.txt?v=1".try_into().unwrap();
+        let join = |link| base.join(link).unwrap().to_string();
+        assert_eq!(
+            join("latest.txt"),
+            "https://example.com:443/lists/latest.txt"
+        );
+        assert_eq!(join("/latest.txt"), "https://example.com:443/latest.txt");
+        assert_eq!(join("//cdn.example.com/a"), "https://cdn.example.com:443/a");
+        assert_eq!(
+            join("http://example.org:8080/a"),
+            "http://example.org:8080/a"
+        );
+    }
 }
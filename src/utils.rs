@@ -10,6 +10,9 @@ use std::sync::Arc;
 use std::task::Poll;
 
 use crate::counter::Counter;
+#[cfg(target_os = "linux")]
+use crate::io::{splice_duplex, SpliceEnd};
+use crate::protocol::AsyncStream;
 
 async fn copy_with_stats(
     mut r: impl AsyncRead + Unpin + Send + Sync,
@@ -60,6 +63,47 @@ pub async fn copy_duplex(
     race(task1.fuse(), task2.fuse()).await
 }
 
+// How `relay_duplex` moves the bytes between two streams
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayPath {
+    // With splice(2), as both are plain TCP sockets underneath
+    Splice,
+    Copy,
+}
+
+impl RelayPath {
+    #[cfg(target_os = "linux")]
+    pub fn of(d1: &dyn AsyncStream, d2: &dyn AsyncStream) -> Self {
+        match (SpliceEnd::of(d1), SpliceEnd::of(d2)) {
+            (Some(_), Some(_)) => Self::Splice,
+            _ => Self::Copy,
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn of(_: &dyn AsyncStream, _: &dyn AsyncStream) -> Self {
+        Self::Copy
+    }
+}
+
+// Same as copy_duplex, except that on Linux two plain TCP sockets are relayed with splice(2),
+// which keeps the bytes out of userspace
+pub async fn relay_duplex(
+    d1: impl AsyncStream,
+    d2: impl AsyncStream,
+    d1d2_count: Option<Arc<Counter>>,
+    d2d1_count: Option<Arc<Counter>>,
+) -> anyhow::Result<()> {
+    #[cfg(target_os = "linux")]
+    if let (Some(e1), Some(e2)) = (SpliceEnd::of(&d1), SpliceEnd::of(&d2)) {
+        log::debug!("Relaying with splice");
+        return splice_duplex(e1, e2, d1d2_count.as_deref(), d2d1_count.as_deref()).await;
+    }
+
+    log::debug!("Relaying by copying");
+    copy_duplex(d1, d2, d1d2_count, d2d1_count).await
+}
+
 pub fn write_bincode_lengthed(mut buf: &mut Vec<u8>, o: &impl Serialize) -> anyhow::Result<()> {
     let prev_len = buf.len();
     buf.put_u16(0);
@@ -205,4 +249,35 @@ mod test {
             assert_eq!(expected, data);
         });
     }
+
+    #[test]
+    fn relay_duplex_falls_back_to_copying() {
+        block_on(async move {
+            let (client, relay_down) = crate::test::duplex(0).await;
+            let (relay_up, mut server) = crate::test::duplex(0).await;
+            let count = Arc::new(Counter::default());
+
+            // Buffering in between means the sockets can't be spliced
+            let relay = smol::spawn(relay_duplex(
+                futures::io::BufReader::new(relay_down),
+                relay_up,
+                Some(count.clone()),
+                None,
+            ));
+
+            let mut client = client;
+            client.write_all(b"hello, world").await.unwrap();
+            let mut buf = [0u8; 12];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello, world");
+            assert_eq!(count.get(), 12);
+
+            server.write_all(b"hi").await.unwrap();
+            client.read_exact(&mut buf[..2]).await.unwrap();
+            assert_eq!(&buf[..2], b"hi");
+
+            drop(client);
+            relay.await.unwrap();
+        });
+    }
 }
@@ -0,0 +1,352 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use futures::{ready, AsyncRead, AsyncWrite};
+use pin_project_lite::pin_project;
+use serde::{Deserialize, Serialize};
+
+use crate::counter::Counter;
+
+// Not RFC 7692's permessage-deflate: the whole stream is one deflate stream, without
+// per-message framing
+pub const EXTENSION_NAME: &str = "x-deflate";
+const CLIENT_NO_CONTEXT_TAKEOVER: &str = "client_no_context_takeover";
+
+const READ_BUF_LEN: usize = 8192;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DeflateParams {
+    pub client_no_context_takeover: bool,
+}
+
+impl DeflateParams {
+    // Parses the value of a `Sec-WebSocket-Extensions` header, returning the params of the
+    // first deflate offer, if any.
+    pub fn parse(header: &str) -> Option<Self> {
+        header.split(',').find_map(|ext| {
+            let mut params = ext.split(';').map(str::trim);
+            if !params.next()?.eq_ignore_ascii_case(EXTENSION_NAME) {
+                return None;
+            }
+
+            Some(Self {
+                client_no_context_takeover: params
+                    .any(|p| p.eq_ignore_ascii_case(CLIENT_NO_CONTEXT_TAKEOVER)),
+            })
+        })
+    }
+
+    pub fn to_header_value(self) -> String {
+        if self.client_no_context_takeover {
+            format!("{EXTENSION_NAME}; {CLIENT_NO_CONTEXT_TAKEOVER}")
+        } else {
+            EXTENSION_NAME.to_string()
+        }
+    }
+}
+
+// Bytes that went through compressed streams in both directions, before and after
+// compression
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CompressionStats {
+    pub plain: Counter,
+    pub compressed: Counter,
+}
+
+impl CompressionStats {
+    // The compressed size relative to the plain size, once anything has gone through
+    pub fn ratio(&self) -> Option<f64> {
+        match self.plain.get() {
+            0 => None,
+            plain => Some(self.compressed.get() as f64 / plain as f64),
+        }
+    }
+
+    fn record(&self, plain: usize, compressed: usize) {
+        self.plain.inc(plain);
+        self.compressed.inc(compressed);
+    }
+}
+
+struct Deflater {
+    compress: Compress,
+    flush: FlushCompress,
+    decompress: Decompress,
+    // Compressed data waiting to be written to the inner stream
+    write_buf: Vec<u8>,
+    write_offset: usize,
+    read_buf: Vec<u8>,
+    read_offset: usize,
+    stats: Option<Arc<CompressionStats>>,
+}
+
+pin_project! {
+    pub struct DeflateStream<T> {
+        #[pin]
+        inner: T,
+        deflater: Option<Box<Deflater>>,
+    }
+}
+
+impl<T> DeflateStream<T> {
+    // When `reset_context` is set, every write is compressed independently of the previous ones.
+    pub fn new(inner: T, enabled: bool, reset_context: bool) -> Self {
+        let deflater = enabled.then(|| {
+            Box::new(Deflater {
+                compress: Compress::new(Compression::default(), false),
+                flush: if reset_context {
+                    FlushCompress::Full
+                } else {
+                    FlushCompress::Sync
+                },
+                decompress: Decompress::new(false),
+                write_buf: Vec::new(),
+                write_offset: 0,
+                read_buf: Vec::with_capacity(READ_BUF_LEN),
+                read_offset: 0,
+                stats: None,
+            })
+        });
+
+        Self { inner, deflater }
+    }
+
+    // Compresses as the client agreed with the server, if at all
+    pub fn for_client(inner: T, params: Option<DeflateParams>) -> Self {
+        Self::new(
+            inner,
+            params.is_some(),
+            params.is_some_and(|p| p.client_no_context_takeover),
+        )
+    }
+
+    // The server never resets its own compression context
+    pub fn for_server(inner: T, params: Option<DeflateParams>) -> Self {
+        Self::new(inner, params.is_some(), false)
+    }
+
+    // Counts what goes through the stream, if it's compressed
+    pub fn with_stats(mut self, stats: Option<Arc<CompressionStats>>) -> Self {
+        if let Some(d) = &mut self.deflater {
+            d.stats = stats;
+        }
+        self
+    }
+}
+
+fn to_io_error(e: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+impl Deflater {
+    fn compress(&mut self, mut input: &[u8]) -> io::Result<()> {
+        self.write_buf.clear();
+        self.write_offset = 0;
+        let plain_len = input.len();
+
+        loop {
+            self.write_buf.reserve(input.len() / 2 + 64);
+            let before = self.compress.total_in();
+            self.compress
+                .compress_vec(input, &mut self.write_buf, self.flush)
+                .map_err(to_io_error)?;
+            input = &input[(self.compress.total_in() - before) as usize..];
+
+            if input.is_empty() && self.write_buf.len() < self.write_buf.capacity() {
+                if let Some(stats) = &self.stats {
+                    stats.record(plain_len, self.write_buf.len());
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    fn poll_drain<T: AsyncWrite>(
+        &mut self,
+        mut inner: Pin<&mut T>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.write_offset < self.write_buf.len() {
+            match ready!(inner
+                .as_mut()
+                .poll_write(cx, &self.write_buf[self.write_offset..]))?
+            {
+                0 => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                n => self.write_offset += n,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for DeflateStream<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut this = self.project();
+        let d = match this.deflater {
+            Some(d) => d,
+            None => return this.inner.poll_read(cx, buf),
+        };
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        loop {
+            // The decompressor may still hold output from previous input, so call it
+            // even if there is no new input.
+            let (before_in, before_out) = (d.decompress.total_in(), d.decompress.total_out());
+            d.decompress
+                .decompress(&d.read_buf[d.read_offset..], buf, FlushDecompress::Sync)
+                .map_err(to_io_error)?;
+            let consumed = (d.decompress.total_in() - before_in) as usize;
+            let produced = (d.decompress.total_out() - before_out) as usize;
+            d.read_offset += consumed;
+            if let Some(stats) = &d.stats {
+                stats.record(produced, consumed);
+            }
+
+            match produced {
+                0 if d.decompress.total_in() == before_in => {}
+                0 => continue,
+                n => return Poll::Ready(Ok(n)),
+            }
+
+            // Need more input
+            if d.read_offset > 0 {
+                d.read_buf.drain(..d.read_offset);
+                d.read_offset = 0;
+            }
+
+            let len = d.read_buf.len();
+            d.read_buf.resize(len + READ_BUF_LEN, 0);
+            let rc = this.inner.as_mut().poll_read(cx, &mut d.read_buf[len..]);
+            let n = match rc {
+                Poll::Ready(Ok(n)) => n,
+                v => {
+                    d.read_buf.truncate(len);
+                    return v.map_ok(|_| 0);
+                }
+            };
+            d.read_buf.truncate(len + n);
+
+            if n == 0 {
+                return Poll::Ready(Ok(0));
+            }
+        }
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for DeflateStream<T> {
+    // A write is accepted whole once it's compressed. What the inner stream doesn't take
+    // right away is written out by the next write, flush or close.
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut this = self.project();
+        let d = match this.deflater {
+            Some(d) => d,
+            None => return this.inner.poll_write(cx, buf),
+        };
+
+        ready!(d.poll_drain(this.inner.as_mut(), cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        d.compress(buf)?;
+        if let Poll::Ready(Err(e)) = d.poll_drain(this.inner, cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        if let Some(d) = this.deflater {
+            ready!(d.poll_drain(this.inner.as_mut(), cx))?;
+        }
+        this.inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        if let Some(d) = this.deflater {
+            ready!(d.poll_drain(this.inner.as_mut(), cx))?;
+        }
+        this.inner.poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::ChoppyWriter;
+    use futures::{task::noop_waker, AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn pending_writes_never_overreport() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut stream = DeflateStream::new(ChoppyWriter::default(), true, false);
+
+        let long = vec![b'a'; 4096];
+        assert!(matches!(
+            Pin::new(&mut stream).poll_write(&mut cx, &long),
+            Poll::Ready(Ok(4096))
+        ));
+        // The compressed output of the first write is still pending
+        let mut short_written = false;
+        for _ in 0..100 {
+            match Pin::new(&mut stream).poll_write(&mut cx, b"bb") {
+                Poll::Ready(Ok(n)) => {
+                    assert_eq!(n, 2);
+                    short_written = true;
+                    break;
+                }
+                Poll::Ready(Err(e)) => panic!("{e:?}"),
+                Poll::Pending => {}
+            }
+        }
+        assert!(short_written);
+
+        smol::block_on(async move {
+            stream.write_all(b"ccc").await.unwrap();
+            stream.flush().await.unwrap();
+
+            let mut expected = long;
+            expected.extend_from_slice(b"bbccc");
+            let mut reader =
+                DeflateStream::new(futures::io::Cursor::new(stream.inner.written), true, false);
+            let mut received = Vec::new();
+            reader.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, expected);
+        });
+    }
+
+    #[test]
+    fn parse_params_works() {
+        assert_eq!(
+            DeflateParams::parse("x-deflate"),
+            Some(DeflateParams::default())
+        );
+        assert_eq!(
+            DeflateParams::parse("permessage-deflate, x-deflate; client_no_context_takeover"),
+            Some(DeflateParams {
+                client_no_context_takeover: true
+            })
+        );
+        assert_eq!(DeflateParams::parse("permessage-deflate"), None);
+        assert_eq!(DeflateParams::parse(""), None);
+    }
+}
@@ -0,0 +1,467 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use async_io::Timer;
+use futures::{ready, AsyncRead, AsyncWrite};
+use pin_project_lite::pin_project;
+
+pub const EXTENSION_NAME: &str = "x-keepalive";
+
+// Frame structure:
+// |opcode(u8)|payload_len(u16, big endian)|payload|
+const HEADER_LEN: usize = 3;
+const OP_DATA: u8 = 0x2;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+const READ_BUF_LEN: usize = 8192;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepAlive {
+    pub interval: Duration,
+    pub max_missed_pongs: usize,
+}
+
+impl KeepAlive {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            max_missed_pongs: 3,
+        }
+    }
+
+    // The client offers its interval and the server pings at the same one
+    pub fn to_header_value(self) -> String {
+        format!(
+            "{EXTENSION_NAME}; interval_ms={}",
+            self.interval.as_millis()
+        )
+    }
+
+    pub fn parse(header: &str) -> Option<Self> {
+        header.split(',').find_map(|ext| {
+            let mut params = ext.split(';').map(str::trim);
+            if !params.next()?.eq_ignore_ascii_case(EXTENSION_NAME) {
+                return None;
+            }
+
+            params
+                .filter_map(|p| p.split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("interval_ms"))
+                .and_then(|(_, v)| v.trim().parse().ok())
+                .filter(|ms| *ms > 0)
+                .map(|ms| Self::new(Duration::from_millis(ms)))
+        })
+    }
+}
+
+struct KeepAliveState {
+    config: KeepAlive,
+    deadline: Instant,
+    // Only wakes the read side, the write side checks `deadline` as it goes
+    timer: Timer,
+    awaiting_pong: bool,
+    missed_pongs: usize,
+}
+
+impl KeepAliveState {
+    fn reset(&mut self, deadline: Instant) {
+        self.deadline = deadline;
+        self.timer.set_at(deadline);
+    }
+}
+
+struct FrameState {
+    keepalive: Option<KeepAliveState>,
+    dead: bool,
+
+    // Encoded frames waiting to be written to the inner stream
+    write_buf: Vec<u8>,
+    write_offset: usize,
+
+    read_buf: Vec<u8>,
+    read_offset: usize,
+    data_remaining: usize,
+}
+
+pin_project! {
+    // Wraps the data in frames so that ping/pong frames can be interleaved with it.
+    pub struct KeepAliveStream<T> {
+        #[pin]
+        inner: T,
+        state: Option<Box<FrameState>>,
+    }
+}
+
+fn dead_error() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "Peer stopped responding to pings")
+}
+
+impl<T> KeepAliveStream<T> {
+    // Without framing the stream is passed through as is. With framing, pings are only
+    // sent if `keepalive` is given, while pings from the peer are always answered.
+    pub fn new(inner: T, framed: bool, keepalive: Option<KeepAlive>) -> Self {
+        let state = framed.then(|| {
+            Box::new(FrameState {
+                keepalive: keepalive.map(|config| KeepAliveState {
+                    config,
+                    deadline: Instant::now() + config.interval,
+                    timer: Timer::after(config.interval),
+                    awaiting_pong: false,
+                    missed_pongs: 0,
+                }),
+                dead: false,
+                write_buf: Vec::new(),
+                write_offset: 0,
+                read_buf: Vec::with_capacity(READ_BUF_LEN),
+                read_offset: 0,
+                data_remaining: 0,
+            })
+        });
+
+        Self { inner, state }
+    }
+}
+
+impl FrameState {
+    fn push_frame(&mut self, op: u8, payload: &[u8]) {
+        self.write_buf.push(op);
+        self.write_buf
+            .extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        self.write_buf.extend_from_slice(payload);
+    }
+
+    fn poll_drain<T: AsyncWrite>(
+        &mut self,
+        mut inner: Pin<&mut T>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.write_offset < self.write_buf.len() {
+            match ready!(inner
+                .as_mut()
+                .poll_write(cx, &self.write_buf[self.write_offset..]))?
+            {
+                0 => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                n => self.write_offset += n,
+            }
+        }
+
+        self.write_buf.clear();
+        self.write_offset = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    fn mark_alive(&mut self) {
+        if let Some(ka) = &mut self.keepalive {
+            ka.awaiting_pong = false;
+            ka.missed_pongs = 0;
+            ka.reset(Instant::now() + ka.config.interval);
+        }
+    }
+
+    // Queues a ping if one is due, or fails if the peer has missed too many
+    fn check_keepalive(&mut self) -> io::Result<()> {
+        let ka = match &mut self.keepalive {
+            Some(ka) => ka,
+            None => return Ok(()),
+        };
+
+        let now = Instant::now();
+        if now < ka.deadline {
+            return Ok(());
+        }
+
+        if ka.awaiting_pong {
+            ka.missed_pongs += 1;
+            if ka.missed_pongs >= ka.config.max_missed_pongs {
+                self.dead = true;
+                return Err(dead_error());
+            }
+        }
+
+        ka.awaiting_pong = true;
+        ka.reset(now + ka.config.interval);
+        self.push_frame(OP_PING, &[]);
+        Ok(())
+    }
+
+    // As `check_keepalive`, but also arranges for `cx` to be woken when the next ping is due
+    fn poll_keepalive(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        self.check_keepalive()?;
+        while let Some(ka) = &mut self.keepalive {
+            if Pin::new(&mut ka.timer).poll(cx).is_pending() {
+                break;
+            }
+            self.check_keepalive()?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite> AsyncRead for KeepAliveStream<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut this = self.project();
+        let s = match this.state {
+            Some(s) => s,
+            None => return this.inner.poll_read(cx, buf),
+        };
+
+        if s.dead {
+            return Poll::Ready(Err(dead_error()));
+        }
+
+        s.poll_keepalive(cx)?;
+
+        loop {
+            // Control frames are sent from here as the read side is always polled
+            if let Poll::Ready(Err(e)) = s.poll_drain(this.inner.as_mut(), cx) {
+                return Poll::Ready(Err(e));
+            }
+
+            let available = &s.read_buf[s.read_offset..];
+            if s.data_remaining > 0 && !available.is_empty() {
+                let len = buf.len().min(s.data_remaining).min(available.len());
+                buf[..len].copy_from_slice(&available[..len]);
+                s.read_offset += len;
+                s.data_remaining -= len;
+                return Poll::Ready(Ok(len));
+            }
+
+            if s.data_remaining == 0 && available.len() >= HEADER_LEN {
+                let op = available[0];
+                let len = u16::from_be_bytes([available[1], available[2]]) as usize;
+                match op {
+                    OP_DATA => {
+                        s.read_offset += HEADER_LEN;
+                        s.data_remaining = len;
+                        s.mark_alive();
+                        continue;
+                    }
+                    OP_PING | OP_PONG if available.len() >= HEADER_LEN + len => {
+                        s.read_offset += HEADER_LEN + len;
+                        s.mark_alive();
+                        if op == OP_PING {
+                            s.push_frame(OP_PONG, &[]);
+                        }
+                        continue;
+                    }
+                    OP_PING | OP_PONG => {}
+                    op => {
+                        return Poll::Ready(Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("Invalid frame opcode: {op}"),
+                        )))
+                    }
+                }
+            }
+
+            // Need more input
+            if s.read_offset > 0 {
+                s.read_buf.drain(..s.read_offset);
+                s.read_offset = 0;
+            }
+
+            let len = s.read_buf.len();
+            s.read_buf.resize(len + READ_BUF_LEN, 0);
+            let rc = this.inner.as_mut().poll_read(cx, &mut s.read_buf[len..]);
+            let n = match rc {
+                Poll::Ready(Ok(n)) => n,
+                v => {
+                    s.read_buf.truncate(len);
+                    return v.map_ok(|_| 0);
+                }
+            };
+            s.read_buf.truncate(len + n);
+
+            if n == 0 {
+                return Poll::Ready(Ok(0));
+            }
+        }
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for KeepAliveStream<T> {
+    // A write is accepted once it's framed. What the inner stream doesn't take right away
+    // is written out by the next write, flush or close.
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut this = self.project();
+        let s = match this.state {
+            Some(s) => s,
+            None => return this.inner.poll_write(cx, buf),
+        };
+
+        if s.dead {
+            return Poll::Ready(Err(dead_error()));
+        }
+
+        // Pings go out ahead of the data when the read side isn't being polled
+        s.check_keepalive()?;
+
+        ready!(s.poll_drain(this.inner.as_mut(), cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let len = buf.len().min(u16::MAX as usize);
+        s.push_frame(OP_DATA, &buf[..len]);
+        if let Poll::Ready(Err(e)) = s.poll_drain(this.inner, cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        if let Some(s) = this.state {
+            ready!(s.poll_drain(this.inner.as_mut(), cx))?;
+        }
+        this.inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        if let Some(s) = this.state {
+            ready!(s.poll_drain(this.inner.as_mut(), cx))?;
+        }
+        this.inner.poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        io::connect_tcp,
+        test::{create_tcp_server, ChoppyWriter},
+    };
+    use futures::{AsyncReadExt, AsyncWriteExt, FutureExt};
+    use smol_timeout::TimeoutExt;
+    use std::time::Instant;
+
+    const INTERVAL: Duration = Duration::from_millis(50);
+
+    #[test]
+    fn pending_writes_never_overreport() {
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut stream = KeepAliveStream::new(ChoppyWriter::default(), true, None);
+
+        assert!(matches!(
+            Pin::new(&mut stream).poll_write(&mut cx, &[b'a'; 100]),
+            Poll::Ready(Ok(100))
+        ));
+        // The first frame is still pending
+        let mut short_written = false;
+        for _ in 0..100 {
+            match Pin::new(&mut stream).poll_write(&mut cx, b"bb") {
+                Poll::Ready(Ok(n)) => {
+                    assert_eq!(n, 2);
+                    short_written = true;
+                    break;
+                }
+                Poll::Ready(Err(e)) => panic!("{e:?}"),
+                Poll::Pending => {}
+            }
+        }
+        assert!(short_written);
+
+        smol::block_on(stream.flush()).unwrap();
+        let mut expected = vec![OP_DATA, 0, 100];
+        expected.extend_from_slice(&[b'a'; 100]);
+        expected.extend_from_slice(&[OP_DATA, 0, 2, b'b', b'b']);
+        assert_eq!(stream.inner.written, expected);
+    }
+
+    #[test]
+    fn keepalive_works() {
+        smol::block_on(async move {
+            let (server, addr) = create_tcp_server().await;
+            let client = connect_tcp(&addr.into()).await.unwrap();
+            let (mut peer, _) = server.accept().await.unwrap();
+
+            let mut client = KeepAliveStream::new(client, true, Some(KeepAlive::new(INTERVAL)));
+            let mut buf = [0u8; 16];
+
+            // Pings are sent while idle
+            assert!(client.read(&mut buf).timeout(INTERVAL * 2).await.is_none());
+            let mut frame = [0u8; HEADER_LEN];
+            peer.read_exact(&mut frame).await.unwrap();
+            assert_eq!(frame, [OP_PING, 0, 0]);
+
+            // Answering the pings keeps the connection alive and data flows as usual
+            let mut answer = vec![OP_PONG, 0, 0];
+            answer.extend_from_slice(&[OP_DATA, 0, 5]);
+            answer.extend_from_slice(b"hello");
+            peer.write_all(&answer).await.unwrap();
+            assert_eq!(client.read(&mut buf).await.unwrap(), 5);
+            assert_eq!(&buf[..5], b"hello");
+
+            client.write_all(b"world").await.unwrap();
+            let mut frame = [OP_PING, 0, 0];
+            while frame == [OP_PING, 0, 0] {
+                peer.read_exact(&mut frame).await.unwrap();
+            }
+            assert_eq!(frame, [OP_DATA, 0, 5]);
+            let mut data = [0u8; 5];
+            peer.read_exact(&mut data).await.unwrap();
+            assert_eq!(&data, b"world");
+
+            // A peer that stops answering causes the stream to close
+            let started = Instant::now();
+            let err = client
+                .read(&mut buf)
+                .timeout(INTERVAL * 10)
+                .await
+                .expect("No timeout")
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+            assert!(started.elapsed() >= INTERVAL * 3);
+            assert!(client.write_all(b"data").now_or_never().unwrap().is_err());
+        });
+    }
+
+    #[test]
+    fn pings_are_sent_while_only_writing() {
+        smol::block_on(async move {
+            let (server, addr) = create_tcp_server().await;
+            let client = connect_tcp(&addr.into()).await.unwrap();
+            let (mut peer, _) = server.accept().await.unwrap();
+
+            let mut client = KeepAliveStream::new(client, true, Some(KeepAlive::new(INTERVAL)));
+            Timer::after(INTERVAL * 2).await;
+            client.write_all(b"hello").await.unwrap();
+
+            let mut frames = [0u8; HEADER_LEN * 2 + 5];
+            peer.read_exact(&mut frames).await.unwrap();
+            assert_eq!(&frames[..HEADER_LEN], [OP_PING, 0, 0]);
+            assert_eq!(&frames[HEADER_LEN..HEADER_LEN * 2], [OP_DATA, 0, 5]);
+            assert_eq!(&frames[HEADER_LEN * 2..], b"hello");
+        });
+    }
+
+    #[test]
+    fn header_round_trip_works() {
+        let ka = KeepAlive::new(Duration::from_millis(1500));
+        assert_eq!(KeepAlive::parse(&ka.to_header_value()), Some(ka));
+        assert_eq!(
+            KeepAlive::parse("permessage-deflate, x-keepalive; interval_ms=50"),
+            Some(KeepAlive::new(Duration::from_millis(50)))
+        );
+        assert_eq!(KeepAlive::parse("permessage-deflate"), None);
+        assert_eq!(KeepAlive::parse("x-keepalive"), None);
+        assert_eq!(KeepAlive::parse("x-keepalive; interval_ms=0"), None);
+    }
+}
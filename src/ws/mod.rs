@@ -0,0 +1,325 @@
+mod deflate;
+mod keepalive;
+
+use anyhow::{anyhow, bail, Context};
+use futures::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    buf::RWBuffer,
+    http::{
+        parse_request, parse_response, AsyncHttpStream, HttpRequest, HttpRequestBuilder,
+        WithHeaders,
+    },
+    socks5::ConnStatusCode,
+};
+
+pub use deflate::{CompressionStats, DeflateParams, DeflateStream};
+pub use keepalive::{KeepAlive, KeepAliveStream};
+
+const EXTENSIONS_HEADER: &str = "Sec-WebSocket-Extensions";
+
+// Compression is only agreed on here. It's up to the caller to put a `DeflateStream` on top,
+// above any encryption, as ciphertext doesn't compress.
+pub async fn negotiate_websocket(
+    mut builder: HttpRequestBuilder,
+    mut stream: impl AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    deflate: Option<DeflateParams>,
+    keepalive: Option<KeepAlive>,
+) -> anyhow::Result<(
+    impl AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    Option<DeflateParams>,
+)> {
+    builder
+        .put_header_text("Connection", "Upgrade")?
+        .put_header_text("Upgrade", "Websocket")?
+        .put_header_text("Sec-WebSocket-Version", "13")?
+        .put_header_text("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")?;
+
+    let mut extensions = Vec::new();
+    if let Some(params) = deflate {
+        extensions.push(params.to_header_value());
+    }
+    if let Some(keepalive) = &keepalive {
+        extensions.push(keepalive.to_header_value());
+    }
+    if !extensions.is_empty() {
+        builder.put_header_text(EXTENSIONS_HEADER, extensions.join(", "))?;
+    }
+
+    stream
+        .write_all(&builder.finalise())
+        .await
+        .context("Sending request")?;
+
+    let mut http_stream = parse_response(stream, RWBuffer::new_vec_uninitialised(512))
+        .await
+        .context("Parsing initial response")?;
+
+    let status_code = http_stream.status_code;
+    if status_code != 101 {
+        let err = anyhow!(
+            "Expecting 101 response but got {}. Body: {:?}",
+            status_code,
+            http_stream
+                .body()
+                .await
+                .ok()
+                .and_then(|v| String::from_utf8(v).ok())
+        );
+        // The server got as far as trying the destination
+        return Err(match status_code {
+            500..=599 => anyhow::Error::new(ConnStatusCode::HOST_UNREACHABLE).context(err),
+            _ => err,
+        });
+    }
+
+    // Only use the extensions we asked for and the server agreed to
+    let accepted = http_stream
+        .get_header_text(EXTENSIONS_HEADER)
+        .unwrap_or_default();
+    let deflate = deflate.and(DeflateParams::parse(accepted));
+    let framed = keepalive.is_some() && KeepAlive::parse(accepted).is_some();
+
+    Ok((
+        KeepAliveStream::new(http_stream, framed, keepalive),
+        deflate,
+    ))
+}
+
+pub struct WebSocketServeResult<T> {
+    _sec_key: String,
+    deflate: Option<DeflateParams>,
+    keepalive: Option<KeepAlive>,
+    stream: AsyncHttpStream<HttpRequest<'static>, T>,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> WebSocketServeResult<T> {
+    // A keepalive the client offers is honoured, pinging the client at the interval it asked
+    // for. As with `negotiate_websocket`, compression is left to the caller.
+    pub async fn respond_success(
+        mut self,
+    ) -> anyhow::Result<(
+        KeepAliveStream<AsyncHttpStream<HttpRequest<'static>, T>>,
+        Option<DeflateParams>,
+    )> {
+        let mut accepted = Vec::new();
+        if let Some(params) = self.deflate {
+            accepted.push(params.to_header_value());
+        }
+        if let Some(keepalive) = &self.keepalive {
+            accepted.push(keepalive.to_header_value());
+        }
+
+        let extensions = match accepted.is_empty() {
+            true => Default::default(),
+            false => format!("{EXTENSIONS_HEADER}: {}\r\n", accepted.join(", ")),
+        };
+
+        self.stream
+            .write_all(
+                format!(
+                    "HTTP/1.1 101 Switching Protocols\r\n\
+                Upgrade: WebSocket\r\n\
+                Connection: Upgrade\r\n\
+                Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\
+                {extensions}\
+                \r\n"
+                )
+                .as_bytes(),
+            )
+            .await?;
+
+        Ok((
+            KeepAliveStream::new(self.stream, self.keepalive.is_some(), self.keepalive),
+            self.deflate,
+        ))
+    }
+
+    pub async fn respond_fail_with_raw_response(
+        mut self,
+        http_response: &[u8],
+    ) -> anyhow::Result<()> {
+        self.stream.write_all(http_response).await?;
+        Ok(())
+    }
+
+    pub fn request(&self) -> &HttpRequest<'static> {
+        &self.stream
+    }
+
+    // Answers without compression, whatever the client offered
+    pub fn decline_compression(&mut self) {
+        self.deflate = None;
+    }
+}
+
+pub async fn serve_websocket<T: AsyncRead + AsyncWrite + Unpin + Send + Sync>(
+    stream: T,
+) -> anyhow::Result<WebSocketServeResult<T>> {
+    let mut req = parse_request(stream, RWBuffer::new_vec_uninitialised(512))
+        .await
+        .map_err(|(e, _)| e)?;
+
+    if !req.method.eq_ignore_ascii_case("get") {
+        bail!("Expecting GET method but got {}", req.method);
+    }
+
+    let websocket_key = req.get_header_text("sec-websocket-key").unwrap_or_default();
+
+    if req
+        .get_header_text("connection")
+        .unwrap_or_default()
+        .eq_ignore_ascii_case("upgrade")
+        && req
+            .get_header_text("upgrade")
+            .unwrap_or_default()
+            .eq_ignore_ascii_case("websocket")
+        && req
+            .get_header_text("sec-websocket-version")
+            .unwrap_or_default()
+            .eq_ignore_ascii_case("13")
+        && !websocket_key.is_empty()
+    {
+        return Ok(WebSocketServeResult {
+            _sec_key: websocket_key.to_string(),
+            deflate: req
+                .get_header_text(EXTENSIONS_HEADER)
+                .and_then(DeflateParams::parse),
+            keepalive: req
+                .get_header_text(EXTENSIONS_HEADER)
+                .and_then(KeepAlive::parse),
+            stream: req,
+        });
+    }
+
+    req.write_all(b"HTTP/1.1 404 Not found\r\n\r\n").await?;
+    bail!("Invalid websocket parameters");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        counter::Counter,
+        io::{connect_tcp, AsyncStreamCounter},
+        test::create_tcp_server,
+    };
+    use futures::{io::copy, AsyncReadExt};
+    use smol::spawn;
+    use smol_timeout::TimeoutExt;
+    use std::{sync::Arc, time::Duration};
+
+    async fn round_trip(
+        client_deflate: Option<DeflateParams>,
+        compression_stats: Option<Arc<CompressionStats>>,
+    ) -> usize {
+        let (server, addr) = create_tcp_server().await;
+        let _server_task = spawn(async move {
+            let (stream, _) = server.accept().await.unwrap();
+            let (stream, deflate) = serve_websocket(stream)
+                .await
+                .unwrap()
+                .respond_success()
+                .await
+                .unwrap();
+            let (r, mut w) = DeflateStream::for_server(stream, deflate).split();
+            copy(r, &mut w).await.unwrap();
+        });
+
+        let tx: Arc<Counter> = Default::default();
+        let stream = AsyncStreamCounter::new(
+            connect_tcp(&addr.into()).await.unwrap(),
+            Default::default(),
+            tx.clone(),
+        );
+
+        let (stream, deflate) = negotiate_websocket(
+            HttpRequestBuilder::new("GET", "/").unwrap(),
+            stream,
+            client_deflate,
+            None,
+        )
+        .await
+        .unwrap();
+        let (mut r, mut w) = DeflateStream::for_client(stream, deflate)
+            .with_stats(compression_stats)
+            .split();
+
+        let payload = b"hello, world! ".repeat(10000);
+        for _ in 0..2 {
+            let mut received = vec![0u8; payload.len()];
+            let (write, read) = futures::join!(w.write_all(&payload), r.read_exact(&mut received));
+            write.unwrap();
+            read.unwrap();
+            assert_eq!(payload, received);
+        }
+
+        tx.get()
+    }
+
+    #[test]
+    fn server_honours_client_keepalive() {
+        smol::block_on(async move {
+            let (server, addr) = create_tcp_server().await;
+            let server_task = spawn(async move {
+                let (stream, _) = server.accept().await.unwrap();
+                let (mut stream, _) = serve_websocket(stream)
+                    .await
+                    .unwrap()
+                    .respond_success()
+                    .await
+                    .unwrap();
+                let mut buf = [0u8; 16];
+                stream.read(&mut buf).await
+            });
+
+            let interval = Duration::from_millis(50);
+            let _client = negotiate_websocket(
+                HttpRequestBuilder::new("GET", "/").unwrap(),
+                connect_tcp(&addr.into()).await.unwrap(),
+                None,
+                Some(KeepAlive::new(interval)),
+            )
+            .await
+            .unwrap();
+
+            // The client never reads, so the server's pings go unanswered
+            let err = server_task
+                .timeout(interval * 10)
+                .await
+                .expect("Server to give up on the client")
+                .unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        });
+    }
+
+    #[test]
+    fn deflate_round_trip_works() {
+        smol::block_on(async move {
+            let payload_len = b"hello, world! ".len() * 10000 * 2;
+
+            let stats: Arc<CompressionStats> = Default::default();
+            let plain = round_trip(None, Some(stats.clone())).await;
+            assert!(plain > payload_len);
+            // Nothing is counted when the server doesn't compress
+            assert_eq!(stats.ratio(), None);
+
+            for client_no_context_takeover in [false, true] {
+                let stats: Arc<CompressionStats> = Default::default();
+                let compressed = round_trip(
+                    Some(DeflateParams {
+                        client_no_context_takeover,
+                    }),
+                    Some(stats.clone()),
+                )
+                .await;
+                assert!(compressed < payload_len / 10);
+
+                // Sent and received
+                assert_eq!(stats.plain.get(), payload_len * 2);
+                let ratio = stats.ratio().unwrap();
+                assert!(ratio < 1.0, "{ratio}");
+            }
+        });
+    }
+}
@@ -64,6 +64,7 @@ export type UpstreamStatistics = {
     rx: number,
     last_activity: number,
     last_latency: number,
+    connections: number,
 }
 
 export type ClientStatistics = {